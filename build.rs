@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Embeds the git commit and the date of the build, returned by /__version__.
+// The commit can be given with the GIT_COMMIT variable when the sources are
+// built outside of their repository, eg. in a container, and the date with
+// SOURCE_DATE_EPOCH for reproducible builds. The version of the latest
+// migration of the database backend lets /readyz tell whether the database
+// was migrated.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git_commit() -> String {
+    if let Ok(commit) = env::var("GIT_COMMIT") {
+        return commit;
+    }
+    Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// Formats a Unix time as an RFC 3339 UTC date, eg. 2018-04-17T08:19:47Z.
+fn format_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn build_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0)
+        })
+}
+
+// Returns the version diesel gives to the latest migration, eg. 20180717100000
+// for `2018-07-17-100000_add_oidc_subject_to_accounts`.
+fn latest_migration() -> String {
+    let backend = ["mysql", "postgres", "sqlite"]
+        .iter()
+        .find(|backend| env::var(format!("CARGO_FEATURE_{}", backend.to_uppercase())).is_ok());
+    let backend = match backend {
+        Some(backend) => backend,
+        None => return String::new(),
+    };
+    fs::read_dir(format!("migrations/{}", backend))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter_map(|name| {
+                    name.split('_')
+                        .next()
+                        .map(|version| version.replace('-', ""))
+                })
+                .max()
+                .unwrap_or_default()
+        })
+        .unwrap_or_default()
+}
+
+fn main() {
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=BUILD_DATE={}", format_date(build_time()));
+    println!(
+        "cargo:rustc-env=BUILD_LATEST_MIGRATION={}",
+        latest_migration()
+    );
+}
@@ -0,0 +1 @@
+ALTER TABLE domains DROP COLUMN warning_sent_at;
@@ -0,0 +1,8 @@
+CREATE TABLE transfers (
+    id         INTEGER AUTO_INCREMENT PRIMARY KEY NOT NULL,
+    domain_id  INTEGER NOT NULL UNIQUE,
+    code       VARCHAR(36) NOT NULL UNIQUE,
+    expires_at BIGINT NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX transfers_code ON transfers(code);
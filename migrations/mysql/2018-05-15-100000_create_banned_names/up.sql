@@ -0,0 +1,6 @@
+CREATE TABLE banned_names (
+    id        INTEGER AUTO_INCREMENT PRIMARY KEY NOT NULL,
+    name      VARCHAR(253) NOT NULL UNIQUE,
+    banned_at BIGINT NOT NULL);
+
+CREATE UNIQUE INDEX banned_names_name ON banned_names(name);
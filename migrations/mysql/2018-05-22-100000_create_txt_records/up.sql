@@ -0,0 +1,7 @@
+CREATE TABLE txt_records (
+    id        INTEGER AUTO_INCREMENT PRIMARY KEY NOT NULL,
+    domain_id INTEGER NOT NULL,
+    value     VARCHAR(255) NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX txt_records_domain_id ON txt_records(domain_id, value);
@@ -0,0 +1,2 @@
+ALTER TABLE domains DROP COLUMN mx_priority;
+ALTER TABLE domains DROP COLUMN mx_target;
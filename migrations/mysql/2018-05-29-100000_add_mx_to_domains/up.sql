@@ -0,0 +1,2 @@
+ALTER TABLE domains ADD COLUMN mx_target VARCHAR(253) NOT NULL DEFAULT '';
+ALTER TABLE domains ADD COLUMN mx_priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP INDEX srv_records_domain_id;
+DROP TABLE srv_records;
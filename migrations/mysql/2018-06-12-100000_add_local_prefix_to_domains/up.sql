@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN local_prefix VARCHAR(63) NOT NULL DEFAULT '';
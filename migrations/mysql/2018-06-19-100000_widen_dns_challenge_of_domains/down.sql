@@ -0,0 +1 @@
+ALTER TABLE domains MODIFY dns_challenge VARCHAR(63) NOT NULL DEFAULT '';
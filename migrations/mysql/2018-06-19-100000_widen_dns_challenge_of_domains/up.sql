@@ -0,0 +1 @@
+ALTER TABLE domains MODIFY dns_challenge VARCHAR(255) NOT NULL DEFAULT '';
@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN device_token VARCHAR(36) NOT NULL DEFAULT '';
@@ -0,0 +1,11 @@
+CREATE TABLE audit_events (
+    id         INTEGER AUTO_INCREMENT PRIMARY KEY NOT NULL,
+    name       VARCHAR(253) NOT NULL,
+    action     VARCHAR(63) NOT NULL,
+    actor      VARCHAR(63) NOT NULL,
+    client_ip  VARCHAR(45) NOT NULL,
+    request_id VARCHAR(128) NOT NULL,
+    details    TEXT NOT NULL,
+    created_at BIGINT NOT NULL);
+
+CREATE INDEX audit_events_name ON audit_events(name);
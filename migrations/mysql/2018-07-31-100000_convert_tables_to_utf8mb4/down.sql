@@ -0,0 +1,3 @@
+-- The previous character set isn't known, and converting back could lose
+-- characters, so the tables are left in utf8mb4.
+SELECT 1;
@@ -0,0 +1,10 @@
+CREATE TABLE deleted_domains (
+    id         INTEGER AUTO_INCREMENT PRIMARY KEY NOT NULL,
+    name       VARCHAR(253) NOT NULL UNIQUE,
+    token      VARCHAR(36) NOT NULL,
+    record     TEXT NOT NULL,
+    deleted_at BIGINT NOT NULL)
+    CHARACTER SET utf8mb4 COLLATE utf8mb4_bin;
+
+CREATE UNIQUE INDEX deleted_domains_name ON deleted_domains(name);
+CREATE INDEX deleted_domains_token ON deleted_domains(token);
@@ -0,0 +1,5 @@
+DROP INDEX domains_created_at ON domains;
+DROP INDEX domains_verification_token ON domains;
+DROP INDEX domains_reclamation_token ON domains;
+DROP INDEX domains_device_token ON domains;
+DROP INDEX domains_token ON domains;
@@ -0,0 +1,3 @@
+DROP INDEX discovery_tokens_domain_id ON discovery_tokens;
+DROP INDEX discovery_tokens_token ON discovery_tokens;
+DROP TABLE discovery_tokens;
@@ -0,0 +1 @@
+ALTER TABLE discovery_tokens ADD COLUMN expires_at BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN local_ips VARCHAR(1024) NOT NULL DEFAULT '';
@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN wildcard BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE domains DROP COLUMN challenge_set_at;
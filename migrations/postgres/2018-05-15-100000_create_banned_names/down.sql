@@ -0,0 +1,2 @@
+DROP INDEX banned_names_name;
+DROP TABLE banned_names;
@@ -0,0 +1,2 @@
+DROP INDEX txt_records_domain_id;
+DROP TABLE txt_records;
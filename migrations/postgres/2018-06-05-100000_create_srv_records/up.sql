@@ -0,0 +1,11 @@
+CREATE TABLE srv_records (
+    id        SERIAL PRIMARY KEY NOT NULL,
+    domain_id INTEGER NOT NULL,
+    service   VARCHAR(15) NOT NULL,
+    protocol  VARCHAR(3) NOT NULL,
+    priority  INTEGER NOT NULL,
+    weight    INTEGER NOT NULL,
+    port      INTEGER NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX srv_records_domain_id ON srv_records(domain_id, service, protocol);
@@ -0,0 +1 @@
+ALTER TABLE domains ALTER COLUMN dns_challenge TYPE VARCHAR(255);
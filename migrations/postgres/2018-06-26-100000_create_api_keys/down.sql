@@ -0,0 +1,2 @@
+DROP INDEX api_keys_key_hash;
+DROP TABLE api_keys;
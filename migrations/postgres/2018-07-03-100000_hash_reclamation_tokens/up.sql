@@ -0,0 +1,3 @@
+-- The reclamation tokens are now stored hashed. The pending ones were sent
+-- in plain text, so they are dropped and have to be requested again.
+UPDATE domains SET reclamation_token = '';
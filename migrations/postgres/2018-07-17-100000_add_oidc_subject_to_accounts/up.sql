@@ -0,0 +1 @@
+ALTER TABLE accounts ADD COLUMN oidc_subject VARCHAR(255) NOT NULL DEFAULT '';
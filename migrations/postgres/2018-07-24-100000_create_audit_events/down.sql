@@ -0,0 +1,2 @@
+DROP INDEX audit_events_name;
+DROP TABLE audit_events;
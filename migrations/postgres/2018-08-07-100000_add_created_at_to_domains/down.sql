@@ -0,0 +1,2 @@
+ALTER TABLE domains DROP COLUMN updated_at;
+ALTER TABLE domains DROP COLUMN created_at;
@@ -0,0 +1,4 @@
+-- The existing domains only have the time of their last ping.
+ALTER TABLE domains ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE domains ADD COLUMN updated_at BIGINT NOT NULL DEFAULT 0;
+UPDATE domains SET created_at = timestamp, updated_at = timestamp;
@@ -0,0 +1 @@
+ALTER TABLE discovery_tokens DROP COLUMN expires_at;
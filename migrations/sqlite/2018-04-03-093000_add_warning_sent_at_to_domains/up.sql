@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN warning_sent_at BIGINT NOT NULL DEFAULT 0;
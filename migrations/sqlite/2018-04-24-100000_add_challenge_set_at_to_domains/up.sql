@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN challenge_set_at BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN auth_key VARCHAR(64) NOT NULL DEFAULT '';
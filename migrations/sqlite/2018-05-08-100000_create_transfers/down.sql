@@ -0,0 +1,2 @@
+DROP INDEX transfers_code;
+DROP TABLE transfers;
@@ -0,0 +1,10 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE transfers (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id  INTEGER NOT NULL UNIQUE,
+    code       VARCHAR(36) NOT NULL UNIQUE,
+    expires_at BIGINT NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX transfers_code ON transfers(code);
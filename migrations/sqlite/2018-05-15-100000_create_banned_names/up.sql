@@ -0,0 +1,6 @@
+CREATE TABLE banned_names (
+    id        INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name      VARCHAR(253) NOT NULL UNIQUE,
+    banned_at BIGINT NOT NULL);
+
+CREATE UNIQUE INDEX banned_names_name ON banned_names(name);
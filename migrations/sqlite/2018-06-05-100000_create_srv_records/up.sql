@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE srv_records (
+    id        INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id INTEGER NOT NULL,
+    service   VARCHAR(15) NOT NULL,
+    protocol  VARCHAR(3) NOT NULL,
+    priority  INTEGER NOT NULL,
+    weight    INTEGER NOT NULL,
+    port      INTEGER NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX srv_records_domain_id ON srv_records(domain_id, service, protocol);
@@ -0,0 +1,7 @@
+CREATE TABLE api_keys (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name       VARCHAR(63) NOT NULL UNIQUE,
+    key_hash   VARCHAR(64) NOT NULL,
+    created_at BIGINT NOT NULL);
+
+CREATE UNIQUE INDEX api_keys_key_hash ON api_keys(key_hash);
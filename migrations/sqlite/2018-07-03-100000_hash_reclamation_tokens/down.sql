@@ -0,0 +1,3 @@
+-- The dropped reclamation tokens can't be restored, and the hashed ones
+-- don't work without their hashing.
+UPDATE domains SET reclamation_token = '';
@@ -0,0 +1,22 @@
+CREATE TABLE domains_new AS SELECT
+    id,
+    name,
+    account_id,
+    token,
+    description,
+    timestamp,
+    dns_challenge,
+    reclamation_token,
+    verification_token,
+    verified,
+    continent,
+    local_ips,
+    warning_sent_at,
+    wildcard,
+    challenge_set_at,
+    auth_key,
+    mx_target,
+    mx_priority,
+    local_prefix FROM domains;
+DROP TABLE domains;
+ALTER TABLE domains_new RENAME TO domains;
@@ -0,0 +1,8 @@
+CREATE TABLE accounts_new (
+    id    INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    email VARCHAR(254) NOT NULL UNIQUE);
+INSERT INTO accounts_new SELECT id, email FROM accounts;
+DROP INDEX accounts_email;
+DROP TABLE accounts;
+ALTER TABLE accounts_new RENAME TO accounts;
+CREATE UNIQUE INDEX accounts_email ON accounts(email);
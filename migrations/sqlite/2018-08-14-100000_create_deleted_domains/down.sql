@@ -0,0 +1,3 @@
+DROP INDEX deleted_domains_token;
+DROP INDEX deleted_domains_name;
+DROP TABLE deleted_domains;
@@ -0,0 +1,9 @@
+CREATE TABLE deleted_domains (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name       VARCHAR(253) NOT NULL UNIQUE,
+    token      VARCHAR(36) NOT NULL,
+    record     TEXT NOT NULL,
+    deleted_at BIGINT NOT NULL);
+
+CREATE UNIQUE INDEX deleted_domains_name ON deleted_domains(name);
+CREATE INDEX deleted_domains_token ON deleted_domains(token);
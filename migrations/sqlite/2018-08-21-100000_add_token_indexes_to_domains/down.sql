@@ -0,0 +1,5 @@
+DROP INDEX domains_created_at;
+DROP INDEX domains_verification_token;
+DROP INDEX domains_reclamation_token;
+DROP INDEX domains_device_token;
+DROP INDEX domains_token;
@@ -0,0 +1,5 @@
+CREATE INDEX domains_token ON domains(token);
+CREATE INDEX domains_device_token ON domains(device_token);
+CREATE INDEX domains_reclamation_token ON domains(reclamation_token);
+CREATE INDEX domains_verification_token ON domains(verification_token);
+CREATE INDEX domains_created_at ON domains(created_at);
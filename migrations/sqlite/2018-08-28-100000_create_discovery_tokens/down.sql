@@ -0,0 +1,3 @@
+DROP INDEX discovery_tokens_domain_id;
+DROP INDEX discovery_tokens_token;
+DROP TABLE discovery_tokens;
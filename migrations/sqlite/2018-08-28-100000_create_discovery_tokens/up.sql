@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE discovery_tokens (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id    INTEGER NOT NULL,
+    token        VARCHAR(64) NOT NULL,
+    label        VARCHAR(63) NOT NULL,
+    created_at   BIGINT NOT NULL,
+    last_used_at BIGINT NOT NULL DEFAULT 0,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX discovery_tokens_token ON discovery_tokens(token);
+CREATE INDEX discovery_tokens_domain_id ON discovery_tokens(domain_id);
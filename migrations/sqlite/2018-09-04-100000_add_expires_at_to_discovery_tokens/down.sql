@@ -0,0 +1,15 @@
+CREATE TABLE discovery_tokens_new (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id    INTEGER NOT NULL,
+    token        VARCHAR(64) NOT NULL,
+    label        VARCHAR(63) NOT NULL,
+    created_at   BIGINT NOT NULL,
+    last_used_at BIGINT NOT NULL DEFAULT 0,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+INSERT INTO discovery_tokens_new
+    SELECT id, domain_id, token, label, created_at, last_used_at FROM discovery_tokens;
+DROP TABLE discovery_tokens;
+ALTER TABLE discovery_tokens_new RENAME TO discovery_tokens;
+
+CREATE UNIQUE INDEX discovery_tokens_token ON discovery_tokens(token);
+CREATE INDEX discovery_tokens_domain_id ON discovery_tokens(domain_id);
@@ -0,0 +1,13 @@
+-- The hashed transfer codes don't work without their hashing, so the pending
+-- ones are dropped.
+PRAGMA foreign_keys = ON;
+DROP TABLE transfers;
+
+CREATE TABLE transfers (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id  INTEGER NOT NULL UNIQUE,
+    code       VARCHAR(36) NOT NULL UNIQUE,
+    expires_at BIGINT NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX transfers_code ON transfers(code);
@@ -0,0 +1,14 @@
+-- The transfer codes are now stored hashed, which needs a longer column. The
+-- pending ones were stored in plain text, so they are dropped and the owners
+-- have to ask for new ones.
+PRAGMA foreign_keys = ON;
+DROP TABLE transfers;
+
+CREATE TABLE transfers (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    domain_id  INTEGER NOT NULL UNIQUE,
+    code       VARCHAR(64) NOT NULL UNIQUE,
+    expires_at BIGINT NOT NULL,
+    FOREIGN KEY(domain_id) REFERENCES domains(id) ON UPDATE CASCADE ON DELETE CASCADE);
+
+CREATE UNIQUE INDEX transfers_code ON transfers(code);
@@ -3,7 +3,7 @@
 set -e
 
 for db_type in mysql postgres sqlite; do
-    for database in domain_db_test_domains domain_db_test_email domain_db_test_pdns domain_db_test_routes; do
+    for database in domain_db_test_domains domain_db_test_email domain_db_test_pdns domain_db_test_routes domain_db_test_post_routes domain_db_test_malformed domain_db_test_acme domain_db_test_status domain_db_test_ipv6 domain_db_test_local domain_db_test_rate_limits domain_db_test_eviction_warnings domain_db_test_concurrency domain_db_test_soa domain_db_test_cors domain_db_test_metrics domain_db_test_wildcard domain_db_test_dump domain_db_test_cache domain_db_test_reload domain_db_test_signed domain_db_test_versions domain_db_test_resources domain_db_test_checkname domain_db_test_transfer domain_db_test_ban domain_db_test_list domain_db_test_txt domain_db_test_mx domain_db_test_srv domain_db_test_captcha domain_db_test_device_token domain_db_test_oidc domain_db_test_signed_requests domain_db_test_parameter_limits domain_db_test_health domain_db_test_access_log domain_db_test_audit domain_db_test_undelete domain_db_test_eviction_policy domain_db_test_export domain_db_test_discovery; do
         if [ "${db_type}" = "mysql" ]; then
             db_path="mysql://root@127.0.0.1/${database}"
             mysql -uroot -e "drop database ${database}" >/dev/null 2>&1 || true
@@ -0,0 +1,399 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Routes used to operate the server. They require the admin token from the
+// configuration, or one of the API keys created with `--create-api-key`, sent
+// as an "Authorization: Bearer <token>" header. The API keys have a name, so
+// that each deployment tool can get its own key and have it rotated without
+// sharing the admin token. The requests of the API socket don't need them.
+
+use api_socket::LocalRequest;
+use config::Config;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use diesel;
+use dump::{export, to_csv};
+use errors::*;
+use eviction::now;
+use iron::headers::{Authorization, Bearer, ContentType};
+use iron::prelude::*;
+use iron::status::Status;
+use iron::BeforeMiddleware;
+use logging::token_hash;
+use models::{AuditEvent, AuditFilter};
+use parameters::Parameters;
+use routes::{domain_for_name, validate_name, NameError, Revocation};
+use serde_json;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct LastSeen {
+    pub hour: i64,
+    pub day: i64,
+    pub week: i64,
+    pub month: i64,
+}
+
+// The number of audit events returned by default, and at most, by a request.
+const AUDIT_PAGE_SIZE: u64 = 100;
+const MAX_AUDIT_PAGE_SIZE: u64 = 1000;
+
+// A page of audit events. `next` is the `after` value of the following page,
+// and is left out on the last page.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<i32>,
+}
+
+// Only aggregated values are reported here, never tokens or emails.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ServerStatus {
+    pub domains: i64,
+    pub accounts: i64,
+    pub last_seen: LastSeen,
+    // The domains subscribed in each period, rather than those that pinged.
+    pub created: LastSeen,
+}
+
+// Returns the hash stored for an API key.
+fn api_key_hash(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(key);
+    hasher.result_str()
+}
+
+// Creates the API key with this name, or replaces it with a new one, and
+// returns the key. It is only shown here, as the database stores its hash.
+pub fn create_api_key(name: &str, config: &Config) -> Result<String, String> {
+    if name.is_empty() || name.len() > 63 {
+        return Err(format!("Invalid API key name: {:?}", name));
+    }
+
+    let conn = config.db.get_connection()?;
+    let key = format!("{}", Uuid::new_v4());
+    conn.set_api_key(name, &api_key_hash(&key), now())
+        .map_err(|err| format!("Failed to store the API key: {:?}", err))?;
+    Ok(key)
+}
+
+pub fn is_admin(req: &Request, config: &Config) -> bool {
+    let general = &config.options.general;
+    // The clients of the API socket need a token or a key too, as a local
+    // proxy could forward anyone to it.
+    if general.admin_socket_only && !req.extensions.contains::<LocalRequest>() {
+        return false;
+    }
+
+    let token = match req.headers.get::<Authorization<Bearer>>() {
+        Some(&Authorization(Bearer { ref token })) if !token.is_empty() => token,
+        _ => return false,
+    };
+
+    if let Some(ref admin_token) = general.admin_token {
+        if !admin_token.is_empty() && fixed_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+            return true;
+        }
+    }
+
+    // The keys are looked up by hash, so the lookup doesn't leak the keys
+    // through its timing.
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("is_admin(): Failed to get database connection: {:?}", err);
+            return false;
+        }
+    };
+    match conn.get_api_key_by_hash(&api_key_hash(token)) {
+        Ok(key) => {
+            info!("is_admin(): Using the API key {}", key.name);
+            true
+        }
+        Err(diesel::result::Error::NotFound) => false,
+        Err(err) => {
+            error!("is_admin(): Failed to look up the API key: {:?}", err);
+            false
+        }
+    }
+}
+
+// Turns away the requests without the admin token or an API key. It is linked
+// before the handlers of the admin routes.
+pub struct RequireAdmin {
+    config: Config,
+}
+
+impl RequireAdmin {
+    pub fn new(config: &Config) -> Self {
+        RequireAdmin {
+            config: config.clone(),
+        }
+    }
+}
+
+impl BeforeMiddleware for RequireAdmin {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if is_admin(req, &self.config.current()) {
+            return Ok(());
+        }
+        error!(
+            "{} /{}: Missing or invalid admin token",
+            req.method,
+            req.url.path().join("/")
+        );
+        EndpointError::with(ApiError::Unauthorized).map(|_| ())
+    }
+}
+
+pub fn status(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "status(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    macro_rules! count {
+        ($query:expr) => (
+            match $query {
+                Ok(count) => count,
+                Err(err) => {
+                    error!("status(): Failed to count records: {:?}", err);
+                    return EndpointError::with(ApiError::DatabaseFailure);
+                }
+            }
+        )
+    }
+
+    let status = ServerStatus {
+        domains: count!(conn.count_domains()),
+        accounts: count!(conn.count_accounts()),
+        last_seen: LastSeen {
+            hour: count!(conn.count_domains_since(now - 3600)),
+            day: count!(conn.count_domains_since(now - 86400)),
+            week: count!(conn.count_domains_since(now - 7 * 86400)),
+            month: count!(conn.count_domains_since(now - 30 * 86400)),
+        },
+        created: LastSeen {
+            hour: count!(conn.count_domains_created_since(now - 3600)),
+            day: count!(conn.count_domains_created_since(now - 86400)),
+            week: count!(conn.count_domains_created_since(now - 7 * 86400)),
+            month: count!(conn.count_domains_created_since(now - 30 * 86400)),
+        },
+    };
+
+    json_response!(&status)
+}
+
+// Returns the domain named by the "name" parameter of an admin request.
+pub fn domain_param(params: &Parameters, config: &Config) -> Result<String, ApiError> {
+    let name = params.get_string("name")?;
+    match validate_name(&name, &config.options.general.domain, &[]) {
+        Ok(label) => Ok(domain_for_name(&label, config)),
+        Err(NameError::Invalid) => Err(ApiError::InvalidName),
+        Err(NameError::Reserved) => Err(ApiError::ReservedName),
+    }
+}
+
+// Reads the optional criteria of /admin/audit. The token is hashed like the
+// actors of the events, and the addresses are normalized.
+fn audit_filter(params: &Parameters, config: &Config) -> Result<AuditFilter, ApiError> {
+    let name = match params.get_optional_string("name")? {
+        Some(_) => Some(domain_param(params, config)?),
+        None => None,
+    };
+    let actor = match params.get_optional_string("token")? {
+        Some(token) => Some(token_hash(&token)),
+        None => params.get_optional_string("actor")?,
+    };
+    let client_ip = match params.get_optional_string("ip")? {
+        Some(ip) => match ip.parse::<IpAddr>() {
+            Ok(ip) => Some(ip.to_string()),
+            Err(_) => {
+                error!("Invalid value for parameter ip: {}", ip);
+                return Err(ApiError::InvalidParameter);
+            }
+        },
+        None => None,
+    };
+    let limit = params.get_optional_u64("limit")?.unwrap_or(AUDIT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_AUDIT_PAGE_SIZE {
+        error!("Invalid value for parameter limit: {}", limit);
+        return Err(ApiError::InvalidParameter);
+    }
+    let after = params.get_optional_u64("after")?.unwrap_or(0);
+    if after > i32::max_value() as u64 {
+        error!("Invalid value for parameter after: {}", after);
+        return Err(ApiError::InvalidParameter);
+    }
+    Ok(AuditFilter {
+        name: name,
+        actor: actor,
+        client_ip: client_ip,
+        since: params.get_optional_u64("since")?.map(|since| since as i64),
+        until: params.get_optional_u64("until")?.map(|until| until as i64),
+        after: after as i32,
+        limit: limit as i64,
+    })
+}
+
+// Removes a domain, eg. one used for abuse, along with its email address
+// unless other domains use it. The name can be subscribed again.
+pub fn admin_revoke(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "admin_revoke(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /admin/revoke {:?}", req.method, params);
+
+    let full_name = try_param!(domain_param(&params, config));
+    let result = conn
+        .get_domain_by_name(&full_name)
+        .and_then(|record| conn.revoke_domain_by_token(&record.token));
+    match result {
+        Ok(accounts) => {
+            config.dns_cache.forget_name(&full_name);
+            json_response!(&Revocation { accounts: accounts })
+        }
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::NoSuchName),
+        Err(err) => {
+            error!("admin_revoke(): Failed to revoke domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Lists the changes made to the domains, the oldest first, optionally only
+// those of a domain, token, address or time range. They are kept after the
+// domain is removed, for the abuse investigations.
+pub fn admin_audit(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "admin_audit(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /admin/audit {:?}", req.method, params);
+
+    let filter = try_param!(audit_filter(&params, config));
+    match conn.find_audit_events(&filter) {
+        Ok(events) => {
+            // A full page may be followed by other events.
+            let next = if events.len() as i64 == filter.limit {
+                events.last().map(|event| event.id)
+            } else {
+                None
+            };
+            json_response!(&AuditPage {
+                events: events,
+                next: next,
+            })
+        }
+        Err(err) => {
+            error!("admin_audit(): Failed to get the audit events: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Exports all the domains as a JSON dump, like the `dump` binary, or as CSV
+// with `format=csv`. Their secrets are left out unless `tokens=true`.
+pub fn admin_export(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "admin_export(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /admin/export {:?}", req.method, params);
+
+    let csv = match try_param!(params.get_optional_string("format")) {
+        Some(ref format) if format == "csv" => true,
+        Some(ref format) if format != "json" => {
+            error!("admin_export(): Invalid format: {}", format);
+            return EndpointError::with(ApiError::InvalidParameter);
+        }
+        _ => false,
+    };
+    let tokens = match params.get_bool("tokens") {
+        Ok(tokens) => tokens,
+        Err(ApiError::MissingParameter) => false,
+        Err(err) => return EndpointError::with(err),
+    };
+
+    let dump = match export(&conn, !tokens) {
+        Ok(dump) => dump,
+        Err(err) => {
+            error!("admin_export(): Failed to export the domains: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+    if !csv {
+        return json_response!(&dump);
+    }
+    let mut response = Response::with((Status::Ok, to_csv(&dump)));
+    response
+        .headers
+        .set_raw("Content-Type", vec![b"text/csv; charset=utf-8".to_vec()]);
+    Ok(response)
+}
+
+// Removes the domain with this name, if any, and keeps the name from being
+// subscribed again. Once removed, the domain is no longer served by PowerDNS.
+pub fn admin_ban(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "admin_ban(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /admin/ban {:?}", req.method, params);
+
+    let full_name = try_param!(domain_param(&params, config));
+    match conn.ban_name(&full_name, now()) {
+        Ok(_) => {
+            config.dns_cache.forget_name(&full_name);
+            ok_response!()
+        }
+        Err(err) => {
+            error!("admin_ban(): Failed to ban name: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
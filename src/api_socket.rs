@@ -0,0 +1,177 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Serves the HTTP API on a Unix socket, next to the TCP listeners, for the
+// operators' tools. The admin routes still need the admin token or an API key
+// there. With `admin_socket_only`, the admin routes are only served here.
+
+extern crate env_logger;
+use hyper;
+use hyper::net::{NetworkListener, NetworkStream};
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::BeforeMiddleware;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// The address reported for the clients of the socket. It isn't a loopback
+// address, which would be one of the default trusted proxies and let any local
+// client set its address with the forwarding headers.
+fn local_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+pub struct ApiSocketStream(UnixStream);
+
+impl Clone for ApiSocketStream {
+    fn clone(&self) -> Self {
+        ApiSocketStream(
+            self.0
+                .try_clone()
+                .expect("Failed to clone the socket stream"),
+        )
+    }
+}
+
+impl Read for ApiSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for ApiSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for ApiSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(local_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
+
+// The listener of the socket, shared by the threads of the server.
+#[derive(Clone)]
+pub struct ApiSocketListener(Arc<UnixListener>);
+
+impl ApiSocketListener {
+    // Binds the socket, replacing the one left by a previous run.
+    pub fn bind(path: &str) -> io::Result<Self> {
+        if Path::new(path).exists() {
+            fs::remove_file(path)?;
+        }
+        UnixListener::bind(path).map(ApiSocketListener::from)
+    }
+}
+
+// Serves a socket that is already bound, eg. by systemd.
+impl From<UnixListener> for ApiSocketListener {
+    fn from(listener: UnixListener) -> Self {
+        ApiSocketListener(Arc::new(listener))
+    }
+}
+
+impl NetworkListener for ApiSocketListener {
+    type Stream = ApiSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<ApiSocketStream> {
+        let (stream, _) = self.0.accept()?;
+        Ok(ApiSocketStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(local_addr())
+    }
+}
+
+// Set on the requests received on the socket.
+pub struct LocalRequest;
+
+impl Key for LocalRequest {
+    type Value = ();
+}
+
+// Marks the requests of the socket. It is linked before the handlers of the
+// socket server only.
+pub struct MarkLocalRequests;
+
+impl BeforeMiddleware for MarkLocalRequests {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<LocalRequest>(());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_api_socket() {
+    use iron::status;
+    use iron::{Chain, Iron, Protocol};
+    use proxy::is_trusted_proxy;
+    use std::env;
+    use std::thread;
+
+    let _ = env_logger::init();
+
+    let path = env::temp_dir().join("registration_server_test_api.sock");
+    let path = path.to_str().unwrap();
+    // A stale socket is replaced.
+    fs::write(path, b"").unwrap();
+    let listener = ApiSocketListener::bind(path).unwrap();
+
+    let mut chain = Chain::new(|req: &mut Request| -> IronResult<Response> {
+        let local = req.extensions.contains::<LocalRequest>();
+        Ok(Response::with((
+            status::Ok,
+            format!(
+                "{} {} {} {}",
+                req.method,
+                req.url.path().join("/"),
+                local,
+                req.remote_addr.ip()
+            ),
+        )))
+    });
+    chain.link_before(MarkLocalRequests);
+    thread::spawn(move || {
+        Iron::new(chain).listen(listener, Protocol::http()).unwrap();
+    });
+
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream
+        .write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let _ = stream.shutdown(Shutdown::Both);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("GET status true 0.0.0.0"));
+    assert!(!is_trusted_proxy(
+        local_addr().ip(),
+        &["127.0.0.1".to_owned(), "::1".to_owned()]
+    ));
+
+    fs::remove_file(path).unwrap();
+}
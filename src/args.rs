@@ -4,38 +4,97 @@
 
 extern crate env_logger;
 use clap::{App, ArgMatches};
-use config::{Args, Continent, EmailOptions, GeneralOptions, GeoIp, PdnsOptions};
+use config::{Args, Continent, EmailOptions, EvictionPolicy, GeneralOptions, GeoIp, PdnsOptions};
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 use toml;
 
 const USAGE: &str = "--config-file=[path]     'Path to a toml configuration file.'
+--create-api-key=[name]         'Create the API key with this name for the admin routes, or replace it, print it and exit.'
+--import=[path]                 'Import the domains of a dump file before starting, leaving out those whose name or token is already registered.'
 --host=[host]                   'Set local hostname.'
 --http-port=[port]              'Set port to listen on for HTTP connections (0 to turn off).'
 --https-port=[port]             'Set port to listen on for TLS connections (0 to turn off).'
+--metrics-port=[port]           'Set port to serve the Prometheus metrics on (0 to turn off).'
+--statsd-host=[host]            'StatsD or DogStatsD server to also send the metrics to (turned off if not set).'
+--statsd-port=[port]            'UDP port of the StatsD server.'
+--statsd-prefix=[prefix]        'Prefix of the names of the metrics sent to StatsD.'
+--statsd-tags                   'Send the route, status and result of the metrics as DogStatsD tags instead of in their names.'
 --domain=[domain]               'The domain that will be tied to this registration server.'
 --db-path=[path]                'The database path: file path, postgres://..., mysql://...'
+--db-pool-size=[n]              'Maximum number of database connections, shared by the API threads, PowerDNS and the eviction (0 for 10).'
+--db-timeout=[secs]             'How long a request waits for a database connection before failing, in seconds (0 for 30).'
+--slow-query-ms=[ms]            'Log a warning for the database queries slower than this, in milliseconds (0 to turn off).'
+--slow-request-ms=[ms]          'Log a warning for the API requests slower than this, in milliseconds (0 to turn off).'
+--eviction-delay=[secs]         'Delay after which a domain that stopped pinging expires, in seconds (0 to turn off).'
+--eviction-warning-delay=[secs] 'Delay after which the owner of a domain that stopped pinging is warned by email, in seconds (0 to turn off).'
+--eviction-policy=[policy]      'What happens to the domains that stopped pinging for longer than the eviction delay: never, soft to stop resolving them until they ping again, or hard to also remove them.'
+--undelete-grace=[secs]         'How long an unsubscribed domain can be restored with /undelete before its name is freed, in seconds (0 to turn off).'
+--reserved-names=[names]        'Comma separated list of names that can not be subscribed, in addition to api, www, mail, ns1 and ns2.'
+--reserved-names-file=[path]    'File listing more names that can not be subscribed, one per line.'
+--disable-get-routes            'Only accept POST requests on the endpoints that modify a domain.'
+--legacy-status-codes           'Answer the errors like older versions, for older clients: 400 instead of 404 and 409, 501 instead of 500, without the error code.'
+--cors-origins=[origins]        'Comma separated list of the origins of the web pages allowed to call the API, * for any.'
+--trusted-proxies=[addresses]   'Comma separated list of the addresses of the reverse proxies trusted to forward the client address.'
+--admin-token=[token]           'Token required to access the /status endpoint (turned off if not set).'
+--sentry-dsn=[dsn]              'DSN of the Sentry project receiving the internal errors and the panics (turned off if not set).'
+--max-subscribes-per-hour=[n]   'Maximum number of /subscribe requests per client IP and hour (0 to turn off).'
+--max-emails-per-hour=[n]       'Maximum number of /reclaim and /setemail requests per client IP and hour (0 to turn off).'
+--captcha-secret=[secret]       'Secret of the hCaptcha or reCAPTCHA site, to require a CAPTCHA solution when subscribing a new name (turned off if not set).'
+--captcha-verify-url=[url]      'URL of the CAPTCHA verification API, eg. https://www.google.com/recaptcha/api/siteverify for reCAPTCHA.'
+--oidc-client-id=[id]           'Client ID registered with the OpenID Connect provider, to let the owners link their domains to an account (turned off if not set).'
+--oidc-client-secret=[secret]   'Client secret registered with the OpenID Connect provider.'
+--oidc-authorize-url=[url]      'URL of the authorization endpoint of the OpenID Connect provider.'
+--oidc-token-url=[url]          'URL of the token endpoint of the OpenID Connect provider.'
+--oidc-userinfo-url=[url]       'URL of the userinfo endpoint of the OpenID Connect provider.'
+--oidc-redirect-url=[url]       'URL of the /oidc/callback endpoint as seen by the browsers, registered with the OpenID Connect provider.'
 --identity-directory=[dir]      'Identity directory.'
 --identity-password=[password]  'Identity password.'
+--tls-certificate=[path]        'PEM certificate chain of the TLS listener, used instead of the identity along with --tls-key.'
+--tls-key=[path]                'PEM private key of the TLS listener, in the PKCS#8 or RSA format.'
+--acme-webroot=[dir]            'Directory of the ACME HTTP-01 challenges, served under /.well-known/acme-challenge/ (turned off if not set).'
+--shutdown-timeout=[secs]       'How long the server waits for the requests being handled when stopping, in seconds.'
+--api-socket-path=[path]        'Path of a Unix socket also serving the API, for local tools (turned off if not set).'
+--admin-socket-only             'Only serve the admin routes on the API socket.'
+--keep-alive-timeout=[secs]     'How long an idle API connection is kept open for the next request, in seconds (0 to turn off keep-alive).'
+--server-threads=[n]            'Number of threads handling the API connections, each serving one connection at a time (0 for 8 per CPU).'
+--json-logs                     'Write the logs as JSON records, with a record of each API request.'
+--access-log=[path]             'File the records of the API requests are appended to, instead of the other logs.'
+--disable-access-log            'Turn off the records of the API requests.'
+--require-signed-pings          'Reject the pings of the domains subscribed without a signing secret.'
+--require-signed-requests       'Reject the /ping, /dnsconfig and /clearchallenge requests without a valid X-Signature header.'
+--nonce-skew=[secs]             'How far the nonce of a signed ping can be from the server time, in seconds.'
+--min-ping-interval=[secs]      'Pings closer than this to the previous one of the domain are not saved, unless they change it, in seconds (0 to turn off).'
+--transfer-code-ttl=[secs]      'How long the code of a domain transfer can be redeemed, in seconds.'
+--local-prefix=[label]          'The label of the local names of the domains, eg. local in local.<name>.<domain>, unless a domain sets its own.'
 --dns-ttl=[ttl]                 'TTL of the SOA/MX/TXT/CAA DNS records, in seconds.'
 --api-ttl=[ttl]                 'TTL of the DNS records for the api subdomain, in seconds.'
 --tunnel-ttl=[ttl]              'TTL of the DNS records for tunnels, in seconds.'
---soa-content=[dns]             'The content of the SOA record for this tunnel.'
+--challenge-ttl=[ttl]           'TTL of the DNS-01 challenge TXT records, and of the addresses of a domain during a challenge, in seconds.'
+--challenge-window=[secs]       'How long the addresses of a domain use the challenge TTL after a challenge is set, in seconds.'
+--cache-ttl=[secs]              'How long the domains looked up for PowerDNS are cached, in seconds (0 to turn off).'
+--negative-cache-ttl=[secs]     'How long the unknown names looked up for PowerDNS are cached, in seconds (0 to turn off).'
+--soa-content=[dns]             'The primary name server and hostmaster email of the SOA record.'
+--soa-refresh=[secs]            'The refresh interval of the SOA record, in seconds.'
+--soa-retry=[secs]              'The retry interval of the SOA record, in seconds.'
+--soa-expire=[secs]             'The expiration delay of the SOA record, in seconds.'
+--soa-minimum=[secs]            'The negative caching TTL of the SOA record, in seconds.'
+--name-servers=[names]          'Comma separated list of the name servers of the domain.'
 --socket-path=[path]            'The path to the socket used to communicate with PowerDNS.'
 --mx-record=[record]            'The MX record the PowerDNS server should return.'
 --caa-record=[record]           'The CAA record the PowerDNS server should return.'
 --txt-record=[record]           'The TXT record the PowerDNS server should return.'
 --psl-record=[record]           'The TXT record used to authenticate against the Public Suffix List.'
---geoip-default=[ip]            'The IP address of the default tunnel endpoint.'
+--geoip-default=[ip]            'Comma separated IPv4 and IPv6 addresses of the default tunnel endpoint.'
 --geoip-database=[path]         'Path to the GeoIP2/GeoLite2 database.'
---geoip-continent-af=[ip]       'The IP address of the tunnel endpoint for Africa.'
---geoip-continent-an=[ip]       'The IP address of the tunnel endpoint for Antarctica.'
---geoip-continent-as=[ip]       'The IP address of the tunnel endpoint for Asia.'
---geoip-continent-eu=[ip]       'The IP address of the tunnel endpoint for Europe.'
---geoip-continent-na=[ip]       'The IP address of the tunnel endpoint for North America.'
---geoip-continent-oc=[ip]       'The IP address of the tunnel endpoint for Oceania.'
---geoip-continent-sa=[ip]       'The IP address of the tunnel endpoint for South America.'
+--geoip-continent-af=[ip]       'The IP addresses of the tunnel endpoint for Africa.'
+--geoip-continent-an=[ip]       'The IP addresses of the tunnel endpoint for Antarctica.'
+--geoip-continent-as=[ip]       'The IP addresses of the tunnel endpoint for Asia.'
+--geoip-continent-eu=[ip]       'The IP addresses of the tunnel endpoint for Europe.'
+--geoip-continent-na=[ip]       'The IP addresses of the tunnel endpoint for North America.'
+--geoip-continent-oc=[ip]       'The IP addresses of the tunnel endpoint for Oceania.'
+--geoip-continent-sa=[ip]       'The IP addresses of the tunnel endpoint for South America.'
 --email-server=[name]           'The name of the SMTP server.'
 --email-user=[username]         'The username to authenticate with.'
 --email-password=[pass]         'The password for this email account.'
@@ -44,18 +103,50 @@ const USAGE: &str = "--config-file=[path]     'Path to a toml configuration file
 --reclamation-body=[s]          'The body of the domain reclamation email.'
 --confirmation-title=[s]        'The title of the confirmation email.'
 --confirmation-body=[s]         'The body of the confirmation email.'
+--expiration-title=[s]          'The title of the domain expiration warning email.'
+--expiration-body=[s]           'The body of the domain expiration warning email.'
 --success-page=[s]              'HTML content of the email confirmation success page.'
 --error-page=[s]                'HTML content of the email confirmation error page.'";
 
 pub struct ArgsParser;
 
 impl ArgsParser {
-    fn from_file(path: &PathBuf) -> Args {
-        let mut file = File::open(path).expect("Can't open config file");
+    // Gets the args from a toml configuration file.
+    pub fn from_file(path: &PathBuf) -> Args {
+        match ArgsParser::try_from_file(path) {
+            Ok(args) => args,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Like from_file, for a running server that must not stop on errors.
+    pub fn try_from_file(path: &PathBuf) -> Result<Args, String> {
         let mut source = String::new();
-        file.read_to_string(&mut source)
-            .expect("Unable to read config file");
-        toml::from_str(&source).expect("Invalid config file")
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|err| format!("Can't read config file {:?}: {}", path, err))?;
+        let args = toml::from_str(&source)
+            .map_err(|err| format!("Invalid config file {:?}: {}", path, err))?;
+        ArgsParser::add_reserved_names_file(args)
+    }
+
+    // Adds the names of the reserved_names_file to the reserved names. Empty
+    // lines and the text after a # are ignored.
+    fn add_reserved_names_file(mut args: Args) -> Result<Args, String> {
+        if let Some(path) = args.general.reserved_names_file.clone() {
+            let mut source = String::new();
+            File::open(&path)
+                .and_then(|mut file| file.read_to_string(&mut source))
+                .map_err(|err| format!("Can't read reserved names file {:?}: {}", path, err))?;
+            args.general.reserved_names.extend(
+                source
+                    .lines()
+                    .map(|line| line.split('#').next().unwrap_or("").trim())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_owned()),
+            );
+        }
+        Ok(args)
     }
 
     fn from_matches(matches: &ArgMatches) -> Args {
@@ -80,6 +171,18 @@ impl ArgsParser {
         };
 
         optional!(identity_password, "identity-password");
+        optional!(tls_certificate, "tls-certificate");
+        optional!(tls_key, "tls-key");
+        optional!(acme_webroot, "acme-webroot");
+        optional!(api_socket_path, "api-socket-path");
+        optional!(reserved_names_file, "reserved-names-file");
+        optional!(admin_token, "admin-token");
+        optional!(sentry_dsn, "sentry-dsn");
+        optional!(access_log, "access-log");
+        optional!(statsd_host, "statsd-host");
+        optional!(captcha_secret, "captcha-secret");
+        optional!(oidc_client_id, "oidc-client-id");
+        optional!(oidc_client_secret, "oidc-client-secret");
         optional!(email_server, "email-server");
         optional!(email_user, "email-user");
         optional!(email_password, "email-password");
@@ -88,6 +191,8 @@ impl ArgsParser {
         optional!(reclamation_body, "reclamation-body");
         optional!(confirmation_title, "confirmation-title");
         optional!(confirmation_body, "confirmation-body");
+        optional!(expiration_title, "expiration-title");
+        optional!(expiration_body, "expiration-body");
         optional!(success_page, "success-page");
         optional!(error_page, "error-page");
         optional!(psl_record, "psl-record");
@@ -100,27 +205,137 @@ impl ArgsParser {
         optional!(geoip_continent_oc, "geoip-continent-oc");
         optional!(geoip_continent_sa, "geoip-continent-sa");
 
-        Args {
+        let args = Args {
             general: GeneralOptions {
                 host: matches.value_of("host").unwrap_or("0.0.0.0").to_owned(),
                 http_port: value_t!(matches, "http-port", u16).unwrap_or(4242),
                 https_port: value_t!(matches, "https-port", u16).unwrap_or(4343),
+                metrics_port: value_t!(matches, "metrics-port", u16).unwrap_or(0),
+                statsd_host: statsd_host,
+                statsd_port: value_t!(matches, "statsd-port", u16).unwrap_or(8125),
+                statsd_prefix: matches
+                    .value_of("statsd-prefix")
+                    .unwrap_or("registration")
+                    .to_owned(),
+                statsd_tags: matches.is_present("statsd-tags"),
                 domain: matches
                     .value_of("domain")
                     .unwrap_or("mydomain.org")
                     .to_owned(),
                 db_path: String::from(matches.value_of("db-path").unwrap_or("./domains.sqlite")),
+                db_pool_size: value_t!(matches, "db-pool-size", u32).unwrap_or(0),
+                db_timeout: value_t!(matches, "db-timeout", u64).unwrap_or(0),
+                slow_query_ms: value_t!(matches, "slow-query-ms", u64).unwrap_or(0),
+                slow_request_ms: value_t!(matches, "slow-request-ms", u64).unwrap_or(0),
+                eviction_delay: value_t!(matches, "eviction-delay", u64).unwrap_or(0),
+                eviction_warning_delay: value_t!(matches, "eviction-warning-delay", u64)
+                    .unwrap_or(0),
+                eviction_policy: value_t!(matches, "eviction-policy", EvictionPolicy)
+                    .unwrap_or(EvictionPolicy::Hard),
+                undelete_grace: value_t!(matches, "undelete-grace", u64).unwrap_or(0),
+                reserved_names: matches
+                    .value_of("reserved-names")
+                    .unwrap_or("")
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                reserved_names_file: reserved_names_file.map(PathBuf::from),
+                disable_get_routes: matches.is_present("disable-get-routes"),
+                legacy_status_codes: matches.is_present("legacy-status-codes"),
+                cors_origins: matches
+                    .value_of("cors-origins")
+                    .unwrap_or("")
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                trusted_proxies: matches
+                    .value_of("trusted-proxies")
+                    .unwrap_or("127.0.0.1,::1")
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                admin_token: admin_token,
+                sentry_dsn: sentry_dsn,
+                max_subscribes_per_hour: value_t!(matches, "max-subscribes-per-hour", u32)
+                    .unwrap_or(10),
+                max_emails_per_hour: value_t!(matches, "max-emails-per-hour", u32).unwrap_or(10),
+                captcha_secret: captcha_secret,
+                captcha_verify_url: matches
+                    .value_of("captcha-verify-url")
+                    .unwrap_or("https://hcaptcha.com/siteverify")
+                    .to_owned(),
+                oidc_client_id: oidc_client_id,
+                oidc_client_secret: oidc_client_secret,
+                oidc_authorize_url: matches
+                    .value_of("oidc-authorize-url")
+                    .unwrap_or("https://accounts.google.com/o/oauth2/v2/auth")
+                    .to_owned(),
+                oidc_token_url: matches
+                    .value_of("oidc-token-url")
+                    .unwrap_or("https://oauth2.googleapis.com/token")
+                    .to_owned(),
+                oidc_userinfo_url: matches
+                    .value_of("oidc-userinfo-url")
+                    .unwrap_or("https://openidconnect.googleapis.com/v1/userinfo")
+                    .to_owned(),
+                oidc_redirect_url: matches
+                    .value_of("oidc-redirect-url")
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| {
+                        format!(
+                            "https://api.{}/oidc/callback",
+                            matches.value_of("domain").unwrap_or("mydomain.org")
+                        )
+                    }),
                 identity_directory: identity_directory,
                 identity_password: identity_password,
+                tls_certificate: tls_certificate.map(PathBuf::from),
+                tls_key: tls_key.map(PathBuf::from),
+                acme_webroot: acme_webroot.map(PathBuf::from),
+                shutdown_timeout: value_t!(matches, "shutdown-timeout", u64).unwrap_or(30),
+                api_socket_path: api_socket_path,
+                admin_socket_only: matches.is_present("admin-socket-only"),
+                keep_alive_timeout: value_t!(matches, "keep-alive-timeout", u64).unwrap_or(5),
+                server_threads: value_t!(matches, "server-threads", usize).unwrap_or(0),
+                json_logs: matches.is_present("json-logs"),
+                access_log: access_log,
+                disable_access_log: matches.is_present("disable-access-log"),
+                require_signed_pings: matches.is_present("require-signed-pings"),
+                require_signed_requests: matches.is_present("require-signed-requests"),
+                nonce_skew: value_t!(matches, "nonce-skew", u64).unwrap_or(300),
+                min_ping_interval: value_t!(matches, "min-ping-interval", u64).unwrap_or(0),
+                transfer_code_ttl: value_t!(matches, "transfer-code-ttl", u64).unwrap_or(86400),
+                local_prefix: matches
+                    .value_of("local-prefix")
+                    .unwrap_or("local")
+                    .to_owned(),
             },
             pdns: PdnsOptions {
                 api_ttl: value_t!(matches, "api-ttl", u32).unwrap_or(10),
                 dns_ttl: value_t!(matches, "dns-ttl", u32).unwrap_or(600),
                 tunnel_ttl: value_t!(matches, "tunnel-ttl", u32).unwrap_or(60),
+                challenge_ttl: value_t!(matches, "challenge-ttl", u32).unwrap_or(60),
+                challenge_window: value_t!(matches, "challenge-window", u32).unwrap_or(600),
+                cache_ttl: value_t!(matches, "cache-ttl", u32).unwrap_or(60),
+                negative_cache_ttl: value_t!(matches, "negative-cache-ttl", u32).unwrap_or(10),
                 soa_content: matches
                     .value_of("soa-content")
                     .unwrap_or("_soa_not_configured_")
                     .to_owned(),
+                soa_refresh: value_t!(matches, "soa-refresh", u32).unwrap_or(10800),
+                soa_retry: value_t!(matches, "soa-retry", u32).unwrap_or(3600),
+                soa_expire: value_t!(matches, "soa-expire", u32).unwrap_or(604800),
+                soa_minimum: value_t!(matches, "soa-minimum", u32).unwrap_or(10800),
+                name_servers: matches
+                    .value_of("name-servers")
+                    .unwrap_or("")
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
                 socket_path: matches.value_of("socket-path").map(|s| s.to_owned()),
                 mx_record: matches
                     .value_of("mx-record")
@@ -161,17 +376,35 @@ impl ArgsParser {
                 reclamation_body: reclamation_body,
                 confirmation_title: confirmation_title,
                 confirmation_body: confirmation_body,
+                expiration_title: expiration_title,
+                expiration_body: expiration_body,
                 success_page: success_page,
                 error_page: error_page,
             },
+        };
+        match ArgsParser::add_reserved_names_file(args) {
+            Ok(args) => args,
+            Err(err) => panic!("{}", err),
         }
     }
 
     // Gets the args from the default command line.
-    pub fn from_env() -> Args {
-        ArgsParser::from_matches(&App::new("registration_server")
+    // Also returns the path of the configuration file, if any, so that it can
+    // be reloaded, the name of the API key to create, if any, and the path of
+    // the dump to import, if any.
+    pub fn from_env() -> (Args, Option<PathBuf>, Option<String>, Option<PathBuf>) {
+        let matches = App::new("registration_server")
             .args_from_usage(USAGE)
-            .get_matches())
+            .get_matches();
+        let config_file = matches.value_of("config-file").map(PathBuf::from);
+        let api_key_name = matches.value_of("create-api-key").map(str::to_owned);
+        let import_path = matches.value_of("import").map(PathBuf::from);
+        (
+            ArgsParser::from_matches(&matches),
+            config_file,
+            api_key_name,
+            import_path,
+        )
     }
 
     // Gets the args from a string array.
@@ -192,14 +425,86 @@ fn test_args() {
     assert_eq!(args.general.host, "0.0.0.0");
     assert_eq!(args.general.http_port, 4242);
     assert_eq!(args.general.https_port, 4343);
+    assert_eq!(args.general.metrics_port, 0);
+    assert_eq!(args.general.statsd_host, None);
+    assert_eq!(args.general.statsd_port, 8125);
+    assert_eq!(args.general.statsd_prefix, "registration");
+    assert_eq!(args.general.statsd_tags, false);
     assert_eq!(args.general.domain, "mydomain.org");
     assert_eq!(args.general.db_path, "./domains.sqlite");
+    assert_eq!(args.general.db_pool_size, 0);
+    assert_eq!(args.general.db_timeout, 0);
+    assert_eq!(args.general.slow_query_ms, 0);
+    assert_eq!(args.general.slow_request_ms, 0);
+    assert_eq!(args.general.eviction_delay, 0);
+    assert_eq!(args.general.eviction_warning_delay, 0);
+    assert_eq!(args.general.eviction_policy, EvictionPolicy::Hard);
+    assert_eq!(args.general.undelete_grace, 0);
+    assert!(args.general.reserved_names.is_empty());
+    assert_eq!(args.general.reserved_names_file, None);
+    assert_eq!(args.general.disable_get_routes, false);
+    assert_eq!(args.general.legacy_status_codes, false);
+    assert!(args.general.cors_origins.is_empty());
+    assert_eq!(args.general.trusted_proxies, vec!["127.0.0.1", "::1"]);
+    assert_eq!(args.general.admin_token, None);
+    assert_eq!(args.general.sentry_dsn, None);
+    assert_eq!(args.general.max_subscribes_per_hour, 10);
+    assert_eq!(args.general.max_emails_per_hour, 10);
+    assert_eq!(args.general.captcha_secret, None);
+    assert_eq!(
+        args.general.captcha_verify_url,
+        "https://hcaptcha.com/siteverify"
+    );
+    assert_eq!(args.general.oidc_client_id, None);
+    assert_eq!(args.general.oidc_client_secret, None);
+    assert_eq!(
+        args.general.oidc_authorize_url,
+        "https://accounts.google.com/o/oauth2/v2/auth"
+    );
+    assert_eq!(
+        args.general.oidc_token_url,
+        "https://oauth2.googleapis.com/token"
+    );
+    assert_eq!(
+        args.general.oidc_userinfo_url,
+        "https://openidconnect.googleapis.com/v1/userinfo"
+    );
+    assert_eq!(
+        args.general.oidc_redirect_url,
+        "https://api.mydomain.org/oidc/callback"
+    );
     assert_eq!(args.general.identity_directory, None);
     assert_eq!(args.general.identity_password, None);
+    assert_eq!(args.general.tls_certificate, None);
+    assert_eq!(args.general.tls_key, None);
+    assert_eq!(args.general.acme_webroot, None);
+    assert_eq!(args.general.shutdown_timeout, 30);
+    assert_eq!(args.general.api_socket_path, None);
+    assert_eq!(args.general.admin_socket_only, false);
+    assert_eq!(args.general.keep_alive_timeout, 5);
+    assert_eq!(args.general.server_threads, 0);
+    assert_eq!(args.general.json_logs, false);
+    assert_eq!(args.general.access_log, None);
+    assert_eq!(args.general.disable_access_log, false);
+    assert_eq!(args.general.require_signed_pings, false);
+    assert_eq!(args.general.require_signed_requests, false);
+    assert_eq!(args.general.nonce_skew, 300);
+    assert_eq!(args.general.min_ping_interval, 0);
+    assert_eq!(args.general.transfer_code_ttl, 86400);
+    assert_eq!(args.general.local_prefix, "local");
     assert_eq!(args.pdns.api_ttl, 10);
     assert_eq!(args.pdns.dns_ttl, 600);
     assert_eq!(args.pdns.tunnel_ttl, 60);
+    assert_eq!(args.pdns.challenge_ttl, 60);
+    assert_eq!(args.pdns.challenge_window, 600);
+    assert_eq!(args.pdns.cache_ttl, 60);
+    assert_eq!(args.pdns.negative_cache_ttl, 10);
     assert_eq!(args.pdns.soa_content, "_soa_not_configured_");
+    assert_eq!(args.pdns.soa_refresh, 10800);
+    assert_eq!(args.pdns.soa_retry, 3600);
+    assert_eq!(args.pdns.soa_expire, 604800);
+    assert_eq!(args.pdns.soa_minimum, 10800);
+    assert!(args.pdns.name_servers.is_empty());
     assert_eq!(args.pdns.socket_path, None);
     assert_eq!(args.pdns.mx_record, "_mx_not_configured_");
     assert_eq!(args.pdns.caa_record, "_caa_not_configured_");
@@ -222,6 +527,8 @@ fn test_args() {
     assert_eq!(args.email.reclamation_body, None);
     assert_eq!(args.email.confirmation_title, None);
     assert_eq!(args.email.confirmation_body, None);
+    assert_eq!(args.email.expiration_title, None);
+    assert_eq!(args.email.expiration_body, None);
     assert_eq!(args.email.success_page, None);
     assert_eq!(args.email.error_page, None);
 
@@ -230,10 +537,58 @@ fn test_args() {
         "--host=127.0.1.1",
         "--http-port=4343",
         "--https-port=4444",
+        "--metrics-port=9090",
+        "--statsd-host=127.0.0.1",
+        "--statsd-port=9125",
+        "--statsd-prefix=gateways",
+        "--statsd-tags",
         "--domain=example.com",
         "--db-path=/tmp/mydata/domains.sqlite",
+        "--db-pool-size=32",
+        "--db-timeout=10",
+        "--slow-query-ms=100",
+        "--slow-request-ms=500",
+        "--eviction-delay=86400",
+        "--eviction-warning-delay=43200",
+        "--eviction-policy=soft",
+        "--undelete-grace=604800",
+        "--reserved-names=api, mail,ns1",
+        "--reserved-names-file=./config/reserved_names.txt",
+        "--disable-get-routes",
+        "--legacy-status-codes",
+        "--cors-origins=http://gateway.local, https://gateway.local",
+        "--trusted-proxies=10.0.0.1, 2001:db8::1",
+        "--admin-token=my_admin_token",
+        "--sentry-dsn=https://key@sentry.mydomain.org/42",
+        "--max-subscribes-per-hour=5",
+        "--max-emails-per-hour=3",
+        "--captcha-secret=my_captcha_secret",
+        "--captcha-verify-url=https://www.google.com/recaptcha/api/siteverify",
+        "--oidc-client-id=my_client_id",
+        "--oidc-client-secret=my_client_secret",
+        "--oidc-authorize-url=https://login.example.com/authorize",
+        "--oidc-token-url=https://login.example.com/token",
+        "--oidc-userinfo-url=https://login.example.com/userinfo",
+        "--oidc-redirect-url=https://registration.example.com/oidc/callback",
         "--identity-directory=/tmp/mycerts",
         "--identity-password=mypass",
+        "--tls-certificate=/etc/letsencrypt/live/api.mydomain.org/fullchain.pem",
+        "--tls-key=/etc/letsencrypt/live/api.mydomain.org/privkey.pem",
+        "--acme-webroot=/var/www/acme",
+        "--shutdown-timeout=60",
+        "--api-socket-path=/tmp/api.sock",
+        "--admin-socket-only",
+        "--keep-alive-timeout=30",
+        "--server-threads=256",
+        "--json-logs",
+        "--access-log=/var/log/registration/access.log",
+        "--disable-access-log",
+        "--require-signed-pings",
+        "--require-signed-requests",
+        "--nonce-skew=600",
+        "--min-ping-interval=60",
+        "--transfer-code-ttl=3600",
+        "--local-prefix=lan",
         "--geoip-default=1.2.3.4",
         "--geoip-database=/path/to/mmdb",
         "--geoip-continent-af=1.1.1.1",
@@ -246,7 +601,16 @@ fn test_args() {
         "--api-ttl=120",
         "--dns-ttl=140",
         "--tunnel-ttl=160",
+        "--challenge-ttl=180",
+        "--challenge-window=900",
+        "--cache-ttl=120",
+        "--negative-cache-ttl=5",
         "--soa-content=_my_soa",
+        "--soa-refresh=7200",
+        "--soa-retry=1800",
+        "--soa-expire=1209600",
+        "--soa-minimum=300",
+        "--name-servers=ns1.example.com, ns2.example.com",
         "--socket-path=/tmp/socket",
         "--mx-record=_my_mx",
         "--caa-record=_my_caa",
@@ -260,6 +624,8 @@ fn test_args() {
         "--reclamation-body=Reclamation_Body",
         "--confirmation-title=Confirmation_Title",
         "--confirmation-body=Confirmation_Body",
+        "--expiration-title=Expiration_Title",
+        "--expiration-body=Expiration_Body",
         "--success-page=this is success",
         "--error-page=this is error",
     ]);
@@ -267,17 +633,132 @@ fn test_args() {
     assert_eq!(args.general.host, "127.0.1.1");
     assert_eq!(args.general.http_port, 4343);
     assert_eq!(args.general.https_port, 4444);
+    assert_eq!(args.general.metrics_port, 9090);
+    assert_eq!(args.general.statsd_host, Some("127.0.0.1".to_owned()));
+    assert_eq!(args.general.statsd_port, 9125);
+    assert_eq!(args.general.statsd_prefix, "gateways");
+    assert_eq!(args.general.statsd_tags, true);
     assert_eq!(args.general.domain, "example.com");
     assert_eq!(args.general.db_path, "/tmp/mydata/domains.sqlite");
+    assert_eq!(args.general.db_pool_size, 32);
+    assert_eq!(args.general.db_timeout, 10);
+    assert_eq!(args.general.slow_query_ms, 100);
+    assert_eq!(args.general.slow_request_ms, 500);
+    assert_eq!(args.general.eviction_delay, 86400);
+    assert_eq!(args.general.eviction_warning_delay, 43200);
+    assert_eq!(args.general.eviction_policy, EvictionPolicy::Soft);
+    assert_eq!(args.general.undelete_grace, 604800);
+    assert_eq!(
+        args.general.reserved_names,
+        vec!["api", "mail", "ns1", "admin", "support"]
+    );
+    assert_eq!(
+        args.general.reserved_names_file,
+        Some(PathBuf::from("./config/reserved_names.txt"))
+    );
+    assert_eq!(args.general.disable_get_routes, true);
+    assert_eq!(args.general.legacy_status_codes, true);
+    assert_eq!(
+        args.general.cors_origins,
+        vec!["http://gateway.local", "https://gateway.local"]
+    );
+    assert_eq!(
+        args.general.trusted_proxies,
+        vec!["10.0.0.1", "2001:db8::1"]
+    );
+    assert_eq!(args.general.admin_token, Some("my_admin_token".to_owned()));
+    assert_eq!(
+        args.general.sentry_dsn,
+        Some("https://key@sentry.mydomain.org/42".to_owned())
+    );
+    assert_eq!(args.general.max_subscribes_per_hour, 5);
+    assert_eq!(args.general.max_emails_per_hour, 3);
+    assert_eq!(
+        args.general.captcha_secret,
+        Some("my_captcha_secret".to_owned())
+    );
+    assert_eq!(
+        args.general.captcha_verify_url,
+        "https://www.google.com/recaptcha/api/siteverify"
+    );
+    assert_eq!(args.general.oidc_client_id, Some("my_client_id".to_owned()));
+    assert_eq!(
+        args.general.oidc_client_secret,
+        Some("my_client_secret".to_owned())
+    );
+    assert_eq!(
+        args.general.oidc_authorize_url,
+        "https://login.example.com/authorize"
+    );
+    assert_eq!(
+        args.general.oidc_token_url,
+        "https://login.example.com/token"
+    );
+    assert_eq!(
+        args.general.oidc_userinfo_url,
+        "https://login.example.com/userinfo"
+    );
+    assert_eq!(
+        args.general.oidc_redirect_url,
+        "https://registration.example.com/oidc/callback"
+    );
     assert_eq!(
         args.general.identity_directory,
         Some(PathBuf::from("/tmp/mycerts"))
     );
     assert_eq!(args.general.identity_password, Some("mypass".to_owned()));
+    assert_eq!(
+        args.general.tls_certificate,
+        Some(PathBuf::from(
+            "/etc/letsencrypt/live/api.mydomain.org/fullchain.pem"
+        ))
+    );
+    assert_eq!(
+        args.general.tls_key,
+        Some(PathBuf::from(
+            "/etc/letsencrypt/live/api.mydomain.org/privkey.pem"
+        ))
+    );
+    assert_eq!(
+        args.general.acme_webroot,
+        Some(PathBuf::from("/var/www/acme"))
+    );
+    assert_eq!(args.general.shutdown_timeout, 60);
+    assert_eq!(
+        args.general.api_socket_path,
+        Some("/tmp/api.sock".to_owned())
+    );
+    assert_eq!(args.general.admin_socket_only, true);
+    assert_eq!(args.general.keep_alive_timeout, 30);
+    assert_eq!(args.general.server_threads, 256);
+    assert_eq!(args.general.json_logs, true);
+    assert_eq!(
+        args.general.access_log,
+        Some("/var/log/registration/access.log".to_owned())
+    );
+    assert_eq!(args.general.disable_access_log, true);
+    assert_eq!(args.general.require_signed_pings, true);
+    assert_eq!(args.general.require_signed_requests, true);
+    assert_eq!(args.general.nonce_skew, 600);
+    assert_eq!(args.general.min_ping_interval, 60);
+    assert_eq!(args.general.transfer_code_ttl, 3600);
+    assert_eq!(args.general.local_prefix, "lan");
     assert_eq!(args.pdns.api_ttl, 120);
     assert_eq!(args.pdns.dns_ttl, 140);
     assert_eq!(args.pdns.tunnel_ttl, 160);
+    assert_eq!(args.pdns.challenge_ttl, 180);
+    assert_eq!(args.pdns.challenge_window, 900);
+    assert_eq!(args.pdns.cache_ttl, 120);
+    assert_eq!(args.pdns.negative_cache_ttl, 5);
     assert_eq!(args.pdns.soa_content, "_my_soa");
+    assert_eq!(args.pdns.soa_refresh, 7200);
+    assert_eq!(args.pdns.soa_retry, 1800);
+    assert_eq!(args.pdns.soa_expire, 1209600);
+    assert_eq!(args.pdns.soa_minimum, 300);
+    assert_eq!(
+        args.pdns.name_servers,
+        vec!["ns1.example.com", "ns2.example.com"]
+    );
     assert_eq!(args.pdns.socket_path, Some("/tmp/socket".to_owned()));
     assert_eq!(args.pdns.mx_record, "_my_mx");
     assert_eq!(args.pdns.caa_record, "_my_caa");
@@ -312,10 +793,18 @@ fn test_args() {
         args.email.confirmation_body,
         Some("Confirmation_Body".to_owned())
     );
+    assert_eq!(
+        args.email.expiration_title,
+        Some("Expiration_Title".to_owned())
+    );
+    assert_eq!(
+        args.email.expiration_body,
+        Some("Expiration_Body".to_owned())
+    );
     assert_eq!(args.email.success_page, Some("this is success".to_owned()));
     assert_eq!(args.email.error_page, Some("this is error".to_owned()));
 
-    let soa = "a.dns.gandi.net hostmaster.gandi.net 1476196782 10800 3600 604800 10800";
+    let soa = "a.dns.gandi.net hostmaster.gandi.net";
     let mx = "";
     let caa = "0 issue \"letsencrypt.org\"";
     let txt = "";
@@ -326,6 +815,10 @@ fn test_args() {
     let conf_title = "Welcome to your Mozilla IoT Gateway";
     let conf_body = "Hello,\n\nWelcome to your Mozilla IoT Gateway! To confirm \
                      your email address, follow this link: {link}";
+    let exp_title = "Your Mozilla IoT Gateway domain will expire";
+    let exp_body = "Hello,\n\nYour gateway domain {domain} has not been seen since \
+                    {last_seen}. It will be removed soon unless your gateway \
+                    connects to the server again.";
     let success = "<!DOCTYPE html>
 <html>
   <head><title>Email Confirmation Successful!</title></head>
@@ -348,8 +841,60 @@ fn test_args() {
     assert_eq!(args.general.host, "127.0.0.1");
     assert_eq!(args.general.http_port, 4141);
     assert_eq!(args.general.https_port, 4142);
+    assert_eq!(args.general.metrics_port, 4143);
+    assert_eq!(args.general.statsd_host, None);
+    assert_eq!(args.general.statsd_port, 8125);
+    assert_eq!(args.general.statsd_prefix, "registration");
+    assert_eq!(args.general.statsd_tags, false);
     assert_eq!(args.general.domain, "mydomain.org");
     assert_eq!(args.general.db_path, "/tmp/domains.sqlite");
+    assert_eq!(args.general.db_pool_size, 4);
+    assert_eq!(args.general.db_timeout, 5);
+    assert_eq!(args.general.slow_query_ms, 250);
+    assert_eq!(args.general.slow_request_ms, 1000);
+    assert_eq!(args.general.eviction_delay, 2592000);
+    assert_eq!(args.general.eviction_warning_delay, 2160000);
+    assert_eq!(args.general.eviction_policy, EvictionPolicy::Hard);
+    assert_eq!(args.general.undelete_grace, 0);
+    assert_eq!(
+        args.general.reserved_names,
+        vec!["api", "www", "ns1", "ns2", "admin", "support"]
+    );
+    assert_eq!(
+        args.general.reserved_names_file,
+        Some(PathBuf::from("./config/reserved_names.txt"))
+    );
+    assert_eq!(args.general.disable_get_routes, false);
+    assert_eq!(args.general.legacy_status_codes, false);
+    assert_eq!(args.general.cors_origins, vec!["http://gateway.local"]);
+    assert_eq!(args.general.trusted_proxies, vec!["127.0.0.1", "::1"]);
+    assert_eq!(args.general.admin_token, Some("admin_secret".to_owned()));
+    assert_eq!(args.general.sentry_dsn, None);
+    assert_eq!(args.general.max_subscribes_per_hour, 0);
+    assert_eq!(args.general.max_emails_per_hour, 0);
+    assert_eq!(args.general.captcha_secret, None);
+    assert_eq!(
+        args.general.captcha_verify_url,
+        "https://hcaptcha.com/siteverify"
+    );
+    assert_eq!(args.general.oidc_client_id, None);
+    assert_eq!(args.general.oidc_client_secret, None);
+    assert_eq!(
+        args.general.oidc_authorize_url,
+        "https://accounts.google.com/o/oauth2/v2/auth"
+    );
+    assert_eq!(
+        args.general.oidc_token_url,
+        "https://oauth2.googleapis.com/token"
+    );
+    assert_eq!(
+        args.general.oidc_userinfo_url,
+        "https://openidconnect.googleapis.com/v1/userinfo"
+    );
+    assert_eq!(
+        args.general.oidc_redirect_url,
+        "https://api.mydomain.org/oidc/callback"
+    );
     assert_eq!(
         args.general.identity_directory,
         Some(PathBuf::from("/tmp/certs"))
@@ -358,10 +903,39 @@ fn test_args() {
         args.general.identity_password,
         Some("mypassword".to_owned())
     );
+    assert_eq!(args.general.tls_certificate, None);
+    assert_eq!(args.general.tls_key, None);
+    assert_eq!(args.general.acme_webroot, None);
+    assert_eq!(args.general.shutdown_timeout, 5);
+    assert_eq!(args.general.api_socket_path, None);
+    assert_eq!(args.general.admin_socket_only, false);
+    assert_eq!(args.general.keep_alive_timeout, 10);
+    assert_eq!(args.general.server_threads, 16);
+    assert_eq!(args.general.json_logs, false);
+    assert_eq!(args.general.access_log, None);
+    assert_eq!(args.general.disable_access_log, false);
+    assert_eq!(args.general.require_signed_pings, false);
+    assert_eq!(args.general.require_signed_requests, false);
+    assert_eq!(args.general.nonce_skew, 120);
+    assert_eq!(args.general.min_ping_interval, 0);
+    assert_eq!(args.general.transfer_code_ttl, 7200);
+    assert_eq!(args.general.local_prefix, "local");
     assert_eq!(args.pdns.api_ttl, 10);
     assert_eq!(args.pdns.dns_ttl, 600);
     assert_eq!(args.pdns.tunnel_ttl, 60);
+    assert_eq!(args.pdns.challenge_ttl, 30);
+    assert_eq!(args.pdns.challenge_window, 300);
+    assert_eq!(args.pdns.cache_ttl, 0);
+    assert_eq!(args.pdns.negative_cache_ttl, 0);
     assert_eq!(args.pdns.soa_content, soa);
+    assert_eq!(args.pdns.soa_refresh, 10800);
+    assert_eq!(args.pdns.soa_retry, 3600);
+    assert_eq!(args.pdns.soa_expire, 604800);
+    assert_eq!(args.pdns.soa_minimum, 10800);
+    assert_eq!(
+        args.pdns.name_servers,
+        vec!["ns1.mydomain.org", "ns2.mydomain.org"]
+    );
     assert_eq!(
         args.pdns.socket_path,
         Some("/tmp/powerdns_tunnel.sock".to_owned())
@@ -393,6 +967,8 @@ fn test_args() {
     assert_eq!(args.email.reclamation_body, Some(recl_body.to_string()));
     assert_eq!(args.email.confirmation_title, Some(conf_title.to_string()));
     assert_eq!(args.email.confirmation_body, Some(conf_body.to_string()));
+    assert_eq!(args.email.expiration_title, Some(exp_title.to_string()));
+    assert_eq!(args.email.expiration_body, Some(exp_body.to_string()));
     assert_eq!(args.email.success_page, Some(success.to_string()));
     assert_eq!(args.email.error_page, Some(error.to_string()));
 }
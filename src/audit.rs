@@ -0,0 +1,283 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Records the changes made to the domains by the API requests in the
+// append-only audit_events table, for the abuse investigations: who made each
+// change, when, from which address, and with which parameters. The domain is
+// looked up before the handler runs, as the request may remove it, and the
+// event is only added once the handler succeeded. The pings are only recorded
+// when they change the local addresses that the clients discover the gateway
+// with. The events of a domain are listed by /admin/audit.
+
+extern crate env_logger;
+use admin_routes::domain_param;
+use config::Config;
+use eviction::now;
+use iron::method::Method;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware};
+use logging::token_hash;
+use models::NewAuditEvent;
+use parameters::read_parsed;
+use ping_auth::auth_key;
+use proxy::client_ip;
+use request_id::RequestId;
+use routes::{get_domain_for_device, parse_local_ips};
+use serde_json;
+
+// The endpoints whose successful requests are recorded, besides the /domains
+// resources and the admin routes.
+const AUDITED_ENDPOINTS: [&str; 22] = [
+    "ping",
+    "subscribe",
+    "unsubscribe",
+    "undelete",
+    "revoke",
+    "dnsconfig",
+    "clearchallenge",
+    "setwildcard",
+    "updatedesc",
+    "settxt",
+    "deletetxt",
+    "setmx",
+    "setsrv",
+    "deletesrv",
+    "reclaim",
+    "transfer",
+    "redeem",
+    "devicetoken",
+    "adddiscovery",
+    "revokediscovery",
+    "setemail",
+    "revokeemail",
+];
+
+// Returns the action of a request that changes a domain, along with the token
+// of its path if any. The routes are only known once the router ran, so the
+// path is matched here.
+fn audited_action(method: &Method, path: &[&str]) -> Option<(String, Option<String>)> {
+    let segment = |back: usize| {
+        if path.len() > back {
+            Some(path[path.len() - 1 - back])
+        } else {
+            None
+        }
+    };
+    match (method, segment(2), segment(1), segment(0)) {
+        (&Method::Put, Some("domains"), Some(token), Some("ip")) => {
+            Some(("ping".to_owned(), Some(token.to_owned())))
+        }
+        (&Method::Delete, _, Some("domains"), Some(token)) => {
+            Some(("unsubscribe".to_owned(), Some(token.to_owned())))
+        }
+        (&Method::Post, _, _, Some("domains")) => Some(("subscribe".to_owned(), None)),
+        (_, _, Some("admin"), Some(name)) if name == "revoke" || name == "ban" => {
+            Some((format!("admin/{}", name), None))
+        }
+        (_, _, _, Some(name)) if AUDITED_ENDPOINTS.contains(&name) => Some((name.to_owned(), None)),
+        _ => None,
+    }
+}
+
+// The event of a request, waiting for its handler to succeed.
+struct PendingEvent {
+    name: String,
+    action: String,
+    actor: String,
+    details: String,
+}
+
+impl Key for PendingEvent {
+    type Value = PendingEvent;
+}
+
+// Records the changes. It is linked after the parameters are validated, and
+// once the response is known.
+#[derive(Clone)]
+pub struct AuditChanges {
+    config: Config,
+}
+
+impl AuditChanges {
+    pub fn new(config: &Config) -> Self {
+        AuditChanges {
+            config: config.clone(),
+        }
+    }
+
+    fn pending_event(&self, req: &mut Request) -> Option<PendingEvent> {
+        let (action, path_token) = audited_action(&req.method, &req.url.path())?;
+        let config = self.config.current();
+        let params = read_parsed(req);
+        let token = path_token.or_else(|| {
+            params
+                .as_ref()
+                .and_then(|params| params.get_string("token").ok())
+        });
+        let reclamation_token = params
+            .as_ref()
+            .and_then(|params| params.get_string("reclamationToken").ok())
+            .filter(|token| !token.is_empty());
+        // Most pings don't carry local addresses, and are left out without a
+        // query.
+        let local_ips = params
+            .as_ref()
+            .and_then(|params| params.get_optional_string_list("local_ip").ok())
+            .and_then(|values| values)
+            .and_then(|values| parse_local_ips(&values));
+        if action == "ping" && local_ips.is_none() {
+            return None;
+        }
+
+        let conn = match config.db.get_connection() {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!(
+                    "pending_event(): Failed to get database connection: {}",
+                    err
+                );
+                return None;
+            }
+        };
+        let record = match (token.as_ref(), reclamation_token) {
+            (Some(token), _) => get_domain_for_device(&conn, token).ok(),
+            (None, Some(reclamation_token)) => conn
+                .get_domain_by_reclamation_token(&auth_key(&reclamation_token))
+                .ok(),
+            (None, None) => None,
+        };
+        let name = match record {
+            Some(ref record) => record.name.clone(),
+            // The domain being undeleted is only kept among the deleted ones.
+            None if action == "undelete" => token
+                .as_ref()
+                .and_then(|token| conn.get_deleted_domain_by_token(token, 0).ok())
+                .map(|deleted| deleted.name)
+                .unwrap_or_default(),
+            None => params
+                .as_ref()
+                .and_then(|params| domain_param(params, &config).ok())
+                .unwrap_or_default(),
+        };
+
+        let mut details = params
+            .as_ref()
+            .map(|params| params.sanitized())
+            .unwrap_or_default();
+        if action == "ping" {
+            match (local_ips, record) {
+                (Some(ref local_ips), Some(ref record)) if *local_ips != record.local_ips => {
+                    details.insert("previous_local_ip".to_owned(), record.local_ips.clone());
+                }
+                _ => return None,
+            }
+        }
+
+        let actor = if action.starts_with("admin/") {
+            "admin".to_owned()
+        } else {
+            token.map(|token| token_hash(&token)).unwrap_or_default()
+        };
+        Some(PendingEvent {
+            name: name,
+            action: action,
+            actor: actor,
+            details: serde_json::to_string(&details).unwrap_or_default(),
+        })
+    }
+
+    fn record(&self, req: &Request, event: &PendingEvent) {
+        let config = self.config.current();
+        let client_ip = client_ip(req, &config).to_string();
+        let request_id = req
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_default();
+        let result = config
+            .db
+            .get_connection()
+            .map_err(|err| err.to_owned())
+            .and_then(|conn| {
+                conn.add_audit_event(&NewAuditEvent {
+                    name: &event.name,
+                    action: &event.action,
+                    actor: &event.actor,
+                    client_ip: &client_ip,
+                    request_id: &request_id,
+                    details: &event.details,
+                    created_at: now(),
+                })
+                .map_err(|err| format!("{:?}", err))
+            });
+        if let Err(err) = result {
+            error!(
+                "record(): Failed to record the {} of {}: {}",
+                event.action, event.name, err
+            );
+        }
+    }
+}
+
+impl BeforeMiddleware for AuditChanges {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if let Some(event) = self.pending_event(req) {
+            req.extensions.insert::<PendingEvent>(event);
+        }
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for AuditChanges {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        let succeeded = res.status.map_or(false, |status| status.is_success());
+        if let Some(event) = req.extensions.remove::<PendingEvent>() {
+            if succeeded {
+                self.record(req, &event);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn test_audited_action() {
+    let _ = env_logger::init();
+
+    let action = |method: Method, path: &str| {
+        let path: Vec<&str> = path.split('/').collect();
+        audited_action(&method, &path)
+    };
+    assert_eq!(
+        action(Method::Post, "subscribe"),
+        Some(("subscribe".to_owned(), None))
+    );
+    assert_eq!(
+        action(Method::Get, "v1/dnsconfig"),
+        Some(("dnsconfig".to_owned(), None))
+    );
+    assert_eq!(
+        action(Method::Post, "v1/domains"),
+        Some(("subscribe".to_owned(), None))
+    );
+    assert_eq!(
+        action(Method::Put, "domains/abc/ip"),
+        Some(("ping".to_owned(), Some("abc".to_owned())))
+    );
+    assert_eq!(
+        action(Method::Delete, "v1/domains/abc"),
+        Some(("unsubscribe".to_owned(), Some("abc".to_owned())))
+    );
+    assert_eq!(
+        action(Method::Post, "admin/revoke"),
+        Some(("admin/revoke".to_owned(), None))
+    );
+
+    assert_eq!(action(Method::Get, "domains"), None);
+    assert_eq!(action(Method::Get, "domains/abc"), None);
+    assert_eq!(action(Method::Get, "info"), None);
+    assert_eq!(action(Method::Get, "admin/audit"), None);
+    assert_eq!(action(Method::Get, "ping/extra"), None);
+}
@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Exports the registered domains to a JSON file, or imports them from it:
+//   dump --config-file=config.toml export --out=dump.json [--redact-tokens]
+//   dump --config-file=config.toml import --in=dump.json [--on-conflict=skip]
+
+extern crate clap;
+extern crate env_logger;
+extern crate registration_server;
+extern crate serde_json;
+
+use clap::{App, ArgMatches, SubCommand};
+use registration_server::args::ArgsParser;
+use registration_server::config::Config;
+use registration_server::dump::{self, OnConflict};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+fn export(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let conn = config.db.get_connection()?;
+    let dump = dump::export(&conn, matches.is_present("redact-tokens"))
+        .map_err(|err| format!("Failed to read the domains: {:?}", err))?;
+    let json = serde_json::to_string_pretty(&dump).unwrap();
+
+    let path = matches.value_of("out").unwrap();
+    // The dump holds the tokens of the domains, so only the owner can read it.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|err| format!("Can't create {}: {}", path, err))?;
+    file.write_all(json.as_bytes())
+        .map_err(|err| format!("Can't write {}: {}", path, err))?;
+
+    println!("Exported {} domains to {}", dump.domains.len(), path);
+    Ok(())
+}
+
+fn import(matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let on_conflict = match matches.value_of("on-conflict").unwrap_or("error") {
+        "error" => OnConflict::Error,
+        "skip" => OnConflict::Skip,
+        value => return Err(format!("Invalid --on-conflict value: {}", value)),
+    };
+
+    let path = matches.value_of("in").unwrap();
+    let dump = dump::read_dump(Path::new(path))?;
+
+    let conn = config.db.get_connection()?;
+    let report = dump::import(&conn, &dump, &config.options.general, on_conflict)?;
+    println!(
+        "Imported {} domains from {}, skipped {}",
+        report.imported, path, report.skipped
+    );
+    Ok(())
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("dump")
+        .about("Exports and imports the domains of the registration server.")
+        .args_from_usage("--config-file=<path> 'Path to the toml configuration file of the server.'")
+        .subcommand(SubCommand::with_name("export").args_from_usage(
+            "--out=<path>    'The file to write the domains to.'
+             --redact-tokens 'Leave out the tokens and challenges, to share the dump for debugging.'",
+        ))
+        .subcommand(SubCommand::with_name("import").args_from_usage(
+            "--in=<path>             'The file to read the domains from.'
+             --on-conflict=[action]  'What to do when a name is already registered: error (default) or skip.'",
+        ))
+        .get_matches();
+
+    let args = ArgsParser::from_file(&PathBuf::from(matches.value_of("config-file").unwrap()));
+    let config = Config::from_args(args);
+    if let Err(err) = config.db.run_migrations() {
+        eprintln!("Failed to migrate the database: {}", err);
+        process::exit(1);
+    }
+
+    let result = match matches.subcommand() {
+        ("export", Some(matches)) => export(matches, &config),
+        ("import", Some(matches)) => import(matches, &config),
+        _ => Err(matches.usage().to_owned()),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-extern crate env_logger;
+extern crate hyper;
 extern crate hyper_native_tls;
 extern crate iron;
 #[macro_use]
@@ -10,42 +10,188 @@ extern crate log;
 extern crate mount;
 extern crate registration_server;
 
+use hyper::net::{HttpListener, HttpsListener};
 use hyper_native_tls::NativeTlsServer;
-use iron::Iron;
+use iron::{Chain, Iron, Protocol};
+use std::process;
 use std::thread;
+use std::time::Duration;
 
+use registration_server::admin_routes;
+use registration_server::api_socket::{ApiSocketListener, MarkLocalRequests};
 use registration_server::args::ArgsParser;
 use registration_server::config::Config;
+use registration_server::dump::{self, OnConflict};
+use registration_server::eviction;
+use registration_server::logging;
+use registration_server::metrics;
 use registration_server::routes;
+use registration_server::sentry;
 use registration_server::pdns;
+use registration_server::signals;
+use registration_server::systemd::ListenFds;
+use registration_server::tls;
 
-fn main() {
-    env_logger::init().unwrap();
+// Returns the server of the API, with the keep-alive and threads of the
+// configuration.
+fn api_server(config: &Config) -> Iron<Chain> {
+    let general = &config.options.general;
+    let mut server = Iron::new(routes::create_chain("/", config));
+    server.timeouts.keep_alive = match general.keep_alive_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    if general.server_threads != 0 {
+        server.threads = general.server_threads;
+    }
+    server
+}
 
-    let args = ArgsParser::from_env();
+// Returns the listener of this address, or the socket passed by systemd under
+// this name.
+fn tcp_listener(fds: &mut ListenFds, name: &str, addr: &str) -> hyper::Result<HttpListener> {
+    match fds.take_tcp(name) {
+        Some(listener) => Ok(HttpListener::from(listener)),
+        None => HttpListener::new(addr),
+    }
+}
+
+fn main() {
+    let (args, config_file, api_key_name, import_path) = ArgsParser::from_env();
+    logging::init(args.general.json_logs);
 
     info!("Managing the domain {}", args.general.domain);
 
     let config = Config::from_args(args.clone());
+    sentry::install_panic_hook(&config);
+
+    if let Err(err) = config.db.run_migrations() {
+        error!("Failed to migrate the database: {}", err);
+        return;
+    }
 
-    pdns::start_socket_endpoint(&config);
+    // Creating or rotating an API key doesn't start the server.
+    if let Some(name) = api_key_name {
+        match admin_routes::create_api_key(&name, &config) {
+            Ok(key) => println!("{}", key),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // The domains already registered are left out, so that restarting with
+    // the same dump changes nothing. An invalid dump stops the server before
+    // anything is imported.
+    if let Some(path) = import_path {
+        let result = dump::read_dump(&path).and_then(|dump| {
+            let conn = config.db.get_connection()?;
+            dump::import(&conn, &dump, &config.options.general, OnConflict::Skip)
+        });
+        match result {
+            Ok(report) => info!(
+                "Imported {} domains from {}, skipped {}",
+                report.imported,
+                path.display(),
+                report.skipped
+            ),
+            Err(err) => {
+                error!("Failed to import {}: {}", path.display(), err);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The sockets passed by systemd replace the ones of the configuration.
+    let mut fds = ListenFds::from_env();
+
+    match fds.take_unix("pdns") {
+        Some(socket) => pdns::serve_socket(socket, &config),
+        None => pdns::start_socket_endpoint(&config),
+    }
+    eviction::start_eviction_thread(&config);
 
     let mut threads = Vec::new();
 
-    if config.options.general.http_port != 0 {
+    if config.options.general.http_port != 0 || fds.contains("http") {
         let cfg = config.clone();
         let addr = format!(
             "{}:{}",
             config.options.general.host, config.options.general.http_port
         );
-        threads.push(thread::spawn(move || {
-            let iron_server = Iron::new(routes::create_chain("/", &cfg));
-            info!("Starting HTTP server on {}", addr);
-            iron_server.http(addr.as_ref() as &str).unwrap();
-        }));
+        match tcp_listener(&mut fds, "http", &addr) {
+            Ok(listener) => threads.push(thread::spawn(move || {
+                let iron_server = api_server(&cfg);
+                info!("Starting HTTP server on {}", addr);
+                iron_server.listen(listener, Protocol::http()).unwrap();
+            })),
+            Err(err) => error!("Failed to listen on {}: {}", addr, err),
+        }
+    }
+
+    // The API socket runs along with the TCP listeners, and is the only one
+    // serving the admin routes with admin_socket_only.
+    let api_socket = match (
+        fds.take_unix("api"),
+        &config.options.general.api_socket_path,
+    ) {
+        (Some(socket), _) => Some(Ok(ApiSocketListener::from(socket))),
+        (None, &Some(ref path)) => Some(ApiSocketListener::bind(path)),
+        (None, &None) => None,
+    };
+    match api_socket {
+        Some(Ok(listener)) => {
+            let cfg = config.clone();
+            threads.push(thread::spawn(move || {
+                let mut iron_server = api_server(&cfg);
+                iron_server.handler.link_before(MarkLocalRequests);
+                info!("Starting HTTP server on the API socket");
+                iron_server.listen(listener, Protocol::http()).unwrap();
+            }));
+        }
+        Some(Err(err)) => error!("Failed to bind the API socket: {}", err),
+        None => {}
+    }
+
+    if config.options.general.metrics_port != 0 || fds.contains("metrics") {
+        let cfg = config.clone();
+        let addr = format!(
+            "{}:{}",
+            config.options.general.host, config.options.general.metrics_port
+        );
+        match tcp_listener(&mut fds, "metrics", &addr) {
+            Ok(listener) => threads.push(thread::spawn(move || {
+                let iron_server = Iron::new(metrics::create_router(&cfg));
+                info!("Starting metrics server on {}", addr);
+                iron_server.listen(listener, Protocol::http()).unwrap();
+            })),
+            Err(err) => error!("Failed to listen on {}: {}", addr, err),
+        }
     }
 
-    if config.options.general.https_port != 0 {
+    let general = &config.options.general;
+    let https = general.https_port != 0 || fds.contains("https");
+    if https && general.tls_certificate.is_some() && general.tls_key.is_some() {
+        let cfg = config.clone();
+        let addr = format!("{}:{}", general.host, general.https_port);
+        let certificate = general.tls_certificate.clone().unwrap();
+        let key = general.tls_key.clone().unwrap();
+        match (
+            tcp_listener(&mut fds, "https", &addr),
+            tls::tls_server(&certificate, &key),
+        ) {
+            (Ok(listener), Ok(ssl)) => threads.push(thread::spawn(move || {
+                let iron_server = api_server(&cfg);
+                info!("Starting TLS server on {} with {:?}", addr, certificate);
+                let listener = HttpsListener::with_listener(listener, ssl);
+                iron_server.listen(listener, Protocol::https()).unwrap();
+            })),
+            (Err(err), _) => error!("Failed to listen on {}: {}", addr, err),
+            (_, Err(err)) => error!("Failed to load the TLS certificate: {}", err),
+        }
+    } else if https {
         if config.options.general.identity_directory.is_none() {
             error!("Identity directory not set!");
         } else if config.options.general.identity_password.is_none() {
@@ -57,22 +203,31 @@ fn main() {
                 config.options.general.host, config.options.general.https_port
             );
             let identity_directory = config.options.general.identity_directory.clone().unwrap();
-            threads.push(thread::spawn(move || {
-                let iron_server = Iron::new(routes::create_chain("/", &cfg));
-                info!("Starting TLS server on {}", addr);
+            let identity_password = config.options.general.identity_password.clone().unwrap();
+            match tcp_listener(&mut fds, "https", &addr) {
+                Ok(listener) => threads.push(thread::spawn(move || {
+                    let iron_server = api_server(&cfg);
+                    info!("Starting TLS server on {}", addr);
 
-                let identity_password = config.options.general.identity_password.unwrap();
-                let mut identity = identity_directory.clone();
-                identity.push("identity.p12");
+                    let mut identity = identity_directory.clone();
+                    identity.push("identity.p12");
 
-                info!("Using identity: '{:?}'", identity);
-                let ssl = NativeTlsServer::new(identity, &identity_password).unwrap();
-                iron_server.https(addr.as_ref() as &str, ssl).unwrap();
-            }));
+                    info!("Using identity: '{:?}'", identity);
+                    let ssl = NativeTlsServer::new(identity, &identity_password).unwrap();
+                    let listener = HttpsListener::with_listener(listener, ssl);
+                    iron_server.listen(listener, Protocol::https()).unwrap();
+                })),
+                Err(err) => error!("Failed to listen on {}: {}", addr, err),
+            }
         }
     }
 
-    while let Some(t) = threads.pop() {
-        let _ = t.join();
+    // The server threads can't be stopped, so the process exits when the
+    // signal handling is done with them.
+    if let Err(err) = signals::wait_for_signals(&config, config_file) {
+        error!("Failed to handle signals: {}", err);
+        while let Some(t) = threads.pop() {
+            let _ = t.join();
+        }
     }
 }
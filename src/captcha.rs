@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Verifies the CAPTCHA solutions sent to /subscribe. hCaptcha and reCAPTCHA
+// share the same verification API: the secret of the site, the solution and
+// the IP address of the client are posted as a form to `captcha_verify_url`,
+// which answers with a JSON object whose `success` field tells whether the
+// solution is valid.
+
+extern crate env_logger;
+use config::GeneralOptions;
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use hyper::Client;
+use hyper_native_tls::NativeTlsClient;
+use serde_json;
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// How long to wait for the verification server.
+const TIMEOUT_SECS: u64 = 10;
+
+#[derive(Deserialize)]
+struct Verification {
+    success: bool,
+}
+
+// Encodes a value of an application/x-www-form-urlencoded body.
+pub fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ if byte.is_ascii_alphanumeric() => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn parse_verification(json: &str) -> Result<bool, String> {
+    serde_json::from_str::<Verification>(json)
+        .map(|verification| verification.success)
+        .map_err(|err| format!("Invalid verification response: {}", err))
+}
+
+// Returns whether the solution is valid, or an error if the verification
+// server could not be reached.
+pub fn verify(solution: &str, remote_ip: IpAddr, options: &GeneralOptions) -> Result<bool, String> {
+    let secret = match options.captcha_secret {
+        Some(ref secret) => secret,
+        None => return Err("No CAPTCHA secret is configured".to_owned()),
+    };
+
+    let tls = NativeTlsClient::new().map_err(|err| format!("TLS failure: {}", err))?;
+    let mut client = Client::with_connector(HttpsConnector::new(tls));
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+
+    let body = format!(
+        "secret={}&response={}&remoteip={}",
+        form_encode(secret),
+        form_encode(solution),
+        remote_ip
+    );
+    let mut response = client
+        .post(&options.captcha_verify_url)
+        .header(ContentType::form_url_encoded())
+        .body(&body)
+        .send()
+        .map_err(|err| format!("Failed to reach {}: {}", options.captcha_verify_url, err))?;
+
+    let mut json = String::new();
+    response
+        .read_to_string(&mut json)
+        .map_err(|err| format!("Failed to read the verification response: {}", err))?;
+    parse_verification(&json)
+}
+
+#[test]
+fn test_form_encode() {
+    let _ = env_logger::init();
+
+    assert_eq!(form_encode("P0-a._~"), "P0-a._~");
+    assert_eq!(form_encode("a b&c=d+é"), "a%20b%26c%3Dd%2B%C3%A9");
+}
+
+#[test]
+fn test_parse_verification() {
+    let _ = env_logger::init();
+
+    assert_eq!(parse_verification("{\"success\":true}"), Ok(true));
+    assert_eq!(
+        parse_verification("{\"success\":false,\"error-codes\":[\"invalid-input-response\"]}"),
+        Ok(false)
+    );
+    assert!(parse_verification("{}").is_err());
+    assert!(parse_verification("<html>").is_err());
+}
+
+#[test]
+fn test_verify_failure() {
+    use args::ArgsParser;
+
+    let _ = env_logger::init();
+
+    let mut options = ArgsParser::from_vec(vec!["registration_server"]).general;
+    let ip = "127.0.0.1".parse().unwrap();
+    assert!(verify("solution", ip, &options).is_err());
+
+    // Nothing listens on this port.
+    options.captcha_secret = Some("secret".to_owned());
+    options.captcha_verify_url = "http://127.0.0.1:1/".to_owned();
+    assert!(verify("solution", ip, &options).is_err());
+}
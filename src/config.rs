@@ -3,7 +3,15 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use database::DatabasePool;
+use dns_cache::DomainCache;
+use metrics::Metrics;
+use oidc_routes::PendingLogins;
+use ping_auth::NonceCache;
+use rate_limit::RateLimits;
+use signals::InFlight;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 #[derive(Clone, Deserialize)]
 #[allow(non_snake_case)]
@@ -24,24 +32,130 @@ pub struct GeoIp {
     pub continent: Continent,
 }
 
+// What happens to the domains that stopped pinging for longer than the
+// eviction delay: nothing, they stop resolving until they ping again, or they
+// stop resolving and are removed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    Never,
+    Soft,
+    Hard,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "never" => Ok(EvictionPolicy::Never),
+            "soft" => Ok(EvictionPolicy::Soft),
+            "hard" => Ok(EvictionPolicy::Hard),
+            _ => Err(format!("Invalid eviction policy: {}", value)),
+        }
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct GeneralOptions {
     pub host: String,
     pub http_port: u16,
     pub https_port: u16,
+    pub metrics_port: u16,
+    // The metrics are also sent to this StatsD server when set.
+    pub statsd_host: Option<String>,
+    pub statsd_port: u16,
+    pub statsd_prefix: String,
+    // Whether the labels are sent as DogStatsD tags.
+    pub statsd_tags: bool,
     pub db_path: String,
+    pub db_pool_size: u32,
+    // How long to wait for a connection of the pool, in seconds, or 0 for the
+    // r2d2 default.
+    pub db_timeout: u64,
+    // Thresholds of the warnings about the slow queries and requests, in
+    // milliseconds, or 0.
+    pub slow_query_ms: u64,
+    pub slow_request_ms: u64,
+    pub eviction_delay: u64,
+    pub eviction_warning_delay: u64,
+    pub eviction_policy: EvictionPolicy,
+    // How long an unsubscribed domain is kept for /undelete, in seconds, or 0
+    // to remove it right away.
+    pub undelete_grace: u64,
+    pub reserved_names: Vec<String>,
+    pub reserved_names_file: Option<PathBuf>,
+    pub disable_get_routes: bool,
+    // Whether the conflicts are answered with a 400 status, like before the
+    // 409 status was used.
+    pub legacy_status_codes: bool,
+    pub cors_origins: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    pub admin_token: Option<String>,
+    // The internal errors and the panics are reported to Sentry when set.
+    pub sentry_dsn: Option<String>,
+    pub max_subscribes_per_hour: u32,
+    pub max_emails_per_hour: u32,
+    // New names can only be subscribed with a CAPTCHA solution when set.
+    pub captcha_secret: Option<String>,
+    pub captcha_verify_url: String,
+    // The owners can log in with an OpenID Connect provider to link their
+    // domains to an account, and get their tokens back, when set.
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_authorize_url: String,
+    pub oidc_token_url: String,
+    pub oidc_userinfo_url: String,
+    pub oidc_redirect_url: String,
     pub identity_directory: Option<PathBuf>,
     pub identity_password: Option<String>,
+    // Used instead of the identity when both are set.
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub acme_webroot: Option<PathBuf>,
+    pub shutdown_timeout: u64,
+    pub api_socket_path: Option<String>,
+    // The admin routes are then only served on the API socket.
+    pub admin_socket_only: bool,
+    pub keep_alive_timeout: u64,
+    // Each thread serves one connection at a time, including the idle ones
+    // kept alive.
+    pub server_threads: usize,
+    // The logs are then written as JSON records, for log collectors.
+    pub json_logs: bool,
+    // The records of the API requests are then written to this file, in the
+    // format of the other logs.
+    pub access_log: Option<String>,
+    pub disable_access_log: bool,
+    pub require_signed_pings: bool,
+    // The requests of the gateways must then carry an X-Signature header.
+    pub require_signed_requests: bool,
+    pub nonce_skew: u64,
+    // Pings closer than this to the previous one are not saved, unless they
+    // change the local addresses.
+    pub min_ping_interval: u64,
+    pub transfer_code_ttl: u64,
+    // Label of the local names of the domains that didn't choose their own.
+    pub local_prefix: String,
     pub domain: String,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct PdnsOptions {
     pub soa_content: String,
+    pub soa_refresh: u32,
+    pub soa_retry: u32,
+    pub soa_expire: u32,
+    pub soa_minimum: u32,
+    pub name_servers: Vec<String>,
     pub socket_path: Option<String>,
     pub dns_ttl: u32,
     pub tunnel_ttl: u32,
+    pub challenge_ttl: u32,
+    pub challenge_window: u32,
     pub api_ttl: u32,
+    pub cache_ttl: u32,
+    pub negative_cache_ttl: u32,
     pub mx_record: String,
     pub caa_record: String,
     pub txt_record: String,
@@ -59,6 +173,8 @@ pub struct EmailOptions {
     pub reclamation_body: Option<String>,
     pub confirmation_title: Option<String>,
     pub confirmation_body: Option<String>,
+    pub expiration_title: Option<String>,
+    pub expiration_body: Option<String>,
     pub success_page: Option<String>,
     pub error_page: Option<String>,
 }
@@ -70,16 +186,45 @@ pub struct Args {
     pub email: EmailOptions,
 }
 
+// The options swapped in by a reload of the configuration file, with the
+// state built from them.
+#[derive(Clone)]
+struct Reloaded {
+    options: Args,
+    rate_limits: RateLimits,
+    dns_cache: DomainCache,
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub db: DatabasePool,
     pub options: Args,
+    pub rate_limits: RateLimits,
+    pub metrics: Metrics,
+    pub dns_cache: DomainCache,
+    pub in_flight: InFlight,
+    pub nonces: NonceCache,
+    pub logins: PendingLogins,
+    // Shared by all the clones of the configuration.
+    reloaded: Arc<RwLock<Option<Reloaded>>>,
 }
 
 impl Config {
     pub fn from_args(args: Args) -> Self {
         Config {
-            db: DatabasePool::new(&args.general.db_path.clone()),
+            db: DatabasePool::with_options(
+                &args.general.db_path,
+                args.general.db_pool_size,
+                args.general.db_timeout,
+            )
+            .with_slow_query_ms(args.general.slow_query_ms),
+            rate_limits: RateLimits::new(&args.general),
+            metrics: Metrics::with_options(&args.general),
+            dns_cache: DomainCache::new(&args.pdns),
+            in_flight: InFlight::new(),
+            nonces: NonceCache::new(),
+            logins: PendingLogins::new(),
+            reloaded: Arc::new(RwLock::new(None)),
             options: args,
         }
     }
@@ -88,7 +233,108 @@ impl Config {
     pub fn from_args_with_db(args: Args, db: DatabasePool) -> Self {
         Config {
             db: db,
+            rate_limits: RateLimits::new(&args.general),
+            metrics: Metrics::new(),
+            dns_cache: DomainCache::new(&args.pdns),
+            in_flight: InFlight::new(),
+            nonces: NonceCache::new(),
+            logins: PendingLogins::new(),
+            reloaded: Arc::new(RwLock::new(None)),
             options: args,
         }
     }
+
+    // Returns the configuration with the options of the last reload, to be
+    // used for each new request.
+    pub fn current(&self) -> Config {
+        let reloaded = match self.reloaded.read() {
+            Ok(reloaded) => reloaded.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+        match reloaded {
+            Some(reloaded) => Config {
+                options: reloaded.options,
+                rate_limits: reloaded.rate_limits,
+                dns_cache: reloaded.dns_cache,
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    // Swaps in the options of a reloaded configuration file. The options that
+    // are only used when the server starts keep their current value, and the
+    // rate limits and cache are only reset if their options changed.
+    pub fn reload(&self, args: Args) {
+        let current = self.current();
+        let mut options = args;
+
+        macro_rules! keep {
+            ($section:ident, $name:ident) => (
+                if options.$section.$name != current.options.$section.$name {
+                    warn!(
+                        "reload(): Ignoring the new value of {}, which needs a restart",
+                        stringify!($name)
+                    );
+                    options.$section.$name = current.options.$section.$name.clone();
+                }
+            )
+        }
+        keep!(general, host);
+        keep!(general, http_port);
+        keep!(general, https_port);
+        keep!(general, metrics_port);
+        keep!(general, statsd_host);
+        keep!(general, statsd_port);
+        keep!(general, statsd_prefix);
+        keep!(general, statsd_tags);
+        keep!(general, db_path);
+        keep!(general, db_pool_size);
+        keep!(general, db_timeout);
+        keep!(general, slow_query_ms);
+        keep!(general, disable_get_routes);
+        keep!(general, legacy_status_codes);
+        keep!(general, cors_origins);
+        keep!(general, oidc_client_id);
+        keep!(general, identity_directory);
+        keep!(general, identity_password);
+        keep!(general, tls_certificate);
+        keep!(general, tls_key);
+        keep!(general, acme_webroot);
+        keep!(general, api_socket_path);
+        keep!(general, keep_alive_timeout);
+        keep!(general, server_threads);
+        keep!(general, json_logs);
+        keep!(pdns, socket_path);
+
+        let general = &current.options.general;
+        let rate_limits = if options.general.max_subscribes_per_hour
+            == general.max_subscribes_per_hour
+            && options.general.max_emails_per_hour == general.max_emails_per_hour
+        {
+            current.rate_limits.clone()
+        } else {
+            RateLimits::new(&options.general)
+        };
+
+        let pdns = &current.options.pdns;
+        let dns_cache = if options.pdns.cache_ttl == pdns.cache_ttl
+            && options.pdns.negative_cache_ttl == pdns.negative_cache_ttl
+        {
+            current.dns_cache.clone()
+        } else {
+            DomainCache::new(&options.pdns)
+        };
+
+        let reloaded = Reloaded {
+            options: options,
+            rate_limits: rate_limits,
+            dns_cache: dns_cache,
+        };
+        match self.reloaded.write() {
+            Ok(mut value) => *value = Some(reloaded),
+            Err(poisoned) => *poisoned.into_inner() = Some(reloaded),
+        }
+    }
 }
@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Adds the CORS headers to the responses of the endpoints that web pages, like
+// the first-run page of the gateway, are allowed to call. The headers are only
+// added for the origins allowed in the configuration. Preflight requests are
+// answered by the OPTIONS routes of the router.
+
+use iron::AfterMiddleware;
+use iron::headers::{AccessControlAllowMethods, AccessControlAllowOrigin, AccessControlMaxAge};
+use iron::method::Method;
+use iron::prelude::*;
+
+// How long browsers can cache the result of a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE: u32 = 86400;
+
+pub struct Cors {
+    // The allowed origins, "*" allowing any origin.
+    origins: Vec<String>,
+    // The endpoints that can be called from web pages, with their methods.
+    endpoints: Vec<(Vec<Method>, String)>,
+}
+
+impl Cors {
+    pub fn new(origins: &[String], endpoints: Vec<(Vec<Method>, String)>) -> Self {
+        Cors {
+            origins: origins.to_vec(),
+            endpoints: endpoints,
+        }
+    }
+
+    fn add_headers(&self, req: &Request, res: &mut Response) {
+        let origin = match req.headers.get_raw("Origin") {
+            Some(values) if values.len() == 1 => String::from_utf8_lossy(&values[0]).into_owned(),
+            _ => return,
+        };
+
+        let path = req.url.path();
+        let methods = match self
+            .endpoints
+            .iter()
+            .find(|&&(_, ref endpoint)| path.last() == Some(&endpoint.as_str()))
+        {
+            Some(&(ref methods, _)) => methods.clone(),
+            None => return,
+        };
+
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            res.headers.set(AccessControlAllowOrigin::Any);
+        } else if self.origins.contains(&origin) {
+            res.headers.set(AccessControlAllowOrigin::Value(origin));
+            res.headers.set_raw("Vary", vec![b"Origin".to_vec()]);
+        } else {
+            debug!("add_headers(): Origin not allowed: {}", origin);
+            return;
+        }
+
+        if req.method == Method::Options {
+            res.headers.set(AccessControlAllowMethods(methods));
+            res.headers.set_raw(
+                "Access-Control-Allow-Headers",
+                vec![b"Content-Type".to_vec()],
+            );
+            res.headers.set(AccessControlMaxAge(PREFLIGHT_MAX_AGE));
+        }
+    }
+}
+
+impl AfterMiddleware for Cors {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.add_headers(req, &mut res);
+        Ok(res)
+    }
+
+    // Error responses need the headers too, so that web pages can read the
+    // error code.
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        self.add_headers(req, &mut err.response);
+        Err(err)
+    }
+}
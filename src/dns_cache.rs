@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Caches the domains looked up to answer PowerDNS, so that repeated queries,
+// including scans of names that don't exist, don't all hit the database. The
+// cache is keyed by name, as all the record types of a name are built from the
+// same domain. Unknown names are kept for a shorter time. The API endpoints
+// drop the cached domains they change, and the cache is shared by all the
+// clones of the configuration.
+
+extern crate env_logger;
+use config::PdnsOptions;
+use diesel::result::{Error, QueryResult};
+use models::Domain;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Maximum number of cached names, to bound the memory used during scans.
+const MAX_ENTRIES: usize = 10_000;
+
+struct Entry {
+    // None when the name is not registered.
+    domain: Option<Domain>,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct DomainCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl DomainCache {
+    pub fn new(options: &PdnsOptions) -> Self {
+        DomainCache {
+            ttl: Duration::from_secs(u64::from(options.cache_ttl)),
+            negative_ttl: Duration::from_secs(u64::from(options.negative_cache_ttl)),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<HashMap<String, Entry>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Returns the domain with this name, calling `load` to get it from the
+    // database if it's not cached.
+    pub fn get_or_load<F>(&self, name: &str, load: F) -> QueryResult<Domain>
+    where
+        F: FnOnce() -> QueryResult<Domain>,
+    {
+        self.get_or_load_at(name, load, Instant::now())
+    }
+
+    fn get_or_load_at<F>(&self, name: &str, load: F, now: Instant) -> QueryResult<Domain>
+    where
+        F: FnOnce() -> QueryResult<Domain>,
+    {
+        if let Some(entry) = self.entries().get(name) {
+            if entry.expires > now {
+                return entry.domain.clone().ok_or(Error::NotFound);
+            }
+        }
+
+        // The lock is not held while querying the database, so concurrent
+        // lookups of the same name may both load it.
+        let (domain, ttl) = match load() {
+            Ok(domain) => (Some(domain), self.ttl),
+            Err(Error::NotFound) => (None, self.negative_ttl),
+            // Database failures are not cached.
+            Err(err) => return Err(err),
+        };
+        let result = domain.clone().ok_or(Error::NotFound);
+        if ttl == Duration::from_secs(0) {
+            return result;
+        }
+
+        let mut entries = self.entries();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(
+            name.to_owned(),
+            Entry {
+                domain: domain,
+                expires: now + ttl,
+            },
+        );
+        result
+    }
+
+    // Drops the cached lookup of a name, eg. after it was registered.
+    pub fn forget_name(&self, name: &str) {
+        self.entries().remove(name);
+    }
+
+    fn forget_domains<F>(&self, matches: F)
+    where
+        F: Fn(&Domain) -> bool,
+    {
+        self.entries().retain(|_, entry| match entry.domain {
+            Some(ref domain) => !matches(domain),
+            None => true,
+        });
+    }
+
+    // Drops the cached domain with this token, after it was changed.
+    pub fn forget_token(&self, token: &str) {
+        self.forget_domains(|domain| domain.token == token);
+    }
+
+    // Drops the cached domain with this reclamation token hash, after it was
+    // deleted with it.
+    pub fn forget_reclamation_token(&self, token: &str) {
+        self.forget_domains(|domain| domain.reclamation_token == token);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries().len()
+    }
+}
+
+#[test]
+fn test_domain_cache() {
+    use args::ArgsParser;
+    use std::cell::Cell;
+
+    let _ = env_logger::init();
+
+    let mut options = ArgsParser::from_vec(vec!["registration_server"]).pdns;
+    options.cache_ttl = 60;
+    options.negative_cache_ttl = 10;
+    let cache = DomainCache::new(&options);
+
+    let domain = Domain {
+        id: 1,
+        name: "test.mydomain.org.".to_owned(),
+        account_id: 1,
+        token: "test-token".to_owned(),
+        description: "Test Server".to_owned(),
+        timestamp: 0,
+        dns_challenge: "".to_owned(),
+        reclamation_token: "".to_owned(),
+        verification_token: "".to_owned(),
+        verified: false,
+        continent: "".to_owned(),
+        local_ips: "".to_owned(),
+        warning_sent_at: 0,
+        wildcard: false,
+        challenge_set_at: 0,
+        auth_key: "".to_owned(),
+        mx_target: "".to_owned(),
+        mx_priority: 0,
+        local_prefix: "".to_owned(),
+        device_token: "".to_owned(),
+        created_at: 0,
+        updated_at: 0,
+    };
+
+    // Counts the database queries.
+    let queries = Cell::new(0);
+    let found = || -> QueryResult<Domain> {
+        queries.set(queries.get() + 1);
+        Ok(domain.clone())
+    };
+    let not_found = || -> QueryResult<Domain> {
+        queries.set(queries.get() + 1);
+        Err(Error::NotFound)
+    };
+    let failure = || -> QueryResult<Domain> {
+        queries.set(queries.get() + 1);
+        Err(Error::RollbackTransaction)
+    };
+
+    let start = Instant::now();
+    let seconds = |count: u64| start + Duration::from_secs(count);
+    let name = "test.mydomain.org.";
+    let unknown = "unknown.mydomain.org.";
+
+    // The second lookup doesn't hit the database, until the domain expires.
+    assert_eq!(
+        cache.get_or_load_at(name, &found, start),
+        Ok(domain.clone())
+    );
+    assert_eq!(
+        cache.get_or_load_at(name, &found, seconds(59)),
+        Ok(domain.clone())
+    );
+    assert_eq!(queries.get(), 1);
+    assert_eq!(
+        cache.get_or_load_at(name, &found, seconds(60)),
+        Ok(domain.clone())
+    );
+    assert_eq!(queries.get(), 2);
+
+    // Unknown names are cached for a shorter time.
+    assert_eq!(
+        cache.get_or_load_at(unknown, &not_found, start),
+        Err(Error::NotFound)
+    );
+    assert_eq!(
+        cache.get_or_load_at(unknown, &found, seconds(9)),
+        Err(Error::NotFound)
+    );
+    assert_eq!(queries.get(), 3);
+    assert_eq!(
+        cache.get_or_load_at(unknown, &found, seconds(10)),
+        Ok(domain.clone())
+    );
+    assert_eq!(queries.get(), 4);
+
+    // Failures are not cached.
+    let other = "other.mydomain.org.";
+    assert!(cache.get_or_load_at(other, &failure, start).is_err());
+    assert!(cache.get_or_load_at(other, &failure, start).is_err());
+    assert_eq!(queries.get(), 6);
+
+    // Changed domains are dropped.
+    assert_eq!(cache.len(), 2);
+    cache.forget_token("test-token");
+    assert_eq!(cache.len(), 0);
+    assert_eq!(
+        cache.get_or_load_at(unknown, &not_found, seconds(61)),
+        Err(Error::NotFound)
+    );
+    cache.forget_name(unknown);
+    assert_eq!(
+        cache.get_or_load_at(unknown, &found, seconds(61)),
+        Ok(domain.clone())
+    );
+    assert_eq!(queries.get(), 8);
+
+    // A TTL of 0 turns the cache off.
+    options.cache_ttl = 0;
+    options.negative_cache_ttl = 0;
+    let cache = DomainCache::new(&options);
+    cache.get_or_load_at(name, &found, start).unwrap();
+    cache
+        .get_or_load_at(unknown, &not_found, start)
+        .unwrap_err();
+    assert_eq!(cache.len(), 0);
+}
@@ -0,0 +1,550 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Exports the registered domains to a JSON document and imports them back,
+// to move them between servers or database backends. The records are checked
+// with the same rules as /subscribe and /ping before being imported. The
+// exports can also be written as CSV, for the analytics.
+
+extern crate env_logger;
+use config::GeneralOptions;
+use database::Database;
+use diesel;
+use diesel::result::QueryResult;
+use email_routes::is_valid_email;
+use models::Domain;
+use routes::{parse_local_ips, parse_mx_target, validate_local_prefix, validate_name};
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Version of the dump format, to be increased when it changes in a way that
+// older servers can't import.
+pub const DUMP_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Dump {
+    pub version: u32,
+    // Whether the secrets of the domains were left out, in which case the dump
+    // can't be imported.
+    pub redacted: bool,
+    pub domains: Vec<DumpedDomain>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DumpedDomain {
+    pub name: String,
+    pub email: String,
+    pub token: String,
+    pub description: String,
+    pub timestamp: i64,
+    pub dns_challenge: String,
+    pub reclamation_token: String,
+    pub verification_token: String,
+    pub verified: bool,
+    pub continent: String,
+    pub local_ips: String,
+    pub warning_sent_at: i64,
+    pub wildcard: bool,
+    // Missing from the dumps made before the signed pings.
+    #[serde(default)]
+    pub auth_key: String,
+    // Missing from the dumps made before the mail servers.
+    #[serde(default)]
+    pub mx_target: String,
+    #[serde(default)]
+    pub mx_priority: i32,
+    // Missing from the dumps made before the local prefixes.
+    #[serde(default)]
+    pub local_prefix: String,
+    // Missing from the dumps made before the device tokens.
+    #[serde(default)]
+    pub device_token: String,
+    // Missing from the dumps made before the creation and modification times,
+    // whose domains are imported with their timestamp instead.
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnConflict {
+    // Abort the import if a name or token is already registered.
+    Error,
+    // Leave out the records whose name or token is already registered.
+    Skip,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+// Returns all the domains. If `redact_tokens` is set, their secrets (the
+// tokens, the DNS challenge and the key of the signed pings) are replaced by
+// empty strings.
+pub fn export(conn: &Database, redact_tokens: bool) -> QueryResult<Dump> {
+    let mut emails = HashMap::new();
+    let mut dumped = Vec::new();
+
+    for record in conn.get_all_domains()? {
+        if !emails.contains_key(&record.account_id) {
+            let account = conn.get_account_by_id(record.account_id)?;
+            emails.insert(record.account_id, account.email);
+        }
+
+        let secret = |value: String| if redact_tokens { String::new() } else { value };
+        dumped.push(DumpedDomain {
+            name: record.name,
+            email: emails[&record.account_id].clone(),
+            token: secret(record.token),
+            description: record.description,
+            timestamp: record.timestamp,
+            dns_challenge: secret(record.dns_challenge),
+            reclamation_token: secret(record.reclamation_token),
+            verification_token: secret(record.verification_token),
+            verified: record.verified,
+            continent: record.continent,
+            local_ips: record.local_ips,
+            warning_sent_at: record.warning_sent_at,
+            wildcard: record.wildcard,
+            auth_key: secret(record.auth_key),
+            mx_target: record.mx_target,
+            mx_priority: record.mx_priority,
+            local_prefix: record.local_prefix,
+            device_token: secret(record.device_token),
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        });
+    }
+
+    Ok(Dump {
+        version: DUMP_VERSION,
+        redacted: redact_tokens,
+        domains: dumped,
+    })
+}
+
+// The columns of the CSV exports, in the order of the fields of DumpedDomain.
+const CSV_COLUMNS: [&str; 20] = [
+    "name",
+    "email",
+    "token",
+    "description",
+    "timestamp",
+    "dns_challenge",
+    "reclamation_token",
+    "verification_token",
+    "verified",
+    "continent",
+    "local_ips",
+    "warning_sent_at",
+    "wildcard",
+    "auth_key",
+    "mx_target",
+    "mx_priority",
+    "local_prefix",
+    "device_token",
+    "created_at",
+    "updated_at",
+];
+
+// Quotes a CSV field when it holds a separator, a quote or a line break, as
+// in RFC 4180.
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// Writes the domains of a dump as CSV, with a header line. Unlike the JSON
+// dumps, the CSV exports can't be imported.
+pub fn to_csv(dump: &Dump) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push_str("\r\n");
+    for record in &dump.domains {
+        let fields = [
+            csv_field(&record.name),
+            csv_field(&record.email),
+            csv_field(&record.token),
+            csv_field(&record.description),
+            record.timestamp.to_string(),
+            csv_field(&record.dns_challenge),
+            csv_field(&record.reclamation_token),
+            csv_field(&record.verification_token),
+            record.verified.to_string(),
+            csv_field(&record.continent),
+            csv_field(&record.local_ips),
+            record.warning_sent_at.to_string(),
+            record.wildcard.to_string(),
+            csv_field(&record.auth_key),
+            csv_field(&record.mx_target),
+            record.mx_priority.to_string(),
+            csv_field(&record.local_prefix),
+            csv_field(&record.device_token),
+            record.created_at.to_string(),
+            record.updated_at.to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Checks a record and returns it in its canonical form.
+fn validate(record: &DumpedDomain, options: &GeneralOptions) -> Result<Domain, String> {
+    let suffix = format!(".{}.", options.domain);
+    let label = if record.name.ends_with(&suffix) {
+        &record.name[..record.name.len() - suffix.len()]
+    } else {
+        ""
+    };
+    match validate_name(label, &options.domain, &options.reserved_names) {
+        Ok(ref valid) if valid == label => (),
+        _ => return Err(format!("Invalid name: {}", record.name)),
+    }
+
+    if record.token.is_empty() {
+        return Err(format!("Missing token for {}", record.name));
+    }
+    if !record.email.is_empty() && !is_valid_email(&record.email) {
+        return Err(format!("Invalid email address for {}", record.name));
+    }
+    let local_ips = match parse_local_ips(&[record.local_ips.clone()]) {
+        Some(local_ips) => local_ips,
+        None => return Err(format!("Invalid local addresses for {}", record.name)),
+    };
+    let valid_mx = if record.mx_target.is_empty() {
+        record.mx_priority == 0
+    } else {
+        parse_mx_target(&record.mx_target).as_ref() == Some(&record.mx_target)
+            && record.mx_priority >= 0
+            && record.mx_priority <= 65535
+    };
+    if !valid_mx {
+        return Err(format!("Invalid mail server for {}", record.name));
+    }
+    if validate_local_prefix(&record.local_prefix).as_ref() != Some(&record.local_prefix) {
+        return Err(format!("Invalid local prefix for {}", record.name));
+    }
+
+    Ok(Domain {
+        id: 0,
+        name: record.name.clone(),
+        account_id: 0,
+        token: record.token.clone(),
+        description: record.description.clone(),
+        timestamp: record.timestamp,
+        dns_challenge: record.dns_challenge.clone(),
+        reclamation_token: record.reclamation_token.clone(),
+        verification_token: record.verification_token.clone(),
+        verified: record.verified,
+        continent: record.continent.clone(),
+        local_ips: local_ips,
+        warning_sent_at: record.warning_sent_at,
+        wildcard: record.wildcard,
+        // Only lowers the TTL for a few minutes, so it's not worth keeping.
+        challenge_set_at: 0,
+        auth_key: record.auth_key.clone(),
+        mx_target: record.mx_target.clone(),
+        mx_priority: record.mx_priority,
+        local_prefix: record.local_prefix.clone(),
+        device_token: record.device_token.clone(),
+        created_at: if record.created_at == 0 {
+            record.timestamp
+        } else {
+            record.created_at
+        },
+        updated_at: if record.updated_at == 0 {
+            record.timestamp
+        } else {
+            record.updated_at
+        },
+    })
+}
+
+// Reads a dump written by the `dump` binary or /admin/export.
+pub fn read_dump(path: &Path) -> Result<Dump, String> {
+    let mut json = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut json))
+        .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+    serde_json::from_str(&json).map_err(|err| format!("Invalid dump {}: {}", path.display(), err))
+}
+
+// Returns whether a lookup found a domain.
+fn found(result: QueryResult<Domain>) -> Result<bool, String> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(diesel::result::Error::NotFound) => Ok(false),
+        Err(err) => Err(format!("Failed to look up the domains: {:?}", err)),
+    }
+}
+
+// Imports the domains of a dump. Nothing is imported if a record is invalid,
+// or if a name or token is already registered and `on_conflict` is Error.
+pub fn import(
+    conn: &Database,
+    dump: &Dump,
+    options: &GeneralOptions,
+    on_conflict: OnConflict,
+) -> Result<ImportReport, String> {
+    if dump.version != DUMP_VERSION {
+        return Err(format!("Unsupported dump version: {}", dump.version));
+    }
+    if dump.redacted {
+        return Err("Redacted dumps can't be imported".to_owned());
+    }
+
+    let mut names = HashSet::new();
+    let mut tokens = HashSet::new();
+    let mut records = Vec::new();
+    let mut skipped = 0;
+
+    for dumped in &dump.domains {
+        let record = validate(dumped, options)?;
+        match conn.is_name_banned(&record.name) {
+            Ok(false) => (),
+            Ok(true) => return Err(format!("Banned name: {}", record.name)),
+            Err(err) => return Err(format!("Failed to look up the banned names: {:?}", err)),
+        }
+
+        let device_conflict = !record.device_token.is_empty()
+            && (tokens.contains(&record.device_token)
+                || found(conn.get_domain_by_device_token(&record.device_token))?);
+        let conflict = names.contains(&record.name)
+            || tokens.contains(&record.token)
+            || device_conflict
+            || found(conn.get_domain_by_name(&record.name))?
+            || found(conn.get_domain_by_token(&record.token))?;
+        if conflict {
+            match on_conflict {
+                OnConflict::Error => return Err(format!("Already registered: {}", record.name)),
+                OnConflict::Skip => {
+                    info!("import(): Skipping already registered {}", record.name);
+                    skipped += 1;
+                    continue;
+                }
+            }
+        }
+
+        names.insert(record.name.clone());
+        tokens.insert(record.token.clone());
+        if !record.device_token.is_empty() {
+            tokens.insert(record.device_token.clone());
+        }
+        records.push((dumped.email.clone(), record));
+    }
+
+    match conn.insert_domains(&records) {
+        Ok(imported) => Ok(ImportReport {
+            imported: imported,
+            skipped: skipped,
+        }),
+        Err(err) => Err(format!("Failed to insert the domains: {:?}", err)),
+    }
+}
+
+#[test]
+fn test_export_import() {
+    use args::ArgsParser;
+    use database::DatabasePool;
+    use std::env;
+    use std::fs;
+
+    let _ = env_logger::init();
+
+    #[cfg(feature = "mysql")]
+    let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_dump");
+    #[cfg(feature = "postgres")]
+    let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_dump");
+    #[cfg(feature = "sqlite")]
+    let db = DatabasePool::new("domain_db_test_dump.sqlite");
+    let conn = db.get_connection().expect("Getting connection.");
+    conn.flush().expect("Flushing the db");
+
+    let args = ArgsParser::from_vec(vec![
+        "registration_server",
+        "--config-file=./config/config.toml",
+    ]);
+    let options = args.general;
+
+    let account = conn.add_account("owner@example.com").unwrap();
+    let unknown = conn.get_unknown_account().unwrap();
+    for &(label, account_id) in &[("first", account.id), ("second", unknown.id)] {
+        let name = format!("{}.mydomain.org.", label);
+        let token = format!("{}-token", label);
+        conn.add_domain(
+            &name,
+            account_id,
+            &token,
+            label,
+            1000,
+            "challenge",
+            "",
+            "",
+            true,
+            "EU",
+        )
+        .unwrap();
+    }
+    conn.update_domain_local_ips("first-token", "192.168.1.2,fe80::1")
+        .unwrap();
+    conn.update_domain_wildcard("second-token", true).unwrap();
+    conn.update_domain_auth_key("second-token", "signing-key")
+        .unwrap();
+    conn.update_domain_mx("first-token", "mail.example.org.", 5)
+        .unwrap();
+    conn.update_domain_local_prefix("second-token", "lan")
+        .unwrap();
+    conn.update_domain_device_token("first-token", "first-device-token")
+        .unwrap();
+
+    // Round trip through JSON and an empty database.
+    let dump = export(&conn, false).unwrap();
+    assert_eq!(dump.version, DUMP_VERSION);
+    assert_eq!(dump.domains.len(), 2);
+    assert_eq!(dump.domains[0].email, "owner@example.com");
+    assert_eq!(dump.domains[0].local_ips, "192.168.1.2,fe80::1");
+    assert_eq!(dump.domains[1].email, "");
+    assert!(dump.domains[1].wildcard);
+    assert_eq!(dump.domains[1].auth_key, "signing-key");
+    assert_eq!(dump.domains[0].mx_target, "mail.example.org.");
+    assert_eq!(dump.domains[0].mx_priority, 5);
+    assert_eq!(dump.domains[1].local_prefix, "lan");
+    assert_eq!(dump.domains[0].device_token, "first-device-token");
+    assert!(dump.domains[0].created_at > 0);
+    assert!(dump.domains[0].updated_at >= dump.domains[0].created_at);
+    let json = serde_json::to_string(&dump).unwrap();
+    let dump: Dump = serde_json::from_str(&json).unwrap();
+
+    // The dump files are read like the ones of the dump binary.
+    let path = env::temp_dir().join("registration_server_test_dump.json");
+    fs::write(&path, &json).unwrap();
+    assert_eq!(read_dump(&path).unwrap(), dump);
+    fs::write(&path, "{}").unwrap();
+    assert!(read_dump(&path).unwrap_err().starts_with("Invalid dump"));
+    fs::remove_file(&path).unwrap();
+    assert!(read_dump(&path).unwrap_err().starts_with("Can't read"));
+
+    conn.flush().unwrap();
+    assert_eq!(
+        import(&conn, &dump, &options, OnConflict::Error),
+        Ok(ImportReport {
+            imported: 2,
+            skipped: 0,
+        })
+    );
+    assert_eq!(export(&conn, false).unwrap(), dump);
+    assert_eq!(conn.count_accounts(), Ok(1));
+
+    // Conflicts abort the import, or are skipped.
+    assert_eq!(
+        import(&conn, &dump, &options, OnConflict::Error),
+        Err("Already registered: first.mydomain.org.".to_owned())
+    );
+    let mut renamed = export(&conn, false).unwrap();
+    renamed.domains[1].name = "third.mydomain.org.".to_owned();
+    renamed.domains[1].token = "third-token".to_owned();
+    assert_eq!(
+        import(&conn, &renamed, &options, OnConflict::Skip),
+        Ok(ImportReport {
+            imported: 1,
+            skipped: 1,
+        })
+    );
+    assert_eq!(conn.count_domains(), Ok(3));
+
+    // Redacted dumps leave out the secrets, and can't be imported.
+    let redacted = export(&conn, true).unwrap();
+    assert!(redacted.redacted);
+    let json = serde_json::to_string(&redacted).unwrap();
+    assert!(!json.contains("-token") && !json.contains("challenge"));
+    assert!(!json.contains("signing-key"));
+    assert!(import(&conn, &redacted, &options, OnConflict::Skip).is_err());
+
+    // Invalid records and conflicts within the dump abort the whole import.
+    conn.flush().unwrap();
+    let invalid = |change: &Fn(&mut DumpedDomain)| {
+        let mut invalid = Dump {
+            version: DUMP_VERSION,
+            redacted: false,
+            domains: dump.domains.clone(),
+        };
+        change(&mut invalid.domains[1]);
+        let result = import(&conn, &invalid, &options, OnConflict::Error);
+        assert_eq!(conn.count_domains(), Ok(0));
+        result
+    };
+    assert!(invalid(&|record| record.name = "-bad.mydomain.org.".to_owned()).is_err());
+    assert!(invalid(&|record| record.name = "api.mydomain.org.".to_owned()).is_err());
+    assert!(invalid(&|record| record.name = "second.example.org.".to_owned()).is_err());
+    assert!(invalid(&|record| record.name = "Second.mydomain.org.".to_owned()).is_err());
+    assert!(invalid(&|record| record.token = "".to_owned()).is_err());
+    assert!(invalid(&|record| record.email = "not an email".to_owned()).is_err());
+    assert!(invalid(&|record| record.local_ips = "192.168.1.256".to_owned()).is_err());
+    assert!(invalid(&|record| record.mx_target = "mail".to_owned()).is_err());
+    assert!(invalid(&|record| record.mx_priority = 10).is_err());
+    assert!(invalid(&|record| record.local_prefix = "LAN".to_owned()).is_err());
+    assert_eq!(
+        invalid(&|record| record.token = "first-token".to_owned()),
+        Err("Already registered: second.mydomain.org.".to_owned())
+    );
+    assert_eq!(
+        invalid(&|record| record.device_token = "first-device-token".to_owned()),
+        Err("Already registered: second.mydomain.org.".to_owned())
+    );
+    let mut future = export(&conn, false).unwrap();
+    future.version = DUMP_VERSION + 1;
+    assert!(import(&conn, &future, &options, OnConflict::Skip).is_err());
+}
+
+#[test]
+fn test_csv() {
+    let record = DumpedDomain {
+        name: "gateway.mydomain.org.".to_owned(),
+        email: "owner@example.com".to_owned(),
+        token: "gateway-token".to_owned(),
+        description: "Home, \"main\"".to_owned(),
+        timestamp: 1000,
+        dns_challenge: "".to_owned(),
+        reclamation_token: "".to_owned(),
+        verification_token: "".to_owned(),
+        verified: true,
+        continent: "EU".to_owned(),
+        local_ips: "192.168.1.2,fe80::1".to_owned(),
+        warning_sent_at: 0,
+        wildcard: false,
+        auth_key: "".to_owned(),
+        mx_target: "".to_owned(),
+        mx_priority: 0,
+        local_prefix: "".to_owned(),
+        device_token: "".to_owned(),
+        created_at: 900,
+        updated_at: 1000,
+    };
+    let dump = Dump {
+        version: DUMP_VERSION,
+        redacted: false,
+        domains: vec![record],
+    };
+
+    let csv = to_csv(&dump);
+    let lines: Vec<&str> = csv.split("\r\n").collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("name,email,token,description,timestamp,"));
+    assert_eq!(lines[0].split(',').count(), CSV_COLUMNS.len());
+    assert_eq!(
+        lines[1],
+        "gateway.mydomain.org.,owner@example.com,gateway-token,\"Home, \"\"main\"\"\",1000,,,,\
+         true,EU,\"192.168.1.2,fe80::1\",0,false,,,0,,,900,1000"
+    );
+    assert_eq!(lines[2], "");
+}
@@ -17,11 +17,27 @@ use lettre::smtp::extension::ClientId;
 #[cfg(test)]
 use lettre::stub::StubEmailTransport;
 use iron::prelude::*;
-use iron::status::{self, Status};
-use params::{FromValue, Params};
+use iron::status::Status;
+use parameters::Parameters;
+use proxy::client_ip;
+use serde_json;
 use std::str::FromStr;
+use std::thread;
 use uuid::Uuid;
 
+// Returned by /setemail when asked for JSON. A new address is not verified
+// until the emailed link is followed.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct EmailState {
+    pub email: String,
+    pub verified: bool,
+}
+
+// Sends emails. Implemented by EmailSender, and by mock mailers in tests.
+pub trait Mailer {
+    fn send(&mut self, to: &str, body: &str, subject: &str) -> Result<(), ()>;
+}
+
 #[allow(dead_code)]
 pub struct EmailSender {
     connection: SmtpTransport,
@@ -101,46 +117,107 @@ impl EmailSender {
     }
 }
 
+impl Mailer for EmailSender {
+    fn send(&mut self, to: &str, body: &str, subject: &str) -> Result<(), ()> {
+        EmailSender::send(self, to, body, subject)
+    }
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    Mailbox::from_str(email).is_ok() && email.len() <= 254
+}
+
+// Returns the title and body of the email asking to confirm an address, or None
+// if it is not configured.
+fn confirmation_email(config: &Config, verification_token: &str) -> Option<(String, String)> {
+    let scheme = match config.options.general.identity_directory {
+        Some(_) => "https",
+        None => "http",
+    };
+    let full_link = format!(
+        "{}://api.{}/verifyemail?s={}",
+        scheme, config.options.general.domain, verification_token
+    );
+    let options = &config.options.email;
+    match (&options.confirmation_title, &options.confirmation_body) {
+        (&Some(ref title), &Some(ref body)) => {
+            Some((title.clone(), body.replace("{link}", &full_link)))
+        }
+        _ => None,
+    }
+}
+
+// Sends the confirmation email from a separate thread, so that a slow SMTP
+// server doesn't delay the response. Failures are only logged.
+pub fn send_confirmation_email(config: &Config, email: &str, verification_token: &str) {
+    let (title, body) = match confirmation_email(config, verification_token) {
+        Some(content) => content,
+        None => {
+            error!("send_confirmation_email(): The confirmation email is not configured");
+            return;
+        }
+    };
+
+    let config = config.clone();
+    let email = email.to_owned();
+    let result = thread::Builder::new()
+        .name("confirmation email".to_owned())
+        .spawn(move || match EmailSender::new(&config) {
+            Ok(mut sender) => {
+                if let Err(err) = sender.send(&email, &body, &title) {
+                    error!("send_confirmation_email(): Failed to send email: {:?}", err);
+                }
+            }
+            Err(err) => {
+                error!(
+                    "send_confirmation_email(): Failed to create email sender: {:?}",
+                    err
+                );
+            }
+        });
+    if let Err(err) = result {
+        error!(
+            "send_confirmation_email(): Failed to start thread: {:?}",
+            err
+        );
+    }
+}
+
 pub fn setemail(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let real_ip = client_ip(req, config);
+    if let Err(retry_after) = config.rate_limits.email.check(real_ip) {
+        info!("setemail(): Too many requests from {}", real_ip);
+        return EndpointError::rate_limited(retry_after);
+    }
+
     let conn = config.db.get_connection();
     if conn.is_err() {
         error!(
             "setemail(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let token = map.find(&["token"]);
-    let email = map.find(&["email"]);
-
-    info!("GET /setemail {:?}", map);
-
-    if token.is_none() || email.is_none() {
-        error!("setemail(): Token or email not provided");
-        return EndpointError::with(status::BadRequest, 400);
-    }
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /setemail {:?}", req.method, params);
 
-    let token = String::from_value(token.unwrap()).unwrap();
-    let email = String::from_value(email.unwrap()).unwrap();
+    let token = try_param!(params.get_string("token"));
+    let email = try_param!(params.get_string("email"));
+    let format = try_param!(params.get_optional_string("format"));
 
-    // Check that this is a valid email address.
-    if Mailbox::from_str(&email).is_err() || email.len() > 254 {
+    if !is_valid_email(&email) {
         error!("setemail(): Invalid email address: {}", email);
-        return EndpointError::with(status::BadRequest, 400);
+        return EndpointError::with(ApiError::InvalidEmail);
     }
 
-    let account_id = match conn.get_account_by_email(&email) {
+    let account_id = match conn.get_or_add_account(&email) {
         Ok(account) => account.id,
-        Err(_) => match conn.add_account(&email) {
-            Ok(account) => account.id,
-            Err(err) => {
-                error!("setemail(): Failed to add account: {:?}", err);
-                return EndpointError::with(status::InternalServerError, 500);
-            }
-        },
+        Err(err) => {
+            error!("setemail(): Failed to add account: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
     };
 
     let verification_token = format!("{}", Uuid::new_v4());
@@ -148,45 +225,38 @@ pub fn setemail(req: &mut Request, config: &Config) -> IronResult<Response> {
     {
         Ok(count) if count > 0 => match EmailSender::new(config) {
             Ok(mut sender) => {
-                let scheme = match config.options.general.identity_directory {
-                    Some(_) => "https",
-                    None => "http",
+                let (title, body) = match confirmation_email(config, &verification_token) {
+                    Some(content) => content,
+                    None => {
+                        error!("setemail(): The confirmation email is not configured");
+                        return EndpointError::with(ApiError::EmailFailure);
+                    }
                 };
-                let full_link = format!(
-                    "{}://api.{}/verifyemail?s={}",
-                    scheme, config.options.general.domain, verification_token
-                );
-                let body = config
-                    .options
-                    .email
-                    .clone()
-                    .confirmation_body
-                    .unwrap()
-                    .replace("{link}", &full_link);
-                match sender.send(
-                    &email,
-                    &body,
-                    &config.options.email.clone().confirmation_title.unwrap(),
-                ) {
-                    Ok(_) => ok_response!(),
+                match sender.send(&email, &body, &title) {
+                    // Older clients expect an empty response.
+                    Ok(_) if format != Some("json".to_owned()) => ok_response!(),
+                    Ok(_) => json_response!(&EmailState {
+                        email: email,
+                        verified: false,
+                    }),
                     Err(err) => {
                         error!("setemail(): Failed to send email: {:?}", err);
-                        EndpointError::with(status::InternalServerError, 500)
+                        EndpointError::with(ApiError::EmailFailure)
                     }
                 }
             }
             Err(err) => {
                 error!("setemail(): Failed to create email sender: {:?}", err);
-                EndpointError::with(status::InternalServerError, 500)
+                EndpointError::with(ApiError::EmailFailure)
             }
         },
         Ok(_) => {
             error!("setemail(): Domain not found for token: {}", token);
-            EndpointError::with(status::NotFound, 404)
+            EndpointError::with(ApiError::UnknownToken)
         }
         Err(err) => {
             error!("setemail(): Failed to update domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
@@ -199,22 +269,24 @@ pub fn verifyemail(req: &mut Request, config: &Config) -> IronResult<Response> {
             "verifyemail(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let link = map.find(&["s"]);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /verifyemail {:?}", req.method, params);
 
-    info!("GET /verifyemail {:?}", map);
+    let link = try_param!(params.get_string("s"));
 
-    if link.is_none() {
-        error!("verifyemail(): Link not provided");
-        return EndpointError::with(status::BadRequest, 400);
+    // The link is cleared once followed, so an empty one would match the
+    // domains that have no pending verification.
+    if link.is_empty() {
+        return html_error_response!(
+            Status::NotFound,
+            config.options.email.clone().error_page.unwrap_or_default()
+        );
     }
 
-    let link = String::from_value(link.unwrap()).unwrap();
-
     match conn.get_domain_by_verification_token(&link) {
         Ok(record) => match conn.update_domain_verification_data(
             &record.token,
@@ -223,27 +295,27 @@ pub fn verifyemail(req: &mut Request, config: &Config) -> IronResult<Response> {
             true,
         ) {
             Ok(count) if count > 0 => {
-                html_response!(config.options.email.clone().success_page.unwrap())
+                html_response!(config.options.email.success_page.clone().unwrap_or_default())
             }
             Ok(_) => html_error_response!(
                 Status::NotFound,
-                config.options.email.clone().error_page.unwrap()
+                config.options.email.clone().error_page.unwrap_or_default()
             ),
             Err(err) => {
                 error!("verifyemail(): Failed to update domain: {:?}", err);
-                EndpointError::with(status::InternalServerError, 500)
+                EndpointError::with(ApiError::DatabaseFailure)
             }
         },
         Err(diesel::result::Error::NotFound) => html_error_response!(
             Status::NotFound,
-            config.options.email.clone().error_page.unwrap()
+            config.options.email.clone().error_page.unwrap_or_default()
         ),
         Err(err) => {
             error!(
                 "verifyemail(): Failed to lookup domain for {}: {:?}",
                 link, err
             );
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
@@ -255,28 +327,21 @@ pub fn revokeemail(req: &mut Request, config: &Config) -> IronResult<Response> {
             "revokeemail(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let token = map.find(&["token"]);
-
-    info!("GET /revokeemail {:?}", map);
-
-    if token.is_none() {
-        error!("revokeemail(): Token not provided");
-        return EndpointError::with(status::BadRequest, 400);
-    }
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /revokeemail {:?}", req.method, params);
 
-    let token = String::from_value(token.unwrap()).unwrap();
+    let token = try_param!(params.get_string("token"));
 
     match conn.update_domain_verification_data(&token, None, "", false) {
         Ok(count) if count > 0 => ok_response!(),
-        Ok(_) => EndpointError::with(status::NotFound, 404),
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
         Err(err) => {
             error!("revokeemail(): Failed to update domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
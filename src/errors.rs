@@ -3,12 +3,16 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 extern crate env_logger;
-use iron::status;
+use iron::headers::ContentType;
+use iron::AfterMiddleware;
 use iron::prelude::*;
+use iron::status::{self, Status};
 use serde_json;
 use std::error::Error;
 use std::fmt::{self, Debug};
 
+header! { (RetryAfter, "Retry-After") => [u64] }
+
 #[derive(Debug)]
 struct StringError(pub String);
 
@@ -24,41 +28,309 @@ impl Error for StringError {
     }
 }
 
+// The errors returned by the API endpoints. They are serialized as their
+// variant name in the "error" field of the response body, and as their number
+// in the "code" field, so renaming a variant or changing its number is a
+// breaking change for clients.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ApiError {
+    MissingParameter,
+    InvalidParameter,
+    MalformedBody,
+    PayloadTooLarge,
+    InvalidEmail,
+    InvalidName,
+    ReservedName,
+    UnavailableName,
+    UnavailableNameReclamationPossible,
+    ReclamationTokenMismatch,
+    NoSuchName,
+    NoEmail,
+    TooManyRecords,
+    UnknownToken,
+    UnknownTransferCode,
+    UnknownLogin,
+    Unauthorized,
+    InvalidSignature,
+    InvalidCaptcha,
+    UnverifiedEmail,
+    RateLimited,
+    ShuttingDown,
+    DatabaseFailure,
+    EmailFailure,
+    CaptchaFailure,
+    LoginFailure,
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match *self {
+            ApiError::UnknownToken | ApiError::UnknownTransferCode | ApiError::UnknownLogin => {
+                status::NotFound
+            }
+            ApiError::UnavailableName | ApiError::UnavailableNameReclamationPossible => {
+                status::Conflict
+            }
+            ApiError::Unauthorized | ApiError::InvalidSignature => status::Unauthorized,
+            ApiError::PayloadTooLarge => status::PayloadTooLarge,
+            ApiError::RateLimited => status::TooManyRequests,
+            ApiError::ShuttingDown => status::ServiceUnavailable,
+            ApiError::DatabaseFailure
+            | ApiError::EmailFailure
+            | ApiError::CaptchaFailure
+            | ApiError::LoginFailure => status::InternalServerError,
+            _ => status::BadRequest,
+        }
+    }
+
+    // New variants take the next number, and removed ones leave theirs unused.
+    pub fn code(&self) -> u16 {
+        match *self {
+            ApiError::MissingParameter => 1,
+            ApiError::InvalidParameter => 2,
+            ApiError::MalformedBody => 3,
+            ApiError::PayloadTooLarge => 4,
+            ApiError::InvalidEmail => 5,
+            ApiError::InvalidName => 6,
+            ApiError::ReservedName => 7,
+            ApiError::UnavailableName => 8,
+            ApiError::UnavailableNameReclamationPossible => 9,
+            ApiError::ReclamationTokenMismatch => 10,
+            ApiError::NoSuchName => 11,
+            ApiError::NoEmail => 12,
+            ApiError::TooManyRecords => 13,
+            ApiError::UnknownToken => 14,
+            ApiError::UnknownTransferCode => 15,
+            ApiError::UnknownLogin => 16,
+            ApiError::Unauthorized => 17,
+            ApiError::InvalidSignature => 18,
+            ApiError::InvalidCaptcha => 19,
+            ApiError::UnverifiedEmail => 20,
+            ApiError::RateLimited => 21,
+            ApiError::ShuttingDown => 22,
+            ApiError::DatabaseFailure => 23,
+            ApiError::EmailFailure => 24,
+            ApiError::CaptchaFailure => 25,
+            ApiError::LoginFailure => 26,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match *self {
+            ApiError::MissingParameter => "A mandatory parameter is missing.",
+            ApiError::InvalidParameter => "A parameter has an invalid value.",
+            ApiError::MalformedBody => "The request body is not a valid JSON object.",
+            ApiError::PayloadTooLarge => "The request body is too large.",
+            ApiError::InvalidEmail => "The email address is invalid.",
+            ApiError::InvalidName => "The name is not a valid DNS label.",
+            ApiError::ReservedName => "The name is reserved.",
+            ApiError::UnavailableName => "The name is already in use.",
+            ApiError::UnavailableNameReclamationPossible => {
+                "The name is already in use, but can be reclaimed."
+            }
+            ApiError::ReclamationTokenMismatch => "The reclamation token is invalid.",
+            ApiError::NoSuchName => "The name is not registered.",
+            ApiError::NoEmail => "No verified email address is associated with this name.",
+            ApiError::TooManyRecords => "The domain has too many records of this type.",
+            ApiError::UnknownToken => "No domain is associated with this token.",
+            ApiError::UnknownTransferCode => "The transfer code is unknown or has expired.",
+            ApiError::UnknownLogin => "The login is unknown or has expired.",
+            ApiError::Unauthorized => "A valid admin token is required.",
+            ApiError::InvalidSignature => "The ping is not signed with the secret of the domain.",
+            ApiError::InvalidCaptcha => "The CAPTCHA solution is invalid.",
+            ApiError::UnverifiedEmail => {
+                "The identity provider did not confirm the email address of the user."
+            }
+            ApiError::RateLimited => "Too many requests, try again later.",
+            ApiError::ShuttingDown => "The server is shutting down, try again later.",
+            ApiError::DatabaseFailure => "The database could not process the request.",
+            ApiError::EmailFailure => "The email could not be sent.",
+            ApiError::CaptchaFailure => "The CAPTCHA solution could not be verified.",
+            ApiError::LoginFailure => {
+                "The login could not be completed with the identity provider."
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ErrorBody {
     pub code: u16,
-    pub errno: u16,
-    pub error: String,
+    pub error: ApiError,
+    pub message: &'static str,
 }
 
 pub struct EndpointError;
 
 impl EndpointError {
-    pub fn with(status: status::Status, errno: u16) -> IronResult<Response> {
-        let error = status.canonical_reason().unwrap().to_owned();
+    pub fn with(error: ApiError) -> IronResult<Response> {
         let body = ErrorBody {
-            code: status.to_u16(),
-            errno: errno,
-            error: error.clone(),
+            code: error.code(),
+            error: error,
+            message: error.message(),
         };
 
-        Err(IronError::new(
-            StringError(error),
-            (status, serde_json::to_string(&body).unwrap()),
-        ))
+        let mut response = Response::with((error.status(), serde_json::to_string(&body).unwrap()));
+        response.headers.set(ContentType::json());
+
+        Err(IronError {
+            error: Box::new(StringError(error.message().to_owned())),
+            response: response,
+        })
+    }
+
+    // Returns a RateLimited error telling the client how many seconds to wait
+    // before retrying.
+    pub fn rate_limited(retry_after: u64) -> IronResult<Response> {
+        let mut result = EndpointError::with(ApiError::RateLimited);
+        if let Err(ref mut error) = result {
+            error.response.headers.set(RetryAfter(retry_after));
+        }
+        result
+    }
+}
+
+// The body of the errors before they had an error code: the HTTP status,
+// repeated in errno, and its reason phrase.
+#[derive(Debug, Serialize)]
+pub struct LegacyErrorBody {
+    pub code: u16,
+    pub errno: u16,
+    pub error: String,
+}
+
+// Returns the status that the older clients expect instead of this one.
+fn legacy_status(status: Status) -> Status {
+    match status {
+        status::Conflict | status::NotFound => status::BadRequest,
+        status::InternalServerError => status::NotImplemented,
+        status => status,
+    }
+}
+
+// Answers the API errors like the server did before their status codes and
+// bodies changed, for the clients written back then: the taken names and the
+// unknown tokens get a 400 status, the internal errors a 501 one, and the
+// body is a LegacyErrorBody. The other errors, like the unknown routes, are
+// left as they are.
+pub struct LegacyStatusCodes;
+
+impl AfterMiddleware for LegacyStatusCodes {
+    fn catch(&self, _: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if !err.error.is::<StringError>() {
+            return Err(err);
+        }
+        let status = match err.response.status {
+            Some(status) => legacy_status(status),
+            None => return Err(err),
+        };
+        let body = LegacyErrorBody {
+            code: status.to_u16(),
+            errno: status.to_u16(),
+            error: status.canonical_reason().unwrap_or_default().to_owned(),
+        };
+        err.response.status = Some(status);
+        err.response.set_mut(serde_json::to_string(&body).unwrap());
+        Err(err)
     }
 }
 
 #[test]
 fn test_error() {
+    use iron_test::response;
+
     let _ = env_logger::init();
 
     let s_error = StringError(status::BadRequest.canonical_reason().unwrap().to_owned());
     let error = format!("{} {}", s_error, s_error.description());
     assert_eq!(error, r#"StringError("Bad Request") Bad Request"#);
 
-    let ep_error = EndpointError::with(status::InternalServerError, 500);
+    let ep_error = EndpointError::with(ApiError::DatabaseFailure);
     let error = ep_error.unwrap_err();
-    assert_eq!(error.description(), "Internal Server Error");
+    assert_eq!(
+        error.error.description(),
+        "The database could not process the request."
+    );
     assert_eq!(error.response.status.unwrap(), status::InternalServerError);
+    assert_eq!(
+        error.response.headers.get::<ContentType>(),
+        Some(&ContentType::json())
+    );
+
+    let error = EndpointError::with(ApiError::UnknownToken).unwrap_err();
+    assert_eq!(error.response.status.unwrap(), status::NotFound);
+
+    let error = EndpointError::with(ApiError::UnavailableName).unwrap_err();
+    assert_eq!(error.response.status.unwrap(), status::Conflict);
+
+    let error = EndpointError::with(ApiError::PayloadTooLarge).unwrap_err();
+    assert_eq!(error.response.status.unwrap(), status::PayloadTooLarge);
+
+    let error = EndpointError::rate_limited(120).unwrap_err();
+    assert_eq!(error.response.status.unwrap(), status::TooManyRequests);
+    assert_eq!(
+        error.response.headers.get::<RetryAfter>(),
+        Some(&RetryAfter(120))
+    );
+
+    let error = EndpointError::with(ApiError::UnavailableName).unwrap_err();
+    let body = response::extract_body_to_string(error.response);
+    assert_eq!(
+        body,
+        r#"{"code":8,"error":"UnavailableName","message":"The name is already in use."}"#
+    );
+    assert_eq!(ApiError::MissingParameter.code(), 1);
+    assert_eq!(ApiError::LoginFailure.code(), 26);
+}
+
+#[test]
+fn test_legacy_status_codes() {
+    use iron::{Chain, Headers};
+    use iron_test::{request, response};
+    use std::io;
+
+    let _ = env_logger::init();
+
+    let legacy = |error: ApiError| -> (Status, String) {
+        let mut chain = Chain::new(move |_: &mut Request| EndpointError::with(error));
+        chain.link_after(LegacyStatusCodes);
+        let err = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+        let status = err.response.status.unwrap();
+        (status, response::extract_body_to_string(err.response))
+    };
+    let bad_request = (
+        status::BadRequest,
+        r#"{"code":400,"errno":400,"error":"Bad Request"}"#.to_owned(),
+    );
+
+    assert_eq!(legacy(ApiError::UnavailableName), bad_request);
+    assert_eq!(legacy(ApiError::UnknownToken), bad_request);
+    assert_eq!(legacy(ApiError::MissingParameter), bad_request);
+    assert_eq!(
+        legacy(ApiError::DatabaseFailure),
+        (
+            status::NotImplemented,
+            r#"{"code":501,"errno":501,"error":"Not Implemented"}"#.to_owned()
+        )
+    );
+    // The statuses that older clients never got keep their value.
+    assert_eq!(
+        legacy(ApiError::RateLimited),
+        (
+            status::TooManyRequests,
+            r#"{"code":429,"errno":429,"error":"Too Many Requests"}"#.to_owned()
+        )
+    );
+
+    // The errors that don't come from the API are left as they are.
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Err(IronError::new(
+            io::Error::new(io::ErrorKind::Other, "no route"),
+            (status::NotFound, "Not found"),
+        ))
+    });
+    chain.link_after(LegacyStatusCodes);
+    let err = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+    assert_eq!(err.response.status, Some(status::NotFound));
+    assert_eq!(response::extract_body_to_string(err.response), "Not found");
 }
@@ -0,0 +1,461 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Removes the domains that have not pinged the server for longer than the
+// configured eviction delay. Until the next sweep runs, stale domains are
+// treated as if they were already gone. With the soft eviction policy, they
+// only stop resolving until they ping again, and with the never policy they
+// don't expire. The same sweeps warn the owners of
+// domains that are about to be evicted, once per lapse, and purge the
+// unsubscribed domains that can't be undeleted anymore. When warnings are on,
+// a verified domain is only evicted once its owner was warned.
+
+extern crate env_logger;
+use config::{Config, EvictionPolicy, GeneralOptions};
+use email_routes::{EmailSender, Mailer};
+use models::Domain;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Maximum time between two sweeps of the database, in seconds.
+const MAX_SWEEP_INTERVAL: u64 = 3600;
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// Returns the timestamp before which a domain is considered stale, or None
+// when eviction is turned off.
+pub fn eviction_threshold(options: &GeneralOptions, now: i64) -> Option<i64> {
+    if options.eviction_delay == 0 || options.eviction_policy == EvictionPolicy::Never {
+        return None;
+    }
+
+    Some(now - options.eviction_delay as i64)
+}
+
+// Returns when a domain will be evicted if it doesn't ping again, or None when
+// eviction is turned off.
+pub fn eviction_deadline(record: &Domain, options: &GeneralOptions) -> Option<i64> {
+    if options.eviction_delay == 0 || options.eviction_policy == EvictionPolicy::Never {
+        return None;
+    }
+
+    Some(record.timestamp + options.eviction_delay as i64)
+}
+
+// Returns the timestamp before which the owner of a domain is warned that it
+// will be evicted, or None when warnings or eviction are turned off.
+pub fn warning_threshold(options: &GeneralOptions, now: i64) -> Option<i64> {
+    if eviction_threshold(options, now).is_none() || options.eviction_warning_delay == 0 {
+        return None;
+    }
+
+    Some(now - options.eviction_warning_delay as i64)
+}
+
+// Returns the timestamp before which the owner of a stale domain must have been
+// warned for it to be evicted, or None when warnings are turned off. The owner
+// gets the notice that the two delays promise even when a sweep comes late.
+pub fn notice_threshold(options: &GeneralOptions, now: i64) -> Option<i64> {
+    if warning_threshold(options, now).is_none() {
+        return None;
+    }
+
+    let notice = options
+        .eviction_delay
+        .saturating_sub(options.eviction_warning_delay);
+    Some(now - notice as i64)
+}
+
+// Returns the timestamp before which an unsubscribed domain can't be undeleted
+// anymore, and is purged.
+pub fn undelete_threshold(options: &GeneralOptions, now: i64) -> i64 {
+    now - options.undelete_grace as i64
+}
+
+// Formats a Unix timestamp as a UTC date, eg. 2018-02-26.
+// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_date(timestamp: i64) -> String {
+    let z = timestamp / 86400 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Whether a domain stopped resolving for lack of pings.
+pub fn is_stale(record: &Domain, config: &Config) -> bool {
+    match eviction_threshold(&config.options.general, now()) {
+        Some(threshold) => record.timestamp < threshold,
+        None => false,
+    }
+}
+
+// Whether a domain is waiting to be removed, so that its token is refused.
+// With the soft policy, a stale domain is kept and can ping again.
+pub fn is_evicted(record: &Domain, config: &Config) -> bool {
+    config.options.general.eviction_policy == EvictionPolicy::Hard && is_stale(record, config)
+}
+
+fn evict_stale_domains(config: &Config) {
+    if config.options.general.eviction_policy != EvictionPolicy::Hard {
+        return;
+    }
+    let threshold = match eviction_threshold(&config.options.general, now()) {
+        Some(threshold) => threshold,
+        None => return,
+    };
+
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!(
+                "evict_stale_domains(): Failed to get database connection: {:?}",
+                err
+            );
+            return;
+        }
+    };
+
+    let notice = notice_threshold(&config.options.general, now());
+    match conn.delete_domains_older_than(threshold, notice) {
+        Ok(0) => (),
+        Ok(count) => info!("evict_stale_domains(): Evicted {} domain(s)", count),
+        Err(err) => error!("evict_stale_domains(): Failed to evict domains: {:?}", err),
+    }
+}
+
+// Removes the unsubscribed domains whose grace period ended, which frees their
+// names.
+fn purge_deleted_domains(config: &Config) {
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!(
+                "purge_deleted_domains(): Failed to get database connection: {:?}",
+                err
+            );
+            return;
+        }
+    };
+
+    let threshold = undelete_threshold(&config.options.general, now());
+    match conn.purge_deleted_domains(threshold) {
+        Ok(0) => (),
+        Ok(count) => info!("purge_deleted_domains(): Purged {} domain(s)", count),
+        Err(err) => error!(
+            "purge_deleted_domains(): Failed to purge domains: {:?}",
+            err
+        ),
+    }
+}
+
+// Removes the discovery tokens that expired.
+fn purge_expired_discovery_tokens(config: &Config) {
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!(
+                "purge_expired_discovery_tokens(): Failed to get database connection: {:?}",
+                err
+            );
+            return;
+        }
+    };
+
+    match conn.purge_expired_discovery_tokens(now()) {
+        Ok(0) => (),
+        Ok(count) => info!(
+            "purge_expired_discovery_tokens(): Purged {} token(s)",
+            count
+        ),
+        Err(err) => error!(
+            "purge_expired_discovery_tokens(): Failed to purge tokens: {:?}",
+            err
+        ),
+    }
+}
+
+// Sends an email to the owners of the domains that stopped pinging for longer
+// than the warning delay, and records it so that they are warned only once.
+// Returns the number of warnings sent.
+fn warn_expiring_domains(config: &Config, mailer: &mut Mailer, now: i64) -> usize {
+    let threshold = match warning_threshold(&config.options.general, now) {
+        Some(threshold) => threshold,
+        None => return 0,
+    };
+
+    let email = &config.options.email;
+    let (title, body) = match (&email.expiration_title, &email.expiration_body) {
+        (&Some(ref title), &Some(ref body)) => (title, body),
+        _ => {
+            error!("warn_expiring_domains(): The expiration email is not configured");
+            return 0;
+        }
+    };
+
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!(
+                "warn_expiring_domains(): Failed to get database connection: {:?}",
+                err
+            );
+            return 0;
+        }
+    };
+
+    let records = match conn.get_domains_to_warn(threshold) {
+        Ok(records) => records,
+        Err(err) => {
+            error!(
+                "warn_expiring_domains(): Failed to look up domains: {:?}",
+                err
+            );
+            return 0;
+        }
+    };
+
+    let mut count = 0;
+    for record in records {
+        let account = match conn.get_account_by_id(record.account_id) {
+            Ok(ref account) if account.email.is_empty() => continue,
+            Ok(account) => account,
+            Err(err) => {
+                error!(
+                    "warn_expiring_domains(): Failed to look up account: {:?}",
+                    err
+                );
+                continue;
+            }
+        };
+
+        // Failures are retried on the next sweep.
+        let body = body
+            .replace("{domain}", record.name.trim_right_matches('.'))
+            .replace("{last_seen}", &format_date(record.timestamp));
+        if let Err(err) = mailer.send(&account.email, &body, title) {
+            error!("warn_expiring_domains(): Failed to send email: {:?}", err);
+            continue;
+        }
+
+        match conn.update_domain_warning_sent_at(&record.token, now) {
+            Ok(_) => count += 1,
+            Err(err) => error!(
+                "warn_expiring_domains(): Failed to update domain: {:?}",
+                err
+            ),
+        }
+    }
+
+    count
+}
+
+fn send_eviction_warnings(config: &Config) {
+    if warning_threshold(&config.options.general, now()).is_none() {
+        return;
+    }
+
+    match EmailSender::new(config) {
+        Ok(mut sender) => match warn_expiring_domains(config, &mut sender, now()) {
+            0 => (),
+            count => info!("send_eviction_warnings(): Sent {} warning(s)", count),
+        },
+        Err(err) => error!(
+            "send_eviction_warnings(): Failed to create email sender: {:?}",
+            err
+        ),
+    }
+}
+
+pub fn start_eviction_thread(config: &Config) {
+    if eviction_threshold(&config.options.general, now()).is_none() {
+        info!("start_eviction_thread(): Eviction is turned off");
+    }
+
+    // The thread also runs when eviction is turned off, as it can be turned on
+    // by reloading the configuration.
+    let config = config.clone();
+    thread::Builder::new()
+        .name("domain eviction".to_owned())
+        .spawn(move || loop {
+            // The sweep doesn't start once the server is stopping, and the
+            // server waits for the one in progress.
+            if !config.in_flight.start() {
+                return;
+            }
+            let current = config.current();
+            evict_stale_domains(&current);
+            purge_deleted_domains(&current);
+            purge_expired_discovery_tokens(&current);
+            send_eviction_warnings(&current);
+            config.in_flight.finish();
+
+            let delay = current.options.general.eviction_delay;
+            let interval = if delay == 0 {
+                MAX_SWEEP_INTERVAL
+            } else {
+                cmp::min(delay, MAX_SWEEP_INTERVAL)
+            };
+            thread::sleep(Duration::from_secs(interval));
+        })
+        .expect("Failed to start eviction thread.");
+}
+
+#[test]
+fn test_eviction_threshold() {
+    use args::ArgsParser;
+
+    let _ = env_logger::init();
+
+    let args = ArgsParser::from_vec(vec!["registration_server"]);
+    assert_eq!(eviction_threshold(&args.general, 5000), None);
+
+    let args = ArgsParser::from_vec(vec!["registration_server", "--eviction-delay=1000"]);
+    assert_eq!(eviction_threshold(&args.general, 5000), Some(4000));
+
+    let args = ArgsParser::from_vec(vec![
+        "registration_server",
+        "--eviction-delay=1000",
+        "--eviction-policy=soft",
+    ]);
+    assert_eq!(eviction_threshold(&args.general, 5000), Some(4000));
+
+    let args = ArgsParser::from_vec(vec![
+        "registration_server",
+        "--eviction-delay=1000",
+        "--eviction-warning-delay=500",
+        "--eviction-policy=never",
+    ]);
+    assert_eq!(eviction_threshold(&args.general, 5000), None);
+    assert_eq!(warning_threshold(&args.general, 5000), None);
+}
+
+#[test]
+fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(951_782_400), "2000-02-29");
+    assert_eq!(format_date(1_519_603_200), "2018-02-26");
+}
+
+#[test]
+fn test_eviction_warnings() {
+    use args::ArgsParser;
+    use database::DatabasePool;
+
+    struct MockMailer {
+        sent: Vec<(String, String, String)>,
+    }
+
+    impl Mailer for MockMailer {
+        fn send(&mut self, to: &str, body: &str, subject: &str) -> Result<(), ()> {
+            self.sent
+                .push((to.to_owned(), body.to_owned(), subject.to_owned()));
+            Ok(())
+        }
+    }
+
+    let _ = env_logger::init();
+
+    #[cfg(feature = "mysql")]
+    let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_eviction_warnings");
+    #[cfg(feature = "postgres")]
+    let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_eviction_warnings");
+    #[cfg(feature = "sqlite")]
+    let db = DatabasePool::new("domain_db_test_eviction_warnings.sqlite");
+    let conn = db.get_connection().expect("Getting connection.");
+    conn.flush().expect("Flushing the db");
+
+    let args = ArgsParser::from_vec(vec![
+        "registration_server",
+        "--config-file=./config/config.toml",
+    ]);
+    let config = Config::from_args_with_db(args, db.clone());
+
+    let args = ArgsParser::from_vec(vec!["registration_server"]);
+    assert_eq!(warning_threshold(&args.general, 5000), None);
+    assert_eq!(
+        warning_threshold(&config.options.general, 2_160_000 + 5000),
+        Some(5000)
+    );
+
+    let now = now();
+    let day = 86400;
+    let account = conn.add_account("warning@example.com").unwrap();
+    let domains = vec![
+        ("lapsed.mydomain.org.", true, now - 26 * day),
+        ("unverified.mydomain.org.", false, now - 26 * day),
+        ("recent.mydomain.org.", true, now - day),
+    ];
+    for (domain_name, domain_verified, domain_timestamp) in domains {
+        conn.add_domain(
+            domain_name,
+            account.id,
+            &format!("secret-token-{}", domain_name),
+            "",
+            domain_timestamp,
+            "",
+            "",
+            "",
+            domain_verified,
+            "",
+        )
+        .unwrap();
+    }
+
+    // Only the verified lapsed domain is warned, and only once.
+    let mut mailer = MockMailer { sent: vec![] };
+    assert_eq!(warn_expiring_domains(&config, &mut mailer, now), 1);
+    assert_eq!(warn_expiring_domains(&config, &mut mailer, now + 3600), 0);
+    assert_eq!(mailer.sent.len(), 1);
+    let (ref to, ref body, ref subject) = mailer.sent[0];
+    assert_eq!(to, "warning@example.com");
+    assert_eq!(subject, "Your Mozilla IoT Gateway domain will expire");
+    assert!(body.contains("lapsed.mydomain.org has not been seen since"));
+    assert!(body.contains(&format_date(now - 26 * day)));
+
+    // Pinging clears the warning, so the next lapse is warned again.
+    let token = "secret-token-lapsed.mydomain.org.";
+    assert_eq!(
+        conn.get_domain_by_token(token).unwrap().warning_sent_at,
+        now
+    );
+    conn.update_domain_timestamp(token).unwrap();
+    assert_eq!(conn.get_domain_by_token(token).unwrap().warning_sent_at, 0);
+    assert_eq!(warn_expiring_domains(&config, &mut mailer, now), 0);
+
+    let later = now + 27 * day;
+    assert_eq!(warn_expiring_domains(&config, &mut mailer, later), 2);
+    assert_eq!(warn_expiring_domains(&config, &mut mailer, later), 0);
+    assert_eq!(mailer.sent.len(), 3);
+    let mut warned: Vec<&str> = mailer.sent[1..]
+        .iter()
+        .map(|&(_, ref body, _)| body.split_whitespace().nth(4).unwrap())
+        .collect();
+    warned.sort();
+    assert_eq!(warned, vec!["lapsed.mydomain.org", "recent.mydomain.org"]);
+
+    // The verified domains are only evicted once their owner had the notice.
+    assert_eq!(notice_threshold(&args.general, later), None);
+    assert_eq!(
+        notice_threshold(&config.options.general, later),
+        Some(later - 432_000)
+    );
+    assert_eq!(conn.delete_domains_older_than(later, Some(later)), Ok(1));
+    assert!(conn.get_domain_by_name("unverified.mydomain.org.").is_err());
+    assert_eq!(
+        conn.delete_domains_older_than(later, Some(later + 1)),
+        Ok(2)
+    );
+}
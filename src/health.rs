@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// The /health endpoint of the load balancers. Unlike /status, it doesn't need
+// the admin token: it runs a trivial query on the database and, when the
+// server has a PowerDNS socket, sends it the `initialize` request of PowerDNS.
+// Each dependency gets its own result, and any failure turns the response into
+// a 503, so that the instance is taken out of the pool. /__version__ tells the
+// operators which build an instance runs.
+//
+// The orchestrators like Kubernetes get two probes instead: /livez only tells
+// that the process answers, and a failure means it has to be restarted, while
+// /readyz also checks that the database answers and was migrated to the
+// schema of this build, and that the PowerDNS socket answers. A failing
+// readiness only keeps the traffic away until the dependencies are back.
+
+use config::Config;
+use iron::headers::ContentType;
+use iron::prelude::*;
+use iron::status::Status;
+use serde_json;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+// How long the PowerDNS socket has to answer.
+const PDNS_TIMEOUT_SECS: u64 = 2;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Check {
+    pub healthy: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub checks: BTreeMap<String, Check>,
+}
+
+// The commit and the date are set by build.rs.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Version {
+    pub version: String,
+    pub commit: String,
+    pub build_date: String,
+}
+
+fn run_check<F: FnOnce() -> Result<(), String>>(check: F) -> Check {
+    let start = Instant::now();
+    let result = check();
+    let latency = start.elapsed();
+    Check {
+        healthy: result.is_ok(),
+        latency_ms: latency.as_secs() * 1000 + u64::from(latency.subsec_nanos() / 1_000_000),
+        error: result.err(),
+    }
+}
+
+fn check_database(config: &Config) -> Result<(), String> {
+    let conn = config.db.get_connection()?;
+    conn.check()
+        .map(|_| ())
+        .map_err(|err| format!("Query failed: {}", err))
+}
+
+// Sends the request PowerDNS starts its connections with, and expects a
+// successful answer.
+fn check_pdns_socket(path: &str) -> Result<(), String> {
+    let timeout = Some(Duration::from_secs(PDNS_TIMEOUT_SECS));
+    let mut stream =
+        UnixStream::connect(path).map_err(|err| format!("Failed to connect: {}", err))?;
+    stream
+        .set_read_timeout(timeout)
+        .and_then(|_| stream.set_write_timeout(timeout))
+        .and_then(|_| stream.write_all(b"{\"method\":\"initialize\",\"parameters\":{}}\n"))
+        .map_err(|err| format!("Failed to send: {}", err))?;
+
+    let mut answer = String::new();
+    BufReader::new(stream)
+        .read_line(&mut answer)
+        .map_err(|err| format!("Failed to read: {}", err))?;
+    if answer.trim() != "{\"result\":true}" {
+        return Err(format!("Unexpected answer: {}", answer.trim()));
+    }
+    Ok(())
+}
+
+// Checks that the latest migration of this build was run. A database migrated
+// by a newer build is fine, as the migrations only add to the schema.
+fn check_migrations(config: &Config) -> Result<(), String> {
+    let expected = env!("BUILD_LATEST_MIGRATION");
+    let conn = config.db.get_connection()?;
+    let latest = conn
+        .latest_migration()
+        .map_err(|err| format!("Query failed: {}", err))?
+        .unwrap_or_default();
+    if latest.as_str() < expected {
+        return Err(format!(
+            "Latest migration is {:?}, expected {}",
+            latest, expected
+        ));
+    }
+    Ok(())
+}
+
+fn checks_response(checks: BTreeMap<String, Check>) -> Response {
+    let healthy = checks.values().all(|check| check.healthy);
+    let health = Health {
+        healthy: healthy,
+        checks: checks,
+    };
+
+    let mut response = Response::with(serde_json::to_string(&health).unwrap());
+    response.headers.set(ContentType::json());
+    response.status = Some(if healthy {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    });
+    response
+}
+
+pub fn health(_: &mut Request, config: &Config) -> IronResult<Response> {
+    let mut checks = BTreeMap::new();
+    checks.insert("database".to_owned(), run_check(|| check_database(config)));
+    if let Some(ref path) = config.options.pdns.socket_path {
+        checks.insert("pdns".to_owned(), run_check(|| check_pdns_socket(path)));
+    }
+
+    if checks.values().any(|check| !check.healthy) {
+        error!("health(): Failing checks: {:?}", checks);
+    }
+    Ok(checks_response(checks))
+}
+
+// Answers as long as the process can handle requests, without checking its
+// dependencies, so that their outages don't get the instances restarted.
+pub fn livez(_: &mut Request) -> IronResult<Response> {
+    Ok(checks_response(BTreeMap::new()))
+}
+
+pub fn readyz(_: &mut Request, config: &Config) -> IronResult<Response> {
+    let mut checks = BTreeMap::new();
+    checks.insert("database".to_owned(), run_check(|| check_database(config)));
+    checks.insert(
+        "migrations".to_owned(),
+        run_check(|| check_migrations(config)),
+    );
+    if let Some(ref path) = config.options.pdns.socket_path {
+        checks.insert("pdns".to_owned(), run_check(|| check_pdns_socket(path)));
+    }
+
+    if checks.values().any(|check| !check.healthy) {
+        warn!("readyz(): Failing checks: {:?}", checks);
+    }
+    Ok(checks_response(checks))
+}
+
+pub fn version(_: &mut Request) -> IronResult<Response> {
+    json_response!(&Version {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        commit: env!("BUILD_GIT_COMMIT").to_owned(),
+        build_date: env!("BUILD_DATE").to_owned(),
+    })
+}
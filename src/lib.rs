@@ -8,11 +8,16 @@ extern crate crypto;
 #[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 #[macro_use]
 extern crate diesel;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
+#[macro_use]
+extern crate diesel_migrations;
 extern crate email;
 #[macro_use]
 extern crate hyper;
+extern crate hyper_native_tls;
+extern crate hyper_rustls;
+extern crate idna;
 extern crate iron;
-extern crate iron_cors;
 #[cfg(test)]
 extern crate iron_test;
 extern crate lettre;
@@ -27,10 +32,12 @@ extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate regex;
 extern crate router;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate signal_hook;
 extern crate toml;
 extern crate uuid;
 
@@ -77,12 +84,45 @@ macro_rules! ok_response {
     )
 }
 
+// Unwraps the result of a parameter extraction, or returns early with the
+// matching error response.
+macro_rules! try_param {
+    ($result:expr) => (
+        match $result {
+            Ok(value) => value,
+            Err(err) => return EndpointError::with(err),
+        }
+    )
+}
+
+pub mod admin_routes;
+pub mod api_socket;
+pub mod audit;
 pub mod args;
+pub mod captcha;
 pub mod config;
+pub mod cors;
 pub mod database;
+pub mod dns_cache;
+pub mod dump;
 pub mod email_routes;
 pub mod errors;
+pub mod eviction;
+pub mod health;
+pub mod logging;
+pub mod metrics;
 pub mod models;
+pub mod oidc_routes;
+pub mod parameters;
 pub mod pdns;
+pub mod ping_auth;
+pub mod proxy;
+pub mod rate_limit;
+pub mod request_id;
 pub mod routes;
 pub mod schema;
+pub mod sentry;
+pub mod signals;
+pub mod statsd;
+pub mod systemd;
+pub mod tls;
@@ -0,0 +1,346 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Sets up the logger, which writes the text lines of env_logger or, with the
+// `json_logs` option, one JSON record per line for the log collectors like
+// Elasticsearch or Loki. Both carry the id of the request being handled. Each
+// API request also gets an access record once answered, with its endpoint, a
+// hash of its token, the client address and its latency, which the JSON
+// records hold as separate fields. The filters still come from `RUST_LOG`, where
+// the access records have their own `access` target. With `access_log`, they
+// are appended to that file instead, and `disable_access_log` turns them off.
+// The requests slower than `slow_request_ms` also get a warning, along with
+// their parameters without the secrets.
+
+extern crate env_logger;
+use config::Config;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use iron::prelude::*;
+use iron::status::Status;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware};
+use parameters::{read_parsed, read_token};
+use proxy::client_ip;
+use request_id;
+use router::Router;
+use serde_json;
+use std::cell::RefCell;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Length of the token hashes, in hexadecimal digits.
+const TOKEN_HASH_LENGTH: usize = 16;
+
+// Target of the access records.
+const ACCESS_TARGET: &str = "access";
+
+// The fields of the access record of a request.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Access {
+    method: String,
+    endpoint: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_hash: Option<String>,
+    client_ip: String,
+    latency_ms: u64,
+}
+
+thread_local! {
+    static CURRENT_ACCESS: RefCell<Option<Access>> = RefCell::new(None);
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    // Unix time in milliseconds.
+    timestamp: u64,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    message: String,
+    #[serde(flatten)]
+    access: Option<Access>,
+}
+
+fn now_ms() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_nanos() / 1_000_000)
+}
+
+fn text_line(level: &str, target: &str, request_id: Option<String>, message: &str) -> String {
+    match request_id {
+        Some(id) => format!("{}:{}: [{}] {}", level, target, id, message),
+        None => format!("{}:{}: {}", level, target, message),
+    }
+}
+
+fn json_line(
+    timestamp: u64,
+    level: &str,
+    target: &str,
+    request_id: Option<String>,
+    message: String,
+    access: Option<Access>,
+) -> String {
+    let record = JsonRecord {
+        timestamp: timestamp,
+        level: level,
+        target: target,
+        request_id: request_id,
+        message: message,
+        access: access,
+    };
+    serde_json::to_string(&record).unwrap_or_default()
+}
+
+pub fn init(json: bool) {
+    let mut builder = env_logger::LogBuilder::new();
+    if json {
+        builder.format(|record| {
+            json_line(
+                now_ms(),
+                &record.level().to_string(),
+                record.target(),
+                request_id::current(),
+                record.args().to_string(),
+                CURRENT_ACCESS.with(|access| access.borrow().clone()),
+            )
+        });
+    } else {
+        builder.format(|record| {
+            text_line(
+                &record.level().to_string(),
+                record.target(),
+                request_id::current(),
+                &record.args().to_string(),
+            )
+        });
+    }
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init().unwrap();
+}
+
+// Hashes the token of a request, so that its records can be told apart
+// without the logs giving the token away.
+pub fn token_hash(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    let mut hash = hasher.result_str();
+    hash.truncate(TOKEN_HASH_LENGTH);
+    hash
+}
+
+// Returns the path of the request, with the token of the resource routes
+// replaced, eg. `/v1/domains/:token/ip`.
+fn endpoint(path: &[&str], token: Option<&str>) -> String {
+    let segments: Vec<&str> = path
+        .iter()
+        .map(|&segment| match token {
+            Some(token) if segment == token => ":token",
+            _ => segment,
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+// Returns the endpoint of a request that was routed, without its token.
+pub fn request_endpoint(req: &Request) -> String {
+    let path_token = req
+        .extensions
+        .get::<Router>()
+        .and_then(|route| route.find("token"));
+    endpoint(&req.url.path(), path_token)
+}
+
+// The time at which the request was received.
+struct RequestStart;
+
+impl Key for RequestStart {
+    type Value = Instant;
+}
+
+// Writes the access records. It is linked right after the request ids.
+#[derive(Clone)]
+pub struct LogRequests {
+    config: Config,
+    // The access log file, along with its path, opened on the first record.
+    file: Arc<Mutex<Option<(String, File)>>>,
+}
+
+impl LogRequests {
+    pub fn new(config: &Config) -> Self {
+        LogRequests {
+            config: config.clone(),
+            file: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Appends a line to the access log, which is opened again when its path
+    // was changed by a reload.
+    fn write(&self, path: &str, line: &str) {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let reopen = match *file {
+            Some((ref opened, _)) => opened != path,
+            None => true,
+        };
+        if reopen {
+            *file = match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(opened) => Some((path.to_owned(), opened)),
+                Err(err) => {
+                    error!("write(): Failed to open the access log {}: {}", path, err);
+                    None
+                }
+            };
+        }
+        if let Some((_, ref mut opened)) = *file {
+            if let Err(err) = writeln!(opened, "{}", line) {
+                error!(
+                    "write(): Failed to write to the access log {}: {}",
+                    path, err
+                );
+            }
+        }
+    }
+
+    fn log(&self, req: &mut Request, status: Option<Status>) {
+        let latency = match req.extensions.get::<RequestStart>() {
+            Some(start) => start.elapsed(),
+            None => return,
+        };
+        let config = self.config.current();
+        let endpoint = request_endpoint(req);
+        let token_hash = read_token(req).map(|token| token_hash(&token));
+        let access = Access {
+            method: req.method.to_string(),
+            endpoint: endpoint,
+            // Iron answers with a 404 when no status is set.
+            status: status.map_or(404, |status| status.to_u16()),
+            token_hash: token_hash,
+            client_ip: client_ip(req, &config).to_string(),
+            latency_ms: latency.as_secs() * 1000 + u64::from(latency.subsec_nanos() / 1_000_000),
+        };
+        let message = format!(
+            "{} {} {} {} {}ms",
+            access.client_ip, access.method, access.endpoint, access.status, access.latency_ms
+        );
+
+        let slow_request_ms = config.options.general.slow_request_ms;
+        if slow_request_ms > 0 && access.latency_ms >= slow_request_ms {
+            let parameters = read_parsed(req)
+                .map(|params| params.sanitized())
+                .unwrap_or_default();
+            CURRENT_ACCESS.with(|current| *current.borrow_mut() = Some(access.clone()));
+            warn!(
+                "log(): Slow request, {} with the parameters {:?}",
+                message, parameters
+            );
+            CURRENT_ACCESS.with(|current| *current.borrow_mut() = None);
+        }
+        if config.options.general.disable_access_log {
+            return;
+        }
+
+        if let Some(ref path) = config.options.general.access_log {
+            let line = if config.options.general.json_logs {
+                json_line(
+                    now_ms(),
+                    "INFO",
+                    ACCESS_TARGET,
+                    request_id::current(),
+                    message,
+                    Some(access),
+                )
+            } else {
+                text_line("INFO", ACCESS_TARGET, request_id::current(), &message)
+            };
+            self.write(path, &line);
+            return;
+        }
+
+        CURRENT_ACCESS.with(|current| *current.borrow_mut() = Some(access));
+        info!(target: ACCESS_TARGET, "{}", message);
+        CURRENT_ACCESS.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+impl BeforeMiddleware for LogRequests {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<RequestStart>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for LogRequests {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.log(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.log(req, err.response.status);
+        Err(err)
+    }
+}
+
+#[test]
+fn test_log_lines() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+        text_line("INFO", "registration_server::routes", None, "GET /ping"),
+        "INFO:registration_server::routes: GET /ping"
+    );
+    assert_eq!(
+        text_line(
+            "INFO",
+            "registration_server::routes",
+            Some("abc".to_owned()),
+            "GET /ping"
+        ),
+        "INFO:registration_server::routes: [abc] GET /ping"
+    );
+
+    assert_eq!(
+        json_line(
+            1523953187000,
+            "ERROR",
+            "registration_server::pdns",
+            None,
+            "Failed: \"quoted\"".to_owned(),
+            None
+        ),
+        r#"{"timestamp":1523953187000,"level":"ERROR","target":"registration_server::pdns","message":"Failed: \"quoted\""}"#
+    );
+    let access = Access {
+        method: "GET".to_owned(),
+        endpoint: endpoint(&["v1", "domains", "abc", "ip"], Some("abc")),
+        status: 200,
+        token_hash: Some(token_hash("abc")),
+        client_ip: "192.0.2.1".to_owned(),
+        latency_ms: 3,
+    };
+    assert_eq!(
+        json_line(
+            1523953187000,
+            "INFO",
+            "registration_server::logging",
+            Some("id".to_owned()),
+            "access".to_owned(),
+            Some(access)
+        ),
+        r#"{"timestamp":1523953187000,"level":"INFO","target":"registration_server::logging","request_id":"id","message":"access","method":"GET","endpoint":"/v1/domains/:token/ip","status":200,"token_hash":"ba7816bf8f01cfea","client_ip":"192.0.2.1","latency_ms":3}"#
+    );
+}
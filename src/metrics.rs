@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Collects metrics about the server and exports them in the Prometheus text
+// format on the /metrics endpoint, which is served on its own port so that it
+// can be kept private. The metrics are:
+//
+// registration_http_requests_total{route, status}: counter of the HTTP
+//   requests, by route and status class (2xx, 4xx...).
+// registration_http_request_duration_seconds{route}: histogram of the time
+//   spent handling the HTTP requests, by route.
+// registration_dns_lookups_total{result}: counter of the DNS lookups for names
+//   of the domain, by result (hit when the name is registered, miss if not).
+// registration_domains: gauge of the number of registered domains, refreshed
+//   whenever the metrics are scraped.
+//
+// These names are stable, as dashboards and alerts rely on them. The requests
+// and the DNS lookups can also be sent to a StatsD server, see statsd.rs.
+
+extern crate env_logger;
+use config::{Config, GeneralOptions};
+use iron::headers::ContentType;
+use iron::prelude::*;
+use iron::status::Status;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware};
+use router::Router;
+use statsd::Statsd;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// The routes that get their own label, others being counted as "other" so that
+// random paths don't create new series. The versions of a route, eg. /ping and
+// /v1/ping, share their label, the domain resources share the "domains" label,
+// the admin routes the "admin" label and the logins the "oidc" label.
+const ROUTES: [&str; 31] = [
+    "admin",
+    "subscribe",
+    "unsubscribe",
+    "undelete",
+    "revoke",
+    "reclaim",
+    "ping",
+    "dnsconfig",
+    "clearchallenge",
+    "setwildcard",
+    "updatedesc",
+    "settxt",
+    "deletetxt",
+    "setmx",
+    "setsrv",
+    "deletesrv",
+    "transfer",
+    "redeem",
+    "devicetoken",
+    "adddiscovery",
+    "revokediscovery",
+    "discoverytokens",
+    "discovery",
+    "info",
+    "checkname",
+    "setemail",
+    "verifyemail",
+    "revokeemail",
+    "status",
+    "domains",
+    "oidc",
+];
+
+#[derive(Default)]
+struct Histogram {
+    // Number of observations in each bucket of LATENCY_BUCKETS, not cumulative.
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<(&'static str, u16), u64>,
+    latencies: BTreeMap<&'static str, Histogram>,
+    dns_hits: u64,
+    dns_misses: u64,
+}
+
+// Shared by all the clones of the configuration.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    statsd: Option<Arc<Statsd>>,
+}
+
+fn as_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // Also sends the metrics to the StatsD server of the options, if any.
+    pub fn with_options(options: &GeneralOptions) -> Self {
+        let host = match options.statsd_host {
+            Some(ref host) => host,
+            None => return Metrics::new(),
+        };
+        match Statsd::new(
+            host,
+            options.statsd_port,
+            &options.statsd_prefix,
+            options.statsd_tags,
+        ) {
+            Ok(statsd) => Metrics {
+                registry: Arc::default(),
+                statsd: Some(Arc::new(statsd)),
+            },
+            Err(err) => {
+                error!("Not sending the metrics to StatsD: {}", err);
+                Metrics::new()
+            }
+        }
+    }
+
+    fn registry(&self) -> MutexGuard<Registry> {
+        match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn record_request(&self, route: &'static str, status: u16, duration: Duration) {
+        let seconds = as_seconds(duration);
+        let mut registry = self.registry();
+        *registry.requests.entry((route, status / 100)).or_insert(0) += 1;
+
+        let histogram = registry
+            .latencies
+            .entry(route)
+            .or_insert_with(Histogram::default);
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[index] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+
+        if let Some(ref statsd) = self.statsd {
+            let class = format!("{}xx", status / 100);
+            statsd.count("http_requests", &[("route", route), ("status", &class)]);
+            statsd.timing("http_request_duration", duration, &[("route", route)]);
+        }
+    }
+
+    pub fn record_dns_lookup(&self, hit: bool) {
+        {
+            let mut registry = self.registry();
+            if hit {
+                registry.dns_hits += 1;
+            } else {
+                registry.dns_misses += 1;
+            }
+        }
+
+        if let Some(ref statsd) = self.statsd {
+            let result = if hit { "hit" } else { "miss" };
+            statsd.count("dns_lookups", &[("result", result)]);
+        }
+    }
+
+    // Renders the metrics in the Prometheus text format.
+    fn render(&self, domains: Option<i64>) -> String {
+        let registry = self.registry();
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP registration_http_requests_total Number of HTTP requests by route and \
+             status class.\n\
+             # TYPE registration_http_requests_total counter\n",
+        );
+        for (&(route, class), count) in &registry.requests {
+            let _ = writeln!(
+                out,
+                "registration_http_requests_total{{route=\"{}\",status=\"{}xx\"}} {}",
+                route, class, count
+            );
+        }
+
+        out.push_str(
+            "# HELP registration_http_request_duration_seconds Time spent handling HTTP \
+             requests by route.\n\
+             # TYPE registration_http_request_duration_seconds histogram\n",
+        );
+        for (route, histogram) in &registry.latencies {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "registration_http_request_duration_seconds_bucket\
+                     {{route=\"{}\",le=\"{}\"}} {}",
+                    route, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "registration_http_request_duration_seconds_bucket\
+                 {{route=\"{}\",le=\"+Inf\"}} {}\n\
+                 registration_http_request_duration_seconds_sum{{route=\"{}\"}} {}\n\
+                 registration_http_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, histogram.count, route, histogram.sum, route, histogram.count
+            );
+        }
+
+        let _ = write!(
+            out,
+            "# HELP registration_dns_lookups_total Number of DNS lookups for names of the \
+             domain by result.\n\
+             # TYPE registration_dns_lookups_total counter\n\
+             registration_dns_lookups_total{{result=\"hit\"}} {}\n\
+             registration_dns_lookups_total{{result=\"miss\"}} {}\n",
+            registry.dns_hits, registry.dns_misses
+        );
+
+        if let Some(domains) = domains {
+            let _ = write!(
+                out,
+                "# HELP registration_domains Number of registered domains.\n\
+                 # TYPE registration_domains gauge\n\
+                 registration_domains {}\n",
+                domains
+            );
+        }
+
+        out
+    }
+}
+
+struct StartTime;
+
+impl Key for StartTime {
+    type Value = Instant;
+}
+
+// Records the route, status and latency of each request. It has to be linked
+// both before and after the handlers.
+#[derive(Clone)]
+pub struct RequestMetrics {
+    metrics: Metrics,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: &Metrics) -> Self {
+        RequestMetrics {
+            metrics: metrics.clone(),
+        }
+    }
+
+    fn record(&self, req: &mut Request, status: Option<Status>) {
+        let start = match req.extensions.get::<StartTime>() {
+            Some(start) => *start,
+            None => return,
+        };
+
+        let path = req.url.path();
+        let route = ROUTES
+            .iter()
+            .cloned()
+            .find(|route| path.contains(route))
+            .unwrap_or("other");
+        // Iron answers with a 404 when no status is set.
+        let status = status.map(|status| status.to_u16()).unwrap_or(404);
+        self.metrics.record_request(route, status, start.elapsed());
+    }
+}
+
+impl BeforeMiddleware for RequestMetrics {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<StartTime>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestMetrics {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.record(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.record(req, err.response.status);
+        Err(err)
+    }
+}
+
+pub fn metrics(_: &mut Request, config: &Config) -> IronResult<Response> {
+    let domains = match config.db.get_connection() {
+        Ok(conn) => match conn.count_domains() {
+            Ok(count) => Some(count),
+            Err(err) => {
+                error!("metrics(): Failed to count domains: {:?}", err);
+                None
+            }
+        },
+        Err(err) => {
+            error!("metrics(): Failed to get database connection: {:?}", err);
+            None
+        }
+    };
+
+    let mut response = Response::with(config.metrics.render(domains));
+    response.headers.set(ContentType::plaintext());
+    response.status = Some(Status::Ok);
+    Ok(response)
+}
+
+// The router of the metrics port, separate from the API.
+pub fn create_router(config: &Config) -> Router {
+    let mut router = Router::new();
+    let config = config.clone();
+    router.get(
+        "metrics",
+        move |req: &mut Request| -> IronResult<Response> { metrics(req, &config) },
+        "get_metrics",
+    );
+    router
+}
+
+#[test]
+fn test_render() {
+    let _ = env_logger::init();
+
+    let metrics = Metrics::new();
+    metrics.record_request("ping", 200, Duration::from_millis(3));
+    metrics.record_request("ping", 200, Duration::from_millis(30));
+    metrics.record_request("ping", 404, Duration::from_secs(20));
+    metrics.record_dns_lookup(true);
+    metrics.record_dns_lookup(false);
+    metrics.record_dns_lookup(false);
+
+    let text = metrics.render(Some(42));
+    let has = |line: &str| text.lines().any(|l| l == line);
+    assert!(has(
+        "registration_http_requests_total{route=\"ping\",status=\"2xx\"} 2"
+    ));
+    assert!(has(
+        "registration_http_requests_total{route=\"ping\",status=\"4xx\"} 1"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_bucket{route=\"ping\",le=\"0.005\"} 1"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_bucket{route=\"ping\",le=\"0.05\"} 2"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_bucket{route=\"ping\",le=\"10\"} 2"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_bucket{route=\"ping\",le=\"+Inf\"} 3"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_count{route=\"ping\"} 3"
+    ));
+    assert!(has("registration_dns_lookups_total{result=\"hit\"} 1"));
+    assert!(has("registration_dns_lookups_total{result=\"miss\"} 2"));
+    assert!(has("registration_domains 42"));
+
+    // The gauge is left out when the database can't be queried.
+    assert!(!metrics.render(None).contains("registration_domains"));
+}
+
+#[test]
+fn test_scrape() {
+    use args::ArgsParser;
+    use database::DatabasePool;
+    use iron::Headers;
+    use iron_test::{request, response};
+    use routes::create_chain;
+
+    let _ = env_logger::init();
+
+    #[cfg(feature = "mysql")]
+    let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_metrics");
+    #[cfg(feature = "postgres")]
+    let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_metrics");
+    #[cfg(feature = "sqlite")]
+    let db = DatabasePool::new("domain_db_test_metrics.sqlite");
+    let conn = db.get_connection().expect("Getting connection.");
+    conn.flush().expect("Flushing the db");
+
+    let args = ArgsParser::from_vec(vec![
+        "registration_server",
+        "--config-file=./config/config.toml",
+    ]);
+    let config = Config::from_args_with_db(args, db.clone());
+    let chain = create_chain("/", &config);
+
+    let get = |url: &str, handler: &::iron::Handler| -> Status {
+        match request::get(url, Headers::new(), handler) {
+            Ok(response) => response.status.unwrap(),
+            Err(err) => err.response.status.unwrap(),
+        }
+    };
+    assert_eq!(
+        get("http://localhost/subscribe?name=test", &chain),
+        Status::Ok
+    );
+    assert_eq!(
+        get("http://localhost/ping?token=wrong", &chain),
+        Status::NotFound
+    );
+    assert_eq!(
+        get("http://localhost/v1/ping?token=wrong", &chain),
+        Status::NotFound
+    );
+
+    let router = create_router(&config);
+    let resp = request::get("http://localhost/metrics", Headers::new(), &router).unwrap();
+    assert_eq!(resp.status, Some(Status::Ok));
+    let text = response::extract_body_to_string(resp);
+    let has = |line: &str| text.lines().any(|l| l == line);
+    assert!(has(
+        "registration_http_requests_total{route=\"subscribe\",status=\"2xx\"} 1"
+    ));
+    assert!(has(
+        "registration_http_requests_total{route=\"ping\",status=\"4xx\"} 2"
+    ));
+    assert!(has(
+        "registration_http_request_duration_seconds_count{route=\"subscribe\"} 1"
+    ));
+    assert!(has("registration_domains 1"));
+}
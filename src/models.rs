@@ -1,10 +1,14 @@
-use schema::{accounts, domains};
+use schema::{accounts, api_keys, audit_events, deleted_domains, discovery_tokens, domains,
+             srv_records, transfers};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, AsChangeset, Identifiable, Queryable)]
 #[table_name = "accounts"]
 pub struct Account {
     pub id: i32,
     pub email: String,
+    // Identifier of the account at the OpenID Connect provider, empty if the
+    // owner never logged in with it.
+    pub oidc_subject: String,
 }
 
 #[derive(Insertable)]
@@ -24,11 +28,39 @@ pub struct Domain {
     pub token: String,
     pub description: String,
     pub timestamp: i64,
+    // Comma separated DNS challenges, at most two for wildcard certificates.
     pub dns_challenge: String,
     pub reclamation_token: String,
     pub verification_token: String,
     pub verified: bool,
     pub continent: String,
+    // Comma separated addresses of the gateway on its local networks.
+    pub local_ips: String,
+    // When the owner was warned that the domain will be evicted, 0 if not.
+    pub warning_sent_at: i64,
+    // Whether the names under this domain resolve like the domain itself.
+    pub wildcard: bool,
+    // When the DNS challenge was set, or 0 if there is none.
+    pub challenge_set_at: i64,
+    // SHA-256 hash of the secret used to sign the pings, in hexadecimal, or
+    // empty if the pings only carry the token. Never sent to the gateways.
+    #[serde(skip)]
+    pub auth_key: String,
+    // Mail server of the domain, with a trailing dot, or empty if the domain
+    // doesn't receive email.
+    pub mx_target: String,
+    pub mx_priority: i32,
+    // Label of the local name of the domain, or empty to use the one of the
+    // configuration.
+    pub local_prefix: String,
+    // Token that only gives access to what the gateway itself needs: pinging,
+    // the DNS challenges and the tunnel. Empty if the domain has none.
+    pub device_token: String,
+    // When the domain was subscribed, and when its settings or records last
+    // changed. Unlike `timestamp`, the pings don't update them unless they
+    // change the local addresses.
+    pub created_at: i64,
+    pub updated_at: i64,
 }
 
 #[derive(Insertable)]
@@ -44,4 +76,185 @@ pub struct NewDomain<'a> {
     pub verification_token: &'a str,
     pub verified: bool,
     pub continent: &'a str,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+// A key giving access to the admin routes. Only the SHA-256 hash of the key is
+// stored.
+#[derive(Clone, Debug, PartialEq, Identifiable, Queryable)]
+#[table_name = "api_keys"]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub key_hash: String,
+    pub created_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+    pub name: &'a str,
+    pub key_hash: &'a str,
+    pub created_at: i64,
+}
+
+// A change made to a domain by an API request, kept for the abuse
+// investigations. The events are never updated nor removed, even along with
+// their domain.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Identifiable, Queryable)]
+#[table_name = "audit_events"]
+pub struct AuditEvent {
+    pub id: i32,
+    // The full name of the domain.
+    pub name: String,
+    // The endpoint of the request, eg. `subscribe` or `admin/ban`.
+    pub action: String,
+    // `admin`, or the start of the hash of the token of the domain, or empty
+    // for the requests without a token.
+    pub actor: String,
+    pub client_ip: String,
+    pub request_id: String,
+    // The parameters of the request as a JSON object, without the secrets.
+    pub details: String,
+    pub created_at: i64,
+}
+
+// The criteria of a search of the audit events. The events are returned by
+// increasing id, starting after the `after` id, so that the pages stay stable
+// while new events are added.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditFilter {
+    pub name: Option<String>,
+    pub actor: Option<String>,
+    pub client_ip: Option<String>,
+    // The inclusive range of the creation times, in seconds since the epoch.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub after: i32,
+    pub limit: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_events"]
+pub struct NewAuditEvent<'a> {
+    pub name: &'a str,
+    pub action: &'a str,
+    pub actor: &'a str,
+    pub client_ip: &'a str,
+    pub request_id: &'a str,
+    pub details: &'a str,
+    pub created_at: i64,
+}
+
+// A domain removed by /unsubscribe, which its token can restore with /undelete
+// until the grace period ends. Its name can't be subscribed meanwhile.
+#[derive(Clone, Debug, PartialEq, Identifiable, Queryable)]
+#[table_name = "deleted_domains"]
+pub struct DeletedDomain {
+    pub id: i32,
+    pub name: String,
+    pub token: String,
+    // The domain and its records as a JSON `DeletedRecord`.
+    pub record: String,
+    pub deleted_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "deleted_domains"]
+pub struct NewDeletedDomain<'a> {
+    pub name: &'a str,
+    pub token: &'a str,
+    pub record: &'a str,
+    pub deleted_at: i64,
+}
+
+// What is kept of a deleted domain to restore it: the stored fields, with the
+// key of the signed pings that the serialization of `Domain` leaves out, the
+// email address of its account, its custom records and its discovery tokens.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeletedRecord {
+    pub domain: Domain,
+    pub auth_key: String,
+    pub email: String,
+    pub txt_records: Vec<String>,
+    pub srv_records: Vec<SrvRecord>,
+    // Missing from the domains deleted before discovery tokens existed.
+    #[serde(default)]
+    pub discovery_tokens: Vec<DiscoveryToken>,
+}
+
+// A pending transfer of a domain to a new owner, who can redeem the code until
+// it expires.
+#[derive(Clone, Debug, PartialEq, Identifiable, Queryable)]
+#[table_name = "transfers"]
+pub struct Transfer {
+    pub id: i32,
+    pub domain_id: i32,
+    // The SHA-256 hash of the code.
+    pub code: String,
+    pub expires_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "transfers"]
+pub struct NewTransfer<'a> {
+    pub domain_id: i32,
+    pub code: &'a str,
+    pub expires_at: i64,
+}
+
+// A service published by a domain, answered as the SRV record of
+// `_<service>._<protocol>.<name>.<domain>`, which points to the domain itself.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Identifiable, Queryable)]
+#[table_name = "srv_records"]
+pub struct SrvRecord {
+    pub id: i32,
+    pub domain_id: i32,
+    pub service: String,
+    pub protocol: String,
+    pub priority: i32,
+    pub weight: i32,
+    pub port: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "srv_records"]
+pub struct NewSrvRecord<'a> {
+    pub domain_id: i32,
+    pub service: &'a str,
+    pub protocol: &'a str,
+    pub priority: i32,
+    pub weight: i32,
+    pub port: i32,
+}
+
+// A token that lets the apps of a domain find the gateway on the local network
+// without holding the token of the domain. Only its hash is stored, and the
+// label tells the owner which app it was given to.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Identifiable, Queryable)]
+#[table_name = "discovery_tokens"]
+pub struct DiscoveryToken {
+    pub id: i32,
+    pub domain_id: i32,
+    pub token: String,
+    pub label: String,
+    pub created_at: i64,
+    // 0 until the token is first used.
+    pub last_used_at: i64,
+    // 0 for a token that doesn't expire; missing from the domains deleted
+    // before tokens could expire.
+    #[serde(default)]
+    pub expires_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "discovery_tokens"]
+pub struct NewDiscoveryToken<'a> {
+    pub domain_id: i32,
+    pub token: &'a str,
+    pub label: &'a str,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
 }
@@ -0,0 +1,416 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Lets the owners log in with an OpenID Connect provider, with the
+// authorization code flow. /oidc/login sends the browser to the provider,
+// which sends it back to /oidc/callback with a code. The code is exchanged for
+// an access token, and the access token for the subject and email address of
+// the user. Both requests go straight to the provider over TLS, so the ID token
+// doesn't need to be checked.
+//
+// The account of the user is the one linked to the subject, or else the one of
+// its email address, which the provider must have verified. A login started
+// with the token of a domain links the domain to the account, and every login
+// returns the tokens of the domains of the account, so that an owner who lost
+// a token can get it back.
+
+extern crate env_logger;
+use captcha::form_encode;
+use config::{Config, GeneralOptions};
+use database::Database;
+use diesel;
+use diesel::result::QueryResult;
+use email_routes::is_valid_email;
+use errors::*;
+use eviction::now;
+use hyper::net::HttpsConnector;
+use hyper::Client;
+use hyper_native_tls::NativeTlsClient;
+use iron::headers::{Authorization, Bearer, ContentType};
+use iron::modifiers::RedirectRaw;
+use iron::prelude::*;
+use iron::status::Status;
+use models::Account;
+use parameters::Parameters;
+use serde_json;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How long to wait for the provider.
+const TIMEOUT_SECS: u64 = 10;
+
+// How long the user has to log in at the provider, in seconds.
+const LOGIN_TTL: i64 = 600;
+
+// Maximum number of pending logins, to bound the memory used.
+const MAX_LOGINS: usize = 10_000;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct UserInfo {
+    sub: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+// Returned by /oidc/callback, with the domains of the account.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Login {
+    pub email: String,
+    pub domains: Vec<LinkedDomain>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct LinkedDomain {
+    pub name: String,
+    pub token: String,
+}
+
+// The logins sent to the provider, by state, with the token of the domain to
+// link and the time they expire. Shared by all the clones of the
+// configuration.
+#[derive(Clone, Default)]
+pub struct PendingLogins {
+    logins: Arc<Mutex<HashMap<String, (Option<String>, i64)>>>,
+}
+
+impl PendingLogins {
+    pub fn new() -> Self {
+        PendingLogins::default()
+    }
+
+    fn logins(&self) -> MutexGuard<HashMap<String, (Option<String>, i64)>> {
+        match self.logins.lock() {
+            Ok(logins) => logins,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Records a new login, and returns its state.
+    fn start(&self, token: Option<String>, now: i64) -> String {
+        let state = format!("{}", Uuid::new_v4());
+        let mut logins = self.logins();
+        if logins.len() >= MAX_LOGINS {
+            logins.retain(|_, &mut (_, expires)| expires > now);
+            if logins.len() >= MAX_LOGINS {
+                logins.clear();
+            }
+        }
+        logins.insert(state.clone(), (token, now + LOGIN_TTL));
+        state
+    }
+
+    // Returns the token of the domain to link, if any, of a pending login.
+    // A login can only be finished once.
+    fn finish(&self, state: &str, now: i64) -> Option<Option<String>> {
+        match self.logins().remove(state) {
+            Some((token, expires)) if expires > now => Some(token),
+            _ => None,
+        }
+    }
+}
+
+fn client_id(options: &GeneralOptions) -> Result<&str, String> {
+    match options.oidc_client_id {
+        Some(ref client_id) => Ok(client_id),
+        None => Err("No OpenID Connect client is configured".to_owned()),
+    }
+}
+
+// Returns the URL of the provider the browser is sent to.
+fn authorize_url(state: &str, options: &GeneralOptions) -> Result<String, String> {
+    let separator = if options.oidc_authorize_url.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    Ok(format!(
+        "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}",
+        options.oidc_authorize_url,
+        separator,
+        form_encode(client_id(options)?),
+        form_encode(&options.oidc_redirect_url),
+        form_encode("openid email"),
+        state
+    ))
+}
+
+fn parse_token_response(json: &str) -> Result<String, String> {
+    serde_json::from_str::<TokenResponse>(json)
+        .map(|response| response.access_token)
+        .map_err(|err| format!("Invalid token response: {}", err))
+}
+
+fn parse_user_info(json: &str) -> Result<UserInfo, String> {
+    match serde_json::from_str::<UserInfo>(json) {
+        Ok(ref user) if user.sub.is_empty() => Err("Empty subject".to_owned()),
+        Ok(user) => Ok(user),
+        Err(err) => Err(format!("Invalid userinfo response: {}", err)),
+    }
+}
+
+// Returns the user who logged in, or an error if the provider could not be
+// reached or rejected the code.
+fn fetch_user(code: &str, options: &GeneralOptions) -> Result<UserInfo, String> {
+    let tls = NativeTlsClient::new().map_err(|err| format!("TLS failure: {}", err))?;
+    let mut client = Client::with_connector(HttpsConnector::new(tls));
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+
+    let mut body = format!(
+        "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}",
+        form_encode(code),
+        form_encode(&options.oidc_redirect_url),
+        form_encode(client_id(options)?)
+    );
+    if let Some(ref secret) = options.oidc_client_secret {
+        body.push_str(&format!("&client_secret={}", form_encode(secret)));
+    }
+    let mut response = client
+        .post(&options.oidc_token_url)
+        .header(ContentType::form_url_encoded())
+        .body(&body)
+        .send()
+        .map_err(|err| format!("Failed to reach {}: {}", options.oidc_token_url, err))?;
+    let mut json = String::new();
+    response
+        .read_to_string(&mut json)
+        .map_err(|err| format!("Failed to read the token response: {}", err))?;
+    if !response.status.is_success() {
+        return Err(format!(
+            "The code was rejected: {} {}",
+            response.status, json
+        ));
+    }
+    let access_token = parse_token_response(&json)?;
+
+    let mut response = client
+        .get(&options.oidc_userinfo_url)
+        .header(Authorization(Bearer {
+            token: access_token,
+        }))
+        .send()
+        .map_err(|err| format!("Failed to reach {}: {}", options.oidc_userinfo_url, err))?;
+    let mut json = String::new();
+    response
+        .read_to_string(&mut json)
+        .map_err(|err| format!("Failed to read the userinfo response: {}", err))?;
+    if !response.status.is_success() {
+        return Err(format!(
+            "The access token was rejected: {} {}",
+            response.status, json
+        ));
+    }
+    parse_user_info(&json)
+}
+
+// Returns the account of a user, linking it to the subject on the first login.
+fn account_for_user(conn: &Database, user: &UserInfo) -> QueryResult<Account> {
+    match conn.get_account_by_oidc_subject(&user.sub) {
+        Err(diesel::result::Error::NotFound) => {
+            let account = conn.get_or_add_account(&user.email)?;
+            conn.update_account_oidc_subject(account.id, &user.sub)?;
+            Ok(Account {
+                oidc_subject: user.sub.clone(),
+                ..account
+            })
+        }
+        result => result,
+    }
+}
+
+pub fn oidc_login(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /oidc/login {:?}", req.method, params);
+
+    let token = try_param!(params.get_optional_string("token"));
+
+    // The domain is checked now, so that the owner doesn't log in for nothing.
+    if let Some(ref token) = token {
+        let conn = config.db.get_connection();
+        if conn.is_err() {
+            error!(
+                "oidc_login(): Failed to get database connection: {:?}",
+                conn.err()
+            );
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+        match conn.unwrap().get_domain_by_token(token) {
+            Ok(_) => (),
+            Err(diesel::result::Error::NotFound) => {
+                return EndpointError::with(ApiError::UnknownToken);
+            }
+            Err(err) => {
+                error!("oidc_login(): Failed to get domain: {:?}", err);
+                return EndpointError::with(ApiError::DatabaseFailure);
+            }
+        }
+    }
+
+    let state = config.logins.start(token, now());
+    match authorize_url(&state, &config.options.general) {
+        Ok(url) => Ok(Response::with((Status::Found, RedirectRaw(url)))),
+        Err(err) => {
+            error!("oidc_login(): {}", err);
+            EndpointError::with(ApiError::LoginFailure)
+        }
+    }
+}
+
+pub fn oidc_callback(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "oidc_callback(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /oidc/callback {:?}", req.method, params);
+
+    let state = try_param!(params.get_string("state"));
+    let code = try_param!(params.get_string("code"));
+
+    let token = match config.logins.finish(&state, now()) {
+        Some(token) => token,
+        None => return EndpointError::with(ApiError::UnknownLogin),
+    };
+    let user = match fetch_user(&code, &config.options.general) {
+        Ok(user) => user,
+        Err(err) => {
+            error!("oidc_callback(): {}", err);
+            return EndpointError::with(ApiError::LoginFailure);
+        }
+    };
+    if !user.email_verified || !is_valid_email(&user.email) {
+        info!("oidc_callback(): Unverified email address for {}", user.sub);
+        return EndpointError::with(ApiError::UnverifiedEmail);
+    }
+
+    let account = match account_for_user(&conn, &user) {
+        Ok(account) => account,
+        Err(err) => {
+            error!("oidc_callback(): Failed to get account: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+
+    // Logging in proves the email address, like following the link of the
+    // confirmation email.
+    if let Some(token) = token {
+        match conn.update_domain_verification_data(&token, Some(account.id), "", true) {
+            Ok(count) if count > 0 => config.dns_cache.forget_token(&token),
+            Ok(_) => return EndpointError::with(ApiError::UnknownToken),
+            Err(err) => {
+                error!("oidc_callback(): Failed to update domain: {:?}", err);
+                return EndpointError::with(ApiError::DatabaseFailure);
+            }
+        }
+    }
+
+    match conn.get_domains_by_account_id(account.id) {
+        Ok(records) => json_response!(&Login {
+            email: account.email,
+            domains: records
+                .into_iter()
+                .filter(|record| record.verified)
+                .map(|record| LinkedDomain {
+                    name: record.name,
+                    token: record.token,
+                })
+                .collect(),
+        }),
+        Err(err) => {
+            error!("oidc_callback(): Failed to get domains: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+#[test]
+fn test_pending_logins() {
+    let _ = env_logger::init();
+
+    let logins = PendingLogins::new();
+    let state = logins.start(Some("token".to_owned()), 1000);
+    let other = logins.start(None, 1000);
+    assert_ne!(state, other);
+
+    // A login can only be finished once, before it expires.
+    assert_eq!(logins.finish("unknown", 1000), None);
+    assert_eq!(logins.finish(&state, 1000), Some(Some("token".to_owned())));
+    assert_eq!(logins.finish(&state, 1000), None);
+    assert_eq!(logins.finish(&other, 1000 + LOGIN_TTL), None);
+}
+
+#[test]
+fn test_authorize_url() {
+    use args::ArgsParser;
+
+    let _ = env_logger::init();
+
+    let mut options = ArgsParser::from_vec(vec!["registration_server"]).general;
+    assert!(authorize_url("state", &options).is_err());
+
+    options.oidc_client_id = Some("my client".to_owned());
+    assert_eq!(
+        authorize_url("state", &options),
+        Ok(
+            "https://accounts.google.com/o/oauth2/v2/auth?response_type=code&client_id=my%20client\
+             &redirect_uri=https%3A%2F%2Fapi.mydomain.org%2Foidc%2Fcallback&scope=openid%20email\
+             &state=state"
+                .to_owned()
+        )
+    );
+    options.oidc_authorize_url = "https://login.example.com/authorize?tenant=1".to_owned();
+    assert!(authorize_url("state", &options)
+        .unwrap()
+        .starts_with("https://login.example.com/authorize?tenant=1&response_type=code&"));
+}
+
+#[test]
+fn test_parse_responses() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+        parse_token_response(
+            "{\"access_token\":\"abc\",\"token_type\":\"Bearer\",\"id_token\":\"x.y.z\"}"
+        ),
+        Ok("abc".to_owned())
+    );
+    assert!(parse_token_response("{\"error\":\"invalid_grant\"}").is_err());
+
+    assert_eq!(
+        parse_user_info(
+            "{\"sub\":\"1234\",\"email\":\"owner@example.com\",\"email_verified\":true}"
+        ),
+        Ok(UserInfo {
+            sub: "1234".to_owned(),
+            email: "owner@example.com".to_owned(),
+            email_verified: true,
+        })
+    );
+    assert_eq!(
+        parse_user_info("{\"sub\":\"1234\"}"),
+        Ok(UserInfo {
+            sub: "1234".to_owned(),
+            email: "".to_owned(),
+            email_verified: false,
+        })
+    );
+    assert!(parse_user_info("{\"sub\":\"\"}").is_err());
+    assert!(parse_user_info("<html>").is_err());
+}
@@ -0,0 +1,585 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Extracts the parameters of an API request. GET requests carry them in the
+// query string, while POST requests send them as a JSON object in the body
+// so that tokens don't end up in access logs. The routes of the domain
+// resources, like `DELETE /domains/:token`, also carry the token in their
+// path, and may have an empty body.
+
+extern crate env_logger;
+use errors::{ApiError, EndpointError};
+use iron::method::Method;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::BeforeMiddleware;
+use params::{self, Params};
+use router::Router;
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+// Maximum size of a JSON request body, in bytes.
+const MAX_BODY_SIZE: u64 = 16 * 1024;
+
+// Maximum length of a string parameter, in characters, and the lower limits of
+// the parameters stored as they are sent.
+const MAX_PARAMETER_LENGTH: usize = 2048;
+const PARAMETER_LENGTHS: [(&str, usize); 1] = [("desc", 256)];
+
+// The body of a request, once read, so that the middlewares and the handler
+// can all get it.
+struct RawBody;
+
+impl Key for RawBody {
+    type Value = String;
+}
+
+// Returns the body of a request, which can be called more than once.
+pub fn read_body(req: &mut Request) -> Result<String, ApiError> {
+    if let Some(body) = req.extensions.get::<RawBody>() {
+        return Ok(body.clone());
+    }
+
+    let mut body = String::new();
+    let read = Read::by_ref(&mut req.body)
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_string(&mut body);
+    if read.is_err() {
+        return Err(ApiError::MalformedBody);
+    }
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    req.extensions.insert::<RawBody>(body.clone());
+    Ok(body)
+}
+
+// The parameters left out of the logs and the error reports, as they are
+// secrets or personal data.
+const SECRET_PARAMETERS: [&str; 9] = [
+    "token",
+    "tokens",
+    "reclamationToken",
+    "code",
+    "captcha",
+    "mac",
+    "s",
+    "state",
+    "email",
+];
+
+// Maximum length of the values of the error reports, in characters.
+const MAX_REPORTED_LENGTH: usize = 256;
+
+// Returns the parameters of a request that were already read, eg. by its
+// handler, without reading the body again.
+pub fn read_parsed(req: &mut Request) -> Option<Parameters> {
+    match req.method {
+        Method::Post | Method::Put | Method::Delete if !req.extensions.contains::<RawBody>() => {
+            None
+        }
+        _ => Parameters::from_request(req).ok(),
+    }
+}
+
+pub fn read_token(req: &mut Request) -> Option<String> {
+    read_parsed(req).and_then(|params| params.get_string("token").ok())
+}
+
+pub enum Parameters {
+    Query(params::Map),
+    Json(serde_json::Map<String, Value>),
+}
+
+// The handlers log the parameters of their requests, so the secrets are left
+// out of their debug output.
+impl fmt::Debug for Parameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.sanitized(), f)
+    }
+}
+
+impl Parameters {
+    pub fn from_request(req: &mut Request) -> Result<Parameters, ApiError> {
+        let token = req
+            .extensions
+            .get::<Router>()
+            .and_then(|route| route.find("token"))
+            .map(|token| token.to_owned());
+
+        let method = req.method.clone();
+        let mut params = match method {
+            Method::Post | Method::Put | Method::Delete => {
+                let body = read_body(req)?;
+                if method != Method::Post && body.trim().is_empty() {
+                    Parameters::Json(serde_json::Map::new())
+                } else {
+                    Parameters::from_json(&body)?
+                }
+            }
+            _ => match req.get_ref::<Params>() {
+                Ok(map) => Parameters::Query(map.clone()),
+                Err(_) => return Err(ApiError::InvalidParameter),
+            },
+        };
+
+        if let Some(token) = token {
+            params.set_string("token", token)?;
+        }
+        Ok(params)
+    }
+
+    // Sets a parameter, replacing the value sent by the client.
+    fn set_string(&mut self, name: &str, value: String) -> Result<(), ApiError> {
+        match *self {
+            Parameters::Query(ref mut map) => map
+                .assign(name, params::Value::String(value))
+                .map_err(|_| ApiError::InvalidParameter),
+            Parameters::Json(ref mut map) => {
+                map.insert(name.to_owned(), Value::String(value));
+                Ok(())
+            }
+        }
+    }
+
+    // Rejects the string parameters that are too long or hold control
+    // characters, including the strings nested in arrays and objects.
+    pub fn check_limits(&self) -> Result<(), ApiError> {
+        match *self {
+            Parameters::Query(ref map) => map
+                .iter()
+                .map(|(name, value)| check_query_value(name, value))
+                .collect(),
+            Parameters::Json(ref map) => map
+                .iter()
+                .map(|(name, value)| check_json_value(name, value))
+                .collect(),
+        }
+    }
+
+    // Returns the parameters as strings, without the secret ones, for the
+    // error reports.
+    pub fn sanitized(&self) -> BTreeMap<String, String> {
+        let values: Vec<(String, String)> = match *self {
+            Parameters::Query(ref map) => map
+                .iter()
+                .map(|(name, value)| {
+                    let value = match *value {
+                        params::Value::String(ref value) => value.clone(),
+                        ref value => format!("{:?}", value),
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+            Parameters::Json(ref map) => map
+                .iter()
+                .map(|(name, value)| {
+                    let value = match *value {
+                        Value::String(ref value) => value.clone(),
+                        ref value => value.to_string(),
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+        };
+
+        values
+            .into_iter()
+            .map(|(name, value)| {
+                if SECRET_PARAMETERS.contains(&name.as_str()) {
+                    (name, "[redacted]".to_owned())
+                } else {
+                    (name, value.chars().take(MAX_REPORTED_LENGTH).collect())
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_json(body: &str) -> Result<Parameters, ApiError> {
+        match serde_json::from_str(body) {
+            Ok(Value::Object(map)) => Ok(Parameters::Json(map)),
+            _ => Err(ApiError::MalformedBody),
+        }
+    }
+
+    // Returns the value of an optional string parameter. Parameters of any
+    // other type, like arrays or objects, are rejected.
+    pub fn get_optional_string(&self, name: &str) -> Result<Option<String>, ApiError> {
+        match *self {
+            Parameters::Query(ref map) => match map.find(&[name]) {
+                None => Ok(None),
+                Some(&params::Value::String(ref value)) => Ok(Some(value.clone())),
+                Some(value) => {
+                    error!("Invalid value for parameter {}: {:?}", name, value);
+                    Err(ApiError::InvalidParameter)
+                }
+            },
+            Parameters::Json(ref map) => match map.get(name) {
+                None | Some(&Value::Null) => Ok(None),
+                Some(&Value::String(ref value)) => Ok(Some(value.clone())),
+                Some(value) => {
+                    error!("Invalid value for parameter {}: {:?}", name, value);
+                    Err(ApiError::InvalidParameter)
+                }
+            },
+        }
+    }
+
+    // Returns the values of an optional list parameter, sent either as an
+    // array of strings or as a single string.
+    pub fn get_optional_string_list(&self, name: &str) -> Result<Option<Vec<String>>, ApiError> {
+        let values: Option<Vec<String>> = match *self {
+            Parameters::Query(ref map) => match map.find(&[name]) {
+                Some(&params::Value::Array(ref array)) => array
+                    .iter()
+                    .map(|value| match *value {
+                        params::Value::String(ref value) => Some(value.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => return Ok(self.get_optional_string(name)?.map(|value| vec![value])),
+            },
+            Parameters::Json(ref map) => match map.get(name) {
+                Some(&Value::Array(ref array)) => array
+                    .iter()
+                    .map(|value| value.as_str().map(|value| value.to_owned()))
+                    .collect(),
+                _ => return Ok(self.get_optional_string(name)?.map(|value| vec![value])),
+            },
+        };
+
+        match values {
+            Some(values) => Ok(Some(values)),
+            None => {
+                error!("Invalid value for parameter {}", name);
+                Err(ApiError::InvalidParameter)
+            }
+        }
+    }
+
+    // Returns the value of a mandatory string parameter.
+    pub fn get_string(&self, name: &str) -> Result<String, ApiError> {
+        match self.get_optional_string(name)? {
+            Some(value) => Ok(value),
+            None => {
+                error!("Parameter {} not provided", name);
+                Err(ApiError::MissingParameter)
+            }
+        }
+    }
+
+    // Returns the value of a mandatory boolean parameter, sent either as a
+    // JSON boolean or as the "true" and "false" strings.
+    pub fn get_bool(&self, name: &str) -> Result<bool, ApiError> {
+        if let Parameters::Json(ref map) = *self {
+            if let Some(&Value::Bool(value)) = map.get(name) {
+                return Ok(value);
+            }
+        }
+
+        match self.get_string(name)?.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            value => {
+                error!("Invalid value for parameter {}: {}", name, value);
+                Err(ApiError::InvalidParameter)
+            }
+        }
+    }
+
+    // Returns the value of an optional integer parameter between 0 and 65535,
+    // sent either as a JSON number or as a string.
+    pub fn get_optional_u16(&self, name: &str) -> Result<Option<u16>, ApiError> {
+        if let Parameters::Json(ref map) = *self {
+            if let Some(&Value::Number(ref value)) = map.get(name) {
+                return match value.as_u64() {
+                    Some(value) if value <= u64::from(u16::max_value()) => Ok(Some(value as u16)),
+                    _ => {
+                        error!("Invalid value for parameter {}: {}", name, value);
+                        Err(ApiError::InvalidParameter)
+                    }
+                };
+            }
+        }
+
+        match self.get_optional_string(name)? {
+            None => Ok(None),
+            Some(value) => match value.parse::<u16>() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => {
+                    error!("Invalid value for parameter {}: {}", name, value);
+                    Err(ApiError::InvalidParameter)
+                }
+            },
+        }
+    }
+
+    // Returns the value of an optional non-negative integer parameter, like a
+    // timestamp, sent either as a JSON number or as a string.
+    pub fn get_optional_u64(&self, name: &str) -> Result<Option<u64>, ApiError> {
+        if let Parameters::Json(ref map) = *self {
+            if let Some(&Value::Number(ref value)) = map.get(name) {
+                return match value.as_u64() {
+                    Some(value) => Ok(Some(value)),
+                    None => {
+                        error!("Invalid value for parameter {}: {}", name, value);
+                        Err(ApiError::InvalidParameter)
+                    }
+                };
+            }
+        }
+
+        match self.get_optional_string(name)? {
+            None => Ok(None),
+            Some(value) => match value.parse::<u64>() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => {
+                    error!("Invalid value for parameter {}: {}", name, value);
+                    Err(ApiError::InvalidParameter)
+                }
+            },
+        }
+    }
+}
+
+fn check_string(name: &str, value: &str) -> Result<(), ApiError> {
+    let max_length = PARAMETER_LENGTHS
+        .iter()
+        .find(|&&(field, _)| field == name)
+        .map_or(MAX_PARAMETER_LENGTH, |&(_, length)| length);
+    if value.chars().count() > max_length || value.chars().any(char::is_control) {
+        return Err(ApiError::InvalidParameter);
+    }
+    Ok(())
+}
+
+fn check_query_value(name: &str, value: &params::Value) -> Result<(), ApiError> {
+    match *value {
+        params::Value::String(ref value) => check_string(name, value),
+        params::Value::Array(ref values) => values
+            .iter()
+            .map(|value| check_query_value(name, value))
+            .collect(),
+        params::Value::Map(ref map) => map
+            .iter()
+            .map(|(key, value)| check_query_value(key, value))
+            .collect(),
+        _ => Ok(()),
+    }
+}
+
+fn check_json_value(name: &str, value: &Value) -> Result<(), ApiError> {
+    match *value {
+        Value::String(ref value) => check_string(name, value),
+        Value::Array(ref values) => values
+            .iter()
+            .map(|value| check_json_value(name, value))
+            .collect(),
+        Value::Object(ref map) => map
+            .iter()
+            .map(|(key, value)| check_json_value(key, value))
+            .collect(),
+        _ => Ok(()),
+    }
+}
+
+// Rejects the requests whose parameters are over the limits, before any
+// handler stores them. The other errors of the parameters are left to the
+// handlers, which report them with the missing ones.
+pub struct ValidateParameters;
+
+impl BeforeMiddleware for ValidateParameters {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let result = match Parameters::from_request(req) {
+            Ok(params) => params.check_limits(),
+            Err(ApiError::PayloadTooLarge) => Err(ApiError::PayloadTooLarge),
+            Err(_) => Ok(()),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => EndpointError::with(err).map(|_| ()),
+        }
+    }
+}
+
+#[test]
+fn test_json_parameters() {
+    let _ = env_logger::init();
+
+    let params =
+        Parameters::from_json(r#"{"token": "abc", "challenge": "def", "count": 3}"#).unwrap();
+    assert_eq!(params.get_string("token"), Ok("abc".to_owned()));
+    assert_eq!(params.get_string("challenge"), Ok("def".to_owned()));
+    assert_eq!(params.get_string("count"), Err(ApiError::InvalidParameter));
+    assert_eq!(params.get_string("name"), Err(ApiError::MissingParameter));
+    assert_eq!(params.get_optional_string("name"), Ok(None));
+    assert_eq!(
+        params.get_optional_string("count"),
+        Err(ApiError::InvalidParameter)
+    );
+
+    let params = Parameters::from_json(r#"{"token": ["a", "b"], "email": null}"#).unwrap();
+    assert_eq!(params.get_string("token"), Err(ApiError::InvalidParameter));
+    assert_eq!(params.get_optional_string("email"), Ok(None));
+
+    let params =
+        Parameters::from_json(r#"{"on": true, "off": "false", "count": 1, "name": "yes"}"#)
+            .unwrap();
+    assert_eq!(params.get_bool("on"), Ok(true));
+    assert_eq!(params.get_bool("off"), Ok(false));
+    assert_eq!(params.get_bool("count"), Err(ApiError::InvalidParameter));
+    assert_eq!(params.get_bool("name"), Err(ApiError::InvalidParameter));
+    assert_eq!(params.get_bool("token"), Err(ApiError::MissingParameter));
+
+    let params = Parameters::from_json(
+        r#"{"number": 10, "string": "20", "negative": -1, "large": 65536, "name": "ten"}"#,
+    )
+    .unwrap();
+    assert_eq!(params.get_optional_u16("number"), Ok(Some(10)));
+    assert_eq!(params.get_optional_u16("string"), Ok(Some(20)));
+    assert_eq!(params.get_optional_u16("token"), Ok(None));
+    assert_eq!(
+        params.get_optional_u16("negative"),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        params.get_optional_u16("large"),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        params.get_optional_u16("name"),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(params.get_optional_u64("large"), Ok(Some(65536)));
+    assert_eq!(params.get_optional_u64("string"), Ok(Some(20)));
+    assert_eq!(
+        params.get_optional_u64("negative"),
+        Err(ApiError::InvalidParameter)
+    );
+
+    assert_eq!(
+        Parameters::from_json(r#"{"token": "abc""#).unwrap_err(),
+        ApiError::MalformedBody
+    );
+    assert_eq!(
+        Parameters::from_json(r#"["token", "abc"]"#).unwrap_err(),
+        ApiError::MalformedBody
+    );
+    assert_eq!(
+        Parameters::from_json("").unwrap_err(),
+        ApiError::MalformedBody
+    );
+
+    // The token of a resource path replaces the one of the body.
+    let mut params = Parameters::from_json(r#"{"token": "abc", "local_ip": "10.0.0.2"}"#).unwrap();
+    params.set_string("token", "def".to_owned()).unwrap();
+    assert_eq!(params.get_string("token"), Ok("def".to_owned()));
+    assert_eq!(params.get_string("local_ip"), Ok("10.0.0.2".to_owned()));
+}
+
+#[test]
+fn test_check_limits() {
+    let _ = env_logger::init();
+
+    let check = |json: &str| Parameters::from_json(json).unwrap().check_limits();
+    let desc = "é".repeat(256);
+    assert_eq!(
+        check(&format!(r#"{{"desc": "{}", "count": 3}}"#, desc)),
+        Ok(())
+    );
+    assert_eq!(
+        check(&format!(r#"{{"desc": "{}é"}}"#, desc)),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        check(&format!(
+            r#"{{"name": "{}"}}"#,
+            "a".repeat(MAX_PARAMETER_LENGTH)
+        )),
+        Ok(())
+    );
+    assert_eq!(
+        check(&format!(
+            r#"{{"name": "{}"}}"#,
+            "a".repeat(MAX_PARAMETER_LENGTH + 1)
+        )),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        check(r#"{"desc": "line\nbreak"}"#),
+        Err(ApiError::InvalidParameter)
+    );
+
+    // The strings nested in arrays and objects are checked too.
+    assert_eq!(
+        check(r#"{"value": ["a", "b\u0000"]}"#),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        check(&format!(r#"{{"record": {{"desc": "{}é"}}}}"#, desc)),
+        Err(ApiError::InvalidParameter)
+    );
+}
+
+#[test]
+fn test_sanitized() {
+    let _ = env_logger::init();
+
+    let params = Parameters::from_json(&format!(
+        r#"{{"token": "abc", "email": "a@b.org", "name": "test", "count": 3, "desc": "{}"}}"#,
+        "d".repeat(300)
+    ))
+    .unwrap();
+    let sanitized = params.sanitized();
+    assert_eq!(sanitized["token"], "[redacted]");
+    assert_eq!(sanitized["email"], "[redacted]");
+    assert_eq!(sanitized["name"], "test");
+    assert_eq!(sanitized["count"], "3");
+    assert_eq!(sanitized["desc"], "d".repeat(256));
+}
+
+#[test]
+fn test_logged_parameters() {
+    use iron::headers::ContentType;
+    use iron::status;
+    use iron::Headers;
+    use iron_test::{request, response};
+
+    let _ = env_logger::init();
+
+    // The handlers log their parameters like this one.
+    let handler = |req: &mut Request| -> IronResult<Response> {
+        let params = Parameters::from_request(req).unwrap();
+        Ok(Response::with((
+            status::Ok,
+            format!("{} /unsubscribe {:?}", req.method, params),
+        )))
+    };
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    let resp = request::post(
+        "http://localhost/unsubscribe",
+        headers,
+        r#"{"token": "e4c6a2f1-secret", "reclamationToken": "r3cl4im-secret", "name": "test"}"#,
+        &handler,
+    )
+    .unwrap();
+    let line = response::extract_body_to_string(resp);
+    assert!(!line.contains("secret"));
+    assert_eq!(
+        line,
+        r#"POST /unsubscribe {"name": "test", "reclamationToken": "[redacted]", "token": "[redacted]"}"#
+    );
+
+    let resp = request::get(
+        "http://localhost/unsubscribe?token=e4c6a2f1-secret",
+        Headers::new(),
+        &handler,
+    )
+    .unwrap();
+    let line = response::extract_body_to_string(resp);
+    assert_eq!(line, r#"GET /unsubscribe {"token": "[redacted]"}"#);
+}
@@ -8,18 +8,23 @@
 // details about the various requests and responses.
 
 extern crate env_logger;
-use config::Config;
+use config::{Config, PdnsOptions};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use crypto::util::fixed_time_eq;
+use diesel;
+use eviction::{is_stale, now};
 use maxminddb;
 use maxminddb::geoip2;
+use models::{Domain, SrvRecord};
+use routes::local_prefix;
 use serde_json;
+use std::cmp;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::IpAddr;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
-use std::str::FromStr;
 use std::thread;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -89,8 +94,21 @@ fn get_geoip(continent: Option<String>, config: &Config) -> String {
 }
 
 pub fn lookup_continent(remote: IpAddr, config: &Config) -> Option<String> {
-    let reader =
-        maxminddb::Reader::open(&config.clone().options.pdns.geoip.database.unwrap()).unwrap();
+    let path = match config.options.pdns.geoip.database {
+        Some(ref path) => path,
+        None => return None,
+    };
+
+    let reader = match maxminddb::Reader::open(path) {
+        Ok(reader) => reader,
+        Err(err) => {
+            error!(
+                "lookup_continent(): Failed to open GeoIP database: {:?}",
+                err
+            );
+            return None;
+        }
+    };
 
     let result = reader.lookup(remote);
     if result.is_err() {
@@ -105,47 +123,186 @@ pub fn lookup_continent(remote: IpAddr, config: &Config) -> Option<String> {
     }
 }
 
-// Returns an A record for a given qname.
-fn a_response(
+// Converts an IPv4-mapped IPv6 address (::ffff:a.b.c.d) to its IPv4 form, as
+// dual-stack sockets report IPv4 clients this way.
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new(
+                    (s[6] >> 8) as u8,
+                    s[6] as u8,
+                    (s[7] >> 8) as u8,
+                    s[7] as u8,
+                ))
+            } else {
+                ip
+            }
+        }
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// Parses a comma separated list of IPv4 and IPv6 addresses, like the tunnel
+// endpoints configuration values. Invalid entries are skipped.
+fn parse_addresses(list: &str) -> Vec<IpAddr> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse::<IpAddr>() {
+            Ok(ip) => Some(normalize_ip(ip)),
+            Err(_) => {
+                error!("Invalid address: {}", item);
+                None
+            }
+        })
+        .collect()
+}
+
+// Returns the TTL of the addresses of a domain, which is lowered for a while
+// after a DNS challenge is set.
+fn domain_ttl(record: &Domain, options: &PdnsOptions, now: i64) -> u32 {
+    let window_end = record.challenge_set_at + i64::from(options.challenge_window);
+    if record.challenge_set_at > 0 && now < window_end {
+        cmp::min(options.challenge_ttl, options.tunnel_ttl)
+    } else {
+        options.tunnel_ttl
+    }
+}
+
+// Returns an A or AAAA record for a given qname, or None if no tunnel endpoint
+// of this address family is configured.
+fn address_response(
     qname: &str,
+    qtype: &str,
     ttl: u32,
     config: &Config,
     remote: Option<String>,
     continent: Option<String>,
-) -> PdnsLookupResponse {
+) -> Option<PdnsLookupResponse> {
     // Do a GeoIP lookup on the remote IP, if the GeoIP database is configured. If the remote is
-    // not set, use the passed in continent value.
+    // not set or can't be parsed, use the passed in continent value.
     let c = match config.options.pdns.geoip.database {
         Some(_) => match remote {
-            Some(remote_ip) => {
-                let ip: IpAddr = FromStr::from_str(&remote_ip).unwrap();
-                lookup_continent(ip, config)
-            }
+            Some(remote_ip) => match remote_ip.parse::<IpAddr>() {
+                Ok(ip) => lookup_continent(normalize_ip(ip), config),
+                Err(_) => {
+                    error!("Invalid remote address: {}", remote_ip);
+                    continent
+                }
+            },
             None => continent,
         },
         None => continent,
     };
 
     // Determine the proper IP address to return, based on the continent.
-    let result = get_geoip(c, config);
+    let addresses = parse_addresses(&get_geoip(c, config));
+    let address = addresses.iter().find(|ip| match **ip {
+        IpAddr::V4(_) => qtype == "A",
+        IpAddr::V6(_) => qtype == "AAAA",
+    });
+
+    address.map(|ip| PdnsLookupResponse {
+        qtype: qtype.to_owned(),
+        qname: qname.to_owned(),
+        content: ip.to_string(),
+        ttl: ttl,
+        domain_id: None,
+        scope_mask: None,
+        auth: None,
+    })
+}
+
+// Returns the public addresses served for a domain, at most one per address
+// family, like its A and AAAA records.
+pub fn domain_addresses(record: &Domain, config: &Config) -> Vec<String> {
+    let continent = if record.continent.is_empty() {
+        None
+    } else {
+        Some(record.continent.clone())
+    };
+    ["A", "AAAA"]
+        .iter()
+        .filter_map(|qtype| {
+            address_response(&record.name, qtype, 0, config, None, continent.clone())
+        })
+        .map(|response| response.content)
+        .collect()
+}
+
+// Returns an A or AAAA record for an address of the gateway on its local
+// network.
+fn local_address_response(qname: &str, ip: &IpAddr, config: &Config) -> PdnsLookupResponse {
+    let qtype = match *ip {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    };
 
     PdnsLookupResponse {
-        qtype: "A".to_owned(),
+        qtype: qtype.to_owned(),
         qname: qname.to_owned(),
-        content: result.to_owned(),
-        ttl: ttl,
+        content: ip.to_string(),
+        ttl: config.options.pdns.tunnel_ttl,
         domain_id: None,
         scope_mask: None,
         auth: None,
     }
 }
 
+// Returns the serial of the SOA record, which changes whenever a domain is
+// added or updated.
+fn soa_serial(config: &Config) -> u32 {
+    let conn = match config.db.get_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("soa_serial(): Failed to get database connection: {:?}", err);
+            return 0;
+        }
+    };
+
+    match conn.get_newest_timestamp() {
+        Ok(timestamp) => timestamp.unwrap_or(0) as u32,
+        Err(err) => {
+            error!("soa_serial(): Failed to get newest timestamp: {:?}", err);
+            0
+        }
+    }
+}
+
 // Returns an SOA record for a given qname.
 fn soa_response(qname: &str, config: &Config) -> PdnsLookupResponse {
+    let pdns = &config.options.pdns;
+    // Only the primary name server and the hostmaster are taken from the
+    // configured content, the other fields are generated.
+    let names: Vec<&str> = pdns.soa_content.split_whitespace().take(2).collect();
+
     PdnsLookupResponse {
         qtype: "SOA".to_owned(),
         qname: qname.to_owned(),
-        content: config.options.pdns.soa_content.to_owned(),
+        content: format!(
+            "{} {} {} {} {} {}",
+            names.join(" "),
+            soa_serial(config),
+            pdns.soa_refresh,
+            pdns.soa_retry,
+            pdns.soa_expire,
+            pdns.soa_minimum
+        ),
+        ttl: pdns.dns_ttl,
+        domain_id: None,
+        scope_mask: None,
+        auth: None,
+    }
+}
+
+// Returns an NS record for a given qname.
+fn ns_response(qname: &str, config: &Config, name_server: &str) -> PdnsLookupResponse {
+    PdnsLookupResponse {
+        qtype: "NS".to_owned(),
+        qname: qname.to_owned(),
+        content: name_server.to_owned(),
         ttl: config.options.pdns.dns_ttl,
         domain_id: None,
         scope_mask: None,
@@ -166,6 +323,41 @@ fn mx_response(qname: &str, config: &Config) -> PdnsLookupResponse {
     }
 }
 
+// Returns the MX record of a domain that set its own mail server.
+fn domain_mx_response(qname: &str, config: &Config, record: &Domain) -> PdnsLookupResponse {
+    PdnsLookupResponse {
+        qtype: "MX".to_owned(),
+        qname: qname.to_owned(),
+        content: format!("{} {}", record.mx_priority, record.mx_target),
+        ttl: config.options.pdns.dns_ttl,
+        domain_id: None,
+        scope_mask: None,
+        auth: None,
+    }
+}
+
+// Returns the SRV record of a service published by a domain, which points to
+// the domain itself.
+fn srv_response(
+    qname: &str,
+    config: &Config,
+    record: &Domain,
+    srv: &SrvRecord,
+) -> PdnsLookupResponse {
+    PdnsLookupResponse {
+        qtype: "SRV".to_owned(),
+        qname: qname.to_owned(),
+        content: format!(
+            "{} {} {} {}",
+            srv.priority, srv.weight, srv.port, record.name
+        ),
+        ttl: config.options.pdns.dns_ttl,
+        domain_id: None,
+        scope_mask: None,
+        auth: None,
+    }
+}
+
 // Returns a CAA record for a given qname.
 fn caa_response(qname: &str, config: &Config) -> PdnsLookupResponse {
     PdnsLookupResponse {
@@ -198,6 +390,20 @@ fn dns_challenge_response(qname: &str, config: &Config, challenge: &str) -> Pdns
         qtype: "TXT".to_owned(),
         qname: qname.to_owned(),
         content: challenge.to_owned(),
+        ttl: config.options.pdns.challenge_ttl,
+        domain_id: None,
+        scope_mask: None,
+        auth: None,
+    }
+}
+
+// Returns a custom TXT record published with /settxt. The value is quoted, as
+// it can hold spaces, eg. in SPF records.
+fn custom_txt_response(qname: &str, config: &Config, value: &str) -> PdnsLookupResponse {
+    PdnsLookupResponse {
+        qtype: "TXT".to_owned(),
+        qname: qname.to_owned(),
+        content: format!("\"{}\"", value),
         ttl: config.options.pdns.dns_ttl,
         domain_id: None,
         scope_mask: None,
@@ -251,7 +457,14 @@ fn pagekite_query(qname: &str, qtype: &str, config: &Config) -> Result<PdnsRespo
     // Split up the qname.
     let parts: Vec<&str> = qname.split('.').collect();
     let subdomain = format!("{}.{}.", parts[4], config.options.general.domain);
-    let ip = match conn.get_domain_by_name(&subdomain) {
+    let ip = match config
+        .dns_cache
+        .get_or_load(&subdomain, || conn.get_domain_by_name(&subdomain))
+    {
+        Ok(ref record) if is_stale(record, config) => {
+            // Stale domains are about to be evicted, so fail like for unknown ones.
+            "255.255.255.0"
+        }
         Ok(record) => {
             let srand = parts[0];
             let token = parts[1];
@@ -266,16 +479,25 @@ fn pagekite_query(qname: &str, qtype: &str, config: &Config) -> Result<PdnsRespo
                 srand, token, sign, proto, kite_domain
             );
 
-            let mut hasher = Sha1::new();
-            hasher.input_str(&format!("{}{}{}", record.token, payload, salt));
-            let calc = hasher.result_str();
-
-            let calc_sub = calc[..28].to_owned();
             let sign_sub = sign[8..36].to_owned();
+            let signed_with = |secret: &str| {
+                let mut hasher = Sha1::new();
+                hasher.input_str(&format!("{}{}{}", secret, payload, salt));
+                let calc = hasher.result_str();
+                let calc_sub = calc[..28].to_owned();
+
+                debug!("pagekite_query(): Signatures: {} {}", calc_sub, sign_sub);
 
-            debug!("pagekite_query(): Signatures: {} {}", calc_sub, sign_sub);
+                // Compared in constant time, so that the answers don't tell
+                // how much of a forged signature is right.
+                fixed_time_eq(calc_sub.as_bytes(), sign_sub.as_bytes())
+            };
 
-            if calc_sub == sign_sub {
+            // The tunnel is opened by the gateway, which may only hold the
+            // device token.
+            let valid = signed_with(&record.token)
+                || (!record.device_token.is_empty() && signed_with(&record.device_token));
+            if valid {
                 "255.255.254.255"
             } else {
                 "255.255.255.1"
@@ -337,31 +559,64 @@ fn process_request(req: PdnsRequest, config: &Config) -> Result<PdnsResponse, St
         // challenge verification, so remove that part of the domain to
         // retrieve our record.
         // See https://tools.ietf.org/html/draft-ietf-acme-acme-06#section-8.4
-        if qname.starts_with("_acme-challenge.") {
+        let is_challenge = qname.starts_with("_acme-challenge.");
+        if is_challenge {
             qname = qname[16..].to_owned();
         }
 
+        // `local.<name>.<domain>` resolves to the addresses that the gateway
+        // reported for its local networks. When the wildcard flag of the
+        // domain is set, `<anything>.<name>.<domain>` resolves like the domain
+        // and `<anything>.local.<name>.<domain>` like its local name. The
+        // `local` label is the one chosen by the domain, or the configured
+        // one, so it's only checked once the domain is found.
+        // `_<service>._<protocol>.<name>.<domain>` holds the SRV record of a
+        // service published by the domain.
+        let mut is_local = false;
+        let mut is_wildcard = false;
+        let mut local_label = None;
+        let mut srv_service = None;
+        let suffix = format!(".{}.", domain);
+        if !is_challenge && qname.ends_with(&suffix) {
+            let labels: Vec<String> = qname[..qname.len() - suffix.len()]
+                .split('.')
+                .map(|label| label.to_owned())
+                .collect();
+            if labels.len() == 3 && labels[0].starts_with('_') && labels[1].starts_with('_') {
+                srv_service = Some((labels[0][1..].to_owned(), labels[1][1..].to_owned()));
+                qname = format!("{}{}", labels[2], suffix);
+            } else if labels.len() > 1 {
+                local_label = Some((labels[labels.len() - 2].clone(), labels.len() > 2));
+                qname = format!("{}{}", labels[labels.len() - 1], suffix);
+            }
+        }
+
         debug!("process_request(): final qname={}", qname);
 
         let mut pdns_response = PdnsResponse { result: Vec::new() };
 
-        if qtype == "SOA" {
-            pdns_response
-                .result
-                .push(PdnsResponseParams::Lookup(soa_response(
-                    &original_qname,
-                    config,
-                )));
-        }
-
-        if qtype == "ANY" {
-            // Add an "MX" record.
-            pdns_response
-                .result
-                .push(PdnsResponseParams::Lookup(mx_response(
-                    &original_qname,
-                    config,
-                )));
+        // The SOA and NS records make the delegated zone valid, and only
+        // exist at its apex.
+        if original_qname == format!("{}.", domain) {
+            if qtype == "ANY" || qtype == "SOA" {
+                pdns_response
+                    .result
+                    .push(PdnsResponseParams::Lookup(soa_response(
+                        &original_qname,
+                        config,
+                    )));
+            }
+            if qtype == "ANY" || qtype == "NS" {
+                for name_server in &config.options.pdns.name_servers {
+                    pdns_response
+                        .result
+                        .push(PdnsResponseParams::Lookup(ns_response(
+                            &original_qname,
+                            config,
+                            name_server,
+                        )));
+                }
+            }
         }
 
         let conn = config.db.get_connection();
@@ -374,11 +629,124 @@ fn process_request(req: PdnsRequest, config: &Config) -> Result<PdnsResponse, St
         }
         let conn = conn.unwrap();
 
-        let api_domain = format!("api.{}.", domain);
-        let psl_domain = format!("_psl.{}.", domain);
-        let domain_lookup = conn.get_domain_by_name(&qname);
+        let found = config
+            .dns_cache
+            .get_or_load(&qname, || conn.get_domain_by_name(&qname));
+        if let Some((ref label, is_nested)) = local_label {
+            let prefix = match found {
+                Ok(ref record) => local_prefix(record, &config.options.general),
+                Err(_) => &config.options.general.local_prefix,
+            };
+            is_local = label == prefix;
+            is_wildcard = !is_local || is_nested;
+        }
+
+        let is_api = !is_wildcard && qname == format!("api.{}.", domain);
+        let is_psl = !is_wildcard && qname == format!("_psl.{}.", domain);
+        let domain_lookup = match found {
+            Ok(ref record) if is_stale(record, config) => {
+                info!("process_request(): Ignoring stale record for: {}", qname);
+                Err(diesel::result::Error::NotFound)
+            }
+            Ok(ref record) if is_wildcard && !record.wildcard => {
+                info!("process_request(): No wildcard for: {}", qname);
+                Err(diesel::result::Error::NotFound)
+            }
+            result => result,
+        };
+        config.metrics.record_dns_lookup(domain_lookup.is_ok());
+
+        // Domains can set their own mail server with /setmx, which replaces
+        // the one of the configuration, but not for the names under them.
+        let own_mx = match domain_lookup {
+            Ok(ref record) if !is_wildcard && !record.mx_target.is_empty() => {
+                Some(domain_mx_response(&original_qname, config, record))
+            }
+            _ => None,
+        };
+        if !is_challenge && !is_local && srv_service.is_none() {
+            match own_mx {
+                Some(response) => {
+                    if qtype == "ANY" || qtype == "MX" {
+                        pdns_response
+                            .result
+                            .push(PdnsResponseParams::Lookup(response));
+                    }
+                }
+                None => {
+                    if qtype == "ANY" {
+                        // Add an "MX" record.
+                        pdns_response
+                            .result
+                            .push(PdnsResponseParams::Lookup(mx_response(
+                                &original_qname,
+                                config,
+                            )));
+                    }
+                }
+            }
+        }
+
+        if let Some((service, protocol)) = srv_service {
+            // Only answer with the services published with /setsrv.
+            if qtype == "ANY" || qtype == "SRV" {
+                if let Ok(ref record) = domain_lookup {
+                    match conn.get_srv_records(record.id) {
+                        Ok(srv_records) => {
+                            for srv in srv_records
+                                .iter()
+                                .filter(|srv| srv.service == service && srv.protocol == protocol)
+                            {
+                                pdns_response.result.push(PdnsResponseParams::Lookup(
+                                    srv_response(&original_qname, config, record, srv),
+                                ));
+                            }
+                        }
+                        Err(err) => {
+                            error!("process_request(): Failed to get SRV records: {:?}", err)
+                        }
+                    }
+                }
+            }
+
+            return Ok(pdns_response);
+        }
+
+        if is_challenge {
+            // Only answer with the challenges stored by /dnsconfig, and with an
+            // empty result set if there is none.
+            if qtype == "ANY" || qtype == "TXT" {
+                match domain_lookup {
+                    Ok(ref record) if !record.dns_challenge.is_empty() => {
+                        for challenge in record.dns_challenge.split(',') {
+                            pdns_response.result.push(PdnsResponseParams::Lookup(
+                                dns_challenge_response(&original_qname, config, challenge),
+                            ));
+                        }
+                    }
+                    _ => info!("process_request(): No DNS challenge for: {}", qname),
+                }
+            }
+
+            return Ok(pdns_response);
+        }
+
+        if is_local {
+            if let Ok(ref record) = domain_lookup {
+                for ip in parse_addresses(&record.local_ips) {
+                    let response = local_address_response(&original_qname, &ip, config);
+                    if qtype == "ANY" || qtype == response.qtype {
+                        pdns_response
+                            .result
+                            .push(PdnsResponseParams::Lookup(response));
+                    }
+                }
+            }
+
+            return Ok(pdns_response);
+        }
 
-        if qname == psl_domain {
+        if is_psl {
             // Add the PSL record if known. If not, just return, as this subdomain is forbidden
             // otherwise.
             if (qtype == "ANY" || qtype == "TXT") && config.options.pdns.psl_record.is_some() {
@@ -394,58 +762,44 @@ fn process_request(req: PdnsRequest, config: &Config) -> Result<PdnsResponse, St
         }
 
         // Look for a record with the qname.
-        if qname == api_domain || domain_lookup.is_ok() {
+        if is_api || domain_lookup.is_ok() {
             let record = match domain_lookup {
                 Ok(val) => Some(val),
                 Err(_) => None,
             };
+            let domain_id = record.as_ref().map(|record| record.id);
 
-            if qtype == "ANY" || qtype == "A" {
-                // Add an "A" record.
-                if qname == api_domain {
-                    // For the API domain, we can do a GeoIP lookup based on the remote IP.
-                    pdns_response
-                        .result
-                        .push(PdnsResponseParams::Lookup(a_response(
-                            &original_qname,
-                            config.options.pdns.api_ttl,
-                            config,
-                            remote,
-                            None,
-                        )));
+            // For the API domain, we can do a GeoIP lookup based on the remote IP. For a
+            // PageKite subdomain, we need to use the continent stored in the database.
+            let (ttl, remote, continent) = if is_api {
+                (config.options.pdns.api_ttl, remote, None)
+            } else {
+                let record = record.unwrap();
+                let ttl = domain_ttl(&record, &config.options.pdns, now());
+                let continent = if record.continent.is_empty() {
+                    None
                 } else {
-                    let record = record.clone().unwrap();
-                    let continent = if record.continent.is_empty() {
-                        None
-                    } else {
-                        Some(record.continent)
-                    };
-
-                    // For a PageKite subdomain, we need to use the continent stored in the
-                    // database.
-                    pdns_response
-                        .result
-                        .push(PdnsResponseParams::Lookup(a_response(
-                            &original_qname,
-                            config.options.pdns.tunnel_ttl,
-                            config,
-                            None,
-                            continent,
-                        )));
-                }
-            }
+                    Some(record.continent)
+                };
+                (ttl, None, continent)
+            };
 
-            if (qtype == "ANY" || qtype == "TXT") && qname != api_domain {
-                let record = record.clone().unwrap();
-                if !record.dns_challenge.is_empty() {
-                    // Add a "TXT" record with the DNS challenge content.
+            // Add "A" and "AAAA" records for the tunnel endpoints of each address family.
+            for address_type in &["A", "AAAA"] {
+                if qtype != "ANY" && qtype != *address_type {
+                    continue;
+                }
+                if let Some(response) = address_response(
+                    &original_qname,
+                    address_type,
+                    ttl,
+                    config,
+                    remote.clone(),
+                    continent.clone(),
+                ) {
                     pdns_response
                         .result
-                        .push(PdnsResponseParams::Lookup(dns_challenge_response(
-                            &original_qname,
-                            config,
-                            &record.dns_challenge,
-                        )));
+                        .push(PdnsResponseParams::Lookup(response));
                 }
             }
 
@@ -458,6 +812,25 @@ fn process_request(req: PdnsRequest, config: &Config) -> Result<PdnsResponse, St
                         config,
                     )));
             }
+
+            // The custom TXT records are only served for the domain itself. They
+            // are not cached with it, so that changes are visible right away.
+            if (qtype == "ANY" || qtype == "TXT") && !is_wildcard {
+                if let Some(domain_id) = domain_id {
+                    match conn.get_txt_records(domain_id) {
+                        Ok(values) => {
+                            for value in values {
+                                pdns_response.result.push(PdnsResponseParams::Lookup(
+                                    custom_txt_response(&original_qname, config, &value),
+                                ));
+                            }
+                        }
+                        Err(err) => {
+                            error!("process_request(): Failed to get TXT records: {:?}", err)
+                        }
+                    }
+                }
+            }
         } else {
             info!("process_request(): No record for: {}", qname);
 
@@ -477,60 +850,50 @@ fn process_request(req: PdnsRequest, config: &Config) -> Result<PdnsResponse, St
     Err(format!("Unsupported method: {}", req.method))
 }
 
-// Custom method to read just enough characters from the stream to build a JSON
-// object.
-// Directly using read_to_string or serde_json::from_reader causes the stream
-// to reach EOF and subsequent write fail with a "Broken Pipe" error.
-fn read_json_from_stream(mut stream: &UnixStream) -> String {
-    let mut buffer = [0; 1];
-    let mut balance_count = 0;
-    let mut result = String::new();
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(_) => {
-                if buffer[0] == b'{' {
-                    balance_count += 1;
-                } else if buffer[0] == b'}' {
-                    balance_count -= 1;
-                }
-                result.push(buffer[0] as char);
-            }
-            Err(err) => {
-                error!("read_json_from_stream(): Stream reading error: {}", err);
-            }
-        }
+fn handle_socket_request(stream: UnixStream, config: &Config) {
+    let error_response = b"{\"result\":false}";
 
-        if balance_count == 0 {
-            break;
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(err) => {
+            error!("handle_socket_request(): Failed to clone stream: {}", err);
+            return;
         }
-    }
-
-    // Read the trailing \n
-    #[allow(unused_must_use)]
-    {
-        stream.read(&mut buffer);
-    }
-
-    result
-}
-
-fn handle_socket_request(mut stream: UnixStream, config: &Config) {
-    let error_response = b"{\"result\":false}";
+    };
+    let mut stream = stream;
 
     macro_rules! send {
         ($content:expr) => (
-            stream.write_all($content).expect("Failed to write answer to the pdns socket");
+            if let Err(err) = stream.write_all($content) {
+                error!("handle_socket_request(): Failed to write answer: {}", err);
+                break;
+            }
         )
     }
 
-    loop {
-        let s = read_json_from_stream(&stream);
-        debug!("handle_socket_request(): JSON String is {}", s);
-        let input: PdnsRequest = match serde_json::from_str(&s) {
+    // PowerDNS sends one JSON request per line, and waits for our answer
+    // before sending the next one. The loop ends when PowerDNS closes the
+    // connection.
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                error!("handle_socket_request(): Stream reading error: {}", err);
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        debug!("handle_socket_request(): JSON String is {}", line);
+        let input: PdnsRequest = match serde_json::from_str(&line) {
             Ok(value) => value,
             Err(err) => {
                 error!("handle_socket_request(): JSON error: {}", err);
-                break;
+                send!(error_response);
+                continue;
             }
         };
 
@@ -542,7 +905,9 @@ fn handle_socket_request(mut stream: UnixStream, config: &Config) {
             continue;
         }
 
-        match process_request(input, config) {
+        // Each request uses the options of the last reload of the
+        // configuration.
+        match process_request(input, &config.current()) {
             Ok(ref response) => match serde_json::to_string(response) {
                 Ok(serialized) => {
                     debug!("handle_socket_request(): Response is: {}", serialized);
@@ -559,6 +924,8 @@ fn handle_socket_request(mut stream: UnixStream, config: &Config) {
             }
         }
     }
+
+    debug!("handle_socket_request(): Connection closed");
 }
 
 pub fn start_socket_endpoint(config: &Config) {
@@ -581,26 +948,27 @@ pub fn start_socket_endpoint(config: &Config) {
         }
     }
 
+    match UnixListener::bind(path) {
+        Ok(socket) => serve_socket(socket, config),
+        Err(e) => error!("start_socket_endpoint(): Couldn't bind: {:?}", e),
+    }
+}
+
+// Answers the queries of PowerDNS on this socket, eg. the one passed by
+// systemd.
+pub fn serve_socket(socket: UnixListener, config: &Config) {
     let config = config.clone();
-    let path = path.clone();
     thread::Builder::new()
         .name("tunnel pdns socket".to_owned())
         .spawn(move || {
-            let socket = match UnixListener::bind(path) {
-                Ok(sock) => sock,
-                Err(e) => {
-                    error!("start_socket_endpoint(): Couldn't bind: {:?}", e);
-                    return;
-                }
-            };
             for stream in socket.incoming() {
                 match stream {
                     Ok(stream) => {
                         let config = config.clone();
                         thread::spawn(move || handle_socket_request(stream, &config));
                     }
-                    Err(_) => {
-                        break;
+                    Err(err) => {
+                        error!("serve_socket(): Failed to accept connection: {:?}", err);
                     }
                 }
             }
@@ -614,6 +982,13 @@ mod tests {
     use args::ArgsParser;
     use config::Config;
     use database::DatabasePool;
+    use dns_cache::DomainCache;
+    use iron::Headers;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use routes::{create_router, NameAndToken, PingRecord};
+    use std::io::Read;
+    use std::net::Shutdown;
     use std::time::Duration;
 
     fn build_request(
@@ -688,7 +1063,7 @@ mod tests {
 
         let empty_success = b"{\"result\":true}";
         let empty_error = b"{\"result\":[]}";
-        let soa_exampleorg = b"{\"result\":[{\"qtype\":\"SOA\"";
+        let soa_mydomainorg = b"{\"result\":[{\"qtype\":\"SOA\"";
 
         let mut answer: [u8; 256] = [0; 256];
         assert_eq!(stream.read(&mut answer).unwrap(), 15);
@@ -703,14 +1078,23 @@ mod tests {
         assert_eq!(stream.read(&mut answer).unwrap(), 13);
         assert_eq!(&answer[..13], empty_error);
 
-        // Build a SOA lookup request and send it to the stream.
+        // Build a SOA lookup request and send it to the stream. Only the apex
+        // of the domain has one.
         let request = build_request("lookup", Some("SOA"), Some("example.org"), None);
         let body = serde_json::to_string(&request).unwrap();
         stream.write_all(body.as_bytes()).unwrap();
         stream.write_all(b"\n").unwrap();
 
-        assert_eq!(stream.read(&mut answer).unwrap(), 144);
-        assert_eq!(&answer[..25], soa_exampleorg);
+        assert_eq!(stream.read(&mut answer).unwrap(), 13);
+        assert_eq!(&answer[..13], empty_error);
+
+        let request = build_request("lookup", Some("SOA"), Some("mydomain.org."), None);
+        let body = serde_json::to_string(&request).unwrap();
+        stream.write_all(body.as_bytes()).unwrap();
+        stream.write_all(b"\n").unwrap();
+
+        assert!(stream.read(&mut answer).unwrap() > 25);
+        assert_eq!(&answer[..25], soa_mydomainorg);
 
         // SOA PageKite query, to create a successful response without having
         // to setup records in the db.
@@ -894,4 +1278,1227 @@ mod tests {
                          \"ttl\":10}]}";
         assert_eq!(&result, a_success);
     }
+
+    #[test]
+    fn test_socket_connections() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_pdns");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_pdns");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_pdns.sqlite");
+
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let path = "/tmp/powerdns_tunnel_connections.sock";
+        config.options.pdns.socket_path = Some(path.to_owned());
+
+        // Leave a stale socket file behind, as if the server had crashed.
+        drop(UnixListener::bind(path));
+        assert!(Path::new(path).exists());
+
+        start_socket_endpoint(&config);
+        thread::sleep(Duration::new(1, 0));
+
+        let request = build_request("lookup", Some("A"), Some("example.org"), None);
+        let lookup = format!("{}\n", serde_json::to_string(&request).unwrap());
+        let empty_result = b"{\"result\":[]}";
+        let mut answer: [u8; 256] = [0; 256];
+
+        // Start sending a request on a first connection, without finishing it.
+        let mut first = UnixStream::connect(path).unwrap();
+        let (start, end) = lookup.split_at(10);
+        first.write_all(start.as_bytes()).unwrap();
+
+        // A second connection is served in the meantime.
+        let mut second = UnixStream::connect(path).unwrap();
+        second.write_all(lookup.as_bytes()).unwrap();
+        assert_eq!(second.read(&mut answer).unwrap(), 13);
+        assert_eq!(&answer[..13], empty_result);
+
+        // Then the first request is completed.
+        first.write_all(end.as_bytes()).unwrap();
+        assert_eq!(first.read(&mut answer).unwrap(), 13);
+        assert_eq!(&answer[..13], empty_result);
+
+        // Invalid JSON gets an error answer, and the connection stays usable.
+        first.write_all(b"{\"method\": \"lookup\"\n").unwrap();
+        assert_eq!(first.read(&mut answer).unwrap(), 16);
+        assert_eq!(&answer[..16], b"{\"result\":false}");
+
+        // Braces in strings don't confuse the parser.
+        let request = build_request("lookup", Some("A"), Some("}{.example.org"), None);
+        let braces = format!("{}\n", serde_json::to_string(&request).unwrap());
+        first.write_all(braces.as_bytes()).unwrap();
+        assert_eq!(first.read(&mut answer).unwrap(), 13);
+        assert_eq!(&answer[..13], empty_result);
+
+        // The server closes the connection once the client is done.
+        first.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(first.read(&mut answer).unwrap(), 0);
+
+        second.write_all(lookup.as_bytes()).unwrap();
+        assert_eq!(second.read(&mut answer).unwrap(), 13);
+        assert_eq!(&answer[..13], empty_result);
+    }
+
+    #[test]
+    fn test_acme_challenge() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_acme");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_acme");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_acme.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let empty_result = "{\"result\":[]}";
+        let challenge_name = "_acme-challenge.acme.mydomain.org.";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=acme",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+
+        // No challenge has been set yet.
+        assert_eq!(lookup("TXT", challenge_name), empty_result);
+        let address = lookup("A", "acme.mydomain.org.");
+        assert_ne!(address, empty_result);
+        assert!(address.contains("\"ttl\":60"));
+
+        request::get(
+            &format!(
+                "http://localhost/dnsconfig?token={}&challenge=test_challenge",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+
+        let challenge = "{\"result\":[{\"qtype\":\"TXT\",\
+                         \"qname\":\"_acme-challenge.acme.mydomain.org.\",\
+                         \"content\":\"test_challenge\",\
+                         \"ttl\":30}]}";
+        assert_eq!(lookup("TXT", challenge_name), challenge);
+        assert_eq!(lookup("ANY", challenge_name), challenge);
+
+        // Nothing else is served for the challenge name, and the challenge is
+        // not served for the domain itself.
+        assert_eq!(lookup("A", challenge_name), empty_result);
+        assert_eq!(lookup("TXT", "acme.mydomain.org."), empty_result);
+
+        // The addresses use the challenge TTL until the challenge is cleared.
+        assert_eq!(
+            lookup("A", "acme.mydomain.org."),
+            address.replace("\"ttl\":60", "\"ttl\":30")
+        );
+
+        // Wildcard certificates need two challenges at the same time. A third
+        // one replaces the oldest, and setting one without `append` replaces
+        // them all.
+        let set_challenge = |query: &str| {
+            let resp = request::get(
+                &format!(
+                    "http://localhost/dnsconfig?token={}&{}",
+                    registration.token, query
+                ),
+                Headers::new(),
+                &router,
+            )
+            .unwrap();
+            assert_eq!(resp.status.unwrap(), Status::Ok);
+        };
+        set_challenge("challenge=wildcard_challenge&append=true");
+        let challenges = "{\"result\":[{\"qtype\":\"TXT\",\
+                          \"qname\":\"_acme-challenge.acme.mydomain.org.\",\
+                          \"content\":\"test_challenge\",\
+                          \"ttl\":30},\
+                          {\"qtype\":\"TXT\",\
+                          \"qname\":\"_acme-challenge.acme.mydomain.org.\",\
+                          \"content\":\"wildcard_challenge\",\
+                          \"ttl\":30}]}";
+        assert_eq!(lookup("TXT", challenge_name), challenges);
+        assert_eq!(lookup("ANY", challenge_name), challenges);
+        set_challenge("challenge=third_challenge&append=true");
+        let result = lookup("TXT", challenge_name);
+        assert!(!result.contains("\"test_challenge\""));
+        assert!(result.contains("wildcard_challenge") && result.contains("third_challenge"));
+        set_challenge("challenge=test_challenge");
+        assert_eq!(lookup("TXT", challenge_name), challenge);
+
+        request::get(
+            &format!(
+                "http://localhost/dnsconfig?token={}&challenge=",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(lookup("TXT", challenge_name), empty_result);
+        assert_eq!(lookup("A", "acme.mydomain.org."), address);
+    }
+
+    #[test]
+    fn test_txt_records() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_txt");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_txt");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_txt.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let call = |path: &str| -> Status {
+            match request::get(
+                &format!("http://localhost/{}", path),
+                Headers::new(),
+                &router,
+            ) {
+                Ok(resp) => resp.status.unwrap(),
+                Err(err) => err.response.status.unwrap(),
+            }
+        };
+        let empty_result = "{\"result\":[]}";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=txt",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let token = registration.token;
+        assert_eq!(lookup("TXT", "txt.mydomain.org."), empty_result);
+
+        // Invalid values and unknown tokens.
+        assert_eq!(call(&format!("settxt?token={}", token)), Status::BadRequest);
+        assert_eq!(
+            call(&format!("settxt?token={}&value=", token)),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value=a%22b", token)),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value=a%0Ab", token)),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value={}", token, "a".repeat(256))),
+            Status::BadRequest
+        );
+        assert_eq!(call("settxt?token=wrong&value=test"), Status::NotFound);
+        assert_eq!(call("deletetxt?token=wrong&value=test"), Status::NotFound);
+
+        // Setting a value twice only publishes it once.
+        assert_eq!(
+            call(&format!("settxt?token={}&value=v%3Dspf1%20-all", token)),
+            Status::Ok
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value=v%3Dspf1%20-all", token)),
+            Status::Ok
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value=site-verification", token)),
+            Status::Ok
+        );
+        let records = "{\"result\":[{\"qtype\":\"TXT\",\
+                       \"qname\":\"txt.mydomain.org.\",\
+                       \"content\":\"\\\"v=spf1 -all\\\"\",\
+                       \"ttl\":600},\
+                       {\"qtype\":\"TXT\",\
+                       \"qname\":\"txt.mydomain.org.\",\
+                       \"content\":\"\\\"site-verification\\\"\",\
+                       \"ttl\":600}]}";
+        assert_eq!(lookup("TXT", "txt.mydomain.org."), records);
+        let any = lookup("ANY", "txt.mydomain.org.");
+        assert!(any.contains("v=spf1 -all") && any.contains("site-verification"));
+
+        // They are only served for the domain itself.
+        assert!(!lookup("A", "txt.mydomain.org.").contains("TXT"));
+        assert_eq!(lookup("TXT", "local.txt.mydomain.org."), empty_result);
+        assert_eq!(
+            lookup("TXT", "_acme-challenge.txt.mydomain.org."),
+            empty_result
+        );
+        assert_eq!(lookup("TXT", "other.mydomain.org."), empty_result);
+
+        // Deleting a value that isn't published is a no-op.
+        assert_eq!(
+            call(&format!(
+                "deletetxt?token={}&value=site-verification",
+                token
+            )),
+            Status::Ok
+        );
+        assert_eq!(
+            call(&format!(
+                "deletetxt?token={}&value=site-verification",
+                token
+            )),
+            Status::Ok
+        );
+        let txt = lookup("TXT", "txt.mydomain.org.");
+        assert!(txt.contains("v=spf1 -all") && !txt.contains("site-verification"));
+
+        // The number of records is limited.
+        for i in 1..10 {
+            assert_eq!(
+                call(&format!("settxt?token={}&value=record-{}", token, i)),
+                Status::Ok
+            );
+        }
+        assert_eq!(
+            call(&format!("settxt?token={}&value=record-10", token)),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call(&format!("settxt?token={}&value=record-9", token)),
+            Status::Ok
+        );
+
+        // The records go away with the domain.
+        assert_eq!(call(&format!("unsubscribe?token={}", token)), Status::Ok);
+        assert_eq!(lookup("TXT", "txt.mydomain.org."), empty_result);
+    }
+
+    #[test]
+    fn test_mail_server() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_mx");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_mx");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_mx.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let call = |path: &str| -> Status {
+            match request::get(
+                &format!("http://localhost/{}", path),
+                Headers::new(),
+                &router,
+            ) {
+                Ok(resp) => resp.status.unwrap(),
+                Err(err) => err.response.status.unwrap(),
+            }
+        };
+        let empty_result = "{\"result\":[]}";
+        let name = "mx.mydomain.org.";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=mx",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let token = registration.token;
+
+        // The mail server of the configuration is only part of ANY answers.
+        assert_eq!(lookup("MX", name), empty_result);
+        let any = lookup("ANY", name);
+        assert_eq!(any.matches("\"qtype\":\"MX\"").count(), 1);
+        assert!(!any.contains("mail.example.org"));
+
+        // Invalid parameters and unknown tokens.
+        assert_eq!(call(&format!("setmx?token={}", token)), Status::BadRequest);
+        for target in &[
+            "mail",
+            "-mail.example.org",
+            "mail..example.org",
+            "mail_1.example.org",
+        ] {
+            assert_eq!(
+                call(&format!("setmx?token={}&target={}", token, target)),
+                Status::BadRequest
+            );
+        }
+        assert_eq!(
+            call(&format!(
+                "setmx?token={}&target=mail.example.org&priority=65536",
+                token
+            )),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call("setmx?token=wrong&target=mail.example.org"),
+            Status::NotFound
+        );
+
+        // The own mail server of the domain replaces the one of the
+        // configuration, with a default priority of 10.
+        assert_eq!(
+            call(&format!("setmx?token={}&target=Mail.Example.org", token)),
+            Status::Ok
+        );
+        let mx = "{\"result\":[{\"qtype\":\"MX\",\
+                  \"qname\":\"mx.mydomain.org.\",\
+                  \"content\":\"10 mail.example.org.\",\
+                  \"ttl\":600}]}";
+        assert_eq!(lookup("MX", name), mx);
+        let any = lookup("ANY", name);
+        assert_eq!(any.matches("\"qtype\":\"MX\"").count(), 1);
+        assert!(any.contains("10 mail.example.org."));
+        assert_eq!(
+            call(&format!(
+                "setmx?token={}&target=mail.example.org.&priority=20",
+                token
+            )),
+            Status::Ok
+        );
+        assert_eq!(lookup("MX", name), mx.replace("10 ", "20 "));
+
+        // It's not served for the names under the domain.
+        assert_eq!(lookup("MX", "local.mx.mydomain.org."), empty_result);
+        assert_eq!(
+            lookup("MX", "_acme-challenge.mx.mydomain.org."),
+            empty_result
+        );
+
+        // An empty target removes it.
+        assert_eq!(call(&format!("setmx?token={}&target=", token)), Status::Ok);
+        assert_eq!(lookup("MX", name), empty_result);
+        assert_eq!(conn.get_domain_by_token(&token).unwrap().mx_priority, 0);
+    }
+
+    #[test]
+    fn test_srv_records() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_srv");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_srv");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_srv.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let call = |path: &str| -> Status {
+            match request::get(
+                &format!("http://localhost/{}", path),
+                Headers::new(),
+                &router,
+            ) {
+                Ok(resp) => resp.status.unwrap(),
+                Err(err) => err.response.status.unwrap(),
+            }
+        };
+        let empty_result = "{\"result\":[]}";
+        let ssh_name = "_ssh._tcp.srv.mydomain.org.";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=srv",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let token = registration.token;
+        assert_eq!(lookup("SRV", ssh_name), empty_result);
+
+        // Missing and invalid parameters, and unknown tokens.
+        assert_eq!(
+            call(&format!("setsrv?token={}&service=ssh&protocol=tcp", token)),
+            Status::BadRequest
+        );
+        for query in &[
+            "service=ssh&protocol=tcp&port=0",
+            "service=ssh&protocol=tcp&port=65536",
+            "service=ssh&protocol=sctp&port=22",
+            "service=-ssh&protocol=tcp&port=22",
+            "service=a-very-long-service&protocol=tcp&port=22",
+            "service=ssh&protocol=tcp&port=22&weight=-1",
+        ] {
+            assert_eq!(
+                call(&format!("setsrv?token={}&{}", token, query)),
+                Status::BadRequest
+            );
+        }
+        assert_eq!(
+            call("setsrv?token=wrong&service=ssh&protocol=tcp&port=22"),
+            Status::NotFound
+        );
+
+        // The record points to the domain, and a new port replaces the
+        // previous one.
+        assert_eq!(
+            call(&format!(
+                "setsrv?token={}&service=_SSH&protocol=_tcp&port=22",
+                token
+            )),
+            Status::Ok
+        );
+        let ssh = "{\"result\":[{\"qtype\":\"SRV\",\
+                   \"qname\":\"_ssh._tcp.srv.mydomain.org.\",\
+                   \"content\":\"10 0 22 srv.mydomain.org.\",\
+                   \"ttl\":600}]}";
+        assert_eq!(lookup("SRV", ssh_name), ssh);
+        assert_eq!(lookup("ANY", ssh_name), ssh);
+        assert_eq!(lookup("A", ssh_name), empty_result);
+        assert_eq!(
+            call(&format!(
+                "setsrv?token={}&service=ssh&protocol=tcp&port=2222&priority=5&weight=20",
+                token
+            )),
+            Status::Ok
+        );
+        assert_eq!(
+            lookup("SRV", ssh_name),
+            ssh.replace("10 0 22 ", "5 20 2222 ")
+        );
+
+        // Each service and protocol has its own name.
+        assert_eq!(
+            call(&format!(
+                "setsrv?token={}&service=minecraft&protocol=udp&port=25565",
+                token
+            )),
+            Status::Ok
+        );
+        assert!(lookup("SRV", "_minecraft._udp.srv.mydomain.org.").contains("25565"));
+        assert_eq!(
+            lookup("SRV", "_minecraft._tcp.srv.mydomain.org."),
+            empty_result
+        );
+        assert_eq!(lookup("SRV", "_ssh._tcp.other.mydomain.org."), empty_result);
+        assert_eq!(lookup("SRV", "srv.mydomain.org."), empty_result);
+
+        // The number of services is limited.
+        for i in 1..9 {
+            assert_eq!(
+                call(&format!(
+                    "setsrv?token={}&service=service{}&protocol=tcp&port=8000",
+                    token, i
+                )),
+                Status::Ok
+            );
+        }
+        assert_eq!(
+            call(&format!(
+                "setsrv?token={}&service=service9&protocol=tcp&port=8000",
+                token
+            )),
+            Status::BadRequest
+        );
+        assert_eq!(
+            call(&format!(
+                "setsrv?token={}&service=service8&protocol=tcp&port=8080",
+                token
+            )),
+            Status::Ok
+        );
+
+        // Deleting a service that isn't published is a no-op.
+        for _ in 0..2 {
+            assert_eq!(
+                call(&format!(
+                    "deletesrv?token={}&service=ssh&protocol=tcp",
+                    token
+                )),
+                Status::Ok
+            );
+        }
+        assert_eq!(lookup("SRV", ssh_name), empty_result);
+        assert_eq!(
+            call(&format!(
+                "deletesrv?token={}&service=ssh&protocol=icmp",
+                token
+            )),
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        use signal_hook::SIGHUP;
+        use signals::handle_signal;
+        use std::fs::File;
+        use std::path::PathBuf;
+
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_reload");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_reload");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_reload.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        // Like the PowerDNS socket, each lookup uses the current options.
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config.current()).unwrap()).unwrap()
+        };
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=reload",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::Ok);
+        let address = lookup("A", "reload.mydomain.org.");
+        assert!(address.contains("\"ttl\":60"));
+
+        // Change the TTL and the HTTP port in a copy of the configuration.
+        let mut source = String::new();
+        File::open("./config/config.toml")
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .unwrap();
+        assert!(source.contains("tunnel_ttl = 60") && source.contains("http_port = 4141"));
+        let path = PathBuf::from("/tmp/registration_server_test_reload.toml");
+        let reloaded = source
+            .replace("tunnel_ttl = 60", "tunnel_ttl = 90")
+            .replace("http_port = 4141", "http_port = 4242");
+        File::create(&path)
+            .and_then(|mut file| file.write_all(reloaded.as_bytes()))
+            .unwrap();
+
+        assert!(handle_signal(SIGHUP, &config, &Some(path.clone())));
+        assert_eq!(
+            lookup("A", "reload.mydomain.org."),
+            address.replace("\"ttl\":60", "\"ttl\":90")
+        );
+        // The port can't change without a restart.
+        assert_eq!(config.current().options.general.http_port, 4141);
+
+        // Invalid files are ignored.
+        File::create(&path)
+            .and_then(|mut file| file.write_all(b"not a configuration"))
+            .unwrap();
+        assert!(handle_signal(SIGHUP, &config, &Some(path.clone())));
+        assert!(lookup("A", "reload.mydomain.org.").contains("\"ttl\":90"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_domain_ttl() {
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut record = Domain {
+            id: 1,
+            name: "test.mydomain.org.".to_owned(),
+            account_id: 1,
+            token: "test-token".to_owned(),
+            description: "Test Server".to_owned(),
+            timestamp: 0,
+            dns_challenge: "".to_owned(),
+            reclamation_token: "".to_owned(),
+            verification_token: "".to_owned(),
+            verified: false,
+            continent: "".to_owned(),
+            local_ips: "".to_owned(),
+            warning_sent_at: 0,
+            wildcard: false,
+            challenge_set_at: 0,
+            auth_key: "".to_owned(),
+            mx_target: "".to_owned(),
+            mx_priority: 0,
+            local_prefix: "".to_owned(),
+            device_token: "".to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        };
+
+        // Without a challenge, and during and after the window of 300s.
+        assert_eq!(domain_ttl(&record, &args.pdns, 1000), 60);
+        record.challenge_set_at = 1000;
+        assert_eq!(domain_ttl(&record, &args.pdns, 1000), 30);
+        assert_eq!(domain_ttl(&record, &args.pdns, 1299), 30);
+        assert_eq!(domain_ttl(&record, &args.pdns, 1300), 60);
+
+        // The challenge TTL never raises the TTL.
+        let mut options = args.pdns.clone();
+        options.challenge_ttl = 120;
+        assert_eq!(domain_ttl(&record, &options, 1000), 60);
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(
+            normalize_ip("::ffff:1.2.3.4".parse().unwrap()),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            normalize_ip("2001:db8::1".parse().unwrap()),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            normalize_ip("1.2.3.4".parse().unwrap()),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+
+        assert_eq!(
+            parse_addresses(" 1.2.3.4, 2001:DB8:0::1 ,garbage,,::ffff:5.6.7.8"),
+            vec![
+                "1.2.3.4".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse::<IpAddr>().unwrap(),
+                "5.6.7.8".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(parse_addresses(""), Vec::<IpAddr>::new());
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_ipv6");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_ipv6");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_ipv6.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.pdns.geoip.default = "2001:db8::1".to_owned();
+        config.options.pdns.geoip.continent.NA = Some("5.6.7.8, 2001:db8::5678".to_owned());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str, remote: Option<&str>| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), remote);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let empty_result = "{\"result\":[]}";
+
+        // Register from an IPv4-mapped address, which is stored with its
+        // continent.
+        let mut headers = Headers::new();
+        headers.set_raw("X-Real-IP", vec![b"::ffff:8.8.8.8".to_vec()]);
+        let resp = request::get("http://localhost/subscribe?name=dual", headers, &router).unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        assert_eq!(
+            conn.get_domain_by_token(&registration.token)
+                .unwrap()
+                .continent,
+            "NA"
+        );
+
+        let a_record = "{\"qtype\":\"A\",\
+                        \"qname\":\"dual.mydomain.org.\",\
+                        \"content\":\"5.6.7.8\",\
+                        \"ttl\":60}";
+        let aaaa_record = "{\"qtype\":\"AAAA\",\
+                           \"qname\":\"dual.mydomain.org.\",\
+                           \"content\":\"2001:db8::5678\",\
+                           \"ttl\":60}";
+        assert_eq!(
+            lookup("A", "dual.mydomain.org.", None),
+            format!("{{\"result\":[{}]}}", a_record)
+        );
+        assert_eq!(
+            lookup("AAAA", "dual.mydomain.org.", None),
+            format!("{{\"result\":[{}]}}", aaaa_record)
+        );
+        assert!(lookup("ANY", "dual.mydomain.org.", None)
+            .contains(&format!("{},{}", a_record, aaaa_record)));
+
+        // The default endpoint only has an IPv6 address.
+        let aaaa_default = "{\"result\":[{\"qtype\":\"AAAA\",\
+                            \"qname\":\"api.mydomain.org.\",\
+                            \"content\":\"2001:db8::1\",\
+                            \"ttl\":10}]}";
+        assert_eq!(
+            lookup("A", "api.mydomain.org.", Some("not an ip")),
+            empty_result
+        );
+        assert_eq!(
+            lookup("AAAA", "api.mydomain.org.", Some("not an ip")),
+            aaaa_default
+        );
+        assert_eq!(
+            lookup("AAAA", "api.mydomain.org.", Some("::ffff:8.8.8.8")),
+            "{\"result\":[{\"qtype\":\"AAAA\",\
+             \"qname\":\"api.mydomain.org.\",\
+             \"content\":\"2001:db8::5678\",\
+             \"ttl\":10}]}"
+        );
+    }
+
+    #[test]
+    fn test_local_addresses() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_local");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_local");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_local.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let empty_result = "{\"result\":[]}";
+        let local_name = "local.home.mydomain.org.";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=home",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+
+        // Nothing is served until the gateway sends its local addresses.
+        assert_eq!(lookup("ANY", local_name), empty_result);
+
+        let resp = request::get(
+            &format!(
+                "http://localhost/ping?token={}&local_ip=192.168.1.2,10.0.0.2",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::Ok);
+
+        let ethernet = "{\"qtype\":\"A\",\
+                        \"qname\":\"local.home.mydomain.org.\",\
+                        \"content\":\"192.168.1.2\",\
+                        \"ttl\":60}";
+        let wifi = "{\"qtype\":\"A\",\
+                    \"qname\":\"local.home.mydomain.org.\",\
+                    \"content\":\"10.0.0.2\",\
+                    \"ttl\":60}";
+        let link_local = "{\"qtype\":\"AAAA\",\
+                          \"qname\":\"local.home.mydomain.org.\",\
+                          \"content\":\"fe80::1\",\
+                          \"ttl\":60}";
+        assert_eq!(
+            lookup("A", local_name),
+            format!("{{\"result\":[{},{}]}}", ethernet, wifi)
+        );
+        assert_eq!(lookup("AAAA", local_name), empty_result);
+
+        // Pings without local addresses keep the previous ones.
+        request::get(
+            &format!("http://localhost/ping?token={}", registration.token),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(
+            lookup("A", local_name),
+            format!("{{\"result\":[{},{}]}}", ethernet, wifi)
+        );
+
+        // Addresses can also be sent as a JSON array.
+        let resp = request::post(
+            "http://localhost/ping",
+            Headers::new(),
+            &format!(
+                "{{\"token\": \"{}\", \"local_ip\": [\"192.168.1.2\", \"fe80::1\"]}}",
+                registration.token
+            ),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::Ok);
+        assert_eq!(
+            lookup("ANY", local_name),
+            format!("{{\"result\":[{},{}]}}", ethernet, link_local)
+        );
+
+        // Invalid addresses are rejected and don't change the stored ones.
+        let resp = request::get(
+            &format!(
+                "http://localhost/ping?token={}&local_ip=192.168.1.2,garbage",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::BadRequest);
+        assert_eq!(
+            lookup("ANY", local_name),
+            format!("{{\"result\":[{},{}]}}", ethernet, link_local)
+        );
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_wildcard");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_wildcard");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_wildcard.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let empty_result = "{\"result\":[]}";
+
+        let resp = request::get(
+            "http://localhost/subscribe?name=home",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let resp = request::get(
+            &format!(
+                "http://localhost/ping?token={}&local_ip=192.168.1.2",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::Ok);
+
+        let public = lookup("A", "home.mydomain.org.");
+        let local = lookup("A", "local.home.mydomain.org.");
+        assert_ne!(public, empty_result);
+        assert_ne!(local, empty_result);
+
+        let names = vec![
+            ("cam.home.mydomain.org.", false),
+            ("a.b.home.mydomain.org.", false),
+            ("files.local.home.mydomain.org.", true),
+            ("a.b.local.home.mydomain.org.", true),
+        ];
+
+        // Names under the domain are not served until the wildcard is set.
+        for &(name, _) in &names {
+            assert_eq!(lookup("A", name), empty_result);
+        }
+
+        let resp = request::get(
+            &format!(
+                "http://localhost/setwildcard?token={}&enabled=true",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        assert_eq!(resp.status.unwrap(), Status::Ok);
+
+        for &(name, is_local) in &names {
+            let expected = if is_local {
+                local.replace("local.home.mydomain.org.", name)
+            } else {
+                public.replace("home.mydomain.org.", name)
+            };
+            assert_eq!(lookup("A", name), expected);
+        }
+
+        // The wildcard doesn't apply to the challenge, nor to other names.
+        assert_eq!(
+            lookup("TXT", "_acme-challenge.cam.home.mydomain.org."),
+            empty_result
+        );
+        assert_eq!(lookup("A", "cam.other.mydomain.org."), empty_result);
+        assert_eq!(lookup("A", "cam.api.mydomain.org."), empty_result);
+
+        // A domain can choose another label for its local name.
+        let err = request::get(
+            "http://localhost/subscribe?name=office&local_prefix=bad.prefix",
+            Headers::new(),
+            &router,
+        )
+        .unwrap_err();
+        assert_eq!(err.response.status.unwrap(), Status::BadRequest);
+        let resp = request::get(
+            "http://localhost/subscribe?name=office&local_prefix=LAN",
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let resp = request::get(
+            &format!(
+                "http://localhost/ping?token={}&local_ip=192.168.1.3&format=json",
+                registration.token
+            ),
+            Headers::new(),
+            &router,
+        )
+        .unwrap();
+        let ping_record: PingRecord =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        assert_eq!(ping_record.local_name, "lan.office.mydomain.org");
+        assert!(lookup("A", "lan.office.mydomain.org.").contains("192.168.1.3"));
+        assert_eq!(lookup("A", "local.office.mydomain.org."), empty_result);
+        assert!(lookup("A", "local.home.mydomain.org.").contains("192.168.1.2"));
+    }
+
+    #[test]
+    fn test_lookup_cache() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_cache");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_cache");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_cache.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        // The cache is turned off in the test configuration.
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.pdns.cache_ttl = 60;
+        config.options.pdns.negative_cache_ttl = 10;
+        config.dns_cache = DomainCache::new(&config.options.pdns);
+        let router = create_router(&config);
+
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let request = build_request("lookup", Some(qtype), Some(qname), None);
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+        let get = |url: &str| -> String {
+            let resp = request::get(url, Headers::new(), &router).unwrap();
+            assert_eq!(resp.status, Some(Status::Ok));
+            response::extract_body_to_string(resp)
+        };
+        let empty_result = "{\"result\":[]}";
+
+        // The unknown name is cached, but registering it is seen right away.
+        assert_eq!(lookup("A", "home.mydomain.org."), empty_result);
+        let registration: NameAndToken =
+            serde_json::from_str(&get("http://localhost/subscribe?name=home")).unwrap();
+        get(&format!(
+            "http://localhost/ping?token={}&local_ip=192.168.1.2",
+            registration.token
+        ));
+        assert_ne!(lookup("A", "home.mydomain.org."), empty_result);
+        assert!(lookup("A", "local.home.mydomain.org.").contains("192.168.1.2"));
+
+        // Changes of the domain are seen right away too.
+        get(&format!(
+            "http://localhost/ping?token={}&local_ip=192.168.1.3",
+            registration.token
+        ));
+        assert!(lookup("A", "local.home.mydomain.org.").contains("192.168.1.3"));
+        get(&format!(
+            "http://localhost/dnsconfig?token={}&challenge=cache-challenge",
+            registration.token
+        ));
+        assert!(lookup("TXT", "_acme-challenge.home.mydomain.org.").contains("cache-challenge"));
+        get(&format!(
+            "http://localhost/unsubscribe?token={}",
+            registration.token
+        ));
+        assert_eq!(lookup("A", "home.mydomain.org."), empty_result);
+        assert_eq!(lookup("A", "local.home.mydomain.org."), empty_result);
+
+        // Without invalidation, the cached domain is served until it expires.
+        let registration: NameAndToken =
+            serde_json::from_str(&get("http://localhost/subscribe?name=home")).unwrap();
+        assert_ne!(lookup("A", "home.mydomain.org."), empty_result);
+        conn.delete_domain_by_token(&registration.token).unwrap();
+        assert_ne!(lookup("A", "home.mydomain.org."), empty_result);
+    }
+
+    #[test]
+    fn test_soa_and_ns() {
+        let _ = env_logger::init();
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_soa");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_soa");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_soa.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let config = Config::from_args_with_db(args, db.clone());
+
+        // Sends a request as PowerDNS writes it on the socket.
+        let lookup = |qtype: &str, qname: &str| -> String {
+            let line = format!(
+                "{{\"method\":\"lookup\",\"parameters\":{{\"qtype\":\"{}\",\
+                 \"qname\":\"{}\",\"remote\":\"192.0.2.1\",\"local\":\"0.0.0.0\",\
+                 \"real-remote\":\"192.0.2.1/32\",\"zone-id\":-1}}}}",
+                qtype, qname
+            );
+            let request: PdnsRequest = serde_json::from_str(&line).unwrap();
+            serde_json::to_string(&process_request(request, &config).unwrap()).unwrap()
+        };
+
+        // Without any domain, the serial is 0.
+        assert_eq!(
+            lookup("SOA", "mydomain.org."),
+            "{\"result\":[{\"qtype\":\"SOA\",\"qname\":\"mydomain.org.\",\
+             \"content\":\"a.dns.gandi.net hostmaster.gandi.net 0 10800 3600 604800 10800\",\
+             \"ttl\":600}]}"
+        );
+
+        // The serial is the most recent timestamp of the domains.
+        let unknown = conn.get_unknown_account().unwrap();
+        for &(name, timestamp) in &[("old", 1_500_000_000), ("new", 1_519_603_200)] {
+            conn.add_domain(
+                &format!("{}.mydomain.org.", name),
+                unknown.id,
+                &format!("{}-token", name),
+                "",
+                timestamp,
+                "",
+                "",
+                "",
+                false,
+                "",
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            lookup("SOA", "MyDomain.org."),
+            "{\"result\":[{\"qtype\":\"SOA\",\"qname\":\"mydomain.org.\",\
+             \"content\":\"a.dns.gandi.net hostmaster.gandi.net 1519603200 \
+             10800 3600 604800 10800\",\
+             \"ttl\":600}]}"
+        );
+
+        assert_eq!(
+            lookup("NS", "mydomain.org."),
+            "{\"result\":[{\"qtype\":\"NS\",\"qname\":\"mydomain.org.\",\
+             \"content\":\"ns1.mydomain.org\",\"ttl\":600},\
+             {\"qtype\":\"NS\",\"qname\":\"mydomain.org.\",\
+             \"content\":\"ns2.mydomain.org\",\"ttl\":600}]}"
+        );
+
+        // Subdomains have neither SOA nor NS records.
+        let empty_result = "{\"result\":[]}";
+        assert_eq!(lookup("SOA", "new.mydomain.org."), empty_result);
+        assert_eq!(lookup("NS", "new.mydomain.org."), empty_result);
+        assert_eq!(lookup("SOA", "other.org."), empty_result);
+    }
 }
@@ -0,0 +1,586 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Checks the signed pings. A domain subscribed with `signed=true` gets a
+// secret, and its pings carry a `nonce`, the current Unix time, and a `mac`,
+// the HMAC-SHA256 of the nonce. The key of the HMAC is the SHA-256 hash of the
+// secret, which is what the database stores: the secret itself is never kept,
+// but the stored hash is enough to sign pings, so the database must stay
+// private. A nonce is only accepted within `nonce_skew` seconds of the server
+// time, and only once per domain, so a ping seen in transit can't be replayed.
+//
+// The same secret can sign the whole requests of the gateway, for deployments
+// that don't trust the TLS termination in front of the server. The
+// `X-Signature` header of a request then holds a nonce and the HMAC of the
+// nonce, the method, the path, the query string and the body, checked by the
+// RequireSignature middleware before the handler runs.
+
+extern crate env_logger;
+use config::{Config, GeneralOptions};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use errors::{ApiError, EndpointError};
+use eviction::now;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::BeforeMiddleware;
+use models::Domain;
+use mount::OriginalUrl;
+use parameters::{read_body, Parameters};
+use routes::get_domain_for_device;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// The signature of a request, as `<nonce>:<mac>`.
+header! { (XSignature, "X-Signature") => [String] }
+
+// Returns the key stored for a secret.
+pub fn auth_key(secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(secret);
+    hasher.result_str()
+}
+
+// Returns the MAC of a nonce, in lowercase hexadecimal.
+pub fn sign(key: &str, nonce: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), key.as_bytes());
+    hmac.input(nonce.as_bytes());
+    hmac.result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Returns the message signed by the X-Signature header of a request.
+pub fn request_message(nonce: &str, method: &str, path: &str, query: &str, body: &str) -> String {
+    format!("{}\n{}\n{}\n{}\n{}", nonce, method, path, query, body)
+}
+
+// The value of the `auth_mode` field of /info.
+pub fn auth_mode(record: &Domain) -> &'static str {
+    if record.auth_key.is_empty() {
+        "token"
+    } else {
+        "signed"
+    }
+}
+
+// The nonces accepted recently, with the token of their domain. Shared by all
+// the clones of the configuration.
+#[derive(Clone, Default)]
+pub struct NonceCache {
+    nonces: Arc<Mutex<BTreeSet<(i64, String)>>>,
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        NonceCache::default()
+    }
+
+    fn nonces(&self) -> MutexGuard<BTreeSet<(i64, String)>> {
+        match self.nonces.lock() {
+            Ok(nonces) => nonces,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Records a nonce, and returns false if it was already used by this
+    // domain. The nonces older than `oldest` are rejected by the skew check,
+    // so they are dropped.
+    fn insert(&self, token: &str, nonce: i64, oldest: i64) -> bool {
+        let mut nonces = self.nonces();
+        let recent = nonces.split_off(&(oldest, String::new()));
+        *nonces = recent;
+        nonces.insert((nonce, token.to_owned()))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.nonces().len()
+    }
+}
+
+// Checks the nonce and MAC sent with a ping of this domain.
+pub fn check_ping(
+    record: &Domain,
+    nonce: &Option<String>,
+    mac: &Option<String>,
+    options: &GeneralOptions,
+    nonces: &NonceCache,
+) -> Result<(), ApiError> {
+    check_ping_at(record, nonce, mac, options, nonces, now())
+}
+
+fn check_ping_at(
+    record: &Domain,
+    nonce: &Option<String>,
+    mac: &Option<String>,
+    options: &GeneralOptions,
+    nonces: &NonceCache,
+    now: i64,
+) -> Result<(), ApiError> {
+    // Domains subscribed before the signed pings only send their token.
+    if record.auth_key.is_empty() {
+        if options.require_signed_pings {
+            info!("check_ping(): Unsigned ping for {}", record.name);
+            return Err(ApiError::InvalidSignature);
+        }
+        return Ok(());
+    }
+
+    let (nonce, mac) = match (nonce, mac) {
+        (&Some(ref nonce), &Some(ref mac)) => (nonce, mac),
+        _ => return Err(ApiError::MissingParameter),
+    };
+    check_mac(
+        record,
+        nonce,
+        nonce,
+        mac,
+        &record.token,
+        options,
+        nonces,
+        now,
+    )
+}
+
+// Checks the MAC of a message made with the key of the domain, and records its
+// nonce. A nonce can only be used once within a scope.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn check_mac(
+    record: &Domain,
+    nonce: &str,
+    message: &str,
+    mac: &str,
+    scope: &str,
+    options: &GeneralOptions,
+    nonces: &NonceCache,
+    now: i64,
+) -> Result<(), ApiError> {
+    let timestamp = match nonce.parse::<i64>() {
+        Ok(timestamp) => timestamp,
+        Err(_) => return Err(ApiError::InvalidParameter),
+    };
+
+    let skew = options.nonce_skew as i64;
+    if (timestamp - now).abs() > skew {
+        info!("check_mac(): Nonce out of range for {}", record.name);
+        return Err(ApiError::InvalidSignature);
+    }
+    let expected = sign(&record.auth_key, message);
+    if !fixed_time_eq(expected.as_bytes(), mac.to_lowercase().as_bytes()) {
+        info!("check_mac(): Invalid MAC for {}", record.name);
+        return Err(ApiError::InvalidSignature);
+    }
+    if !nonces.insert(scope, timestamp, now - skew) {
+        info!("check_mac(): Replayed nonce for {}", record.name);
+        return Err(ApiError::InvalidSignature);
+    }
+    Ok(())
+}
+
+// The parts of a request covered by its signature.
+struct SignedParts<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    body: &'a str,
+}
+
+// Checks the X-Signature header of a request of this domain.
+fn check_request_at(
+    record: &Domain,
+    signature: &Option<String>,
+    parts: &SignedParts,
+    options: &GeneralOptions,
+    nonces: &NonceCache,
+    now: i64,
+) -> Result<(), ApiError> {
+    let signature = match *signature {
+        Some(ref signature) => signature,
+        None if options.require_signed_requests => {
+            info!("check_request(): Unsigned request for {}", record.name);
+            return Err(ApiError::InvalidSignature);
+        }
+        None => return Ok(()),
+    };
+    if record.auth_key.is_empty() {
+        info!("check_request(): No signing secret for {}", record.name);
+        return Err(ApiError::InvalidSignature);
+    }
+
+    let mut values = signature.splitn(2, ':');
+    let (nonce, mac) = match (values.next(), values.next()) {
+        (Some(nonce), Some(mac)) => (nonce, mac),
+        _ => return Err(ApiError::InvalidParameter),
+    };
+    let message = request_message(nonce, parts.method, parts.path, parts.query, parts.body);
+    // The pings can use the same nonces.
+    let scope = format!("{} {}", record.token, parts.path);
+    check_mac(record, nonce, &message, mac, &scope, options, nonces, now)
+}
+
+// Set on the requests whose X-Signature header was checked.
+pub struct SignedRequest;
+
+impl Key for SignedRequest {
+    type Value = ();
+}
+
+// Checks the X-Signature header of the requests of the gateways. It is linked
+// before the handlers of their routes. The requests with invalid parameters or
+// an unknown token are left to the handler, which answers them.
+pub struct RequireSignature {
+    config: Config,
+}
+
+impl RequireSignature {
+    pub fn new(config: &Config) -> Self {
+        RequireSignature {
+            config: config.clone(),
+        }
+    }
+}
+
+impl BeforeMiddleware for RequireSignature {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let config = self.config.current();
+        let signature = req.headers.get::<XSignature>().map(|value| value.0.clone());
+        if signature.is_none() && !config.options.general.require_signed_requests {
+            return Ok(());
+        }
+
+        let token =
+            match Parameters::from_request(req).and_then(|params| params.get_string("token")) {
+                Ok(token) => token,
+                Err(_) => return Ok(()),
+            };
+        let record = match config.db.get_connection() {
+            Ok(conn) => match get_domain_for_device(&conn, &token) {
+                Ok(record) => record,
+                Err(_) => return Ok(()),
+            },
+            Err(_) => return Ok(()),
+        };
+
+        // The path as sent by the client, before the version prefix is
+        // removed.
+        let url = match req.extensions.get::<OriginalUrl>() {
+            Some(url) => url.clone(),
+            None => req.url.clone(),
+        };
+        let body = read_body(req).unwrap_or_default();
+        let parts = SignedParts {
+            method: &req.method.to_string(),
+            path: &format!("/{}", url.path().join("/")),
+            query: url.query().unwrap_or(""),
+            body: &body,
+        };
+        match check_request_at(
+            &record,
+            &signature,
+            &parts,
+            &config.options.general,
+            &config.nonces,
+            now(),
+        ) {
+            Ok(()) => {
+                if signature.is_some() {
+                    req.extensions.insert::<SignedRequest>(());
+                }
+                Ok(())
+            }
+            Err(err) => EndpointError::with(err).map(|_| ()),
+        }
+    }
+}
+
+#[test]
+fn test_sign() {
+    let _ = env_logger::init();
+
+    let key = auth_key("my-secret");
+    assert_eq!(
+        key,
+        "186ef76e9d6a723ecb570d4d9c287487d001e5d35f7ed4a313350a407950318e"
+    );
+    assert_eq!(
+        sign(&key, "1000"),
+        "55f5a1e123da9d42ce4b28ca82961a751f5b9b1e03c4e64581acb9dec351afc9"
+    );
+}
+
+#[test]
+fn test_check_ping() {
+    use args::ArgsParser;
+
+    let _ = env_logger::init();
+
+    let mut options = ArgsParser::from_vec(vec!["registration_server"]).general;
+    options.nonce_skew = 300;
+    let nonces = NonceCache::new();
+    let mut record = Domain {
+        id: 1,
+        name: "test.mydomain.org.".to_owned(),
+        account_id: 1,
+        token: "test-token".to_owned(),
+        description: "Test Server".to_owned(),
+        timestamp: 0,
+        dns_challenge: "".to_owned(),
+        reclamation_token: "".to_owned(),
+        verification_token: "".to_owned(),
+        verified: false,
+        continent: "".to_owned(),
+        local_ips: "".to_owned(),
+        warning_sent_at: 0,
+        wildcard: false,
+        challenge_set_at: 0,
+        auth_key: "".to_owned(),
+        mx_target: "".to_owned(),
+        mx_priority: 0,
+        local_prefix: "".to_owned(),
+        device_token: "".to_owned(),
+        created_at: 0,
+        updated_at: 0,
+    };
+    let check = |record: &Domain, options: &GeneralOptions, nonce: &str, mac: &str, now: i64| {
+        let value = |value: &str| {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.to_owned())
+            }
+        };
+        check_ping_at(record, &value(nonce), &value(mac), options, &nonces, now)
+    };
+
+    // The legacy path only needs the token, unless signed pings are required.
+    assert_eq!(check(&record, &options, "", "", 1000), Ok(()));
+    assert_eq!(check(&record, &options, "1000", "garbage", 1000), Ok(()));
+    options.require_signed_pings = true;
+    assert_eq!(
+        check(&record, &options, "", "", 1000),
+        Err(ApiError::InvalidSignature)
+    );
+    options.require_signed_pings = false;
+
+    record.auth_key = auth_key("my-secret");
+    let mac = sign(&record.auth_key, "1000");
+    assert_eq!(auth_mode(&record), "signed");
+
+    // Missing or invalid parameters.
+    assert_eq!(
+        check(&record, &options, "", "", 1000),
+        Err(ApiError::MissingParameter)
+    );
+    assert_eq!(
+        check(&record, &options, "1000", "", 1000),
+        Err(ApiError::MissingParameter)
+    );
+    assert_eq!(
+        check(&record, &options, "soon", &mac, 1000),
+        Err(ApiError::InvalidParameter)
+    );
+
+    // Bad MACs: wrong secret, other nonce, truncated.
+    let other = sign(&auth_key("other-secret"), "1000");
+    assert_eq!(
+        check(&record, &options, "1000", &other, 1000),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(
+        check(&record, &options, "1001", &mac, 1000),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(
+        check(&record, &options, "1000", &mac[..32], 1000),
+        Err(ApiError::InvalidSignature)
+    );
+
+    // Nonces too far from the server time.
+    assert_eq!(
+        check(&record, &options, "1000", &mac, 1301),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(
+        check(&record, &options, "1000", &mac, 699),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(nonces.len(), 0);
+
+    // A valid ping can't be replayed, even in uppercase.
+    assert_eq!(check(&record, &options, "1000", &mac, 1300), Ok(()));
+    assert_eq!(
+        check(&record, &options, "1000", &mac.to_uppercase(), 1300),
+        Err(ApiError::InvalidSignature)
+    );
+    let mac = sign(&record.auth_key, "1010");
+    assert_eq!(check(&record, &options, "1010", &mac, 1010), Ok(()));
+    assert_eq!(
+        check(&record, &options, "1010", &mac, 1020),
+        Err(ApiError::InvalidSignature)
+    );
+
+    // Other domains can use the same nonce.
+    record.token = "other-token".to_owned();
+    assert_eq!(check(&record, &options, "1010", &mac, 1010), Ok(()));
+
+    // Expired nonces are dropped.
+    let mac = sign(&record.auth_key, "2000");
+    assert_eq!(check(&record, &options, "2000", &mac, 2000), Ok(()));
+    assert_eq!(nonces.len(), 1);
+}
+
+#[test]
+fn test_check_request() {
+    use args::ArgsParser;
+
+    let _ = env_logger::init();
+
+    let mut options = ArgsParser::from_vec(vec!["registration_server"]).general;
+    options.nonce_skew = 300;
+    let nonces = NonceCache::new();
+    let mut record = Domain {
+        id: 1,
+        name: "test.mydomain.org.".to_owned(),
+        account_id: 1,
+        token: "test-token".to_owned(),
+        description: "Test Server".to_owned(),
+        timestamp: 0,
+        dns_challenge: "".to_owned(),
+        reclamation_token: "".to_owned(),
+        verification_token: "".to_owned(),
+        verified: false,
+        continent: "".to_owned(),
+        local_ips: "".to_owned(),
+        warning_sent_at: 0,
+        wildcard: false,
+        challenge_set_at: 0,
+        auth_key: "".to_owned(),
+        mx_target: "".to_owned(),
+        mx_priority: 0,
+        local_prefix: "".to_owned(),
+        device_token: "".to_owned(),
+        created_at: 0,
+        updated_at: 0,
+    };
+    let parts = SignedParts {
+        method: "POST",
+        path: "/v1/dnsconfig",
+        query: "",
+        body: "{\"token\":\"test-token\"}",
+    };
+    let check = |record: &Domain, options: &GeneralOptions, signature: &str, now: i64| {
+        let signature = if signature.is_empty() {
+            None
+        } else {
+            Some(signature.to_owned())
+        };
+        check_request_at(record, &signature, &parts, options, &nonces, now)
+    };
+    let signature = |key: &str, nonce: &str, parts: &SignedParts| {
+        let message = request_message(nonce, parts.method, parts.path, parts.query, parts.body);
+        format!("{}:{}", nonce, sign(key, &message))
+    };
+
+    // Unsigned requests pass, unless they are required to be signed.
+    assert_eq!(check(&record, &options, "", 1000), Ok(()));
+    options.require_signed_requests = true;
+    assert_eq!(
+        check(&record, &options, "", 1000),
+        Err(ApiError::InvalidSignature)
+    );
+
+    // Domains without a secret can't sign.
+    assert_eq!(
+        check(&record, &options, "1000:garbage", 1000),
+        Err(ApiError::InvalidSignature)
+    );
+
+    record.auth_key = auth_key("my-secret");
+    let valid = signature(&record.auth_key, "1000", &parts);
+
+    // Malformed headers.
+    assert_eq!(
+        check(&record, &options, "garbage", 1000),
+        Err(ApiError::InvalidParameter)
+    );
+    assert_eq!(
+        check(&record, &options, &valid.replace("1000:", "soon:"), 1000),
+        Err(ApiError::InvalidParameter)
+    );
+
+    // The MAC covers every part of the request.
+    let others = [
+        SignedParts {
+            method: "GET",
+            ..parts
+        },
+        SignedParts {
+            path: "/v1/ping",
+            ..parts
+        },
+        SignedParts {
+            query: "token=test-token",
+            ..parts
+        },
+        SignedParts {
+            body: "{\"token\":\"other-token\"}",
+            ..parts
+        },
+    ];
+    for other in &others {
+        assert_eq!(
+            check(
+                &record,
+                &options,
+                &signature(&record.auth_key, "1000", other),
+                1000
+            ),
+            Err(ApiError::InvalidSignature)
+        );
+    }
+    assert_eq!(
+        check(
+            &record,
+            &options,
+            &signature(&auth_key("other"), "1000", &parts),
+            1000
+        ),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(
+        check(&record, &options, &valid, 1301),
+        Err(ApiError::InvalidSignature)
+    );
+    assert_eq!(nonces.len(), 0);
+
+    // A valid request can't be replayed.
+    assert_eq!(check(&record, &options, &valid, 1000), Ok(()));
+    assert_eq!(
+        check(&record, &options, &valid, 1000),
+        Err(ApiError::InvalidSignature)
+    );
+
+    // The nonce can be used again on another route.
+    let path = SignedParts {
+        path: "/v1/ping",
+        ..parts
+    };
+    assert_eq!(
+        check_request_at(
+            &record,
+            &Some(signature(&record.auth_key, "1000", &path)),
+            &path,
+            &options,
+            &nonces,
+            1000
+        ),
+        Ok(())
+    );
+}
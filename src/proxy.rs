@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Determines the IP address of the client of a request. When the server runs
+// behind reverse proxies, the peer of the connection is the last proxy and the
+// client address comes from the headers set by the proxies. These headers are
+// only used when the peer is one of the trusted proxies of the configuration,
+// as any client could set them.
+
+extern crate env_logger;
+use config::Config;
+use iron::Headers;
+use iron::prelude::*;
+use pdns::normalize_ip;
+use std::net::{IpAddr, SocketAddr};
+
+// Parses a hop of a forwarding header, which is an address optionally between
+// brackets and followed by a port, eg. `192.0.2.1`, `192.0.2.1:4711`,
+// `2001:db8::1` or `"[2001:db8::1]:4711"`.
+fn parse_hop(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(normalize_ip(ip));
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(normalize_ip(addr.ip()));
+    }
+    if value.starts_with('[') && value.ends_with(']') {
+        return value[1..value.len() - 1]
+            .parse::<IpAddr>()
+            .ok()
+            .map(normalize_ip);
+    }
+    None
+}
+
+fn header_values(headers: &Headers, name: &str) -> Option<Vec<String>> {
+    headers.get_raw(name).map(|lines| {
+        lines
+            .iter()
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect::<Vec<String>>()
+            .join(",")
+            .split(',')
+            .map(|value| value.trim().to_owned())
+            .collect()
+    })
+}
+
+// Returns the hops listed by the forwarding headers, from the client to the
+// last proxy, or None if there is no such header.
+fn forwarded_hops(headers: &Headers) -> Option<Vec<Option<IpAddr>>> {
+    if let Some(values) = header_values(headers, "X-Forwarded-For") {
+        return Some(values.iter().map(|value| parse_hop(value)).collect());
+    }
+
+    // See RFC 7239, eg. `Forwarded: for=192.0.2.1;proto=https, for=198.51.100.1`.
+    if let Some(values) = header_values(headers, "Forwarded") {
+        let hops = values
+            .iter()
+            .filter_map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| {
+                        let mut parts = pair.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") => {
+                                Some(parse_hop(value))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .next()
+            })
+            .collect();
+        return Some(hops);
+    }
+
+    // Set by nginx with `proxy_set_header X-Real-IP $remote_addr;`.
+    header_values(headers, "X-Real-IP")
+        .map(|values| values.iter().map(|value| parse_hop(value)).collect())
+}
+
+// Returns whether the address is one of the trusted proxies.
+pub fn is_trusted_proxy(ip: IpAddr, trusted_proxies: &[String]) -> bool {
+    let ip = normalize_ip(ip);
+    trusted_proxies
+        .iter()
+        .any(|proxy| parse_hop(proxy) == Some(ip))
+}
+
+// Returns the address of the client, given the headers of the request and the
+// address of the peer.
+fn forwarded_ip(headers: &Headers, peer: IpAddr, trusted_proxies: &[String]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| is_trusted_proxy(ip, trusted_proxies);
+
+    let peer = normalize_ip(peer);
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let hops = match forwarded_hops(headers) {
+        Some(hops) => hops,
+        None => return peer,
+    };
+
+    // Each proxy appends the address of its own peer, so the client is the
+    // rightmost address that wasn't added by a trusted proxy. Invalid entries
+    // can't be attributed, so the last trusted hop is used instead.
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match *hop {
+            Some(ip) => {
+                client = ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            None => {
+                error!("forwarded_ip(): Invalid forwarding header from {}", peer);
+                break;
+            }
+        }
+    }
+    client
+}
+
+pub fn client_ip(req: &Request, config: &Config) -> IpAddr {
+    forwarded_ip(
+        &req.headers,
+        req.remote_addr.ip(),
+        &config.options.general.trusted_proxies,
+    )
+}
+
+#[test]
+fn test_forwarded_ip() {
+    let _ = env_logger::init();
+
+    let trusted_proxies = vec!["10.0.0.1".to_owned(), "2001:db8::1".to_owned()];
+    let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+    let with_header = |name: &str, value: &str| -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw(name.to_owned(), vec![value.as_bytes().to_vec()]);
+        headers
+    };
+
+    // Without headers, the peer is the client.
+    assert_eq!(
+        forwarded_ip(&Headers::new(), proxy, &trusted_proxies),
+        proxy
+    );
+
+    // Headers from untrusted peers are ignored.
+    for &(name, value) in &[
+        ("X-Forwarded-For", "1.2.3.4"),
+        ("Forwarded", "for=1.2.3.4"),
+        ("X-Real-IP", "1.2.3.4"),
+    ] {
+        assert_eq!(
+            forwarded_ip(&with_header(name, value), client, &trusted_proxies),
+            client
+        );
+    }
+
+    // Single hop.
+    let headers = with_header("X-Forwarded-For", "203.0.113.7");
+    assert_eq!(forwarded_ip(&headers, proxy, &trusted_proxies), client);
+    let headers = with_header("X-Real-IP", "203.0.113.7");
+    assert_eq!(forwarded_ip(&headers, proxy, &trusted_proxies), client);
+
+    // Multiple hops: the client can prepend anything, so the rightmost
+    // untrusted address is used.
+    let headers = with_header("X-Forwarded-For", "1.2.3.4, 203.0.113.7, 2001:db8::1");
+    assert_eq!(forwarded_ip(&headers, proxy, &trusted_proxies), client);
+    let mut headers = Headers::new();
+    headers.set_raw(
+        "X-Forwarded-For",
+        vec![b"garbage, 203.0.113.7".to_vec(), b"10.0.0.1".to_vec()],
+    );
+    assert_eq!(forwarded_ip(&headers, proxy, &trusted_proxies), client);
+
+    // Only trusted hops: the leftmost one is the client.
+    let headers = with_header("X-Forwarded-For", "2001:db8::1, 10.0.0.1");
+    assert_eq!(
+        forwarded_ip(&headers, proxy, &trusted_proxies),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+
+    // An invalid hop stops the search at the last trusted one.
+    let headers = with_header("X-Forwarded-For", "203.0.113.7, unknown, 2001:db8::1");
+    assert_eq!(
+        forwarded_ip(&headers, proxy, &trusted_proxies),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+
+    // IPv6 clients and peers, including IPv4-mapped addresses.
+    let headers = with_header("X-Forwarded-For", "2001:db8::cafe");
+    assert_eq!(
+        forwarded_ip(&headers, "2001:db8::1".parse().unwrap(), &trusted_proxies),
+        "2001:db8::cafe".parse::<IpAddr>().unwrap()
+    );
+    let headers = with_header("X-Forwarded-For", "::ffff:203.0.113.7");
+    assert_eq!(
+        forwarded_ip(
+            &headers,
+            "::ffff:10.0.0.1".parse().unwrap(),
+            &trusted_proxies
+        ),
+        client
+    );
+
+    // The Forwarded header, with ports and quoted IPv6 addresses.
+    let headers = with_header(
+        "Forwarded",
+        "for=\"[2001:db8::cafe]:4711\";proto=https, For=10.0.0.1:80",
+    );
+    assert_eq!(
+        forwarded_ip(&headers, proxy, &trusted_proxies),
+        "2001:db8::cafe".parse::<IpAddr>().unwrap()
+    );
+    let headers = with_header("Forwarded", "for=203.0.113.7;by=10.0.0.1");
+    assert_eq!(forwarded_ip(&headers, proxy, &trusted_proxies), client);
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Limits the number of requests a client IP can make to the endpoints that
+// create domains or send emails. Each client gets an in-memory token bucket
+// that refills continuously over the configured period, implemented as the
+// time at which the bucket will be full again. The buckets are shared by all
+// the clones of the configuration.
+
+extern crate env_logger;
+use config::GeneralOptions;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Buckets {
+    // For each client, the time at which its bucket will be full again.
+    clients: HashMap<IpAddr, Instant>,
+    pruned: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    // Maximum number of requests per period, 0 turns the limiter off.
+    capacity: u32,
+    period: Duration,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        RateLimiter {
+            capacity: capacity,
+            period: period,
+            buckets: Arc::new(Mutex::new(Buckets {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            })),
+        }
+    }
+
+    // Takes a token for this client. Returns the number of seconds to wait
+    // before retrying if there is none left.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+
+        // Time needed to get a token back.
+        let interval = self.period / self.capacity;
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // Forget the clients whose bucket is full again, as they are in the
+        // same state as new clients.
+        if now > buckets.pruned && now.duration_since(buckets.pruned) >= self.period {
+            buckets.clients.retain(|_, full| *full > now);
+            buckets.pruned = now;
+        }
+
+        let full = match buckets.clients.get(&ip) {
+            Some(&full) if full > now => full,
+            _ => now,
+        };
+
+        // The bucket holds enough tokens for a period, so a token can be taken
+        // as long as the bucket gets full again within a period.
+        let next = full + interval;
+        let limit = now + self.period;
+        if next <= limit {
+            buckets.clients.insert(ip, next);
+            Ok(())
+        } else {
+            let wait = next.duration_since(limit);
+            Err(wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 })
+        }
+    }
+
+    #[cfg(test)]
+    fn clients(&self) -> usize {
+        self.buckets.lock().unwrap().clients.len()
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimits {
+    pub subscribe: RateLimiter,
+    pub email: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(options: &GeneralOptions) -> Self {
+        let hour = Duration::from_secs(3600);
+        RateLimits {
+            subscribe: RateLimiter::new(options.max_subscribes_per_hour, hour),
+            email: RateLimiter::new(options.max_emails_per_hour, hour),
+        }
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let _ = env_logger::init();
+
+    let first: IpAddr = "1.2.3.4".parse().unwrap();
+    let second: IpAddr = "2001:db8::1".parse().unwrap();
+
+    // 3 requests per hour, so a token every 20 minutes.
+    let limiter = RateLimiter::new(3, Duration::from_secs(3600));
+    let start = Instant::now();
+    let minutes = |count: u64| start + Duration::from_secs(count * 60);
+
+    assert_eq!(limiter.check_at(first, start), Ok(()));
+    assert_eq!(limiter.check_at(first, start), Ok(()));
+    assert_eq!(limiter.check_at(first, start), Ok(()));
+    assert_eq!(limiter.check_at(first, start), Err(1200));
+    assert_eq!(limiter.check_at(first, minutes(5)), Err(900));
+
+    // Other clients have their own bucket.
+    assert_eq!(limiter.check_at(second, minutes(5)), Ok(()));
+
+    // Tokens come back over time.
+    assert_eq!(limiter.check_at(first, minutes(20)), Ok(()));
+    assert_eq!(limiter.check_at(first, minutes(20)), Err(1200));
+    assert_eq!(limiter.clients(), 2);
+
+    // Full buckets are pruned after a period.
+    assert_eq!(limiter.check_at(first, minutes(85)), Ok(()));
+    assert_eq!(limiter.clients(), 1);
+
+    // A capacity of 0 turns the limiter off.
+    let limiter = RateLimiter::new(0, Duration::from_secs(3600));
+    for _ in 0..100 {
+        assert_eq!(limiter.check_at(first, start), Ok(()));
+    }
+}
@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Gives each API request an id, returned in the `X-Request-Id` header and
+// added to the log lines written while the request runs, so that the report of
+// a client can be matched with the logs. A request forwarded by one of the
+// trusted proxies keeps the id the proxy sent, eg. nginx's `$request_id`.
+// Iron runs a request on a single thread, so the id of the request being
+// handled is kept in a thread local for the logger.
+
+extern crate env_logger;
+use config::Config;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware, Headers};
+use proxy::is_trusted_proxy;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+header! { (XRequestId, "X-Request-Id") => [String] }
+
+// Maximum length of an id sent by a proxy.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// Returns the id of the request handled by this thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+fn set_current(id: Option<String>) {
+    CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+// The ids of the proxies end up in the logs and the responses, so they are
+// limited to printable ASCII characters without spaces.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+// Returns the id sent by a trusted proxy, or a new one.
+fn request_id(headers: &Headers, peer: IpAddr, trusted_proxies: &[String]) -> String {
+    if is_trusted_proxy(peer, trusted_proxies) {
+        if let Some(id) = headers.get::<XRequestId>() {
+            if is_valid_id(&id.0) {
+                return id.0.clone();
+            }
+        }
+    }
+    format!("{}", Uuid::new_v4())
+}
+
+// Set on each request, with its id.
+pub struct RequestId;
+
+impl Key for RequestId {
+    type Value = String;
+}
+
+// Assigns the ids. It is linked first, so that the other middlewares log with
+// the id, and sets the header on the responses and the errors.
+#[derive(Clone)]
+pub struct AssignRequestIds {
+    config: Config,
+}
+
+impl AssignRequestIds {
+    pub fn new(config: &Config) -> Self {
+        AssignRequestIds {
+            config: config.clone(),
+        }
+    }
+}
+
+impl BeforeMiddleware for AssignRequestIds {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let config = self.config.current();
+        let id = request_id(
+            &req.headers,
+            req.remote_addr.ip(),
+            &config.options.general.trusted_proxies,
+        );
+        set_current(Some(id.clone()));
+        req.extensions.insert::<RequestId>(id);
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for AssignRequestIds {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        if let Some(id) = req.extensions.get::<RequestId>() {
+            res.headers.set(XRequestId(id.clone()));
+        }
+        set_current(None);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        if let Some(id) = req.extensions.get::<RequestId>() {
+            err.response.headers.set(XRequestId(id.clone()));
+        }
+        set_current(None);
+        Err(err)
+    }
+}
+
+#[test]
+fn test_request_id() {
+    let _ = env_logger::init();
+
+    let trusted_proxies = vec!["10.0.0.1".to_owned()];
+    let proxy = "10.0.0.1".parse().unwrap();
+    let client = "192.0.2.1".parse().unwrap();
+    let with_id = |id: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Request-Id", vec![id.as_bytes().to_vec()]);
+        headers
+    };
+
+    // The id of a trusted proxy is kept.
+    assert_eq!(
+        request_id(&with_id("abc-123"), proxy, &trusted_proxies),
+        "abc-123"
+    );
+
+    // The others get a new one.
+    let id = request_id(&with_id("abc-123"), client, &trusted_proxies);
+    assert_ne!(id, "abc-123");
+    assert!(id.parse::<Uuid>().is_ok());
+    assert!(request_id(&Headers::new(), proxy, &trusted_proxies)
+        .parse::<Uuid>()
+        .is_ok());
+    assert_ne!(
+        request_id(&Headers::new(), proxy, &trusted_proxies),
+        request_id(&Headers::new(), proxy, &trusted_proxies)
+    );
+    let long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+    for invalid in &["", "with space", long.as_str()] {
+        assert_ne!(
+            request_id(&with_id(invalid), proxy, &trusted_proxies),
+            *invalid
+        );
+    }
+
+    assert_eq!(current(), None);
+    set_current(Some("abc".to_owned()));
+    assert_eq!(current(), Some("abc".to_owned()));
+    set_current(None);
+}
@@ -3,37 +3,356 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 extern crate env_logger;
-use config::Config;
+use admin_routes::{admin_audit, admin_ban, admin_export, admin_revoke, is_admin, status,
+                   RequireAdmin};
+use audit::AuditChanges;
+use captcha;
+use config::{Config, GeneralOptions};
+use cors::Cors;
+use crypto::util::fixed_time_eq;
+use database::Database;
 use diesel;
-use email_routes::{revokeemail, setemail, verifyemail, EmailSender};
+use email_routes::{is_valid_email, revokeemail, send_confirmation_email, setemail,
+                   verifyemail, EmailSender};
 use errors::*;
+use eviction::{eviction_deadline, is_evicted, is_stale, now, undelete_threshold};
+use idna;
 use iron::headers::ContentType;
 use iron::method::Method;
 use iron::prelude::*;
-use iron::status::{self, Status};
-use iron_cors::CORS;
+use iron::status::Status;
+use health::{health, livez, readyz, version};
+use logging::LogRequests;
+use metrics::RequestMetrics;
+use models::{DeletedRecord, DiscoveryToken, Domain, NewDeletedDomain, NewDiscoveryToken,
+             NewSrvRecord};
+use oidc_routes::{oidc_callback, oidc_login};
 use mount::Mount;
-use params::{FromValue, Params, Value};
-use pdns::lookup_continent;
+use parameters::{Parameters, ValidateParameters};
+use pdns::{domain_addresses, lookup_continent, normalize_ip};
+use ping_auth::{auth_key, auth_mode, check_ping, RequireSignature, SignedRequest};
+use proxy::client_ip;
 use regex::Regex;
+use request_id::AssignRequestIds;
 use router::Router;
+use sentry::ReportErrors;
 use serde_json;
+use signals::TrackRequests;
 use std::net::IpAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tls::{AcmeChallenges, ACME_CHALLENGE_PATH};
 use uuid::Uuid;
 
-header! { (XRealIP, "X-Real-IP") => [IpAddr] }
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NameAndToken {
     pub name: String,
     pub token: String,
+    // The secret used to sign the pings, when asked for with `signed=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+// Returned by /info, with the way the pings of the domain are authenticated
+// and what the server does with the record.
+#[derive(Debug, Serialize)]
+struct DomainInfo<'a> {
+    #[serde(flatten)]
+    record: &'a Domain,
+    auth_mode: &'static str,
+    // Whether the domain pinged recently enough to be served.
+    live: bool,
+    // When the domain is evicted unless it pings again, left out when
+    // eviction is turned off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+    // The public addresses served for the domain.
+    addresses: Vec<String>,
+}
+
+// A domain in the list of the domains of an email address. The token and the
+// other secrets of the domain are left out.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DomainSummary {
+    pub name: String,
+    pub description: String,
+    pub timestamp: i64,
+    pub created_at: i64,
+    pub verified: bool,
+    pub continent: String,
+    pub wildcard: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DomainList {
+    pub domains: Vec<DomainSummary>,
+}
+
+// Returned by /revoke, with the number of email accounts that were removed
+// along with the domain.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Revocation {
+    pub accounts: usize,
+}
+
+// Returned by /devicetoken, with the new device token of the domain.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeviceToken {
+    pub device_token: String,
+}
+
+// A discovery token of a domain. The token itself is only returned once, by
+// /adddiscovery, since the server only keeps its hash.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DiscoveryTokenInfo {
+    pub id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub label: String,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+}
+
+impl DiscoveryTokenInfo {
+    pub fn new(record: &DiscoveryToken) -> Self {
+        DiscoveryTokenInfo {
+            id: record.id,
+            token: None,
+            label: record.label.clone(),
+            created_at: record.created_at,
+            last_used_at: record.last_used_at,
+            expires_at: record.expires_at,
+        }
+    }
+}
+
+// Returned by /discoverytokens, oldest first.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DiscoveryTokenList {
+    pub tokens: Vec<DiscoveryTokenInfo>,
+}
+
+// Returned by /discovery: what an app needs to reach the gateway, on the local
+// network or through the tunnel.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DiscoveryRecord {
+    pub remote_name: String,
+    pub local_name: String,
+    pub local_ip: Vec<String>,
+}
+
+// Returned by /ping when asked for JSON, so that gateways can check what the
+// server stored and which address it sees them from. The token, the challenge
+// and the email address are left out.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct PingRecord {
+    pub remote_name: String,
+    pub local_name: String,
+    pub public_ip: String,
+    pub local_ip: Vec<String>,
+    pub timestamp: i64,
+}
+
+impl PingRecord {
+    pub fn new(record: &Domain, public_ip: IpAddr, options: &GeneralOptions) -> Self {
+        let remote_name = record.name.trim_right_matches('.').to_owned();
+        PingRecord {
+            local_name: format!("{}.{}", local_prefix(record, options), remote_name),
+            remote_name: remote_name,
+            public_ip: public_ip.to_string(),
+            local_ip: split_local_ips(&record.local_ips),
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+impl DiscoveryRecord {
+    pub fn new(record: &Domain, options: &GeneralOptions) -> Self {
+        let remote_name = record.name.trim_right_matches('.').to_owned();
+        DiscoveryRecord {
+            local_name: format!("{}.{}", local_prefix(record, options), remote_name),
+            remote_name: remote_name,
+            local_ip: split_local_ips(&record.local_ips),
+        }
+    }
 }
 
-fn domain_for_name(name: &str, config: &Config) -> String {
+// The local addresses of a domain are stored comma separated, empty if the
+// gateway never sent any.
+fn split_local_ips(local_ips: &str) -> Vec<String> {
+    local_ips
+        .split(',')
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| ip.to_owned())
+        .collect()
+}
+
+// Returned by /transfer, with the code to hand over to the new owner of the
+// domain and the time at which it expires.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TransferCode {
+    pub code: String,
+    pub expires_at: i64,
+}
+
+// Returned by /checkname. The reason is "invalid", "reserved" or "taken" when
+// the name is not available, and left out otherwise.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct NameAvailability {
+    pub available: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NameError {
+    Invalid,
+    Reserved,
+}
+
+pub fn domain_for_name(name: &str, config: &Config) -> String {
     format!("{}.{}.", name, config.options.general.domain).to_lowercase()
 }
 
+// Names that can never be subscribed, whatever the configuration: the api
+// subdomain is served by PowerDNS itself, and the others are commonly used for
+// the infrastructure of the domain.
+pub const BUILTIN_RESERVED_NAMES: [&str; 5] = ["api", "www", "mail", "ns1", "ns2"];
+
+// Checks that a name can be used as the leftmost label of a domain, and returns
+// its normalized form. A valid name:
+// - Is between 1 and 63 characters long, and short enough for the full domain
+//   name to fit in 253 characters.
+// - Contains only a-z, 0-9, and hyphens, but does not start or end with hyphen.
+// - Is not one of the built-in or configured reserved names.
+// See RFC 1035, section 2.3.1. Unicode names are converted to punycode first,
+// as described in RFC 5891, so "bücher" becomes "xn--bcher-kva".
+pub fn validate_name(
+    name: &str,
+    domain: &str,
+    reserved_names: &[String],
+) -> Result<String, NameError> {
+    let mut label = name.trim().to_lowercase();
+    if !label.is_ascii() {
+        label = match idna::domain_to_ascii(&label) {
+            Ok(ascii) => ascii,
+            Err(_) => return Err(NameError::Invalid),
+        };
+    }
+
+    // The full domain name is "<label>.<domain>."
+    let re = Regex::new(r"^([a-z0-9]|[a-z0-9][a-z0-9-]*[a-z0-9])$").unwrap();
+    if !re.is_match(&label) || label.len() > 63 || label.len() + domain.len() + 2 > 253 {
+        return Err(NameError::Invalid);
+    }
+
+    if BUILTIN_RESERVED_NAMES.contains(&label.as_str())
+        || reserved_names
+            .iter()
+            .any(|reserved| reserved.to_lowercase() == label)
+    {
+        return Err(NameError::Reserved);
+    }
+
+    Ok(label)
+}
+
+// Maximum number of DNS challenges served at the same time for a domain.
+const MAX_DNS_CHALLENGES: usize = 2;
+
+// Adds a challenge to the comma separated list of the current ones, dropping
+// the oldest ones beyond MAX_DNS_CHALLENGES.
+fn append_challenge(current: &str, challenge: &str) -> String {
+    let mut challenges: Vec<&str> = current
+        .split(',')
+        .filter(|value| !value.is_empty() && *value != challenge)
+        .collect();
+    challenges.push(challenge);
+    let first = challenges.len().saturating_sub(MAX_DNS_CHALLENGES);
+    challenges[first..].join(",")
+}
+
+// Returns the label of the local name of a domain, eg. `local` for
+// `local.<name>.<domain>`.
+pub fn local_prefix<'a>(record: &'a Domain, options: &'a GeneralOptions) -> &'a str {
+    if record.local_prefix.is_empty() {
+        &options.local_prefix
+    } else {
+        &record.local_prefix
+    }
+}
+
+// Checks the label chosen by a domain for its local name, and returns its
+// normalized form. Like a name, it must be a valid DNS label, but Unicode
+// labels are not converted. An empty label stands for the one of the
+// configuration.
+pub fn validate_local_prefix(prefix: &str) -> Option<String> {
+    let prefix = prefix.trim().to_lowercase();
+    let re = Regex::new(r"^([a-z0-9]|[a-z0-9][a-z0-9-]*[a-z0-9])$").unwrap();
+    if prefix.is_empty() || (re.is_match(&prefix) && prefix.len() <= 63) {
+        Some(prefix)
+    } else {
+        None
+    }
+}
+
+// Maximum number of local addresses stored for a domain.
+const MAX_LOCAL_IPS: usize = 8;
+
+// Parses the local addresses sent by a gateway, each value being a single
+// address or a comma separated list. Returns their canonical form as a comma
+// separated list, or None if an address is invalid.
+pub fn parse_local_ips(values: &[String]) -> Option<String> {
+    let mut addresses: Vec<String> = Vec::new();
+    for item in values.iter().flat_map(|value| value.split(',')) {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+        let address = match item.parse::<IpAddr>() {
+            Ok(ip) => normalize_ip(ip).to_string(),
+            Err(_) => return None,
+        };
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    if addresses.len() > MAX_LOCAL_IPS {
+        return None;
+    }
+
+    Some(addresses.join(","))
+}
+
+// Maximum number of custom TXT records of a domain.
+const MAX_TXT_RECORDS: usize = 10;
+
+// Returns whether a value can be published as a custom TXT record: a single
+// string of printable ASCII characters, without the quotes and backslashes that
+// would need escaping in the zone.
+pub fn is_valid_txt_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 255
+        && value
+            .chars()
+            .all(|c| c >= ' ' && c <= '~' && c != '"' && c != '\\')
+}
+
+// Maximum number of discovery tokens of a domain.
+const MAX_DISCOVERY_TOKENS: usize = 10;
+
+// Maximum lifetime of an expiring discovery token, in seconds: a year.
+const MAX_DISCOVERY_TTL: u64 = 365 * 24 * 3600;
+
+// Returns whether a label can name a discovery token: up to 63 characters, not
+// only blanks, without control characters.
+pub fn is_valid_discovery_label(label: &str) -> bool {
+    !label.trim().is_empty()
+        && label.chars().count() <= 63
+        && !label.chars().any(|c| c.is_control())
+}
+
 fn ping(req: &mut Request, config: &Config) -> IronResult<Response> {
     let conn = config.db.get_connection();
     if conn.is_err() {
@@ -41,34 +360,130 @@ fn ping(req: &mut Request, config: &Config) -> IronResult<Response> {
             "ping(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
     // Extract the token parameter.
-    let map = req.get_ref::<Params>().unwrap();
-    let token = map.find(&["token"]);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /ping {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let format = try_param!(params.get_optional_string("format"));
+    let nonce = try_param!(params.get_optional_string("nonce"));
+    let mac = try_param!(params.get_optional_string("mac"));
 
-    info!("GET /ping {:?}", map);
+    // Gateways can send the addresses they use on their local networks. These
+    // are kept as is when the parameter is absent.
+    let local_ips = match try_param!(params.get_optional_string_list("local_ip")) {
+        Some(values) => match parse_local_ips(&values) {
+            Some(local_ips) => Some(local_ips),
+            None => {
+                error!("ping(): Invalid local addresses: {:?}", values);
+                return EndpointError::with(ApiError::InvalidParameter);
+            }
+        },
+        None => None,
+    };
+
+    // Domains that are waiting to be evicted can't be brought back. They are
+    // answered like the unknown tokens, after the same single query. The
+    // domain is then updated through its token, also when the ping carries
+    // the device token.
+    let (token, redundant) = match get_domain_for_device(&conn, &token) {
+        Ok(ref record) if is_evicted(record, config) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Ok(record) => {
+            // A signed request already proves that it comes from the gateway.
+            if !req.extensions.contains::<SignedRequest>() {
+                try_param!(check_ping(
+                    &record,
+                    &nonce,
+                    &mac,
+                    &config.options.general,
+                    &config.nonces
+                ));
+            }
+            let redundant = is_redundant_ping(&record, &local_ips, &config.options.general, now());
+            (record.token, redundant)
+        }
+        Err(diesel::result::Error::NotFound) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Err(err) => {
+            error!("ping(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
 
-    if token.is_none() {
-        error!("ping(): Token not provided");
-        return EndpointError::with(status::BadRequest, 400);
+    if redundant {
+        debug!("ping(): Not saving a ping sent too soon");
+    } else {
+        // Save this ping in the database if we know about this token.
+        match conn.update_domain_timestamp(&token) {
+            Ok(count) if count > 0 => (),
+            Ok(_) => return EndpointError::with(ApiError::UnknownToken),
+            Err(err) => {
+                error!("ping(): Failed to update domain: {:?}", err);
+                return EndpointError::with(ApiError::DatabaseFailure);
+            }
+        }
+
+        if let Some(local_ips) = local_ips {
+            if let Err(err) = conn.update_domain_local_ips(&token, &local_ips) {
+                error!("ping(): Failed to update local addresses: {:?}", err);
+                return EndpointError::with(ApiError::DatabaseFailure);
+            }
+        }
+        // The domain is no longer stale, and may have new local addresses.
+        config.dns_cache.forget_token(&token);
     }
 
-    let token = String::from_value(token.unwrap()).unwrap();
+    // Older clients expect an empty response.
+    if format != Some("json".to_owned()) {
+        return ok_response!();
+    }
 
-    // Save this ping in the database if we know about this token.
-    match conn.update_domain_timestamp(&token) {
-        Ok(count) if count > 0 => ok_response!(),
-        Ok(_) => EndpointError::with(status::NotFound, 404),
+    match conn.get_domain_by_token(&token) {
+        Ok(record) => json_response!(&PingRecord::new(
+            &record,
+            client_ip(req, config),
+            &config.options.general
+        )),
         Err(err) => {
-            error!("ping(): Failed to update domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            error!("ping(): Failed to get domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
 
+// Looks up a domain by its token, or by its device token, for the routes that
+// the gateway calls itself. The other routes only accept the token.
+pub fn get_domain_for_device(conn: &Database, token: &str) -> diesel::QueryResult<Domain> {
+    match conn.get_domain_by_token(token) {
+        Err(diesel::result::Error::NotFound) => conn.get_domain_by_device_token(token),
+        result => result,
+    }
+}
+
+// Returns whether a ping can be coalesced with the previous one of the domain:
+// it comes less than `min_ping_interval` seconds after it, and doesn't change
+// the local addresses. Skipping it saves a database write, and the domain
+// stays far from its eviction.
+fn is_redundant_ping(
+    record: &Domain,
+    local_ips: &Option<String>,
+    options: &GeneralOptions,
+    now: i64,
+) -> bool {
+    let unchanged = match *local_ips {
+        Some(ref local_ips) => *local_ips == record.local_ips,
+        None => true,
+    };
+    unchanged && now - record.timestamp < options.min_ping_interval as i64
+}
+
 fn info(req: &mut Request, config: &Config) -> IronResult<Response> {
     let conn = config.db.get_connection();
     if conn.is_err() {
@@ -76,29 +491,100 @@ fn info(req: &mut Request, config: &Config) -> IronResult<Response> {
             "info(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let token = map.find(&["token"]);
-
-    info!("GET /info {:?}", map);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /info {:?}", req.method, params);
 
-    if token.is_none() {
-        error!("info(): Token not provided");
-        return EndpointError::with(status::BadRequest, 400);
-    }
-    let token = String::from_value(token.unwrap()).unwrap();
+    let token = try_param!(params.get_string("token"));
 
     match conn.get_domain_by_token(&token) {
-        Ok(record) => json_response!(&record),
-        Err(diesel::result::Error::NotFound) => EndpointError::with(status::NotFound, 404),
+        Ok(record) => json_response!(&DomainInfo {
+            auth_mode: auth_mode(&record),
+            live: !is_stale(&record, config),
+            expires_at: eviction_deadline(&record, &config.options.general),
+            addresses: domain_addresses(&record, config),
+            record: &record,
+        }),
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::UnknownToken),
         Err(err) => {
             error!("info(): Failed to get domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Lists the domains associated with an email address. Only the admin, and the
+// owners of these domains once the address is verified, can see them.
+fn list_domains(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "list_domains(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /domains {:?}", req.method, params);
+
+    let email = try_param!(params.get_string("email"));
+    let token = try_param!(params.get_optional_string("token"));
+    if !is_valid_email(&email) {
+        error!("list_domains(): Invalid email address: {}", email);
+        return EndpointError::with(ApiError::InvalidEmail);
+    }
+
+    let records = match conn.get_account_by_email(&email) {
+        Ok(account) => match conn.get_domains_by_account_id(account.id) {
+            Ok(records) => records,
+            Err(err) => {
+                error!("list_domains(): Failed to get domains: {:?}", err);
+                return EndpointError::with(ApiError::DatabaseFailure);
+            }
+        },
+        Err(diesel::result::Error::NotFound) => vec![],
+        Err(err) => {
+            error!("list_domains(): Failed to get account: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
         }
+    };
+
+    // All the tokens are compared, in constant time, so that the latency
+    // doesn't tell which domain a token matched, or how much of it.
+    let owner = match token {
+        Some(ref token) => records.iter().fold(false, |owner, record| {
+            let matches = fixed_time_eq(record.token.as_bytes(), token.as_bytes());
+            owner | (record.verified & matches)
+        }),
+        None => false,
+    };
+    // is_admin() takes its own connection, which an in-memory database only
+    // has once this one is released.
+    drop(conn);
+    if !owner && !is_admin(req, config) {
+        error!("list_domains(): Missing or invalid token");
+        return EndpointError::with(ApiError::Unauthorized);
     }
+
+    json_response!(&DomainList {
+        domains: records
+            .into_iter()
+            .map(|record| DomainSummary {
+                name: record.name,
+                description: record.description,
+                timestamp: record.timestamp,
+                created_at: record.created_at,
+                verified: record.verified,
+                continent: record.continent,
+                wildcard: record.wildcard,
+            })
+            .collect(),
+    })
 }
 
 fn unsubscribe(req: &mut Request, config: &Config) -> IronResult<Response> {
@@ -108,289 +594,729 @@ fn unsubscribe(req: &mut Request, config: &Config) -> IronResult<Response> {
             "unsubscribe(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let token = map.find(&["token"]);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /unsubscribe {:?}", req.method, params);
 
-    info!("GET /unsubscribe {:?}", map);
-
-    if token.is_none() {
-        let reclamation_token = map.find(&["reclamationToken"]);
-        match reclamation_token {
-            Some(&Value::String(ref reclamation_token)) => {
-                return match conn.delete_domain_by_reclamation_token(reclamation_token) {
-                    Ok(0) => {
-                        // No record found for this token.
-                        EndpointError::with(status::NotFound, 404)
+    // A domain unsubscribed with its reclamation token is then removed through
+    // its token, so that it can be undeleted too.
+    let (token, reclamation_hash) = match try_param!(params.get_optional_string("token")) {
+        Some(token) => (token, None),
+        None => match try_param!(params.get_optional_string("reclamationToken")) {
+            // Domains that were never reclaimed have an empty reclamation
+            // token, which must not match.
+            Some(ref reclamation_token) if reclamation_token.is_empty() => {
+                return EndpointError::with(ApiError::UnknownToken);
+            }
+            Some(reclamation_token) => {
+                let reclamation_hash = auth_key(&reclamation_token);
+                match conn.get_domain_by_reclamation_token(&reclamation_hash) {
+                    Ok(record) => (record.token, Some(reclamation_hash)),
+                    Err(diesel::result::Error::NotFound) => {
+                        return EndpointError::with(ApiError::UnknownToken);
                     }
-                    Ok(_) => ok_response!(),
                     Err(err) => {
-                        error!("unsubscribe(): Failed to delete domain: {:?}", err);
-                        EndpointError::with(status::InternalServerError, 500)
+                        error!("unsubscribe(): Failed to get domain: {:?}", err);
+                        return EndpointError::with(ApiError::DatabaseFailure);
                     }
-                };
+                }
             }
-            _ => {
-                // No token or reclamation token provided.
-                return EndpointError::with(status::BadRequest, 400);
+            // No token or reclamation token provided.
+            None => return EndpointError::with(ApiError::MissingParameter),
+        },
+    };
+
+    // During the grace period, the domain can still be restored with
+    // /undelete.
+    let result = if config.options.general.undelete_grace > 0 {
+        delete_domain_softly(&conn, &token)
+    } else {
+        conn.delete_domain_by_token(&token)
+    };
+    match result {
+        Ok(0) => EndpointError::with(ApiError::UnknownToken), // No record found for this token.
+        Ok(_) => {
+            config.dns_cache.forget_token(&token);
+            if let Some(ref reclamation_hash) = reclamation_hash {
+                config.dns_cache.forget_reclamation_token(reclamation_hash);
             }
+            ok_response!()
         }
-    }
-    let token = String::from_value(token.unwrap()).unwrap();
-
-    match conn.delete_domain_by_token(&token) {
-        Ok(0) => EndpointError::with(status::BadRequest, 400), // No record found for this token.
-        Ok(_) => ok_response!(),
         Err(err) => {
             error!("unsubscribe(): Failed to delete domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
 
-fn reclaim(req: &mut Request, config: &Config) -> IronResult<Response> {
+// Moves a domain to the deleted domains, with what /undelete needs to restore
+// it. Returns the number of domains removed.
+fn delete_domain_softly(conn: &Database, token: &str) -> diesel::QueryResult<usize> {
+    let record = match conn.get_domain_by_token(token) {
+        Ok(record) => record,
+        Err(diesel::result::Error::NotFound) => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let deleted = DeletedRecord {
+        auth_key: record.auth_key.clone(),
+        email: conn.get_account_by_id(record.account_id)?.email,
+        txt_records: conn.get_txt_records(record.id)?,
+        srv_records: conn.get_srv_records(record.id)?,
+        discovery_tokens: conn.get_discovery_tokens(record.id, now())?,
+        domain: record,
+    };
+    let json = serde_json::to_string(&deleted).unwrap();
+    conn.move_domain_to_deleted(
+        token,
+        &NewDeletedDomain {
+            name: &deleted.domain.name,
+            token: token,
+            record: &json,
+            deleted_at: now(),
+        },
+    )
+}
+
+// Whether a name is still held by an unsubscribed domain that can be
+// undeleted.
+fn is_name_deleted(conn: &Database, full_name: &str, config: &Config) -> diesel::QueryResult<bool> {
+    let threshold = undelete_threshold(&config.options.general, now());
+    match conn.get_deleted_domain_by_name(full_name, threshold) {
+        Ok(_) => Ok(true),
+        Err(diesel::result::Error::NotFound) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Restores a domain removed by /unsubscribe, with its token and its records,
+// until the grace period ends.
+fn undelete(req: &mut Request, config: &Config) -> IronResult<Response> {
     let conn = config.db.get_connection();
     if conn.is_err() {
         error!(
-            "reclaim(): Failed to get database connection: {:?}",
+            "undelete(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let map = req.get_ref::<Params>().unwrap();
-    let name = map.find(&["name"]);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /undelete {:?}", req.method, params);
 
-    info!("GET /reclaim {:?}", map);
+    let token = try_param!(params.get_string("token"));
+    let threshold = undelete_threshold(&config.options.general, now());
+    let deleted = match conn.get_deleted_domain_by_token(&token, threshold) {
+        Ok(deleted) => deleted,
+        Err(diesel::result::Error::NotFound) => return EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("undelete(): Failed to look up deleted domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+    let kept: DeletedRecord = match serde_json::from_str(&deleted.record) {
+        Ok(kept) => kept,
+        Err(err) => {
+            error!("undelete(): Failed to read deleted domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
 
-    if name.is_none() {
-        error!("reclaim(): Name not provided");
-        return EndpointError::with(status::BadRequest, 400);
+    let mut record = kept.domain;
+    record.auth_key = kept.auth_key;
+    match conn.restore_deleted_domain(
+        &deleted,
+        &kept.email,
+        &record,
+        &kept.txt_records,
+        &kept.srv_records,
+        &kept.discovery_tokens,
+    ) {
+        Ok(record) => {
+            config.dns_cache.forget_name(&record.name);
+            json_response!(&NameAndToken {
+                name: record.name.split('.').next().unwrap_or("").to_owned(),
+                token: token,
+                secret: None,
+            })
+        }
+        // Restored or purged meanwhile.
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("undelete(): Failed to restore domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
     }
-    let name = String::from_value(name.unwrap()).unwrap();
-    let subdomain = name.trim().to_lowercase();
-    let full_name = domain_for_name(&subdomain, config);
+}
 
-    match conn.get_domain_by_name(&full_name) {
-        Ok(record) => {
-            match conn.get_account_by_id(record.account_id) {
-                Ok(account) => {
-                    if account.email == "" {
-                        let mut response = Response::with(r#"{"error": "NoEmail"}"#);
-                        response.status = Some(status::BadRequest);
-                        response.headers.set(ContentType::json());
-                        return Ok(response);
-                    }
+// Unlike /unsubscribe, this also removes the email account of the domain when
+// no other domain uses it.
+fn revoke(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "revoke(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
 
-                    let token = format!("{}", Uuid::new_v4());
-                    let result = conn.update_domain_reclamation_token(&record.token, &token);
-                    if result.is_err() {
-                        error!("reclaim(): Failed to update domain: {:?}", result.err());
-                        return EndpointError::with(status::InternalServerError, 500);
-                    }
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /revoke {:?}", req.method, params);
 
-                    if result.unwrap() == 0 {
-                        return EndpointError::with(status::NotFound, 404);
-                    }
+    let token = try_param!(params.get_string("token"));
 
-                    // Send the reclamation token to the user via email.
-                    match EmailSender::new(config) {
-                        Ok(mut sender) => {
-                            let body = config
-                                .options
-                                .email
-                                .clone()
-                                .reclamation_body
-                                .unwrap()
-                                .replace("{token}", &token);
-                            match sender.send(
-                                &account.email,
-                                &body,
-                                &config.options.email.clone().reclamation_title.unwrap(),
-                            ) {
-                                Ok(_) => ok_response!(),
-                                Err(err) => {
-                                    error!("reclaim(): Failed to send email: {:?}", err);
-                                    EndpointError::with(status::InternalServerError, 500)
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            error!("reclaim(): Failed to create email sender: {:?}", err);
-                            EndpointError::with(status::InternalServerError, 500)
-                        }
-                    }
-                }
-                Err(_) => {
-                    // This name doesn't have an associated email address.
-                    let mut response = Response::with(r#"{"error": "NoEmail"}"#);
-                    response.status = Some(status::BadRequest);
-                    response.headers.set(ContentType::json());
-                    Ok(response)
-                }
-            }
+    match conn.revoke_domain_by_token(&token) {
+        Ok(count) => {
+            config.dns_cache.forget_token(&token);
+            json_response!(&Revocation { accounts: count })
         }
-        Err(diesel::result::Error::NotFound) => {
-            // This name doesn't exist, no need to reclaim it.
-            let mut response = Response::with(r#"{"error": "NoSuchName"}"#);
-            response.status = Some(status::BadRequest);
-            response.headers.set(ContentType::json());
-            Ok(response)
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("revoke(): Failed to revoke domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
         }
-        // Other error, like a db issue.
+    }
+}
+
+// Gives a domain a new device token, to be stored on the gateway instead of
+// the token. It can only ping, set the DNS challenges and open the tunnel, so
+// a stolen gateway can't unsubscribe or transfer the domain. The previous
+// device token stops working.
+fn devicetoken(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "devicetoken(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /devicetoken {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let device_token = format!("{}", Uuid::new_v4());
+
+    match conn.update_domain_device_token(&token, &device_token) {
+        Ok(count) if count > 0 => {
+            config.dns_cache.forget_token(&token);
+            json_response!(&DeviceToken {
+                device_token: device_token,
+            })
+        }
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
         Err(err) => {
-            error!("reclaim(): Failed to look up domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            error!("devicetoken(): Failed to update domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
 
-fn subscribe(req: &mut Request, config: &Config) -> IronResult<Response> {
+// Gives a domain a new discovery token, for an app to find the gateway with
+// /discovery. The label tells the tokens apart in /discoverytokens, and the
+// optional ttl makes the token expire after this many seconds.
+fn adddiscovery(req: &mut Request, config: &Config) -> IronResult<Response> {
     let conn = config.db.get_connection();
     if conn.is_err() {
         error!(
-            "subscribe(): Failed to get database connection: {:?}",
+            "adddiscovery(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
-    let real_ip = match req.headers.get::<XRealIP>() {
-        Some(x) => x.0.clone(),
-        None => req.remote_addr.ip(),
-    };
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /adddiscovery {:?}", req.method, params);
 
-    let continent = match lookup_continent(real_ip, &config) {
-        Some(val) => val,
-        None => "".to_owned(),
+    // The token and the label are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let label = try_param!(params.get_string("label"));
+    let ttl = try_param!(params.get_optional_u64("ttl"));
+
+    if !is_valid_discovery_label(&label) {
+        error!("adddiscovery(): Invalid label: {}", label);
+        return EndpointError::with(ApiError::InvalidParameter);
+    }
+    let created_at = now();
+    let expires_at = match ttl {
+        None => 0,
+        Some(ttl) if ttl > 0 && ttl <= MAX_DISCOVERY_TTL => created_at + ttl as i64,
+        Some(ttl) => {
+            error!("adddiscovery(): Invalid ttl: {}", ttl);
+            return EndpointError::with(ApiError::InvalidParameter);
+        }
+    };
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
     };
 
-    // Extract the name parameter.
-    let map = req.get_ref::<Params>().unwrap();
-    let name = map.find(&["name"]);
+    let discovery_token = format!("{}", Uuid::new_v4());
+    let hash = auth_key(&discovery_token);
+    let result = conn
+        .get_discovery_tokens(record.id, created_at)
+        .and_then(|tokens| {
+            if tokens.len() >= MAX_DISCOVERY_TOKENS {
+                return Ok(None);
+            }
+            conn.add_discovery_token(&NewDiscoveryToken {
+                domain_id: record.id,
+                token: &hash,
+                label: &label,
+                created_at: created_at,
+                last_used_at: 0,
+                expires_at: expires_at,
+            })
+            .map(Some)
+        });
+    match result {
+        Ok(Some(added)) => {
+            let mut info = DiscoveryTokenInfo::new(&added);
+            info.token = Some(discovery_token);
+            json_response!(&info)
+        }
+        Ok(None) => EndpointError::with(ApiError::TooManyRecords),
+        Err(err) => {
+            error!("adddiscovery(): Failed to add discovery token: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Lists the discovery tokens of a domain that didn't expire, without the tokens
+// themselves.
+fn discoverytokens(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "discoverytokens(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /discoverytokens {:?}", req.method, params);
 
-    info!("GET /subscribe {:?}", map);
+    let token = try_param!(params.get_string("token"));
 
-    if name.is_none() {
-        error!("subscribe(): Name not provided");
-        return EndpointError::with(status::BadRequest, 400);
+    let record = match conn.get_domain_by_token(&token) {
+        Ok(record) => record,
+        Err(diesel::result::Error::NotFound) => return EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("discoverytokens(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+    match conn.get_discovery_tokens(record.id, now()) {
+        Ok(tokens) => json_response!(&DiscoveryTokenList {
+            tokens: tokens.iter().map(DiscoveryTokenInfo::new).collect(),
+        }),
+        Err(err) => {
+            error!(
+                "discoverytokens(): Failed to get discovery tokens: {:?}",
+                err
+            );
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
     }
-    let name = String::from_value(name.unwrap()).unwrap();
-    let subdomain = name.trim().to_lowercase();
-    let full_name = domain_for_name(&subdomain, config);
+}
 
-    // Ensure that subdomain is valid:
-    // - Contains only a-z, 0-9, and hyphens, but does not start or end
-    //   with hyphen.
-    // - Is not equal to "api", "www", or "_psl" as those are reserved.
-    let re = Regex::new(r"^([a-z0-9]|[a-z0-9][a-z0-9-]*[a-z0-9])$").unwrap();
-    if !re.is_match(&subdomain) || subdomain == "api" || subdomain == "www" || subdomain == "_psl"
-        || subdomain.len() > 63 || full_name.len() > 253
-    {
-        let mut response = Response::with(r#"{"error": "UnavailableName"}"#);
-        response.status = Some(status::BadRequest);
-        response.headers.set(ContentType::json());
-        return Ok(response);
+// Removes a discovery token of a domain, by the id that /discoverytokens
+// returns. Unknown ids are ignored.
+fn revokediscovery(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "revokediscovery(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
+    let conn = conn.unwrap();
 
-    info!("subscribe(): Trying to subscribe: {}", full_name);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /revokediscovery {:?}", req.method, params);
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
+    // Both parameters are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let id = match try_param!(params.get_string("id")).parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => return EndpointError::with(ApiError::InvalidParameter),
+    };
+
+    let record = match conn.get_domain_by_token(&token) {
+        Ok(record) => record,
+        Err(diesel::result::Error::NotFound) => return EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("revokediscovery(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+    match conn.delete_discovery_token(record.id, id) {
+        Ok(_) => ok_response!(),
+        Err(err) => {
+            error!(
+                "revokediscovery(): Failed to delete discovery token: {:?}",
+                err
+            );
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Returns the names and the local addresses of the gateway to an app holding
+// one of its discovery tokens, and records when the token was last used.
+fn discovery(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "discovery(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /discovery {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+
+    match conn.use_discovery_token(&auth_key(&token), now()) {
+        Ok(ref record) if is_evicted(record, config) => EndpointError::with(ApiError::UnknownToken),
+        Ok(record) => json_response!(&DiscoveryRecord::new(&record, &config.options.general)),
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("discovery(): Failed to get domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+fn reclaim(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let real_ip = client_ip(req, config);
+    if let Err(retry_after) = config.rate_limits.email.check(real_ip) {
+        info!("reclaim(): Too many requests from {}", real_ip);
+        return EndpointError::rate_limited(retry_after);
+    }
+
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "reclaim(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /reclaim {:?}", req.method, params);
+
+    let name = try_param!(params.get_string("name"));
+    let subdomain = name.trim().to_lowercase();
+    let full_name = domain_for_name(&subdomain, config);
+
+    match conn.get_domain_by_name(&full_name) {
+        Ok(record) => {
+            match conn.get_account_by_id(record.account_id) {
+                Ok(account) => {
+                    // Only a verified address can receive reclamation tokens.
+                    if account.email == "" || !record.verified {
+                        return EndpointError::with(ApiError::NoEmail);
+                    }
+
+                    // Only the hash of the token is stored, as it is enough
+                    // to take the domain over.
+                    let token = format!("{}", Uuid::new_v4());
+                    let result =
+                        conn.update_domain_reclamation_token(&record.token, &auth_key(&token));
+                    if result.is_err() {
+                        error!("reclaim(): Failed to update domain: {:?}", result.err());
+                        return EndpointError::with(ApiError::DatabaseFailure);
+                    }
+
+                    if result.unwrap() == 0 {
+                        return EndpointError::with(ApiError::NoSuchName);
+                    }
+                    // Lets /unsubscribe drop the cached domain with the new
+                    // reclamation token.
+                    config.dns_cache.forget_token(&record.token);
+
+                    let email = &config.options.email;
+                    let (title, body) = match (&email.reclamation_title, &email.reclamation_body) {
+                        (&Some(ref title), &Some(ref body)) => {
+                            (title, body.replace("{token}", &token))
+                        }
+                        _ => {
+                            error!("reclaim(): The reclamation email is not configured");
+                            return EndpointError::with(ApiError::EmailFailure);
+                        }
+                    };
+
+                    // Send the reclamation token to the user via email.
+                    match EmailSender::new(config) {
+                        Ok(mut sender) => match sender.send(&account.email, &body, title) {
+                            Ok(_) => ok_response!(),
+                            Err(err) => {
+                                error!("reclaim(): Failed to send email: {:?}", err);
+                                EndpointError::with(ApiError::EmailFailure)
+                            }
+                        }
+                        Err(err) => {
+                            error!("reclaim(): Failed to create email sender: {:?}", err);
+                            EndpointError::with(ApiError::EmailFailure)
+                        }
+                    }
+                }
+                Err(_) => {
+                    // This name doesn't have an associated email address.
+                    EndpointError::with(ApiError::NoEmail)
+                }
+            }
+        }
+        Err(diesel::result::Error::NotFound) => {
+            // This name doesn't exist, no need to reclaim it.
+            EndpointError::with(ApiError::NoSuchName)
+        }
+        // Other error, like a db issue.
+        Err(err) => {
+            error!("reclaim(): Failed to look up domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Gateways that sign their pings get a secret when asked for with
+// `signed=true`, of which only the hash is stored.
+fn signing_secret(params: &Parameters) -> Result<Option<String>, ApiError> {
+    match params.get_bool("signed") {
+        Ok(true) => Ok(Some(format!("{}", Uuid::new_v4()))),
+        Ok(false) | Err(ApiError::MissingParameter) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn subscribe(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let real_ip = client_ip(req, config);
+    if let Err(retry_after) = config.rate_limits.subscribe.check(real_ip) {
+        info!("subscribe(): Too many requests from {}", real_ip);
+        return EndpointError::rate_limited(retry_after);
+    }
+
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "subscribe(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let continent = match lookup_continent(real_ip, &config) {
+        Some(val) => val,
+        None => "".to_owned(),
+    };
+
+    // Extract the name parameter.
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /subscribe {:?}", req.method, params);
+
+    let name = try_param!(params.get_string("name"));
+    let subdomain = match validate_name(
+        &name,
+        &config.options.general.domain,
+        &config.options.general.reserved_names,
+    ) {
+        Ok(subdomain) => subdomain,
+        Err(NameError::Invalid) => return EndpointError::with(ApiError::InvalidName),
+        Err(NameError::Reserved) => return EndpointError::with(ApiError::ReservedName),
+    };
+    let full_name = domain_for_name(&subdomain, config);
+
+    // The names banned by an admin are refused like the reserved ones.
+    match conn.is_name_banned(&full_name) {
+        Ok(false) => (),
+        Ok(true) => return EndpointError::with(ApiError::ReservedName),
+        Err(err) => {
+            error!("subscribe(): Failed to look up banned names: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    }
+
+    // The names of the unsubscribed domains stay taken while their owners can
+    // undelete them.
+    match is_name_deleted(&conn, &full_name, config) {
+        Ok(false) => (),
+        Ok(true) => return EndpointError::with(ApiError::UnavailableName),
+        Err(err) => {
+            error!("subscribe(): Failed to look up deleted domains: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    }
+
+    let secret = try_param!(signing_secret(&params));
+    let key = match secret {
+        Some(ref secret) => auth_key(secret),
+        None => "".to_owned(),
+    };
+    let prefix = match try_param!(params.get_optional_string("local_prefix")) {
+        Some(prefix) => match validate_local_prefix(&prefix) {
+            Some(prefix) => prefix,
+            None => {
+                error!("subscribe(): Invalid local prefix: {}", prefix);
+                return EndpointError::with(ApiError::InvalidParameter);
+            }
+        },
+        None => "".to_owned(),
+    };
+
+    info!("subscribe(): Trying to subscribe: {}", full_name);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
         .as_secs() as i64;
 
     match conn.get_domain_by_name(&full_name) {
         Ok(record) => {
-            let reclamation_token = map.find(&["reclamationToken"]);
+            let reclamation_token = try_param!(params.get_optional_string("reclamationToken"));
             if !reclamation_token.is_none() {
-                let reclamation_token = String::from_value(reclamation_token.unwrap()).unwrap();
-                if reclamation_token == record.reclamation_token {
+                let reclamation_token = reclamation_token.unwrap();
+                let matches = fixed_time_eq(
+                    auth_key(&reclamation_token).as_bytes(),
+                    record.reclamation_token.as_bytes(),
+                );
+                // An empty token would match the domains that were never
+                // reclaimed.
+                if !reclamation_token.is_empty() && matches {
                     // Create a new token and update the existing record.
                     let token = format!("{}", Uuid::new_v4());
                     match conn.update_domain_token(&record.name, &token, &continent) {
                         Ok(count) if count > 0 => {
+                            config.dns_cache.forget_name(&record.name);
+                            // The reclamation token can only be used once,
+                            // and the previous owner may have signed its
+                            // pings, chosen another local name or handed a
+                            // device token out.
+                            let result = conn
+                                .update_domain_reclamation_token(&token, "")
+                                .and_then(|_| conn.update_domain_auth_key(&token, &key))
+                                .and_then(|_| conn.update_domain_local_prefix(&token, &prefix))
+                                .and_then(|_| conn.update_domain_device_token(&token, ""));
+                            if let Err(err) = result {
+                                error!("subscribe(): Failed to update domain: {:?}", err);
+                                return EndpointError::with(ApiError::DatabaseFailure);
+                            }
                             // We don't want the full domain name or the DNS
                             // challenge in the response, so we create a local
                             // struct.
                             let n_and_t = NameAndToken {
                                 name: subdomain.to_owned(),
                                 token: token,
+                                secret: secret,
                             };
                             json_response!(&n_and_t)
                         }
-                        Ok(_) => EndpointError::with(status::NotFound, 404),
+                        Ok(_) => EndpointError::with(ApiError::NoSuchName),
                         Err(err) => {
                             error!("subscribe(): Failed to update domain: {:?}", err);
-                            EndpointError::with(status::InternalServerError, 500)
+                            EndpointError::with(ApiError::DatabaseFailure)
                         }
                     }
                 } else {
-                    let mut response = Response::with(r#"{"error": "ReclamationTokenMismatch"}"#);
-                    response.status = Some(status::BadRequest);
-                    response.headers.set(ContentType::json());
-                    Ok(response)
+                    EndpointError::with(ApiError::ReclamationTokenMismatch)
                 }
             } else {
                 // We already have a record for this name, return an error.
-                let email = map.find(&["email"]);
+                let email = try_param!(params.get_optional_string("email"));
                 if !email.is_none() {
-                    let email = String::from_value(email.unwrap()).unwrap();
+                    let email = email.unwrap();
                     if !email.is_empty() {
                         match conn.get_account_by_id(record.account_id) {
                             Ok(account) => {
-                                if email == account.email {
-                                    let mut response = Response::with(
-                                        "{\"error\": \
-                                         \"UnavailableNameReclamationPossible\"}",
+                                if record.verified && email == account.email {
+                                    return EndpointError::with(
+                                        ApiError::UnavailableNameReclamationPossible,
                                     );
-                                    response.status = Some(status::BadRequest);
-                                    response.headers.set(ContentType::json());
-                                    return Ok(response);
                                 }
                             }
                             Err(_) => {
-                                let mut response =
-                                    Response::with(r#"{"error": "UnavailableName"}"#);
-                                response.status = Some(status::BadRequest);
-                                response.headers.set(ContentType::json());
-                                return Ok(response);
+                                return EndpointError::with(ApiError::UnavailableName);
                             }
                         }
                     }
                 }
 
-                let mut response = Response::with(r#"{"error": "UnavailableName"}"#);
-                response.status = Some(status::BadRequest);
-                response.headers.set(ContentType::json());
-                Ok(response)
+                EndpointError::with(ApiError::UnavailableName)
             }
         }
         Err(diesel::result::Error::NotFound) => {
+            // New names need a CAPTCHA solution when it's turned on, to slow
+            // down automated squatting.
+            if config.options.general.captcha_secret.is_some() {
+                let solution = try_param!(params.get_string("captcha"));
+                match captcha::verify(&solution, real_ip, &config.options.general) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        info!("subscribe(): Invalid CAPTCHA solution for {}", full_name);
+                        return EndpointError::with(ApiError::InvalidCaptcha);
+                    }
+                    Err(err) => {
+                        error!("subscribe(): Failed to verify the CAPTCHA: {}", err);
+                        return EndpointError::with(ApiError::CaptchaFailure);
+                    }
+                }
+            }
+
             // Create a token, create and store a record, and finally,
             // return the token.
             let token = format!("{}", Uuid::new_v4());
 
-            let description = match map.find(&["desc"]) {
-                Some(&Value::String(ref desc)) => desc.to_owned(),
-                _ => format!("{}'s server", name),
+            let description = match try_param!(params.get_optional_string("desc")) {
+                Some(desc) => desc,
+                _ => default_description(&name),
+            };
+
+            // An email address can be given right away. Like with /setemail,
+            // it is only trusted once verified through the emailed link.
+            let email = match try_param!(params.get_optional_string("email")) {
+                Some(ref email) if email.is_empty() => None,
+                Some(email) => {
+                    if !is_valid_email(&email) {
+                        error!("subscribe(): Invalid email address: {}", email);
+                        return EndpointError::with(ApiError::InvalidEmail);
+                    }
+                    Some(email)
+                }
+                None => None,
             };
 
-            let result = conn.get_unknown_account();
+            let result = match email {
+                Some(ref email) => conn.get_or_add_account(email),
+                None => conn.get_unknown_account(),
+            };
             if result.is_err() {
-                error!(
-                    "subscribe(): Failed to get the unknown account: {:?}",
-                    result.err()
-                );
-                return EndpointError::with(status::InternalServerError, 500);
+                error!("subscribe(): Failed to get the account: {:?}", result.err());
+                return EndpointError::with(ApiError::DatabaseFailure);
             }
 
             let account = result.unwrap();
+            let verification_token = match email {
+                Some(_) => format!("{}", Uuid::new_v4()),
+                None => "".to_owned(),
+            };
             match conn.add_domain(
                 &full_name,
                 account.id,
@@ -399,34 +1325,111 @@ fn subscribe(req: &mut Request, config: &Config) -> IronResult<Response> {
                 timestamp,
                 "",
                 "",
-                "",
+                &verification_token,
                 false,
                 &continent,
             ) {
                 Ok(_) => {
+                    if !key.is_empty() {
+                        if let Err(err) = conn.update_domain_auth_key(&token, &key) {
+                            error!("subscribe(): Failed to update domain: {:?}", err);
+                            return EndpointError::with(ApiError::DatabaseFailure);
+                        }
+                    }
+                    if !prefix.is_empty() {
+                        if let Err(err) = conn.update_domain_local_prefix(&token, &prefix) {
+                            error!("subscribe(): Failed to update domain: {:?}", err);
+                            return EndpointError::with(ApiError::DatabaseFailure);
+                        }
+                    }
+                    // Unknown names are cached too.
+                    config.dns_cache.forget_name(&full_name);
+                    if let Some(ref email) = email {
+                        send_confirmation_email(config, email, &verification_token);
+                    }
+
                     // We don't want the full domain name or the DNS
                     // challenge in the response, so we create a local
                     // struct.
                     let n_and_t = NameAndToken {
                         name: subdomain.to_owned(),
                         token: token,
+                        secret: secret,
                     };
                     json_response!(&n_and_t)
                 }
                 Err(err) => {
                     error!("subscribe(): Failed to add domain: {:?}", err);
-                    EndpointError::with(status::InternalServerError, 500)
+                    EndpointError::with(ApiError::DatabaseFailure)
                 }
             }
         }
         // Other error, like a db issue.
         Err(err) => {
             error!("subscribe(): Failed to look up domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
 
+// Tells whether a name can be subscribed, without reserving it.
+fn checkname(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "checkname(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /checkname {:?}", req.method, params);
+
+    let name = try_param!(params.get_string("name"));
+    let reason = match validate_name(
+        &name,
+        &config.options.general.domain,
+        &config.options.general.reserved_names,
+    ) {
+        Ok(subdomain) => {
+            let full_name = domain_for_name(&subdomain, config);
+            match conn.is_name_banned(&full_name) {
+                Ok(true) => Some("reserved"),
+                Ok(false) => match conn.get_domain_by_name(&full_name) {
+                    Ok(_) => Some("taken"),
+                    Err(diesel::result::Error::NotFound) => {
+                        match is_name_deleted(&conn, &full_name, config) {
+                            Ok(true) => Some("taken"),
+                            Ok(false) => None,
+                            Err(err) => {
+                                error!("checkname(): Failed to look up deleted domains: {:?}", err);
+                                return EndpointError::with(ApiError::DatabaseFailure);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("checkname(): Failed to look up domain: {:?}", err);
+                        return EndpointError::with(ApiError::DatabaseFailure);
+                    }
+                },
+                Err(err) => {
+                    error!("checkname(): Failed to look up banned names: {:?}", err);
+                    return EndpointError::with(ApiError::DatabaseFailure);
+                }
+            }
+        }
+        Err(NameError::Invalid) => Some("invalid"),
+        Err(NameError::Reserved) => Some("reserved"),
+    };
+
+    json_response!(&NameAvailability {
+        available: reason.is_none(),
+        reason: reason.map(|reason| reason.to_owned()),
+    })
+}
+
 fn dnsconfig(req: &mut Request, config: &Config) -> IronResult<Response> {
     let conn = config.db.get_connection();
     if conn.is_err() {
@@ -434,119 +1437,833 @@ fn dnsconfig(req: &mut Request, config: &Config) -> IronResult<Response> {
             "dnsconfig(): Failed to get database connection: {:?}",
             conn.err()
         );
-        return EndpointError::with(status::InternalServerError, 500);
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
     let conn = conn.unwrap();
 
     // Extract the challenge and token parameter.
-    let map = req.get_ref::<Params>().unwrap();
-    let challenge = map.find(&["challenge"]);
-    let token = map.find(&["token"]);
-
-    info!("GET /dnsconfig {:?}", map);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /dnsconfig {:?}", req.method, params);
 
     // Both parameters are mandatory.
-    if challenge.is_none() || token.is_none() {
-        error!("dnsconfig(): Challenge or token not provided");
-        return EndpointError::with(status::BadRequest, 400);
-    }
+    let challenge = try_param!(params.get_string("challenge"));
+    let token = try_param!(params.get_string("token"));
+    let append = match params.get_bool("append") {
+        Ok(append) => append,
+        Err(ApiError::MissingParameter) => false,
+        Err(err) => return EndpointError::with(err),
+    };
 
-    let challenge = String::from_value(challenge.unwrap()).unwrap();
-    if challenge.len() > 63 {
+    if challenge.len() > 63 || challenge.contains(',') {
         error!("dnsconfig(): Invalid challenge: {}", challenge);
-        return EndpointError::with(status::BadRequest, 400);
+        return EndpointError::with(ApiError::InvalidParameter);
     }
 
-    let token = String::from_value(token.unwrap()).unwrap();
+    let record = match get_domain_for_device(&conn, &token) {
+        Ok(record) => record,
+        Err(diesel::result::Error::NotFound) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Err(err) => {
+            error!("dnsconfig(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+
+    // Wildcard certificates need a challenge for the domain and another one
+    // for the wildcard, served at the same time.
+    let challenges = if append && !challenge.is_empty() {
+        append_challenge(&record.dns_challenge, &challenge)
+    } else {
+        challenge
+    };
 
-    match conn.update_domain_dns_challenge(&token, &challenge) {
-        Ok(count) if count > 0 => ok_response!(),
-        Ok(_) => EndpointError::with(status::NotFound, 404),
+    match conn.update_domain_dns_challenge(&record.token, &challenges) {
+        Ok(count) if count > 0 => {
+            config.dns_cache.forget_token(&record.token);
+            ok_response!()
+        }
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
         Err(err) => {
             error!("dnsconfig(): Failed to update domain: {:?}", err);
-            EndpointError::with(status::InternalServerError, 500)
+            EndpointError::with(ApiError::DatabaseFailure)
         }
     }
 }
 
-pub fn create_router(config: &Config) -> Router {
-    let mut router = Router::new();
-
-    macro_rules! handler {
-        ($name:ident) => (
-            let config_ = config.clone();
-            router.get(stringify!($name),
-                       move |req: &mut Request| -> IronResult<Response> {
-                $name(req, &config_)
-            }, stringify!($name));
-        )
+// Removes the DNS challenge of a domain once the certificate is issued, so
+// that it's no longer served.
+fn clearchallenge(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "clearchallenge(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
     }
+    let conn = conn.unwrap();
 
-    handler!(ping);
-    handler!(info);
-    handler!(subscribe);
-    handler!(unsubscribe);
-    handler!(dnsconfig);
-    handler!(reclaim);
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /clearchallenge {:?}", req.method, params);
 
-    handler!(verifyemail);
-    handler!(setemail);
-    handler!(revokeemail);
+    let token = try_param!(params.get_string("token"));
+    let token = match get_domain_for_device(&conn, &token) {
+        Ok(record) => record.token,
+        Err(diesel::result::Error::NotFound) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Err(err) => {
+            error!("clearchallenge(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
 
-    router
+    match conn.update_domain_dns_challenge(&token, "") {
+        Ok(count) if count > 0 => {
+            config.dns_cache.forget_token(&token);
+            ok_response!()
+        }
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("clearchallenge(): Failed to update domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
 }
 
-pub fn create_chain(root_path: &str, config: &Config) -> Chain {
-    let mut mount = Mount::new();
-    mount.mount(root_path, create_router(config));
+fn setwildcard(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "setwildcard(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
 
-    let mut chain = Chain::new(mount);
-    let cors = CORS::new(vec![
-        (vec![Method::Get], "subscribe".to_owned()),
-        (vec![Method::Get], "unsubscribe".to_owned()),
-        (vec![Method::Get], "reclaim".to_owned()),
-        (vec![Method::Get], "ping".to_owned()),
-        (vec![Method::Get], "dnsconfig".to_owned()),
-        (vec![Method::Get], "info".to_owned()),
-        (vec![Method::Get], "setemail".to_owned()),
-        (vec![Method::Get], "verifyemail".to_owned()),
-        (vec![Method::Get], "revokeemail".to_owned()),
-    ]);
-    chain.link_after(cors);
-    chain
-}
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /setwildcard {:?}", req.method, params);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use args::ArgsParser;
-    use config::Config;
-    use database::DatabasePool;
-    use hyper;
-    use iron::{Handler, Url};
-    use iron::status::Status;
-    use iron::method;
-    use iron;
-    use iron_test::response;
-    use iron_test::mock_stream::MockStream;
-    use models::Domain;
-    use std::io::Cursor;
-    use std::thread::sleep;
-    use std;
-    use std::time;
-    use self::hyper::buffer::BufReader;
-    use self::hyper::net::NetworkStream;
+    // Both parameters are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let enabled = try_param!(params.get_bool("enabled"));
 
-    fn get(path: &str, router: &Router) -> (String, Status) {
-        let resp = match request(method::Method::Get, path, "", router) {
-            Ok(response) => response,
-            Err(err) => err.response,
-        };
-        let status = resp.status.unwrap();
-        (response::extract_body_to_string(resp), status)
+    if let Err(err) = live_domain(&conn, &token, config) {
+        return EndpointError::with(err);
+    }
+    match conn.update_domain_wildcard(&token, enabled) {
+        Ok(count) if count > 0 => {
+            config.dns_cache.forget_token(&token);
+            ok_response!()
+        }
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("setwildcard(): Failed to update domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
     }
+}
 
-    // Triggers a request for a URL on the router.
+// Returns the canonical form of the name of a mail server, lowercase with a
+// trailing dot, or None if it's not a valid host name with at least two labels.
+pub fn parse_mx_target(value: &str) -> Option<String> {
+    let host = value.trim().trim_right_matches('.').to_lowercase();
+    let re = Regex::new(r"^([a-z0-9]|[a-z0-9][a-z0-9-]*[a-z0-9])$").unwrap();
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() < 2
+        || host.len() > 252
+        || labels
+            .iter()
+            .any(|label| label.len() > 63 || !re.is_match(label))
+    {
+        return None;
+    }
+    Some(format!("{}.", host))
+}
+
+// Maximum number of services published by a domain.
+const MAX_SRV_RECORDS: usize = 10;
+
+// Returns the service and protocol of an SRV record in their canonical form,
+// lowercase and without the leading underscores, or None if they are invalid.
+// Service names are limited to 15 characters by RFC 6335.
+pub fn parse_srv_service(service: &str, protocol: &str) -> Option<(String, String)> {
+    let service = service.trim().trim_left_matches('_').to_lowercase();
+    let protocol = protocol.trim().trim_left_matches('_').to_lowercase();
+    let re = Regex::new(r"^([a-z0-9]|[a-z0-9][a-z0-9-]*[a-z0-9])$").unwrap();
+    if !re.is_match(&service) || service.len() > 15 || (protocol != "tcp" && protocol != "udp") {
+        return None;
+    }
+    Some((service, protocol))
+}
+
+// The description of the domains subscribed without one.
+fn default_description(name: &str) -> String {
+    format!("{}'s server", name)
+}
+
+// Returns the domain with this token, unless it's unknown or waiting to be
+// evicted.
+fn live_domain(conn: &Database, token: &str, config: &Config) -> Result<Domain, ApiError> {
+    match conn.get_domain_by_token(token) {
+        Ok(ref record) if is_evicted(record, config) => Err(ApiError::UnknownToken),
+        Ok(record) => Ok(record),
+        Err(diesel::result::Error::NotFound) => Err(ApiError::UnknownToken),
+        Err(err) => {
+            error!("live_domain(): Failed to get domain: {:?}", err);
+            Err(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Changes the description of a domain, shown by /ping and /info. An empty
+// description is replaced by the default one of /subscribe.
+fn updatedesc(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "updatedesc(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /updatedesc {:?}", req.method, params);
+
+    // Both parameters are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let desc = try_param!(params.get_string("desc"));
+
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
+    };
+    let description = if desc.trim().is_empty() {
+        default_description(record.name.split('.').next().unwrap_or(""))
+    } else {
+        desc
+    };
+
+    match conn.update_domain_description(&token, &description) {
+        Ok(_) => ok_response!(),
+        Err(err) => {
+            error!("updatedesc(): Failed to update domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Publishes a custom TXT record for a domain, eg. for SPF or to verify the
+// ownership of the domain with a third party. Setting a value again is a no-op.
+fn settxt(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "settxt(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /settxt {:?}", req.method, params);
+
+    // Both parameters are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let value = try_param!(params.get_string("value"));
+
+    if !is_valid_txt_value(&value) {
+        error!("settxt(): Invalid value: {}", value);
+        return EndpointError::with(ApiError::InvalidParameter);
+    }
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
+    };
+
+    let result = conn.get_txt_records(record.id).and_then(|values| {
+        if values.contains(&value) {
+            Ok(true)
+        } else if values.len() >= MAX_TXT_RECORDS {
+            Ok(false)
+        } else {
+            conn.add_txt_record(record.id, &value).map(|_| true)
+        }
+    });
+    match result {
+        Ok(true) => ok_response!(),
+        Ok(false) => EndpointError::with(ApiError::TooManyRecords),
+        Err(err) => {
+            error!("settxt(): Failed to add TXT record: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Removes a custom TXT record of a domain. Removing a value that isn't
+// published is a no-op.
+fn deletetxt(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "deletetxt(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /deletetxt {:?}", req.method, params);
+
+    // Both parameters are mandatory.
+    let token = try_param!(params.get_string("token"));
+    let value = try_param!(params.get_string("value"));
+
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
+    };
+    match conn.delete_txt_record(record.id, &value) {
+        Ok(_) => ok_response!(),
+        Err(err) => {
+            error!("deletetxt(): Failed to delete TXT record: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Sets the mail server of a domain, for gateways that receive email. An empty
+// target removes it.
+fn setmx(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "setmx(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /setmx {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let target = try_param!(params.get_string("target"));
+    let priority = try_param!(params.get_optional_u16("priority")).unwrap_or(10);
+
+    let (target, priority) = if target.is_empty() {
+        (String::new(), 0)
+    } else {
+        match parse_mx_target(&target) {
+            Some(target) => (target, i32::from(priority)),
+            None => {
+                error!("setmx(): Invalid target: {}", target);
+                return EndpointError::with(ApiError::InvalidParameter);
+            }
+        }
+    };
+
+    if let Err(err) = live_domain(&conn, &token, config) {
+        return EndpointError::with(err);
+    }
+    match conn.update_domain_mx(&token, &target, priority) {
+        Ok(count) if count > 0 => {
+            config.dns_cache.forget_token(&token);
+            ok_response!()
+        }
+        Ok(_) => EndpointError::with(ApiError::UnknownToken),
+        Err(err) => {
+            error!("setmx(): Failed to update domain: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Publishes a service of a domain as an SRV record, so that clients can find
+// the port it listens on. The record of the same service and protocol, if
+// any, is replaced.
+fn setsrv(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "setsrv(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /setsrv {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let service = try_param!(params.get_string("service"));
+    let protocol = try_param!(params.get_string("protocol"));
+    let port = match try_param!(params.get_optional_u16("port")) {
+        Some(port) if port > 0 => port,
+        Some(_) => return EndpointError::with(ApiError::InvalidParameter),
+        None => return EndpointError::with(ApiError::MissingParameter),
+    };
+    let priority = try_param!(params.get_optional_u16("priority")).unwrap_or(10);
+    let weight = try_param!(params.get_optional_u16("weight")).unwrap_or(0);
+
+    let (service, protocol) = match parse_srv_service(&service, &protocol) {
+        Some(result) => result,
+        None => {
+            error!("setsrv(): Invalid service: {} {}", service, protocol);
+            return EndpointError::with(ApiError::InvalidParameter);
+        }
+    };
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
+    };
+
+    let new_record = NewSrvRecord {
+        domain_id: record.id,
+        service: &service,
+        protocol: &protocol,
+        priority: i32::from(priority),
+        weight: i32::from(weight),
+        port: i32::from(port),
+    };
+    let result = conn.get_srv_records(record.id).and_then(|records| {
+        let replaced = records
+            .iter()
+            .any(|record| record.service == service && record.protocol == protocol);
+        if !replaced && records.len() >= MAX_SRV_RECORDS {
+            Ok(false)
+        } else {
+            conn.set_srv_record(&new_record).map(|_| true)
+        }
+    });
+    match result {
+        Ok(true) => ok_response!(),
+        Ok(false) => EndpointError::with(ApiError::TooManyRecords),
+        Err(err) => {
+            error!("setsrv(): Failed to set SRV record: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Removes a service published with /setsrv. Removing a service that isn't
+// published is a no-op.
+fn deletesrv(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "deletesrv(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /deletesrv {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let service = try_param!(params.get_string("service"));
+    let protocol = try_param!(params.get_string("protocol"));
+
+    let (service, protocol) = match parse_srv_service(&service, &protocol) {
+        Some(result) => result,
+        None => {
+            error!("deletesrv(): Invalid service: {} {}", service, protocol);
+            return EndpointError::with(ApiError::InvalidParameter);
+        }
+    };
+    let record = match live_domain(&conn, &token, config) {
+        Ok(record) => record,
+        Err(err) => return EndpointError::with(err),
+    };
+    match conn.delete_srv_record(record.id, &service, &protocol) {
+        Ok(_) => ok_response!(),
+        Err(err) => {
+            error!("deletesrv(): Failed to delete SRV record: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Starts the transfer of a domain to a new owner, by giving its current owner
+// a code to hand over. A new code replaces the previous one.
+fn transfer(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "transfer(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /transfer {:?}", req.method, params);
+
+    let token = try_param!(params.get_string("token"));
+    let record = match conn.get_domain_by_token(&token) {
+        Ok(ref record) if is_evicted(record, config) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Ok(record) => record,
+        Err(diesel::result::Error::NotFound) => {
+            return EndpointError::with(ApiError::UnknownToken);
+        }
+        Err(err) => {
+            error!("transfer(): Failed to get domain: {:?}", err);
+            return EndpointError::with(ApiError::DatabaseFailure);
+        }
+    };
+
+    let now = now();
+    // Only the hash of the code is stored, so the code is only returned here.
+    let code = format!("{}", Uuid::new_v4());
+    let expires_at = now + config.options.general.transfer_code_ttl as i64;
+    match conn.add_transfer(record.id, &auth_key(&code), expires_at, now) {
+        Ok(transfer) => json_response!(&TransferCode {
+            code: code,
+            expires_at: transfer.expires_at,
+        }),
+        Err(err) => {
+            error!("transfer(): Failed to add transfer: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Redeems a transfer code, giving its domain a new token. The token of the
+// previous owner stops working.
+fn redeem(req: &mut Request, config: &Config) -> IronResult<Response> {
+    let conn = config.db.get_connection();
+    if conn.is_err() {
+        error!(
+            "redeem(): Failed to get database connection: {:?}",
+            conn.err()
+        );
+        return EndpointError::with(ApiError::DatabaseFailure);
+    }
+    let conn = conn.unwrap();
+
+    let params = try_param!(Parameters::from_request(req));
+    info!("{} /redeem {:?}", req.method, params);
+
+    let code = try_param!(params.get_string("code"));
+    let secret = try_param!(signing_secret(&params));
+    let key = match secret {
+        Some(ref secret) => auth_key(secret),
+        None => "".to_owned(),
+    };
+
+    let token = format!("{}", Uuid::new_v4());
+    match conn.redeem_transfer(&auth_key(&code), &token, &key, now()) {
+        Ok(record) => {
+            config.dns_cache.forget_name(&record.name);
+            json_response!(&NameAndToken {
+                name: record.name.split('.').next().unwrap_or("").to_owned(),
+                token: token,
+                secret: secret,
+            })
+        }
+        Err(diesel::result::Error::NotFound) => EndpointError::with(ApiError::UnknownTransferCode),
+        Err(err) => {
+            error!("redeem(): Failed to redeem transfer: {:?}", err);
+            EndpointError::with(ApiError::DatabaseFailure)
+        }
+    }
+}
+
+// Answers the CORS preflight requests, the headers being added by the Cors
+// middleware.
+fn preflight(_: &mut Request) -> IronResult<Response> {
+    Ok(Response::with(Status::NoContent))
+}
+
+// Returns the endpoints that web pages are allowed to call, with the methods
+// they accept.
+fn cors_endpoints(config: &Config) -> Vec<(Vec<Method>, String)> {
+    let mutating = if config.options.general.disable_get_routes {
+        vec![Method::Post]
+    } else {
+        vec![Method::Get, Method::Post]
+    };
+
+    vec![
+        (mutating.clone(), "subscribe".to_owned()),
+        (mutating.clone(), "unsubscribe".to_owned()),
+        (mutating.clone(), "undelete".to_owned()),
+        (mutating.clone(), "revoke".to_owned()),
+        (mutating.clone(), "reclaim".to_owned()),
+        (mutating.clone(), "ping".to_owned()),
+        (mutating.clone(), "dnsconfig".to_owned()),
+        (mutating.clone(), "clearchallenge".to_owned()),
+        (mutating.clone(), "setwildcard".to_owned()),
+        (mutating.clone(), "updatedesc".to_owned()),
+        (mutating.clone(), "settxt".to_owned()),
+        (mutating.clone(), "deletetxt".to_owned()),
+        (mutating.clone(), "setmx".to_owned()),
+        (mutating.clone(), "setsrv".to_owned()),
+        (mutating.clone(), "deletesrv".to_owned()),
+        (mutating.clone(), "transfer".to_owned()),
+        (mutating.clone(), "redeem".to_owned()),
+        (mutating.clone(), "devicetoken".to_owned()),
+        (mutating.clone(), "adddiscovery".to_owned()),
+        (mutating.clone(), "revokediscovery".to_owned()),
+        (vec![Method::Get], "discoverytokens".to_owned()),
+        (vec![Method::Get], "discovery".to_owned()),
+        (vec![Method::Get], "info".to_owned()),
+        (vec![Method::Get], "checkname".to_owned()),
+        (mutating.clone(), "setemail".to_owned()),
+        (vec![Method::Get], "verifyemail".to_owned()),
+        (mutating, "revokeemail".to_owned()),
+    ]
+}
+
+pub fn create_router(config: &Config) -> Router {
+    let mut router = Router::new();
+
+    // Each request uses the options of the last reload of the configuration.
+    macro_rules! handler {
+        ($method:ident, $name:ident) => (
+            let config_ = config.clone();
+            router.$method(
+                stringify!($name),
+                move |req: &mut Request| -> IronResult<Response> { $name(req, &config_.current()) },
+                concat!(stringify!($method), "_", stringify!($name)),
+            );
+        )
+    }
+
+    // Endpoints that change the state of a domain accept POST requests with a
+    // JSON body. They are also available as GET requests for older clients,
+    // unless this is turned off in the configuration.
+    macro_rules! mutating_handler {
+        ($name:ident) => (
+            if !config.options.general.disable_get_routes {
+                handler!(get, $name);
+            }
+            handler!(post, $name);
+        )
+    }
+
+    // The routes called by the gateways check the X-Signature header of the
+    // request before running the handler.
+    macro_rules! signed_handler {
+        ($method:ident, $path:expr, $name:ident) => (
+            let config_ = config.clone();
+            let mut chain = Chain::new(move |req: &mut Request| -> IronResult<Response> {
+                $name(req, &config_.current())
+            });
+            chain.link_before(RequireSignature::new(config));
+            router.$method($path, chain, concat!(stringify!($method), "_", $path));
+        )
+    }
+
+    macro_rules! signed_mutating_handler {
+        ($name:ident) => (
+            if !config.options.general.disable_get_routes {
+                signed_handler!(get, stringify!($name), $name);
+            }
+            signed_handler!(post, stringify!($name), $name);
+        )
+    }
+
+    handler!(get, info);
+    handler!(get, checkname);
+    handler!(get, health);
+    handler!(get, readyz);
+    router.get("livez", livez, "get_livez");
+    router.get("__version__", version, "get___version__");
+    signed_mutating_handler!(ping);
+    mutating_handler!(subscribe);
+    mutating_handler!(unsubscribe);
+    mutating_handler!(undelete);
+    mutating_handler!(revoke);
+    signed_mutating_handler!(dnsconfig);
+    signed_mutating_handler!(clearchallenge);
+    mutating_handler!(setwildcard);
+    mutating_handler!(updatedesc);
+    mutating_handler!(settxt);
+    mutating_handler!(deletetxt);
+    mutating_handler!(setmx);
+    mutating_handler!(setsrv);
+    mutating_handler!(deletesrv);
+    mutating_handler!(reclaim);
+    mutating_handler!(transfer);
+    mutating_handler!(redeem);
+    mutating_handler!(devicetoken);
+    mutating_handler!(adddiscovery);
+    mutating_handler!(revokediscovery);
+    handler!(get, discoverytokens);
+    handler!(get, discovery);
+
+    handler!(get, verifyemail);
+    mutating_handler!(setemail);
+    mutating_handler!(revokeemail);
+
+    // The domains are also resources identified by their token, the method of
+    // the request telling what to do with them. These routes don't depend on
+    // the GET routes being enabled.
+    macro_rules! resource_handler {
+        ($method:ident, $path:tt, $name:ident) => (
+            let config_ = config.clone();
+            router.$method(
+                $path,
+                move |req: &mut Request| -> IronResult<Response> { $name(req, &config_.current()) },
+                concat!(stringify!($method), "_", $path),
+            );
+        )
+    }
+
+    resource_handler!(post, "domains", subscribe);
+    resource_handler!(get, "domains", list_domains);
+    resource_handler!(get, "domains/:token", info);
+    signed_handler!(put, "domains/:token/ip", ping);
+    resource_handler!(delete, "domains/:token", unsubscribe);
+
+    // The logins with an OpenID Connect provider are browser navigations, so
+    // they only accept GET requests.
+    if config.options.general.oidc_client_id.is_some() {
+        resource_handler!(get, "oidc/login", oidc_login);
+        resource_handler!(get, "oidc/callback", oidc_callback);
+    }
+
+    // The admin routes are only reached with the admin token or an API key.
+    macro_rules! admin_handler {
+        ($method:ident, $path:tt, $name:ident) => (
+            let config_ = config.clone();
+            let mut chain = Chain::new(move |req: &mut Request| -> IronResult<Response> {
+                $name(req, &config_.current())
+            });
+            chain.link_before(RequireAdmin::new(config));
+            router.$method($path, chain, concat!(stringify!($method), "_", $path));
+        )
+    }
+
+    admin_handler!(get, "status", status);
+    // The admin routes that change the domains of others only accept POST
+    // requests.
+    admin_handler!(post, "admin/revoke", admin_revoke);
+    admin_handler!(post, "admin/ban", admin_ban);
+    admin_handler!(get, "admin/audit", admin_audit);
+    admin_handler!(get, "admin/export", admin_export);
+
+    for (_, name) in cors_endpoints(config) {
+        let id = format!("options_{}", name);
+        router.options(name, preflight, id);
+    }
+
+    router
+}
+
+// The versions of the API, each served under its own prefix, eg. /v1/ping.
+// The paths without a prefix serve the first version, for the existing
+// clients. A new version gets its own router, which can register the handlers
+// of the previous one and replace those whose parameters or responses change.
+const API_VERSIONS: [(&str, fn(&Config) -> Router); 1] = [("v1", create_router)];
+
+pub fn create_chain(root_path: &str, config: &Config) -> Chain {
+    let mut mount = Mount::new();
+    mount.mount(root_path, create_router(config));
+    for &(version, create_version_router) in &API_VERSIONS {
+        let path = format!("{}/{}", root_path.trim_right_matches('/'), version);
+        mount.mount(&path, create_version_router(config));
+    }
+    if let Some(ref webroot) = config.options.general.acme_webroot {
+        mount.mount(ACME_CHALLENGE_PATH, AcmeChallenges::new(webroot));
+    }
+
+    let mut chain = Chain::new(mount);
+    let request_ids = AssignRequestIds::new(config);
+    let log_requests = LogRequests::new(config);
+    chain.link_before(request_ids.clone());
+    chain.link_before(log_requests.clone());
+    let request_metrics = RequestMetrics::new(&config.metrics);
+    let track_requests = TrackRequests::new(&config.in_flight);
+    chain.link_before(request_metrics.clone());
+    chain.link_before(track_requests.clone());
+    chain.link_before(ValidateParameters);
+    let audit_changes = AuditChanges::new(config);
+    chain.link_before(audit_changes.clone());
+    chain.link_after(track_requests);
+    chain.link_after(audit_changes);
+    if config.options.general.legacy_status_codes {
+        chain.link_after(LegacyStatusCodes);
+    }
+    chain.link_after(request_metrics);
+    chain.link_after(Cors::new(
+        &config.options.general.cors_origins,
+        cors_endpoints(config),
+    ));
+    chain.link_after(ReportErrors::new(config));
+    chain.link_after(log_requests);
+    chain.link_after(request_ids);
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use admin_routes::ServerStatus;
+    use api_socket::MarkLocalRequests;
+    use args::ArgsParser;
+    use config::Config;
+    use database::DatabasePool;
+    use hyper;
+    use iron::headers::{Authorization, Bearer};
+    use iron::{Handler, Headers, Url};
+    use iron::status::{self, Status};
+    use iron::method;
+    use iron;
+    use iron_test;
+    use iron_test::response;
+    use iron_test::mock_stream::MockStream;
+    use models::Domain;
+    use rate_limit::RateLimits;
+    use std::io::Cursor;
+    use std::thread::sleep;
+    use std;
+    use std::time;
+    use self::hyper::buffer::BufReader;
+    use self::hyper::net::NetworkStream;
+
+    fn error_response(error: ApiError) -> (String, Status) {
+        let body = ErrorBody {
+            code: error.code(),
+            error: error,
+            message: error.message(),
+        };
+        (serde_json::to_string(&body).unwrap(), error.status())
+    }
+
+    fn get(path: &str, router: &Router) -> (String, Status) {
+        let resp = match request(method::Method::Get, path, "", router) {
+            Ok(response) => response,
+            Err(err) => err.response,
+        };
+        let status = resp.status.unwrap();
+        (response::extract_body_to_string(resp), status)
+    }
+
+    fn post(path: &str, body: &str, router: &Router) -> (String, Status) {
+        let resp = match request(method::Method::Post, path, body, router) {
+            Ok(response) => response,
+            Err(err) => err.response,
+        };
+        let status = resp.status.unwrap();
+        (response::extract_body_to_string(resp), status)
+    }
+
+    // Triggers a request for a URL on the router.
     fn request(
         method: method::Method,
         path: &str,
@@ -560,34 +2277,2984 @@ mod tests {
         let mut buffer = String::new();
         buffer.push_str(&format!("{} {} HTTP/1.1\r\n", &method, url));
         buffer.push_str(&format!("Content-Length: {}\r\n", body.len() as u64));
+        if !body.is_empty() {
+            buffer.push_str("Content-Type: application/json\r\n");
+        }
         buffer.push_str("\r\n");
         buffer.push_str(body);
 
-        let addr = "127.0.0.1:3000".parse().unwrap();
-        let protocol = match url.scheme() {
-            "http" => iron::Protocol::http(),
-            "https" => iron::Protocol::https(),
-            _ => panic!("unknown protocol {}", url.scheme()),
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let protocol = match url.scheme() {
+            "http" => iron::Protocol::http(),
+            "https" => iron::Protocol::https(),
+            _ => panic!("unknown protocol {}", url.scheme()),
+        };
+
+        let mut stream = MockStream::new(Cursor::new(buffer.as_bytes().to_vec()));
+        let mut buf_reader = BufReader::new(&mut stream as &mut NetworkStream);
+        let http_request = hyper::server::Request::new(&mut buf_reader, addr).unwrap();
+        let mut req = Request::from_http(http_request, addr, &protocol).unwrap();
+
+        router.handle(&mut req)
+    }
+
+    #[test]
+    fn test_validate_name() {
+        let reserved = vec!["api".to_owned(), "www".to_owned()];
+        let domain = "mydomain.org";
+
+        assert_eq!(validate_name("test", domain, &reserved), Ok("test".to_owned()));
+        assert_eq!(validate_name(" Test ", domain, &reserved), Ok("test".to_owned()));
+        assert_eq!(validate_name("a", domain, &reserved), Ok("a".to_owned()));
+        assert_eq!(
+            validate_name("my-gateway-2", domain, &reserved),
+            Ok("my-gateway-2".to_owned())
+        );
+        assert_eq!(
+            validate_name(&"a".repeat(63), domain, &reserved),
+            Ok("a".repeat(63))
+        );
+
+        assert_eq!(validate_name("", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("-test", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("test-", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("foo.bar", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("foo bar", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("foo_bar", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(validate_name("_psl", domain, &reserved), Err(NameError::Invalid));
+        assert_eq!(
+            validate_name(&"a".repeat(64), domain, &reserved),
+            Err(NameError::Invalid)
+        );
+        assert_eq!(
+            validate_name("test", &"a".repeat(249), &reserved),
+            Err(NameError::Invalid)
+        );
+
+        assert_eq!(validate_name("api", domain, &reserved), Err(NameError::Reserved));
+        assert_eq!(validate_name("WWW", domain, &reserved), Err(NameError::Reserved));
+
+        // Unicode names are stored in punycode, and must still be valid labels.
+        assert_eq!(
+            validate_name("bücher", domain, &reserved),
+            Ok("xn--bcher-kva".to_owned())
+        );
+        assert_eq!(
+            validate_name(" MÜNCHEN ", domain, &reserved),
+            Ok("xn--mnchen-3ya".to_owned())
+        );
+        assert_eq!(
+            validate_name("bü.cher", domain, &reserved),
+            Err(NameError::Invalid)
+        );
+        assert_eq!(
+            validate_name("bü_cher", domain, &reserved),
+            Err(NameError::Invalid)
+        );
+        assert_eq!(
+            validate_name(&"ü".repeat(60), domain, &reserved),
+            Err(NameError::Invalid)
+        );
+
+        // The built-in names are reserved even if the configuration doesn't
+        // list them.
+        let reserved = vec!["Gateway".to_owned()];
+        assert_eq!(
+            validate_name("mail", domain, &reserved),
+            Err(NameError::Reserved)
+        );
+        assert_eq!(
+            validate_name("NS1", domain, &reserved),
+            Err(NameError::Reserved)
+        );
+        assert_eq!(
+            validate_name("gateway", domain, &reserved),
+            Err(NameError::Reserved)
+        );
+        assert_eq!(validate_name("mail", domain, &[]), Err(NameError::Reserved));
+        assert_eq!(
+            validate_name("mailbox", domain, &[]),
+            Ok("mailbox".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_append_challenge() {
+        assert_eq!(append_challenge("", "first"), "first");
+        assert_eq!(append_challenge("first", "second"), "first,second");
+        assert_eq!(append_challenge("first,second", "third"), "second,third");
+        // Setting the same challenge again doesn't serve it twice.
+        assert_eq!(append_challenge("first,second", "first"), "second,first");
+        assert_eq!(append_challenge("first", "first"), "first");
+    }
+
+    #[test]
+    fn test_validate_local_prefix() {
+        assert_eq!(validate_local_prefix("lan"), Some("lan".to_owned()));
+        assert_eq!(validate_local_prefix(" Home-1 "), Some("home-1".to_owned()));
+        assert_eq!(validate_local_prefix(""), Some("".to_owned()));
+
+        assert_eq!(validate_local_prefix("-lan"), None);
+        assert_eq!(validate_local_prefix("my.lan"), None);
+        assert_eq!(validate_local_prefix("_lan"), None);
+        assert_eq!(validate_local_prefix("maison-ü"), None);
+        assert_eq!(validate_local_prefix(&"a".repeat(64)), None);
+    }
+
+    #[test]
+    fn test_parse_local_ips() {
+        let values =
+            |list: &[&str]| -> Vec<String> { list.iter().map(|v| v.to_string()).collect() };
+
+        assert_eq!(
+            parse_local_ips(&values(&["192.168.1.2"])),
+            Some("192.168.1.2".to_owned())
+        );
+        assert_eq!(
+            parse_local_ips(&values(&[
+                "192.168.1.2, FE80::0:1",
+                "10.0.0.2",
+                "::ffff:192.168.1.2"
+            ])),
+            Some("192.168.1.2,fe80::1,10.0.0.2".to_owned())
+        );
+        assert_eq!(parse_local_ips(&values(&[""])), Some("".to_owned()));
+        assert_eq!(parse_local_ips(&values(&["192.168.1.2", "garbage"])), None);
+        assert_eq!(parse_local_ips(&values(&["192.168.1.256"])), None);
+        assert_eq!(
+            parse_local_ips(&values(&["10.0.0.1,10.0.0.2,10.0.0.3,10.0.0.4,10.0.0.5,\
+                                       10.0.0.6,10.0.0.7,10.0.0.8,10.0.0.9"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ping_record() {
+        let options = ArgsParser::from_vec(vec!["registration_server"]).general;
+        let record = Domain {
+            id: 1,
+            name: "test.mydomain.org.".to_owned(),
+            account_id: 1,
+            token: "secret-token".to_owned(),
+            description: "Test Server".to_owned(),
+            timestamp: 1234,
+            dns_challenge: "secret-challenge".to_owned(),
+            reclamation_token: "secret-reclamation".to_owned(),
+            verification_token: "secret-verification".to_owned(),
+            verified: true,
+            continent: "EU".to_owned(),
+            local_ips: "192.168.1.2,fe80::1".to_owned(),
+            warning_sent_at: 0,
+            wildcard: false,
+            challenge_set_at: 0,
+            auth_key: "".to_owned(),
+            mx_target: "".to_owned(),
+            mx_priority: 0,
+            local_prefix: "".to_owned(),
+            device_token: "".to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        };
+
+        let json = serde_json::to_string(&PingRecord::new(
+            &record,
+            "203.0.113.7".parse().unwrap(),
+            &options,
+        ))
+        .unwrap();
+        assert_eq!(
+            json,
+            "{\"remote_name\":\"test.mydomain.org\",\
+             \"local_name\":\"local.test.mydomain.org\",\
+             \"public_ip\":\"203.0.113.7\",\
+             \"local_ip\":[\"192.168.1.2\",\"fe80::1\"],\
+             \"timestamp\":1234}"
+        );
+        assert!(!json.contains("secret"));
+
+        // No local addresses gives an empty list.
+        let record = Domain {
+            local_ips: "".to_owned(),
+            ..record
+        };
+        let ping_record = PingRecord::new(&record, "::1".parse().unwrap(), &options);
+        assert_eq!(ping_record.local_ip, Vec::<String>::new());
+        assert_eq!(ping_record.public_ip, "::1");
+
+        // The local name uses the label chosen by the domain, if any.
+        let record = Domain {
+            local_prefix: "lan".to_owned(),
+            ..record
+        };
+        let ping_record = PingRecord::new(&record, "::1".parse().unwrap(), &options);
+        assert_eq!(ping_record.local_name, "lan.test.mydomain.org");
+    }
+
+    #[test]
+    fn test_redundant_ping() {
+        let mut options = ArgsParser::from_vec(vec!["registration_server"]).general;
+        let record = Domain {
+            id: 1,
+            name: "test.mydomain.org.".to_owned(),
+            account_id: 1,
+            token: "test-token".to_owned(),
+            description: "Test Server".to_owned(),
+            timestamp: 1000,
+            dns_challenge: "".to_owned(),
+            reclamation_token: "".to_owned(),
+            verification_token: "".to_owned(),
+            verified: false,
+            continent: "".to_owned(),
+            local_ips: "192.168.1.2".to_owned(),
+            warning_sent_at: 0,
+            wildcard: false,
+            challenge_set_at: 0,
+            auth_key: "".to_owned(),
+            mx_target: "".to_owned(),
+            mx_priority: 0,
+            local_prefix: "".to_owned(),
+            device_token: "".to_owned(),
+            created_at: 0,
+            updated_at: 0,
+        };
+        let same = Some("192.168.1.2".to_owned());
+        let other = Some("192.168.1.3".to_owned());
+
+        // Every ping is saved by default.
+        assert!(!is_redundant_ping(&record, &None, &options, 1000));
+
+        options.min_ping_interval = 60;
+        assert!(is_redundant_ping(&record, &None, &options, 1059));
+        assert!(is_redundant_ping(&record, &same, &options, 1059));
+        assert!(!is_redundant_ping(&record, &other, &options, 1059));
+        assert!(!is_redundant_ping(&record, &None, &options, 1060));
+    }
+
+    #[test]
+    fn test_router() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_routes");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_routes");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_routes.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let empty_ok = ("".to_owned(), status::Ok);
+
+        // Subscribe a test user.
+        assert_eq!(
+            get("subscribe", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        let invalid_name_error = error_response(ApiError::InvalidName);
+        let reserved_name_error = error_response(ApiError::ReservedName);
+        assert_eq!(get("subscribe?name=", &router), invalid_name_error);
+        assert_eq!(get("subscribe?name=-test", &router), invalid_name_error);
+        assert_eq!(get("subscribe?name=test-", &router), invalid_name_error);
+        assert_eq!(get("subscribe?name=foo.bar", &router), invalid_name_error);
+        assert_eq!(get("subscribe?name=api", &router), reserved_name_error);
+        assert_eq!(get("subscribe?name=www", &router), reserved_name_error);
+        assert_eq!(get("subscribe?name=NS1", &router), reserved_name_error);
+        assert_eq!(get("subscribe?name=mail", &router), reserved_name_error);
+        assert_eq!(get("subscribe?name=support", &router), reserved_name_error);
+        assert_eq!(
+            get(
+                "subscribe?name=abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxy\
+                 zabcdefghijklmnopqrstuvwxyz",
+                &router
+            ),
+            invalid_name_error
+        );
+
+        let resp = get("subscribe?name=test", &router);
+        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        let token = registration.token;
+
+        assert_eq!(registration.name, "test".to_owned());
+
+        // Unicode names are registered in punycode.
+        let resp = get("subscribe?name=b%C3%BCcher", &router);
+        let unicode: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        assert_eq!(unicode.name, "xn--bcher-kva".to_owned());
+        assert!(conn
+            .get_domain_by_name("xn--bcher-kva.mydomain.org.")
+            .is_ok());
+        assert_eq!(
+            get("subscribe?name=xn--bcher-kva", &router),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(
+            get(&format!("unsubscribe?token={}", unicode.token), &router),
+            empty_ok
+        );
+
+        // Unsubscribe
+        assert_eq!(
+            get("unsubscribe", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("unsubscribe?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("unsubscribe?token={}", token), &router),
+            empty_ok
+        );
+
+        // Subscribe again
+        let resp = get("subscribe?name=test", &router);
+        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        let token = registration.token;
+
+        assert_eq!(registration.name, "test".to_owned());
+
+        // Fail to register the same name twice, regardless of its case.
+        let res = get("subscribe?name=test", &router);
+        assert_eq!(res, error_response(ApiError::UnavailableName));
+        let res = get("subscribe?name=Test", &router);
+        assert_eq!(res, error_response(ApiError::UnavailableName));
+
+        // An empty reclamation token doesn't match a domain that was never
+        // reclaimed.
+        let res = get("subscribe?name=test&reclamationToken=", &router);
+        assert_eq!(res, error_response(ApiError::ReclamationTokenMismatch));
+        assert_eq!(
+            get("unsubscribe?reclamationToken=", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert!(conn.get_domain_by_token(&token).is_ok());
+
+        // Test reclaiming domain
+        let email = "test@example.com".to_owned();
+        assert_eq!(
+            get("subscribe?name=test&email=", &router),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(
+            get(&format!("subscribe?name=test&email={}", email), &router),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(
+            get("reclaim", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        let res = get("reclaim?name=nonexistent", &router);
+        assert_eq!(res, error_response(ApiError::NoSuchName));
+        let res = get("reclaim?name=test", &router);
+        assert_eq!(res, error_response(ApiError::NoEmail));
+
+        // Ping without the expected parameters.
+        assert_eq!(
+            get("ping", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("ping?name=test", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("ping?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+
+        // Ping properly.
+        sleep(time::Duration::from_secs(1));
+        assert_eq!(get(&format!("ping?token={}", token), &router), empty_ok);
+
+        // Ping and get the stored record back.
+        let response = get(
+            &format!("ping?token={}&local_ip=10.0.0.2&format=json", token),
+            &router,
+        );
+        assert_eq!(response.1, status::Ok);
+        assert!(!response.0.contains(&token));
+        let ping_record: PingRecord = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(ping_record.remote_name, "test.mydomain.org");
+        assert_eq!(ping_record.local_name, "local.test.mydomain.org");
+        assert_eq!(ping_record.public_ip, "127.0.0.1");
+        assert_eq!(ping_record.local_ip, vec!["10.0.0.2".to_owned()]);
+        assert_eq!(
+            ping_record.timestamp,
+            conn.get_domain_by_token(&token).unwrap().timestamp
+        );
+
+        // Get the full info
+        assert_eq!(
+            get("info", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("info?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+
+        let response = get(&format!("info?token={}", token), &router);
+        assert_eq!(response.1, status::Ok);
+        let record: Domain = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(record.token, token);
+        assert_eq!(record.name, "test.mydomain.org.");
+        assert_eq!(record.description, r#"test's server"#);
+
+        // With the computed fields.
+        let info: serde_json::Value = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(info["live"], true);
+        assert_eq!(
+            info["expires_at"].as_i64(),
+            Some(record.timestamp + 2_592_000)
+        );
+        assert_eq!(info["addresses"].as_array().map(|list| list.len()), Some(1));
+        assert_eq!(info["addresses"][0], "5.6.7.8");
+        assert_eq!(info["verified"], false);
+
+        // Test the LE challenge endpoints.
+        assert_eq!(
+            get("dnsconfig", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("dnsconfig?token=wrong_token", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get(&format!("dnsconfig?token={}", token), &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get(
+                "dnsconfig?token=wrong_token&challenge=test_challenge",
+                &router
+            ),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(
+                &format!(
+                    "dnsconfig?token={}&challenge=abcdefghijklmnopqrstuvwxyz\
+                     abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyz",
+                    token
+                ),
+                &router
+            ),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            get(
+                &format!("dnsconfig?token={}&challenge=test_challenge", token),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().dns_challenge,
+            "test_challenge"
+        );
+        assert_eq!(
+            get(&format!("dnsconfig?token={}&challenge=a,b", token), &router),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            get(
+                &format!("dnsconfig?token={}&challenge=other&append=maybe", token),
+                &router
+            ),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            get(
+                "dnsconfig?token=wrong_token&challenge=other&append=true",
+                &router
+            ),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(
+                &format!("dnsconfig?token={}&challenge=other&append=true", token),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().dns_challenge,
+            "test_challenge,other"
+        );
+
+        // Test the challenge removal.
+        assert_eq!(
+            get("clearchallenge", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("clearchallenge?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("clearchallenge?token={}", token), &router),
+            empty_ok
+        );
+        let record = conn.get_domain_by_token(&token).unwrap();
+        assert_eq!(record.dns_challenge, "");
+        assert_eq!(record.challenge_set_at, 0);
+
+        // Test the wildcard endpoint.
+        assert_eq!(
+            get(&format!("setwildcard?token={}", token), &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get(&format!("setwildcard?token={}&enabled=yes", token), &router),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            get("setwildcard?token=wrong_token&enabled=true", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(
+                &format!("setwildcard?token={}&enabled=true", token),
+                &router
+            ),
+            empty_ok
+        );
+        assert!(conn.get_domain_by_token(&token).unwrap().wildcard);
+        assert_eq!(
+            get(
+                &format!("setwildcard?token={}&enabled=false", token),
+                &router
+            ),
+            empty_ok
+        );
+        assert!(!conn.get_domain_by_token(&token).unwrap().wildcard);
+
+        // Test the description endpoint.
+        assert_eq!(
+            get(&format!("updatedesc?token={}", token), &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("updatedesc?token=wrong_token&desc=Living%20room", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(
+                &format!("updatedesc?token={}&desc=Living%20room", token),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().description,
+            "Living room"
+        );
+        assert_eq!(
+            get(&format!("updatedesc?token={}&desc=", token), &router),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().description,
+            "test's server"
+        );
+
+        // Email routes tests
+        // 1. set an email address
+        assert_eq!(
+            get("setemail", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("setemail?token=wrong_token", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("setemail?token=wrong_token&email=me@example.com", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(
+                &format!("setemail?token={}&email=not_an_email", token),
+                &router
+            ),
+            error_response(ApiError::InvalidEmail)
+        );
+        assert_eq!(
+            get(
+                &format!(
+                    "setemail?token={}&email=abc@{}com",
+                    token,
+                    std::iter::repeat("makeasuperlongfakedomain.")
+                        .take(10)
+                        .collect::<String>()
+                ),
+                &router
+            ),
+            error_response(ApiError::InvalidEmail)
+        );
+        assert_eq!(
+            get(
+                &format!("setemail?token={}&email={}&format=json", token, email),
+                &router
+            ),
+            (
+                format!(r#"{{"email":"{}","verified":false}}"#, email),
+                status::Ok
+            )
+        );
+        assert_eq!(
+            get(
+                &format!("setemail?token={}&email={}", token, email),
+                &router
+            ),
+            empty_ok
+        );
+        let record = conn.get_domain_by_token(&token).unwrap();
+        let account = conn.get_account_by_id(record.account_id).unwrap();
+        assert_eq!(account.email, email);
+        assert!(!record.verified);
+        let link = record.verification_token;
+
+        // The address can't be used before being verified.
+        assert_eq!(
+            get("reclaim?name=test", &router),
+            error_response(ApiError::NoEmail)
+        );
+        assert_eq!(
+            get(&format!("subscribe?name=test&email={}", email), &router),
+            error_response(ApiError::UnavailableName)
+        );
+
+        // 2. verify the email
+        assert_eq!(
+            get("verifyemail", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        let error_page = (
+            config.options.email.error_page.clone().unwrap(),
+            status::NotFound,
+        );
+        assert_eq!(get("verifyemail?s=wrong_link", &router), error_page);
+        // The domains without a pending verification have an empty link.
+        assert_eq!(get("verifyemail?s=", &router), error_page);
+        assert_eq!(
+            get(&format!("verifyemail?s={}", link), &router),
+            (config.options.email.success_page.unwrap(), status::Ok)
+        );
+        // A link can only be followed once.
+        assert_eq!(get(&format!("verifyemail?s={}", link), &router), error_page);
+
+        // 3. check that the email has been set on the domain record.
+        let domain_record = conn.get_domain_by_token(&token).unwrap();
+        assert!(domain_record.verified);
+
+        // 3a. Before revoking, finish testing domain reclamation.
+        assert_eq!(
+            get(&format!("subscribe?name=test&email={}", email), &router),
+            error_response(ApiError::UnavailableNameReclamationPossible)
+        );
+        assert_eq!(get("reclaim?name=test", &router), empty_ok);
+        // Only the hash of the emailed token is stored, so it is replaced by
+        // a known one.
+        let domain_record = conn.get_domain_by_token(&token).unwrap();
+        assert_eq!(domain_record.reclamation_token.len(), 64);
+        conn.update_domain_reclamation_token(&token, &auth_key("reclaim-token"))
+            .unwrap();
+        let res = get("subscribe?name=test&reclamationToken=wrongtoken", &router);
+        assert_eq!(res, error_response(ApiError::ReclamationTokenMismatch));
+        let res = get(
+            "subscribe?name=test&reclamationToken=reclaim-token",
+            &router,
+        );
+        let registration: NameAndToken = serde_json::from_str(&res.0).unwrap();
+        let token = registration.token;
+        assert_eq!(registration.name, "test".to_owned());
+
+        // The previous token and the reclamation token stop working.
+        assert_eq!(
+            get(&format!("info?token={}", domain_record.token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let res = get(
+            "subscribe?name=test&reclamationToken=reclaim-token",
+            &router,
+        );
+        assert_eq!(res, error_response(ApiError::ReclamationTokenMismatch));
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().reclamation_token,
+            ""
+        );
+
+        // 4. email revocation
+        assert_eq!(
+            get("revokeemail", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("revokeemail?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("revokeemail?token={}", token), &router),
+            empty_ok
+        );
+
+        // 5. Verify the verification link is empty.
+        let record = conn.get_domain_by_token(&token).unwrap();
+        assert_eq!(record.verification_token, "");
+        assert!(!record.verified);
+
+        // 6. Subscribe with an email address.
+        assert_eq!(
+            get("subscribe?name=withemail&email=not_an_email", &router),
+            error_response(ApiError::InvalidEmail)
+        );
+        let res = get(
+            &format!("subscribe?name=withemail&email={}", email),
+            &router,
+        );
+        assert_eq!(res.1, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&res.0).unwrap();
+        let record = conn.get_domain_by_token(&registration.token).unwrap();
+        let account = conn.get_account_by_id(record.account_id).unwrap();
+        assert_eq!(account.email, email);
+        assert!(!record.verified);
+        assert!(!record.verification_token.is_empty());
+
+        let res = get(
+            &format!("verifyemail?s={}", record.verification_token),
+            &router,
+        );
+        assert_eq!(res.1, status::Ok);
+        let res = get(&format!("info?token={}", registration.token), &router);
+        let info: Domain = serde_json::from_str(&res.0).unwrap();
+        assert!(info.verified);
+
+        // 7. Revoke the domains, the account is removed with the last one.
+        assert_eq!(
+            get("revoke", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("revoke?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let res = get(&format!("revoke?token={}", registration.token), &router);
+        assert_eq!(res.1, status::Ok);
+        let revocation: Revocation = serde_json::from_str(&res.0).unwrap();
+        assert_eq!(revocation, Revocation { accounts: 0 });
+        let res = get(&format!("revoke?token={}", token), &router);
+        let revocation: Revocation = serde_json::from_str(&res.0).unwrap();
+        assert_eq!(revocation, Revocation { accounts: 1 });
+        assert!(conn.get_account_by_email(&email).is_err());
+        assert_eq!(
+            get(&format!("revoke?token={}", token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+    }
+
+    #[test]
+    fn test_post_routes() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_post_routes");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_post_routes");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_post_routes.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.general.disable_get_routes = true;
+        let router = create_router(&config);
+
+        let empty_ok = ("".to_owned(), status::Ok);
+
+        // GET is turned off for the endpoints that modify a domain.
+        assert_eq!(get("subscribe?name=test", &router).1, status::NotFound);
+        assert_eq!(get("ping?token=wrong_token", &router).1, status::NotFound);
+
+        // Malformed bodies are rejected.
+        assert_eq!(
+            post("subscribe", r#"{"name": "test""#, &router),
+            error_response(ApiError::MalformedBody)
+        );
+        assert_eq!(
+            post("subscribe", r#"["test"]"#, &router),
+            error_response(ApiError::MalformedBody)
+        );
+        assert_eq!(
+            post("subscribe", "", &router),
+            error_response(ApiError::MalformedBody)
+        );
+        assert_eq!(
+            post("subscribe", "{}", &router),
+            error_response(ApiError::MissingParameter)
+        );
+
+        let resp = post("subscribe", r#"{"name": "test"}"#, &router);
+        assert_eq!(resp.1, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        let token = registration.token;
+        assert_eq!(registration.name, "test".to_owned());
+
+        assert_eq!(
+            post("ping", r#"{"token": "wrong_token"}"#, &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            post("ping", &format!(r#"{{"token": "{}"}}"#, token), &router),
+            empty_ok
+        );
+
+        assert_eq!(
+            post(
+                "dnsconfig",
+                &format!(r#"{{"token": "{}", "challenge": "test_challenge"}}"#, token),
+                &router
+            ),
+            empty_ok
+        );
+        let record = conn.get_domain_by_token(&token).unwrap();
+        assert_eq!(record.dns_challenge, "test_challenge");
+
+        // Read only endpoints are still available with GET.
+        assert_eq!(get(&format!("info?token={}", token), &router).1, status::Ok);
+
+        assert_eq!(
+            post(
+                "unsubscribe",
+                &format!(r#"{{"token": "{}"}}"#, token),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            post(
+                "unsubscribe",
+                &format!(r#"{{"token": "{}"}}"#, token),
+                &router
+            ),
+            error_response(ApiError::UnknownToken)
+        );
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_malformed");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_malformed");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_malformed.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let get_endpoints = vec![
+            "ping",
+            "info",
+            "checkname",
+            "subscribe",
+            "unsubscribe",
+            "undelete",
+            "dnsconfig",
+            "clearchallenge",
+            "setwildcard",
+            "updatedesc",
+            "settxt",
+            "deletetxt",
+            "setmx",
+            "setsrv",
+            "deletesrv",
+            "reclaim",
+            "transfer",
+            "redeem",
+            "devicetoken",
+            "adddiscovery",
+            "revokediscovery",
+            "discoverytokens",
+            "discovery",
+            "setemail",
+            "verifyemail",
+            "revokeemail",
+        ];
+        let post_endpoints = vec![
+            "ping",
+            "subscribe",
+            "unsubscribe",
+            "undelete",
+            "dnsconfig",
+            "clearchallenge",
+            "setwildcard",
+            "updatedesc",
+            "settxt",
+            "deletetxt",
+            "setmx",
+            "setsrv",
+            "deletesrv",
+            "reclaim",
+            "transfer",
+            "redeem",
+            "devicetoken",
+            "adddiscovery",
+            "revokediscovery",
+            "setemail",
+            "revokeemail",
+        ];
+
+        for endpoint in &get_endpoints {
+            assert_eq!(
+                get(endpoint, &router),
+                error_response(ApiError::MissingParameter)
+            );
+
+            // Array and map values instead of strings.
+            assert_eq!(
+                get(
+                    &format!(
+                        "{}?token[]=a&token[]=b&name[]=a&name[]=b&s[]=a&s[]=b\
+                         &challenge[]=a&challenge[]=b&code[]=a&code[]=b&value[]=a&value[]=b",
+                        endpoint
+                    ),
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+            assert_eq!(
+                get(
+                    &format!(
+                        "{}?token[a]=b&name[a]=b&s[a]=b&challenge[a]=b&code[a]=b&value[a]=b",
+                        endpoint
+                    ),
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+
+            // Values that are not valid UTF-8.
+            let (_, status) = get(
+                &format!(
+                    "{}?token=%FF%FE&name=%FF%FE&s=%FF%FE&challenge=%FF%FE&email=%FF%FE\
+                     &code=%FF%FE&value=%FF%FE",
+                    endpoint
+                ),
+                &router,
+            );
+            assert!(status.is_client_error());
+        }
+
+        for endpoint in &post_endpoints {
+            assert_eq!(
+                post(endpoint, "{}", &router),
+                error_response(ApiError::MissingParameter)
+            );
+            assert_eq!(
+                post(endpoint, "}{", &router),
+                error_response(ApiError::MalformedBody)
+            );
+            assert_eq!(
+                post(
+                    endpoint,
+                    r#"{"token": ["a", "b"], "name": ["a", "b"], "challenge": ["a", "b"],
+                        "code": ["a", "b"], "value": ["a", "b"]}"#,
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+            assert_eq!(
+                post(
+                    endpoint,
+                    r#"{"token": {"a": "b"}, "name": 42, "challenge": true, "code": 1,
+                        "value": false}"#,
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_status");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_status");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_status.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let unknown = conn.get_unknown_account().unwrap();
+        let account = conn.add_account("status@example.com").unwrap();
+        let domains = vec![
+            ("recent.mydomain.org.", unknown.id, now - 60),
+            ("today.mydomain.org.", unknown.id, now - 7200),
+            ("thisweek.mydomain.org.", account.id, now - 3 * 86400),
+            ("old.mydomain.org.", account.id, now - 60 * 86400),
+        ];
+        for (domain_name, domain_account, domain_timestamp) in domains {
+            conn.add_domain(
+                domain_name,
+                domain_account,
+                &format!("secret-token-{}", domain_name),
+                "",
+                domain_timestamp,
+                "",
+                "",
+                "",
+                false,
+                "",
+            )
+            .unwrap();
+        }
+
+        let get_status = |token: Option<&str>| -> (String, Status) {
+            let mut headers = Headers::new();
+            if let Some(token) = token {
+                headers.set(Authorization(Bearer {
+                    token: token.to_owned(),
+                }));
+            }
+            let resp = match iron_test::request::get("http://localhost/status", headers, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+
+        let unauthorized = error_response(ApiError::Unauthorized);
+        assert_eq!(get_status(None), unauthorized);
+        assert_eq!(get_status(Some("")), unauthorized);
+        assert_eq!(get_status(Some("wrong_secret")), unauthorized);
+        assert_eq!(get("status?token=admin_secret", &router), unauthorized);
+
+        let (body, status) = get_status(Some("admin_secret"));
+        assert_eq!(status, status::Ok);
+        assert!(!body.contains("secret-token"));
+        assert!(!body.contains("status@example.com"));
+
+        let server_status: ServerStatus = serde_json::from_str(&body).unwrap();
+        assert_eq!(server_status.domains, 4);
+        assert_eq!(server_status.accounts, 1);
+        assert_eq!(server_status.last_seen.hour, 1);
+        assert_eq!(server_status.last_seen.day, 2);
+        assert_eq!(server_status.last_seen.week, 3);
+        assert_eq!(server_status.last_seen.month, 3);
+        // All the domains were just subscribed, whatever their last ping.
+        assert_eq!(server_status.created.hour, 4);
+        assert_eq!(server_status.created.month, 4);
+
+        // The admin routes can be kept to the API socket.
+        let mut socket_config = config.clone();
+        socket_config.options.general.api_socket_path = Some("/tmp/api.sock".to_owned());
+        socket_config.options.general.admin_socket_only = true;
+        let socket_router = create_router(&socket_config);
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer {
+            token: "admin_secret".to_owned(),
+        }));
+        let resp = match iron_test::request::get("http://localhost/status", headers, &socket_router)
+        {
+            Ok(response) => response,
+            Err(err) => err.response,
+        };
+        assert_eq!(resp.status.unwrap(), status::Unauthorized);
+
+        // The clients of the socket need the token too.
+        let mut socket_chain = Chain::new(socket_router);
+        socket_chain.link_before(MarkLocalRequests);
+        let socket_get = |headers: Headers| -> Status {
+            match iron_test::request::get("http://localhost/status", headers, &socket_chain) {
+                Ok(response) => response.status.unwrap(),
+                Err(err) => err.response.status.unwrap(),
+            }
+        };
+        assert_eq!(socket_get(Headers::new()), status::Unauthorized);
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer {
+            token: "admin_secret".to_owned(),
+        }));
+        assert_eq!(socket_get(headers), status::Ok);
+
+        // The endpoint is turned off when no admin token is configured.
+        let mut config = config.clone();
+        config.options.general.admin_token = None;
+        let router = create_router(&config);
+        let resp = match iron_test::request::get("http://localhost/status", Headers::new(), &router)
+        {
+            Ok(response) => response,
+            Err(err) => err.response,
+        };
+        assert_eq!(resp.status.unwrap(), status::Unauthorized);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_rate_limits");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_rate_limits");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_rate_limits.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.general.max_subscribes_per_hour = 2;
+        config.options.general.max_emails_per_hour = 1;
+        config.rate_limits = RateLimits::new(&config.options.general);
+        let router = create_router(&config);
+
+        let get_from = |path: &str, ip: &str| -> Response {
+            let mut headers = Headers::new();
+            headers.set_raw("X-Real-IP", vec![ip.as_bytes().to_vec()]);
+            match iron_test::request::get(&format!("http://localhost/{}", path), headers, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            }
+        };
+        let rate_limited = error_response(ApiError::RateLimited);
+
+        let resp = get_from("subscribe?name=first", "1.2.3.4");
+        assert_eq!(resp.status.unwrap(), status::Ok);
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+        let resp = get_from("subscribe?name=second", "::ffff:1.2.3.4");
+        assert_eq!(resp.status.unwrap(), status::Ok);
+
+        // The third subscription within an hour is rejected.
+        let resp = get_from("subscribe?name=third", "1.2.3.4");
+        assert_eq!(resp.headers.get::<RetryAfter>(), Some(&RetryAfter(1800)));
+        let status = resp.status.unwrap();
+        assert_eq!(
+            (response::extract_body_to_string(resp), status),
+            rate_limited
+        );
+        assert_eq!(
+            conn.get_domain_by_name("third.mydomain.org."),
+            Err(diesel::result::Error::NotFound)
+        );
+
+        // Other clients are not affected.
+        let resp = get_from("subscribe?name=third", "5.6.7.8");
+        assert_eq!(resp.status.unwrap(), status::Ok);
+
+        // Pings and info requests are not limited.
+        for _ in 0..5 {
+            let resp = get_from(&format!("ping?token={}", registration.token), "1.2.3.4");
+            assert_eq!(resp.status.unwrap(), status::Ok);
+            let resp = get_from(&format!("info?token={}", registration.token), "1.2.3.4");
+            assert_eq!(resp.status.unwrap(), status::Ok);
+        }
+
+        // Email requests have their own limit.
+        let resp = get_from("reclaim?name=first", "1.2.3.4");
+        assert_eq!(resp.status.unwrap(), status::BadRequest);
+        let resp = get_from("reclaim?name=first", "1.2.3.4");
+        assert_eq!(resp.status.unwrap(), status::TooManyRequests);
+        let resp = get_from(
+            &format!(
+                "setemail?token={}&email=test@example.com",
+                registration.token
+            ),
+            "1.2.3.4",
+        );
+        assert_eq!(resp.status.unwrap(), status::TooManyRequests);
+    }
+
+    #[test]
+    fn test_signed_pings() {
+        use eviction::now;
+        use ping_auth::sign;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_signed");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_signed");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_signed.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let invalid_signature = error_response(ApiError::InvalidSignature);
+        let auth_mode = |token: &str, router: &Router| -> String {
+            let response = get(&format!("info?token={}", token), router);
+            assert_eq!(response.1, status::Ok);
+            let info: serde_json::Value = serde_json::from_str(&response.0).unwrap();
+            assert!(info.get("auth_key").is_none());
+            info["auth_mode"].as_str().unwrap().to_owned()
+        };
+
+        // The legacy path: no secret, and the token is enough.
+        let response = get("subscribe?name=legacy", &router);
+        assert_eq!(response.1, status::Ok);
+        assert!(!response.0.contains("secret"));
+        let legacy: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(
+            get(&format!("ping?token={}", legacy.token), &router),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(auth_mode(&legacy.token, &router), "token");
+
+        assert_eq!(
+            get("subscribe?name=signed&signed=maybe", &router),
+            error_response(ApiError::InvalidParameter)
+        );
+        let response = get("subscribe?name=signed&signed=true", &router);
+        assert_eq!(response.1, status::Ok);
+        let signed: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let secret = signed.secret.clone().unwrap();
+        assert_eq!(auth_mode(&signed.token, &router), "signed");
+
+        // Only the hash of the secret is stored.
+        let key = conn.get_domain_by_token(&signed.token).unwrap().auth_key;
+        assert_eq!(key, auth_key(&secret));
+        assert!(!key.contains(&secret));
+
+        // Signed domains need a valid MAC.
+        let nonce = now().to_string();
+        let ping = |nonce: &str, mac: &str| {
+            get(
+                &format!("ping?token={}&nonce={}&mac={}", signed.token, nonce, mac),
+                &router,
+            )
+        };
+        assert_eq!(
+            get(&format!("ping?token={}", signed.token), &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            ping(&nonce, &sign(&auth_key("wrong-secret"), &nonce)),
+            invalid_signature
+        );
+        assert_eq!(ping(&nonce, &sign(&secret, &nonce)), invalid_signature);
+        let stale = (now() - 3600).to_string();
+        assert_eq!(ping(&stale, &sign(&key, &stale)), invalid_signature);
+
+        // A valid ping can't be replayed.
+        let mac = sign(&key, &nonce);
+        assert_eq!(ping(&nonce, &mac), ("".to_owned(), status::Ok));
+        assert_eq!(ping(&nonce, &mac), invalid_signature);
+
+        // Reclaiming without `signed` goes back to the legacy path.
+        conn.update_domain_reclamation_token(&signed.token, &auth_key("reclaim-token"))
+            .unwrap();
+        let response = get(
+            "subscribe?name=signed&reclamationToken=reclaim-token",
+            &router,
+        );
+        assert_eq!(response.1, status::Ok);
+        let reclaimed: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(reclaimed.secret, None);
+        assert_eq!(auth_mode(&reclaimed.token, &router), "token");
+
+        // Unsigned pings can be turned off.
+        config.options.general.require_signed_pings = true;
+        let router = create_router(&config);
+        assert_eq!(
+            get(&format!("ping?token={}", legacy.token), &router),
+            invalid_signature
+        );
+    }
+
+    #[test]
+    fn test_signed_requests() {
+        use ping_auth::{request_message, sign};
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_signed_requests");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_signed_requests");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_signed_requests.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let empty_ok = ("".to_owned(), status::Ok);
+        let invalid_signature = error_response(ApiError::InvalidSignature);
+
+        let response = get("subscribe?name=signed&signed=true", &router);
+        assert_eq!(response.1, status::Ok);
+        let signed: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let key = auth_key(&signed.secret.clone().unwrap());
+        let token = signed.token;
+
+        // Sends a request with this X-Signature header.
+        let send = |method: &str,
+                    path: &str,
+                    query: &str,
+                    body: &str,
+                    signature: &str,
+                    router: &Router| {
+            let mut headers = Headers::new();
+            if !signature.is_empty() {
+                headers.set_raw("X-Signature", vec![signature.as_bytes().to_vec()]);
+            }
+            let url = format!("http://localhost/{}?{}", path, query);
+            let resp = match method {
+                "GET" => iron_test::request::get(&url, headers, router),
+                "POST" => iron_test::request::post(&url, headers, body, router),
+                _ => iron_test::request::put(&url, headers, body, router),
+            };
+            let resp = match resp {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+        let signature = |nonce: &str, method: &str, path: &str, query: &str, body: &str| {
+            let message = request_message(nonce, method, &format!("/{}", path), query, body);
+            format!("{}:{}", nonce, sign(&key, &message))
+        };
+
+        // A signed ping doesn't need a nonce and a MAC.
+        let nonce = now().to_string();
+        let query = format!("token={}", token);
+        let valid = signature(&nonce, "GET", "ping", &query, "");
+        assert_eq!(send("GET", "ping", &query, "", &valid, &router), empty_ok);
+        assert_eq!(
+            send("GET", "ping", &query, "", &valid, &router),
+            invalid_signature
+        );
+        assert_eq!(
+            send("GET", "ping", &query, "", "garbage", &router),
+            error_response(ApiError::InvalidParameter)
+        );
+
+        // The signature covers the method, the path, the query and the body.
+        let body = format!("{{\"token\":\"{}\",\"challenge\":\"challenge\"}}", token);
+        for other in &[
+            signature(&nonce, "GET", "dnsconfig", "", &body),
+            signature(&nonce, "POST", "ping", "", &body),
+            signature(&nonce, "POST", "dnsconfig", "debug=true", &body),
+            signature(&nonce, "POST", "dnsconfig", "", "{}"),
+        ] {
+            assert_eq!(
+                send("POST", "dnsconfig", "", &body, other, &router),
+                invalid_signature
+            );
+        }
+        let valid = signature(&nonce, "POST", "dnsconfig", "", &body);
+        assert_eq!(
+            send("POST", "dnsconfig", "", &body, &valid, &router),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().dns_challenge,
+            "challenge"
+        );
+        let valid = signature(&nonce, "GET", "clearchallenge", &query, "");
+        assert_eq!(
+            send("GET", "clearchallenge", &query, "", &valid, &router),
+            empty_ok
+        );
+        assert_eq!(conn.get_domain_by_token(&token).unwrap().dns_challenge, "");
+        let path = format!("domains/{}/ip", token);
+        let valid = signature(&nonce, "PUT", &path, "", "");
+        assert_eq!(send("PUT", &path, "", "", &valid, &router), empty_ok);
+
+        // Unknown tokens are still answered by the handler.
+        assert_eq!(
+            send("GET", "ping", "token=unknown", "", &valid, &router),
+            error_response(ApiError::UnknownToken)
+        );
+
+        // Unsigned requests can be turned off.
+        config.options.general.require_signed_requests = true;
+        let router = create_router(&config);
+        let body = format!("{{\"token\":\"{}\",\"challenge\":\"challenge\"}}", token);
+        assert_eq!(
+            send("POST", "dnsconfig", "", &body, "", &router),
+            invalid_signature
+        );
+        let nonce = (now() + 1).to_string();
+        let valid = signature(&nonce, "POST", "dnsconfig", "", &body);
+        assert_eq!(
+            send("POST", "dnsconfig", "", &body, &valid, &router),
+            empty_ok
+        );
+
+        // The other routes don't need a signature.
+        assert_eq!(get(&format!("info?token={}", token), &router).1, status::Ok);
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_concurrency");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_concurrency");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_concurrency.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = Arc::new(create_router(&config));
+
+        // Each request gets its own connection from the pool, so they don't
+        // have to wait for each other.
+        let threads: Vec<_> = (0..50)
+            .map(|index| {
+                let router = router.clone();
+                thread::spawn(move || {
+                    let res = get(&format!("subscribe?name=concurrent{}", index), &router);
+                    assert_eq!(res.1, status::Ok);
+                    let registration: NameAndToken = serde_json::from_str(&res.0).unwrap();
+                    let res = get(&format!("ping?token={}", registration.token), &router);
+                    assert_eq!(res, ("".to_owned(), status::Ok));
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("Request thread panicked");
+        }
+
+        assert_eq!(conn.count_domains(), Ok(50));
+    }
+
+    #[test]
+    fn test_checkname() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_checkname");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_checkname");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_checkname.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let check = |name: &str| -> NameAvailability {
+            let response = get(&format!("checkname?name={}", name), &router);
+            assert_eq!(response.1, status::Ok);
+            serde_json::from_str(&response.0).unwrap()
+        };
+        let unavailable = |reason: &str| NameAvailability {
+            available: false,
+            reason: Some(reason.to_owned()),
+        };
+
+        assert_eq!(
+            get("checkname", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("checkname?name=free", &router),
+            (r#"{"available":true}"#.to_owned(), status::Ok)
+        );
+        assert_eq!(check("-bad"), unavailable("invalid"));
+        assert_eq!(check("www"), unavailable("reserved"));
+
+        // Checking a name doesn't reserve it.
+        assert_eq!(conn.count_domains(), Ok(0));
+        assert_eq!(get("subscribe?name=Free", &router).1, status::Ok);
+        assert_eq!(check("free"), unavailable("taken"));
+        assert_eq!(check("FREE"), unavailable("taken"));
+    }
+
+    #[test]
+    fn test_captcha() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_captcha");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_captcha");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_captcha.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+
+        // Without a secret, no CAPTCHA is needed.
+        let router = create_router(&config);
+        let response = get("subscribe?name=test", &router);
+        assert_eq!(response.1, status::Ok);
+        let n_and_t: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        conn.update_domain_reclamation_token(&n_and_t.token, &auth_key("reclaim-me"))
+            .expect("Setting the reclamation token");
+
+        // Nothing listens on this port, so the solutions can't be verified.
+        config.options.general.captcha_secret = Some("captcha-secret".to_owned());
+        config.options.general.captcha_verify_url = "http://127.0.0.1:1/".to_owned();
+        let router = create_router(&config);
+        assert_eq!(
+            get("subscribe?name=other", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("subscribe?name=other&captcha=solution", &router),
+            error_response(ApiError::CaptchaFailure)
+        );
+        assert_eq!(conn.count_domains(), Ok(1));
+
+        // Taken names and reclamations are not checked.
+        assert_eq!(
+            get("subscribe?name=test", &router),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(
+            get("subscribe?name=test&reclamationToken=reclaim-me", &router).1,
+            status::Ok
+        );
+    }
+
+    #[test]
+    fn test_oidc() {
+        use iron::headers::Location;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_oidc");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_oidc");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_oidc.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+
+        // Without a client ID, the routes don't exist.
+        let router = create_router(&config);
+        assert_eq!(get("oidc/login", &router).1, status::NotFound);
+
+        // Nothing listens on this port, so the codes can't be exchanged.
+        config.options.general.oidc_client_id = Some("client-id".to_owned());
+        config.options.general.oidc_token_url = "http://127.0.0.1:1/token".to_owned();
+        let router = create_router(&config);
+        let login = |path: &str| -> String {
+            let resp = request(method::Method::Get, path, "", &router).unwrap();
+            assert_eq!(resp.status, Some(status::Found));
+            let location = resp.headers.get::<Location>().unwrap().0.clone();
+            assert!(location.starts_with(
+                "https://accounts.google.com/o/oauth2/v2/auth?response_type=code\
+                 &client_id=client-id&"
+            ));
+            location.split("&state=").nth(1).unwrap().to_owned()
+        };
+
+        let response = get("subscribe?name=test", &router);
+        let n_and_t: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(
+            get("oidc/login?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let state = login(&format!("oidc/login?token={}", n_and_t.token));
+        assert_ne!(login("oidc/login"), state);
+
+        assert_eq!(
+            get("oidc/callback?code=code", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("oidc/callback?code=code&state=unknown", &router),
+            error_response(ApiError::UnknownLogin)
+        );
+        assert_eq!(
+            get(&format!("oidc/callback?code=code&state={}", state), &router),
+            error_response(ApiError::LoginFailure)
+        );
+
+        // The state can't be used again, and the domain was left alone.
+        assert_eq!(
+            get(&format!("oidc/callback?code=code&state={}", state), &router),
+            error_response(ApiError::UnknownLogin)
+        );
+        assert!(!conn.get_domain_by_token(&n_and_t.token).unwrap().verified);
+    }
+
+    #[test]
+    fn test_device_token() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_device_token");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_device_token");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_device_token.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let empty_ok = ("".to_owned(), status::Ok);
+        let new_device_token = |token: &str| -> String {
+            let response = get(&format!("devicetoken?token={}", token), &router);
+            assert_eq!(response.1, status::Ok);
+            let device: DeviceToken = serde_json::from_str(&response.0).unwrap();
+            device.device_token
+        };
+
+        let response = get("subscribe?name=test", &router);
+        let n_and_t: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let token = n_and_t.token;
+
+        assert_eq!(
+            get("devicetoken", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("devicetoken?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let device_token = new_device_token(&token);
+        assert!(!device_token.is_empty() && device_token != token);
+
+        // The device token is enough for what the gateway does itself.
+        assert_eq!(
+            get(&format!("ping?token={}", device_token), &router),
+            empty_ok
+        );
+        assert_eq!(
+            get(
+                &format!("dnsconfig?token={}&challenge=challenge", device_token),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().dns_challenge,
+            "challenge"
+        );
+        assert_eq!(
+            get(
+                &format!(
+                    "dnsconfig?token={}&challenge=wildcard&append=true",
+                    device_token
+                ),
+                &router
+            ),
+            empty_ok
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&token).unwrap().dns_challenge,
+            "challenge,wildcard"
+        );
+        assert_eq!(
+            get(&format!("clearchallenge?token={}", device_token), &router),
+            empty_ok
+        );
+        assert_eq!(conn.get_domain_by_token(&token).unwrap().dns_challenge, "");
+
+        // The other routes need the token.
+        for path in &[
+            "info?token={}",
+            "unsubscribe?token={}",
+            "transfer?token={}",
+            "devicetoken?token={}",
+            "setwildcard?token={}&enabled=true",
+            "setmx?token={}&target=",
+        ] {
+            assert_eq!(
+                get(&path.replace("{}", &device_token), &router),
+                error_response(ApiError::UnknownToken)
+            );
+        }
+
+        // A new device token replaces the previous one.
+        let other_device_token = new_device_token(&token);
+        assert_ne!(other_device_token, device_token);
+        assert_eq!(
+            get(&format!("ping?token={}", device_token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("ping?token={}", other_device_token), &router),
+            empty_ok
+        );
+        assert_eq!(get(&format!("ping?token={}", token), &router), empty_ok);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_transfer");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_transfer");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_transfer.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let start_transfer = |token: &str| -> TransferCode {
+            let response = get(&format!("transfer?token={}", token), &router);
+            assert_eq!(response.1, status::Ok);
+            serde_json::from_str(&response.0).unwrap()
+        };
+        let unknown_code_error = error_response(ApiError::UnknownTransferCode);
+
+        // A domain with an email address, local addresses under its own
+        // prefix, the wildcard and signed pings.
+        let resp = get(
+            "subscribe?name=gateway&signed=true&local_prefix=lan",
+            &router,
+        );
+        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        let old_token = registration.token;
+        let account = conn.add_account("owner@example.com").unwrap();
+        conn.update_domain_verification_data(&old_token, Some(account.id), "", true)
+            .unwrap();
+        conn.update_domain_local_ips(&old_token, "192.168.1.2")
+            .unwrap();
+        conn.update_domain_wildcard(&old_token, true).unwrap();
+        let domain_id = conn.get_domain_by_token(&old_token).unwrap().id;
+        conn.add_txt_record(domain_id, "v=spf1 -all").unwrap();
+        conn.set_srv_record(&NewSrvRecord {
+            domain_id: domain_id,
+            service: "ssh",
+            protocol: "tcp",
+            priority: 10,
+            weight: 0,
+            port: 2222,
+        })
+        .unwrap();
+
+        assert_eq!(
+            get("transfer", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("transfer?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get("redeem", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(get("redeem?code=wrong_code", &router), unknown_code_error);
+
+        // A new code replaces the previous one, and the domain keeps working
+        // until a code is redeemed.
+        let first = start_transfer(&old_token);
+        assert!(first.expires_at >= now() + 7200 - 60);
+        let second = start_transfer(&old_token);
+        assert_ne!(first.code, second.code);
+        assert_eq!(
+            get(&format!("redeem?code={}", first.code), &router),
+            unknown_code_error
+        );
+        assert_eq!(
+            get(&format!("info?token={}", old_token), &router).1,
+            status::Ok
+        );
+
+        // The new owner gets a new token, and what belonged to the previous
+        // owner is reset.
+        let resp = get(&format!("redeem?code={}", second.code), &router);
+        assert_eq!(resp.1, status::Ok);
+        let redeemed: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        assert_eq!(redeemed.name, "gateway");
+        assert_ne!(redeemed.token, old_token);
+        assert_eq!(redeemed.secret, None);
+        assert_eq!(
+            get(&format!("info?token={}", old_token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let record = conn.get_domain_by_token(&redeemed.token).unwrap();
+        assert_eq!(record.name, "gateway.mydomain.org.");
+        assert_eq!(record.account_id, conn.get_unknown_account().unwrap().id);
+        assert!(!record.verified);
+        assert_eq!(record.local_ips, "");
+        assert_eq!(record.local_prefix, "");
+        assert!(!record.wildcard);
+        assert_eq!(auth_mode(&record), "token");
+        assert_eq!(conn.get_txt_records(record.id), Ok(vec![]));
+        assert_eq!(conn.get_srv_records(record.id), Ok(vec![]));
+
+        // A code can only be redeemed once.
+        assert_eq!(
+            get(&format!("redeem?code={}", second.code), &router),
+            unknown_code_error
+        );
+
+        // The new owner can ask for signed pings, and hand the domain over in
+        // turn.
+        let code = start_transfer(&redeemed.token).code;
+        let resp = get(&format!("redeem?code={}&signed=true", code), &router);
+        let signed: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+        assert!(signed.secret.is_some());
+        let record = conn.get_domain_by_token(&signed.token).unwrap();
+        assert_eq!(auth_mode(&record), "signed");
+
+        // Expired codes can't be redeemed, and are dropped with the next
+        // transfer.
+        conn.add_transfer(record.id, &auth_key("expired-code"), now() - 1, now())
+            .unwrap();
+        assert_eq!(get("redeem?code=expired-code", &router), unknown_code_error);
+        start_transfer(&signed.token);
+        assert!(conn
+            .get_transfer_by_code(&auth_key("expired-code"))
+            .is_err());
+
+        // Only the hash of a code is stored, and the pending transfers go
+        // away with their domain.
+        let code = start_transfer(&signed.token).code;
+        assert!(conn.get_transfer_by_code(&code).is_err());
+        assert!(conn.get_transfer_by_code(&auth_key(&code)).is_ok());
+        assert_eq!(
+            get(&format!("unsubscribe?token={}", signed.token), &router).1,
+            status::Ok
+        );
+        assert!(conn.get_transfer_by_code(&auth_key(&code)).is_err());
+    }
+
+    #[test]
+    fn test_admin_routes() {
+        use admin_routes::create_api_key;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_ban");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_ban");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_ban.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let admin = |path: &str, body: &str, token: Option<&str>| -> (String, Status) {
+            let mut headers = Headers::new();
+            if let Some(token) = token {
+                headers.set(Authorization(Bearer {
+                    token: token.to_owned(),
+                }));
+            }
+            headers.set(ContentType::json());
+            let url = format!("http://localhost/{}", path);
+            let resp = match iron_test::request::post(&url, headers, body, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+        let subscribe = |name: &str| -> String {
+            let resp = get(&format!("subscribe?name={}", name), &router);
+            assert_eq!(resp.1, status::Ok);
+            let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
+            registration.token
+        };
+        let secret = Some("admin_secret");
+
+        // Both routes require the admin token.
+        let unauthorized = error_response(ApiError::Unauthorized);
+        for path in &["admin/revoke", "admin/ban"] {
+            assert_eq!(admin(path, r#"{"name": "abuse"}"#, None), unauthorized);
+            assert_eq!(
+                admin(path, r#"{"name": "abuse"}"#, Some("wrong_secret")),
+                unauthorized
+            );
+            assert_eq!(
+                admin(path, "{}", secret),
+                error_response(ApiError::MissingParameter)
+            );
+            assert_eq!(
+                admin(path, r#"{"name": "-abuse"}"#, secret),
+                error_response(ApiError::InvalidName)
+            );
+        }
+
+        // A revoked name can be subscribed again.
+        let token = subscribe("revoked");
+        assert_eq!(
+            admin("admin/revoke", r#"{"name": "Revoked"}"#, secret),
+            (r#"{"accounts":0}"#.to_owned(), status::Ok)
+        );
+        assert_eq!(
+            get(&format!("info?token={}", token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            admin("admin/revoke", r#"{"name": "revoked"}"#, secret),
+            error_response(ApiError::NoSuchName)
+        );
+        subscribe("revoked");
+
+        // A banned name is removed and can't be subscribed again, even if it
+        // was not registered.
+        let token = subscribe("abuse");
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "abuse"}"#, secret),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "abuse"}"#, secret),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "unused"}"#, secret),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            get(&format!("info?token={}", token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let reserved_name_error = error_response(ApiError::ReservedName);
+        assert_eq!(get("subscribe?name=abuse", &router), reserved_name_error);
+        assert_eq!(get("subscribe?name=Unused", &router), reserved_name_error);
+        assert_eq!(
+            get("checkname?name=abuse", &router),
+            (
+                r#"{"available":false,"reason":"reserved"}"#.to_owned(),
+                status::Ok
+            )
+        );
+        assert_eq!(conn.is_name_banned("abuse.mydomain.org."), Ok(true));
+        assert_eq!(conn.is_name_banned("revoked.mydomain.org."), Ok(false));
+        assert_eq!(conn.count_domains(), Ok(1));
+
+        // The API keys work like the admin token, until they are rotated.
+        assert!(create_api_key("", &config).is_err());
+        let key = create_api_key("deploy", &config).unwrap();
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "spam"}"#, Some(&key)),
+            ("".to_owned(), status::Ok)
+        );
+        let rotated = create_api_key("deploy", &config).unwrap();
+        assert_ne!(key, rotated);
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "spam"}"#, Some(&key)),
+            unauthorized
+        );
+        assert_eq!(
+            admin("admin/ban", r#"{"name": "spam"}"#, Some(&rotated)),
+            ("".to_owned(), status::Ok)
+        );
+    }
+
+    #[test]
+    fn test_list_domains() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_list");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_list");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_list.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let list = |query: &str, admin_token: Option<&str>| -> (String, Status) {
+            let mut headers = Headers::new();
+            if let Some(token) = admin_token {
+                headers.set(Authorization(Bearer {
+                    token: token.to_owned(),
+                }));
+            }
+            let url = format!("http://localhost/domains?{}", query);
+            let resp = match iron_test::request::get(&url, headers, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+        let names = |response: (String, Status)| -> Vec<String> {
+            assert_eq!(response.1, status::Ok);
+            let list: DomainList = serde_json::from_str(&response.0).unwrap();
+            list.domains.into_iter().map(|domain| domain.name).collect()
+        };
+
+        // Two domains with a verified address, one waiting for verification,
+        // and one of someone else.
+        let owner = conn.add_account("owner@example.com").unwrap();
+        let other = conn.add_account("other@example.com").unwrap();
+        for &(label, account_id, verified) in &[
+            ("first", owner.id, true),
+            ("second", owner.id, true),
+            ("pending", owner.id, false),
+            ("others", other.id, true),
+        ] {
+            conn.add_domain(
+                &format!("{}.mydomain.org.", label),
+                account_id,
+                &format!("{}-token", label),
+                label,
+                1000,
+                "challenge",
+                "",
+                "",
+                verified,
+                "EU",
+            )
+            .unwrap();
+        }
+        let owned = vec![
+            "first.mydomain.org.".to_owned(),
+            "second.mydomain.org.".to_owned(),
+            "pending.mydomain.org.".to_owned(),
+        ];
+
+        assert_eq!(
+            list("", Some("admin_secret")),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            list("email=not_an_email", Some("admin_secret")),
+            error_response(ApiError::InvalidEmail)
+        );
+
+        // The admin sees the domains of any address.
+        assert_eq!(
+            names(list("email=owner@example.com", Some("admin_secret"))),
+            owned
+        );
+        assert_eq!(
+            names(list("email=nobody@example.com", Some("admin_secret"))),
+            Vec::<String>::new()
+        );
+
+        // The owners need the token of one of their verified domains.
+        let unauthorized = error_response(ApiError::Unauthorized);
+        assert_eq!(list("email=owner@example.com", None), unauthorized);
+        assert_eq!(
+            list("email=owner@example.com", Some("wrong_secret")),
+            unauthorized
+        );
+        assert_eq!(
+            list("email=owner@example.com&token=pending-token", None),
+            unauthorized
+        );
+        assert_eq!(
+            list("email=owner@example.com&token=others-token", None),
+            unauthorized
+        );
+        assert_eq!(
+            list("email=nobody@example.com&token=first-token", None),
+            unauthorized
+        );
+        let response = list("email=owner@example.com&token=second-token", None);
+        assert!(!response.0.contains("-token") && !response.0.contains("challenge"));
+        assert_eq!(names(response), owned);
+    }
+
+    #[test]
+    fn test_resources() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_resources");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_resources");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_resources.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.general.disable_get_routes = true;
+        let router = create_router(&config);
+        let send = |method: method::Method, path: &str, body: &str| -> (String, Status) {
+            let resp = match request(method, path, body, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+
+        // Create a domain.
+        assert_eq!(
+            send(method::Method::Post, "domains", ""),
+            error_response(ApiError::MalformedBody)
+        );
+        let response = send(method::Method::Post, "domains", r#"{"name": "resource"}"#);
+        assert_eq!(response.1, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let path = format!("domains/{}", registration.token);
+
+        // Read it, with the token of the path.
+        let response = send(method::Method::Get, &path, "");
+        assert_eq!(response.1, status::Ok);
+        let record: Domain = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(record.name, "resource.mydomain.org.");
+        assert_eq!(
+            send(method::Method::Get, "domains/wrong-token", ""),
+            error_response(ApiError::UnknownToken)
+        );
+
+        // Update its addresses, with or without a body.
+        assert_eq!(
+            send(method::Method::Put, &format!("{}/ip", path), ""),
+            ("".to_owned(), status::Ok)
+        );
+        let body = r#"{"token": "wrong-token", "local_ip": "10.0.0.2"}"#;
+        assert_eq!(
+            send(method::Method::Put, &format!("{}/ip", path), body),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            conn.get_domain_by_token(&registration.token)
+                .unwrap()
+                .local_ips,
+            "10.0.0.2"
+        );
+
+        // Delete it, which GET requests can't do.
+        assert_eq!(
+            send(method::Method::Get, "unsubscribe", "").1,
+            status::NotFound
+        );
+        assert_eq!(
+            send(method::Method::Get, &format!("{}/ip", path), "").1,
+            status::NotFound
+        );
+        assert_eq!(
+            send(method::Method::Delete, &path, ""),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            send(method::Method::Delete, &path, ""),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(conn.count_domains(), Ok(0));
+    }
+
+    #[test]
+    fn test_versions() {
+        use iron_test::request;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_versions");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_versions");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_versions.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let get = |url: &str, chain: &Chain| -> (String, Status) {
+            let resp = match request::get(url, Headers::new(), chain) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
         };
 
-        let mut stream = MockStream::new(Cursor::new(buffer.as_bytes().to_vec()));
-        let mut buf_reader = BufReader::new(&mut stream as &mut NetworkStream);
-        let http_request = hyper::server::Request::new(&mut buf_reader, addr).unwrap();
-        let mut req = Request::from_http(http_request, addr, &protocol).unwrap();
+        // The endpoints are served with and without the version prefix.
+        let chain = create_chain("/", &config);
+        let response = get("http://localhost/v1/subscribe?name=versioned", &chain);
+        assert_eq!(response.1, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let ping = format!("ping?token={}", registration.token);
+        assert_eq!(
+            get(&format!("http://localhost/v1/{}", ping), &chain),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            get(&format!("http://localhost/{}", ping), &chain),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            get("http://localhost/v1/ping?token=wrong", &chain),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("http://localhost/v2/{}", ping), &chain).1,
+            status::NotFound
+        );
+
+        // Under another root path too.
+        let chain = create_chain("/api", &config);
+        assert_eq!(
+            get(&format!("http://localhost/api/v1/{}", ping), &chain),
+            ("".to_owned(), status::Ok)
+        );
+        assert_eq!(
+            get(&format!("http://localhost/api/{}", ping), &chain),
+            ("".to_owned(), status::Ok)
+        );
+
+        // Taken names get a 409 status, or a 400 one with the old body for
+        // older clients, like the unknown tokens.
+        let taken = "http://localhost/subscribe?name=versioned";
+        let chain = create_chain("/", &config);
+        assert_eq!(
+            get(taken, &chain),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(get(taken, &chain).1, status::Conflict);
+        let mut config = config;
+        config.options.general.legacy_status_codes = true;
+        let chain = create_chain("/", &config);
+        assert_eq!(
+            get(taken, &chain),
+            (
+                r#"{"code":400,"errno":400,"error":"Bad Request"}"#.to_owned(),
+                status::BadRequest
+            )
+        );
+        assert_eq!(
+            get("http://localhost/info?token=unknown", &chain).1,
+            status::BadRequest
+        );
+
+        // The responses and the errors carry the id of their request, which
+        // the proxies of the tests can set.
+        let request_id = |url: &str, id: Option<&str>| -> String {
+            let mut headers = Headers::new();
+            if let Some(id) = id {
+                headers.set_raw("X-Request-Id", vec![id.as_bytes().to_vec()]);
+            }
+            let resp = match request::get(url, headers, &chain) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let id = resp.headers.get_raw("X-Request-Id").unwrap();
+            String::from_utf8(id[0].clone()).unwrap()
+        };
+        let ping = format!("http://localhost/{}", ping);
+        assert_eq!(request_id(&ping, Some("proxy-id-1")), "proxy-id-1");
+        assert_eq!(request_id(taken, Some("proxy-id-2")), "proxy-id-2");
+        let id = request_id(&ping, None);
+        assert!(id.parse::<Uuid>().is_ok());
+        assert_ne!(request_id(&ping, None), id);
+    }
+
+    #[test]
+    fn test_parameter_limits() {
+        use iron_test::{request, response};
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_parameter_limits");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_parameter_limits");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_parameter_limits.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let chain = create_chain("/", &config);
+        let send = |resp: IronResult<Response>| -> (String, Status) {
+            let resp = match resp {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+        let get = |path: &str| {
+            let url = format!("http://localhost/{}", path);
+            send(request::get(&url, Headers::new(), &chain))
+        };
+        let post = |path: &str, body: &str| {
+            let url = format!("http://localhost/{}", path);
+            send(request::post(&url, Headers::new(), body, &chain))
+        };
+
+        let desc = "d".repeat(256);
+        assert_eq!(
+            get(&format!("subscribe?name=limits&desc={}", desc)).1,
+            status::Ok
+        );
+        assert_eq!(
+            get(&format!("subscribe?name=limits2&desc={}d", desc)),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            post("subscribe", r#"{"name": "limits2", "desc": "line\nbreak"}"#),
+            error_response(ApiError::InvalidParameter)
+        );
+        assert_eq!(
+            get(&format!("checkname?name={}", "a".repeat(4096))),
+            error_response(ApiError::InvalidParameter)
+        );
+
+        // Bodies over 16 KiB are rejected before being parsed.
+        let body = format!(
+            r#"{{"name": "limits2", "padding": "{}"}}"#,
+            " ".repeat(16384)
+        );
+        assert_eq!(
+            post("subscribe", &body),
+            error_response(ApiError::PayloadTooLarge)
+        );
+        assert_eq!(post("subscribe", &body).1, status::PayloadTooLarge);
+        assert_eq!(
+            get("checkname?name=limits2"),
+            (r#"{"available":true}"#.to_owned(), status::Ok)
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        use health::{Health, Version};
+        use pdns::serve_socket;
+        use std::env;
+        use std::fs;
+        use std::os::unix::net::UnixListener;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_health");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_health");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_health.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let probe = |path: &str, config: &Config| -> (Health, Status) {
+            let (body, status) = get(path, &create_router(config));
+            (serde_json::from_str(&body).unwrap(), status)
+        };
+        let health = |config: &Config| probe("health", config);
+
+        // Without a PowerDNS socket, only the database is checked.
+        config.options.pdns.socket_path = None;
+        let (response, status) = health(&config);
+        assert_eq!(status, status::Ok);
+        assert!(response.healthy);
+        assert_eq!(response.checks.len(), 1);
+        assert!(response.checks["database"].healthy);
+
+        // The readiness also checks that the database was migrated.
+        let (response, status) = probe("readyz", &config);
+        assert_eq!(status, status::Ok);
+        assert!(response.healthy);
+        assert_eq!(response.checks.len(), 2);
+        assert!(response.checks["database"].healthy);
+        assert!(response.checks["migrations"].healthy);
+
+        let path = env::temp_dir().join("registration_server_test_health.sock");
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+        serve_socket(UnixListener::bind(&path).unwrap(), &config);
+        config.options.pdns.socket_path = Some(path.clone());
+        let (response, status) = health(&config);
+        assert_eq!(status, status::Ok);
+        assert!(response.checks["pdns"].healthy);
+        assert_eq!(response.checks["pdns"].error, None);
+
+        // Nothing serves this socket.
+        fs::remove_file(&path).unwrap();
+        let (response, status) = health(&config);
+        assert_eq!(status, status::ServiceUnavailable);
+        assert!(!response.healthy);
+        assert!(response.checks["database"].healthy);
+        assert!(!response.checks["pdns"].healthy);
+        assert!(response.checks["pdns"].error.is_some());
+        let (response, status) = probe("readyz", &config);
+        assert_eq!(status, status::ServiceUnavailable);
+        assert!(response.checks["migrations"].healthy);
+        assert!(!response.checks["pdns"].healthy);
+
+        // The liveness doesn't depend on the socket.
+        let (response, status) = probe("livez", &config);
+        assert_eq!(status, status::Ok);
+        assert!(response.healthy);
+        assert!(response.checks.is_empty());
+
+        let (body, status) = get("__version__", &create_router(&config));
+        assert_eq!(status, status::Ok);
+        let version: Version = serde_json::from_str(&body).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+        assert!(!version.commit.is_empty());
+        assert_eq!(version.build_date.len(), "2018-04-17T08:19:47Z".len());
+    }
+
+    #[test]
+    fn test_access_log() {
+        use iron_test::request;
+        use std::env;
+        use std::fs;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_access_log");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_access_log");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_access_log.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let path = env::temp_dir().join("registration_server_test_access.log");
+        let _ = fs::remove_file(&path);
+        config.options.general.access_log = Some(path.to_str().unwrap().to_owned());
+        let ping = |config: &Config| {
+            let chain = create_chain("/", config);
+            let _ = request::get("http://localhost/ping?token=wrong", Headers::new(), &chain);
+        };
+        let lines = || -> Vec<String> {
+            fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.to_owned())
+                .collect()
+        };
+
+        ping(&config);
+        let written = lines();
+        assert_eq!(written.len(), 1);
+        assert!(written[0].starts_with("INFO:access: ["));
+        assert!(written[0].contains(" GET /ping 404 "));
+        assert!(written[0].ends_with("ms"));
+
+        config.options.general.json_logs = true;
+        ping(&config);
+        let written = lines();
+        assert_eq!(written.len(), 2);
+        assert!(written[1].starts_with(r#"{"timestamp":"#));
+        assert!(written[1].contains(r#""target":"access""#));
+        assert!(written[1].contains(r#""method":"GET","endpoint":"/ping","status":404"#));
+        assert!(written[1].contains(r#""token_hash":""#));
+
+        config.options.general.disable_access_log = true;
+        ping(&config);
+        assert_eq!(lines().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_audit() {
+        use admin_routes::AuditPage;
+        use iron_test::request;
+        use models::AuditEvent;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_audit");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_audit");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_audit.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let chain = create_chain("/", &config);
+        let get = |path: &str| -> (String, Status) {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: "admin_secret".to_owned(),
+            }));
+            let url = format!("http://localhost/{}", path);
+            let resp = match request::get(&url, headers, &chain) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+        let page = |query: &str| -> AuditPage {
+            let (body, status) = get(&format!("admin/audit?{}", query));
+            assert_eq!(status, status::Ok);
+            serde_json::from_str(&body).unwrap()
+        };
+        let events = |name: &str| -> Vec<AuditEvent> { page(&format!("name={}", name)).events };
+
+        let (body, status) = get("subscribe?name=audited");
+        assert_eq!(status, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&body).unwrap();
+        let token = registration.token;
+
+        // Only the pings that change the local addresses are recorded.
+        let ping = format!("ping?token={}&local_ip=10.0.0.2", token);
+        assert_eq!(get(&ping).1, status::Ok);
+        assert_eq!(get(&ping).1, status::Ok);
+        assert_eq!(get(&format!("ping?token={}", token)).1, status::Ok);
+
+        // The failed requests are not recorded.
+        assert_eq!(get("unsubscribe?token=wrong").1, status::NotFound);
+        assert_eq!(get(&format!("unsubscribe?token={}", token)).1, status::Ok);
+
+        // The events outlive their domain.
+        let recorded = events("audited");
+        let actions: Vec<&str> = recorded.iter().map(|event| event.action.as_str()).collect();
+        assert_eq!(actions, vec!["subscribe", "ping", "unsubscribe"]);
+        for event in &recorded {
+            assert_eq!(event.name, "audited.mydomain.org.");
+            assert!(!event.client_ip.is_empty());
+            assert!(!event.request_id.is_empty());
+            assert!(!event.details.contains(&token));
+        }
+        assert_eq!(recorded[0].actor, "");
+        assert_eq!(recorded[0].details, r#"{"name":"audited"}"#);
+        assert_eq!(recorded[1].actor, recorded[2].actor);
+        assert_eq!(recorded[1].actor.len(), 16);
+        assert_eq!(
+            recorded[1].details,
+            r#"{"local_ip":"10.0.0.2","previous_local_ip":"","token":"[redacted]"}"#
+        );
+        assert!(events("unknown").is_empty());
+
+        // The events can be filtered by token, address and time, and are
+        // returned by pages.
+        let (_, status) = get("subscribe?name=other");
+        assert_eq!(status, status::Ok);
+        let token_events = page(&format!("token={}", token)).events;
+        assert_eq!(token_events, recorded[1..].to_vec());
+        let by_actor = page(&format!("actor={}", recorded[1].actor)).events;
+        assert_eq!(by_actor, token_events);
+        assert_eq!(page("").events.len(), 4);
+        assert_eq!(
+            page(&format!("ip={}", recorded[0].client_ip)).events.len(),
+            4
+        );
+        assert!(page("ip=192.0.2.1").events.is_empty());
+        let created_at = recorded[0].created_at;
+        assert_eq!(page(&format!("since={}", created_at)).events.len(), 4);
+        assert!(page(&format!("until={}", created_at - 1)).events.is_empty());
+
+        let first = page("limit=3");
+        assert_eq!(first.events, recorded);
+        assert_eq!(first.next, Some(recorded[2].id));
+        let last = page(&format!("limit=3&after={}", recorded[2].id));
+        assert_eq!(last.events.len(), 1);
+        assert_eq!(last.events[0].name, "other.mydomain.org.");
+        assert_eq!(last.next, None);
+        assert_eq!(page("").next, None);
+
+        assert_eq!(get("admin/audit?limit=0").1, status::BadRequest);
+        assert_eq!(get("admin/audit?limit=1001").1, status::BadRequest);
+        assert_eq!(get("admin/audit?ip=nowhere").1, status::BadRequest);
+        assert_eq!(get("admin/audit?since=yesterday").1, status::BadRequest);
+    }
+
+    #[test]
+    fn test_cors() {
+        use iron::headers::{AccessControlAllowMethods, AccessControlAllowOrigin};
+        use iron_test::request;
+
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_cors");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_cors");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_cors.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let chain = create_chain("/", &config);
+
+        let with_origin = |origin: &str| -> Headers {
+            let mut headers = Headers::new();
+            headers.set_raw("Origin", vec![origin.as_bytes().to_vec()]);
+            headers
+        };
+        let gateway = "http://gateway.local";
+        let allowed = AccessControlAllowOrigin::Value(gateway.to_owned());
+
+        // Preflight requests.
+        for path in &["subscribe", "ping"] {
+            let url = format!("http://localhost/{}", path);
+            let resp = request::options(&url, with_origin(gateway), &chain).unwrap();
+            assert_eq!(resp.status, Some(Status::NoContent));
+            assert_eq!(
+                resp.headers.get::<AccessControlAllowOrigin>(),
+                Some(&allowed)
+            );
+            assert_eq!(
+                resp.headers.get::<AccessControlAllowMethods>(),
+                Some(&AccessControlAllowMethods(vec![Method::Get, Method::Post]))
+            );
+        }
+
+        // Cross-origin requests, including failing ones.
+        let resp = request::get(
+            "http://localhost/subscribe?name=cors",
+            with_origin(gateway),
+            &chain,
+        )
+        .unwrap();
+        assert_eq!(resp.status, Some(Status::Ok));
+        assert_eq!(
+            resp.headers.get::<AccessControlAllowOrigin>(),
+            Some(&allowed)
+        );
+        let registration: NameAndToken =
+            serde_json::from_str(&response::extract_body_to_string(resp)).unwrap();
+
+        let resp = request::get(
+            &format!("http://localhost/ping?token={}", registration.token),
+            with_origin(gateway),
+            &chain,
+        )
+        .unwrap();
+        assert_eq!(
+            resp.headers.get::<AccessControlAllowOrigin>(),
+            Some(&allowed)
+        );
+        assert!(resp.headers.get::<AccessControlAllowMethods>().is_none());
+
+        let err = request::get(
+            "http://localhost/ping?token=wrong_token",
+            with_origin(gateway),
+            &chain,
+        )
+        .unwrap_err();
+        assert_eq!(err.response.status, Some(Status::NotFound));
+        assert_eq!(
+            err.response.headers.get::<AccessControlAllowOrigin>(),
+            Some(&allowed)
+        );
+
+        // No headers for other origins, for requests without an origin, and
+        // for the endpoints that are not meant to be called by web pages.
+        let url = "http://localhost/subscribe?name=cors";
+        let err = request::get(url, with_origin("http://evil.example"), &chain).unwrap_err();
+        assert!(err
+            .response
+            .headers
+            .get::<AccessControlAllowOrigin>()
+            .is_none());
+        let err = request::get(url, Headers::new(), &chain).unwrap_err();
+        assert!(err
+            .response
+            .headers
+            .get::<AccessControlAllowOrigin>()
+            .is_none());
+        let err =
+            request::get("http://localhost/status", with_origin(gateway), &chain).unwrap_err();
+        assert!(err
+            .response
+            .headers
+            .get::<AccessControlAllowOrigin>()
+            .is_none());
+
+        // Any origin can be allowed.
+        config.options.general.cors_origins = vec!["*".to_owned()];
+        let chain = create_chain("/", &config);
+        let resp = request::options(
+            "http://localhost/ping",
+            with_origin("http://evil.example"),
+            &chain,
+        )
+        .unwrap();
+        assert_eq!(
+            resp.headers.get::<AccessControlAllowOrigin>(),
+            Some(&AccessControlAllowOrigin::Any)
+        );
+    }
+
+    #[test]
+    fn test_undelete() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_undelete");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_undelete");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_undelete.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.general.undelete_grace = 3600;
+        let router = create_router(&config);
+        let empty_ok = ("".to_owned(), status::Ok);
+
+        let response = get("subscribe?name=kept&email=kept@example.com", &router);
+        assert_eq!(response.1, status::Ok);
+        let kept: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(
+            get(&format!("settxt?token={}&value=hello", kept.token), &router),
+            empty_ok
+        );
+        let record = conn.get_domain_by_token(&kept.token).unwrap();
+        conn.set_srv_record(&NewSrvRecord {
+            domain_id: record.id,
+            service: "http",
+            protocol: "tcp",
+            priority: 0,
+            weight: 0,
+            port: 8080,
+        })
+        .unwrap();
+        let record = conn.get_domain_by_token(&kept.token).unwrap();
+
+        // The name stays taken once unsubscribed.
+        assert_eq!(
+            get(&format!("unsubscribe?token={}", kept.token), &router),
+            empty_ok
+        );
+        assert_eq!(conn.count_domains(), Ok(0));
+        assert_eq!(conn.get_txt_records(record.id), Ok(vec![]));
+        assert_eq!(conn.get_srv_records(record.id), Ok(vec![]));
+        assert_eq!(
+            get(&format!("info?token={}", kept.token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get("subscribe?name=kept", &router),
+            error_response(ApiError::UnavailableName)
+        );
+        assert_eq!(
+            get("checkname?name=kept", &router),
+            (
+                r#"{"available":false,"reason":"taken"}"#.to_owned(),
+                status::Ok
+            )
+        );
+
+        // Undeleting restores the domain, its account and its records, once.
+        assert_eq!(
+            get("undelete", &router),
+            error_response(ApiError::MissingParameter)
+        );
+        assert_eq!(
+            get("undelete?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let response = get(&format!("undelete?token={}", kept.token), &router);
+        assert_eq!(response.1, status::Ok);
+        let restored: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(restored.name, "kept");
+        assert_eq!(restored.token, kept.token);
+        assert_eq!(restored.secret, None);
+        assert_eq!(
+            get(&format!("undelete?token={}", kept.token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+
+        let mut restored = conn.get_domain_by_token(&kept.token).unwrap();
+        assert_eq!(
+            conn.get_account_by_id(restored.account_id).unwrap().email,
+            "kept@example.com"
+        );
+        assert_eq!(
+            conn.get_txt_records(restored.id),
+            Ok(vec!["hello".to_owned()])
+        );
+        let services = conn.get_srv_records(restored.id).unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].port, 8080);
+        restored.id = record.id;
+        restored.account_id = record.account_id;
+        assert_eq!(restored, record);
+
+        // So can a domain unsubscribed with its reclamation token.
+        let response = get("subscribe?name=reclaimed", &router);
+        let reclaimed: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        conn.update_domain_reclamation_token(&reclaimed.token, &auth_key("reclaim-token"))
+            .unwrap();
+        assert_eq!(
+            get("unsubscribe?reclamationToken=reclaim-token", &router),
+            empty_ok
+        );
+        assert!(conn.get_domain_by_token(&reclaimed.token).is_err());
+        assert_eq!(
+            get("subscribe?name=reclaimed", &router),
+            error_response(ApiError::UnavailableName)
+        );
+        let response = get(&format!("undelete?token={}", reclaimed.token), &router);
+        assert_eq!(response.1, status::Ok);
+        assert_eq!(
+            conn.get_domain_by_token(&reclaimed.token).unwrap().name,
+            "reclaimed.mydomain.org."
+        );
 
-        router.handle(&mut req)
+        // Past the grace period, the domain can't be undeleted and its name
+        // is free again.
+        let response = get("subscribe?name=old", &router);
+        let old: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let deleted = NewDeletedDomain {
+            name: "old.mydomain.org.",
+            token: &old.token,
+            record: "{}",
+            deleted_at: now() - 7200,
+        };
+        assert_eq!(conn.move_domain_to_deleted(&old.token, &deleted), Ok(1));
+        assert_eq!(
+            get(&format!("undelete?token={}", old.token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(get("subscribe?name=old", &router).1, status::Ok);
+
+        // Without a grace period, the domains are removed right away.
+        config.options.general.undelete_grace = 0;
+        let router = create_router(&config);
+        let response = get("subscribe?name=gone", &router);
+        let gone: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(
+            get(&format!("unsubscribe?token={}", gone.token), &router),
+            empty_ok
+        );
+        config.options.general.undelete_grace = 3600;
+        let router = create_router(&config);
+        assert_eq!(
+            get(&format!("undelete?token={}", gone.token), &router),
+            error_response(ApiError::UnknownToken)
+        );
     }
 
     #[test]
-    fn test_router() {
+    fn test_eviction_policy() {
+        use config::EvictionPolicy;
+
         let _ = env_logger::init();
 
         #[cfg(feature = "mysql")]
-        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_routes");
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_eviction_policy");
         #[cfg(feature = "postgres")]
-        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_routes");
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_eviction_policy");
         #[cfg(feature = "sqlite")]
-        let db = DatabasePool::new("domain_db_test_routes.sqlite");
+        let db = DatabasePool::new("domain_db_test_eviction_policy.sqlite");
         let conn = db.get_connection().expect("Getting connection.");
         conn.flush().expect("Flushing the db");
 
@@ -595,288 +5262,475 @@ mod tests {
             "registration_server",
             "--config-file=./config/config.toml",
         ]);
-        let config = Config::from_args_with_db(args, db.clone());
-        let router = create_router(&config);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        let account = conn.get_unknown_account().unwrap();
+        conn.add_domain(
+            "lapsed.mydomain.org.",
+            account.id,
+            "lapsed-token",
+            "",
+            now() - 31 * 86400,
+            "",
+            "",
+            "",
+            false,
+            "",
+        )
+        .unwrap();
+        let info = |router: &Router| -> serde_json::Value {
+            let response = get("info?token=lapsed-token", router);
+            assert_eq!(response.1, status::Ok);
+            serde_json::from_str(&response.0).unwrap()
+        };
 
-        let bad_request_error = (
-            r#"{"code":400,"errno":400,"error":"Bad Request"}"#.to_owned(),
-            status::BadRequest,
-        );
-        let not_found_error = (
-            r#"{"code":404,"errno":404,"error":"Not Found"}"#.to_owned(),
-            status::NotFound,
+        // The lapsed domain never expires.
+        config.options.general.eviction_policy = EvictionPolicy::Never;
+        let router = create_router(&config);
+        assert_eq!(info(&router)["live"], true);
+        assert!(info(&router).get("expires_at").is_none());
+        assert_eq!(
+            get("settxt?token=lapsed-token&value=kept", &router),
+            ("".to_owned(), status::Ok)
         );
-        let empty_ok = ("".to_owned(), status::Ok);
 
-        // Subscribe a test user.
-        assert_eq!(get("subscribe", &router), bad_request_error);
+        // Its token is refused while it waits to be removed.
+        config.options.general.eviction_policy = EvictionPolicy::Hard;
+        let router = create_router(&config);
+        assert_eq!(info(&router)["live"], false);
         assert_eq!(
-            get("subscribe?name=", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("ping?token=lapsed-token", &router),
+            error_response(ApiError::UnknownToken)
         );
         assert_eq!(
-            get("subscribe?name=-test", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("settxt?token=lapsed-token&value=refused", &router),
+            error_response(ApiError::UnknownToken)
         );
         assert_eq!(
-            get("subscribe?name=test-", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("setwildcard?token=lapsed-token&enabled=true", &router),
+            error_response(ApiError::UnknownToken)
         );
+        assert!(!conn.get_domain_by_token("lapsed-token").unwrap().wildcard);
         assert_eq!(
-            get("subscribe?name=api", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("setmx?token=lapsed-token&target=mail.example.org", &router),
+            error_response(ApiError::UnknownToken)
         );
         assert_eq!(
-            get("subscribe?name=www", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            conn.get_domain_by_token("lapsed-token").unwrap().mx_target,
+            ""
         );
+
+        // It stops resolving, but pinging brings it back.
+        config.options.general.eviction_policy = EvictionPolicy::Soft;
+        let router = create_router(&config);
+        assert_eq!(info(&router)["live"], false);
         assert_eq!(
-            get(
-                "subscribe?name=abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxy\
-                 zabcdefghijklmnopqrstuvwxyz",
-                &router
-            ),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("ping?token=lapsed-token", &router),
+            ("".to_owned(), status::Ok)
         );
+        assert_eq!(info(&router)["live"], true);
+        assert_eq!(conn.count_domains(), Ok(1));
+    }
 
-        let resp = get("subscribe?name=test", &router);
-        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
-        let token = registration.token;
+    #[test]
+    fn test_admin_export() {
+        use dump::Dump;
 
-        assert_eq!(registration.name, "test".to_owned());
+        let _ = env_logger::init();
 
-        // Unsubscribe
-        assert_eq!(get("unsubscribe", &router), bad_request_error);
-        assert_eq!(
-            get("unsubscribe?token=wrong_token", &router),
-            bad_request_error
-        );
-        assert_eq!(
-            get(&format!("unsubscribe?token={}", token), &router),
-            empty_ok
-        );
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_export");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_export");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_export.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
 
-        // Subscribe again
-        let resp = get("subscribe?name=test", &router);
-        let registration: NameAndToken = serde_json::from_str(&resp.0).unwrap();
-        let token = registration.token;
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let router = create_router(&config);
+        let admin = |path: &str, token: &str| -> (String, Status, Option<String>) {
+            let mut headers = Headers::new();
+            headers.set(Authorization(Bearer {
+                token: token.to_owned(),
+            }));
+            let url = format!("http://localhost/{}", path);
+            let resp = match iron_test::request::get(&url, headers, &router) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            let content_type = resp
+                .headers
+                .get::<ContentType>()
+                .map(|value| value.to_string());
+            (response::extract_body_to_string(resp), status, content_type)
+        };
 
-        assert_eq!(registration.name, "test".to_owned());
+        let response = get("subscribe?name=exported&email=owner@example.com", &router);
+        assert_eq!(response.1, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&response.0).unwrap();
 
-        // Fail to register the same name twice.
-        let res = get("subscribe?name=test", &router);
+        let (body, status, _) = admin("admin/export", "wrong_secret");
+        assert_eq!((body, status), error_response(ApiError::Unauthorized));
+        let (body, status, _) = admin("admin/export?format=xml", "admin_secret");
+        assert_eq!((body, status), error_response(ApiError::InvalidParameter));
+        let (body, status, _) = admin("admin/export?tokens=maybe", "admin_secret");
+        assert_eq!((body, status), error_response(ApiError::InvalidParameter));
+
+        // The tokens are only exported when asked for.
+        let (body, status, content_type) = admin("admin/export", "admin_secret");
+        assert_eq!(status, status::Ok);
+        assert_eq!(content_type, Some("application/json".to_owned()));
+        let dump: Dump = serde_json::from_str(&body).unwrap();
+        assert!(dump.redacted);
+        assert_eq!(dump.domains.len(), 1);
+        assert_eq!(dump.domains[0].name, "exported.mydomain.org.");
+        assert_eq!(dump.domains[0].email, "owner@example.com");
+        assert_eq!(dump.domains[0].token, "");
+
+        let (body, _, _) = admin("admin/export?tokens=true", "admin_secret");
+        let dump: Dump = serde_json::from_str(&body).unwrap();
+        assert!(!dump.redacted);
+        assert_eq!(dump.domains[0].token, registration.token);
+
+        let (body, status, content_type) = admin("admin/export?format=csv", "admin_secret");
+        assert_eq!(status, status::Ok);
+        assert_eq!(content_type, Some("text/csv; charset=utf-8".to_owned()));
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("name,email,token,"));
+        assert!(lines[1].starts_with("exported.mydomain.org.,owner@example.com,,"));
+    }
+
+    #[test]
+    fn test_discovery() {
+        let _ = env_logger::init();
+
+        #[cfg(feature = "mysql")]
+        let db = DatabasePool::new("mysql://root@127.0.0.1/domain_db_test_discovery");
+        #[cfg(feature = "postgres")]
+        let db = DatabasePool::new("postgres://postgres@127.0.0.1/domain_db_test_discovery");
+        #[cfg(feature = "sqlite")]
+        let db = DatabasePool::new("domain_db_test_discovery.sqlite");
+        let conn = db.get_connection().expect("Getting connection.");
+        conn.flush().expect("Flushing the db");
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let mut config = Config::from_args_with_db(args, db.clone());
+        config.options.general.undelete_grace = 3600;
+        let router = create_router(&config);
+        let empty_ok = ("".to_owned(), status::Ok);
+        let list = |token: &str| -> Vec<DiscoveryTokenInfo> {
+            let response = get(&format!("discoverytokens?token={}", token), &router);
+            assert_eq!(response.1, status::Ok);
+            let list: DiscoveryTokenList = serde_json::from_str(&response.0).unwrap();
+            list.tokens
+        };
+
+        let response = get("subscribe?name=test", &router);
+        let n_and_t: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        let token = n_and_t.token;
         assert_eq!(
-            res,
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get(&format!("ping?token={}&local_ip=10.0.0.2", token), &router),
+            empty_ok
         );
 
-        // Test reclaiming domain
-        let email = "test@example.com".to_owned();
         assert_eq!(
-            get("subscribe?name=test&email=", &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get(&format!("adddiscovery?token={}", token), &router),
+            error_response(ApiError::MissingParameter)
         );
         assert_eq!(
-            get(&format!("subscribe?name=test&email={}", email), &router),
-            (
-                r#"{"error": "UnavailableName"}"#.to_owned(),
-                status::BadRequest
-            )
+            get("adddiscovery?token=wrong_token&label=phone", &router),
+            error_response(ApiError::UnknownToken)
         );
-        assert_eq!(get("reclaim", &router), bad_request_error);
-        let res = get("reclaim?name=nonexistent", &router);
+        let long_label = "x".repeat(64);
+        for label in &["%20%20", "a%0Ab", long_label.as_str()] {
+            assert_eq!(
+                get(
+                    &format!("adddiscovery?token={}&label={}", token, label),
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+        }
+        assert_eq!(list(&token), vec![]);
+
+        // The token is only returned when it is added.
+        let response = get(
+            &format!("adddiscovery?token={}&label=Living%20room%20tablet", token),
+            &router,
+        );
+        assert_eq!(response.1, status::Ok);
+        let added: DiscoveryTokenInfo = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(added.label, "Living room tablet");
+        assert!(added.created_at > 0);
+        assert_eq!(added.last_used_at, 0);
+        assert_eq!(added.expires_at, 0);
+        let discovery_token = added.token.clone().unwrap();
+        assert!(!discovery_token.is_empty() && discovery_token != token);
         assert_eq!(
-            res,
-            (r#"{"error": "NoSuchName"}"#.to_owned(), status::BadRequest)
+            list(&token),
+            vec![DiscoveryTokenInfo {
+                id: added.id,
+                token: None,
+                label: added.label.clone(),
+                created_at: added.created_at,
+                last_used_at: 0,
+                expires_at: 0,
+            }]
         );
-        let res = get("reclaim?name=test", &router);
         assert_eq!(
-            res,
-            (r#"{"error": "NoEmail"}"#.to_owned(), status::BadRequest)
+            get("discoverytokens?token=wrong_token", &router),
+            error_response(ApiError::UnknownToken)
         );
 
-        // Ping without the expected parameters.
-        assert_eq!(get("ping", &router), bad_request_error);
-        assert_eq!(get("ping?name=test", &router), bad_request_error);
-        assert_eq!(get("ping?token=wrong_token", &router), not_found_error);
-
-        // Ping properly.
-        sleep(time::Duration::from_secs(1));
-        assert_eq!(get(&format!("ping?token={}", token), &router), empty_ok);
-
-        // Get the full info
-        assert_eq!(get("info", &router), bad_request_error);
-        assert_eq!(get("info?token=wrong_token", &router), not_found_error);
-
-        let response = get(&format!("info?token={}", token), &router);
+        // The discovery token finds the gateway, and nothing else.
+        let response = get(&format!("discovery?token={}", discovery_token), &router);
         assert_eq!(response.1, status::Ok);
-        let record: Domain = serde_json::from_str(&response.0).unwrap();
-        assert_eq!(record.token, token);
-        assert_eq!(record.name, "test.mydomain.org.");
-        assert_eq!(record.description, r#"test's server"#);
-
-        // Test the LE challenge endpoints.
-        assert_eq!(get("dnsconfig", &router), bad_request_error);
+        let found: DiscoveryRecord = serde_json::from_str(&response.0).unwrap();
         assert_eq!(
-            get("dnsconfig?token=wrong_token", &router),
-            bad_request_error
+            found,
+            DiscoveryRecord {
+                remote_name: "test.mydomain.org".to_owned(),
+                local_name: "local.test.mydomain.org".to_owned(),
+                local_ip: vec!["10.0.0.2".to_owned()],
+            }
         );
+        assert!(list(&token)[0].last_used_at > 0);
         assert_eq!(
-            get(&format!("dnsconfig?token={}", token), &router),
-            bad_request_error
+            get(&format!("discovery?token={}", token), &router),
+            error_response(ApiError::UnknownToken)
         );
         assert_eq!(
-            get(
-                "dnsconfig?token=wrong_token&challenge=test_challenge",
-                &router
-            ),
-            not_found_error
+            get(&format!("info?token={}", discovery_token), &router),
+            error_response(ApiError::UnknownToken)
         );
         assert_eq!(
             get(
-                &format!(
-                    "dnsconfig?token={}&challenge=abcdefghijklmnopqrstuvwxyz\
-                     abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyz",
-                    token
-                ),
+                &format!("discoverytokens?token={}", discovery_token),
                 &router
             ),
-            bad_request_error
+            error_response(ApiError::UnknownToken)
         );
+
+        // Only the hash of the token is stored.
+        let record = conn.get_domain_by_token(&token).unwrap();
+        let stored = conn.get_discovery_tokens(record.id, now()).unwrap();
+        assert_eq!(stored[0].token, auth_key(&discovery_token));
+
+        for i in 1..MAX_DISCOVERY_TOKENS {
+            let response = get(
+                &format!("adddiscovery?token={}&label=app{}", token, i),
+                &router,
+            );
+            assert_eq!(response.1, status::Ok);
+        }
         assert_eq!(
             get(
-                &format!("dnsconfig?token={}&challenge=test_challenge", token),
+                &format!("adddiscovery?token={}&label=one+more", token),
                 &router
             ),
-            empty_ok
+            error_response(ApiError::TooManyRecords)
         );
+        assert_eq!(list(&token).len(), MAX_DISCOVERY_TOKENS);
 
-        // Email routes tests
-        // 1. set an email address
-        assert_eq!(get("setemail", &router), bad_request_error);
-        assert_eq!(
-            get("setemail?token=wrong_token", &router),
-            bad_request_error
-        );
+        // Revoking needs the token of the domain, and ignores unknown ids.
         assert_eq!(
-            get("setemail?token=wrong_token&email=me@example.com", &router),
-            not_found_error
+            get(&format!("revokediscovery?token={}", token), &router),
+            error_response(ApiError::MissingParameter)
         );
         assert_eq!(
             get(
-                &format!("setemail?token={}&email=not_an_email", token),
+                &format!("revokediscovery?token={}&id=first", token),
                 &router
             ),
-            bad_request_error
+            error_response(ApiError::InvalidParameter)
         );
         assert_eq!(
             get(
-                &format!(
-                    "setemail?token={}&email=abc@{}com",
-                    token,
-                    std::iter::repeat("makeasuperlongfakedomain.")
-                        .take(10)
-                        .collect::<String>()
-                ),
+                &format!("revokediscovery?token={}&id={}", discovery_token, added.id),
                 &router
             ),
-            bad_request_error
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("revokediscovery?token={}&id=0", token), &router),
+            empty_ok
         );
+        assert_eq!(list(&token).len(), MAX_DISCOVERY_TOKENS);
         assert_eq!(
             get(
-                &format!("setemail?token={}&email={}", token, email),
+                &format!("revokediscovery?token={}&id={}", token, added.id),
                 &router
             ),
             empty_ok
         );
-        let record = conn.get_domain_by_token(&token).unwrap();
-        let account = conn.get_account_by_id(record.account_id).unwrap();
-        assert_eq!(account.email, email);
-        assert!(!record.verified);
-        let link = record.verification_token;
+        assert_eq!(
+            get(&format!("discovery?token={}", discovery_token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        let remaining = list(&token);
+        assert_eq!(remaining.len(), MAX_DISCOVERY_TOKENS - 1);
+        assert!(remaining.iter().all(|entry| entry.id != added.id));
 
-        // 2. verify the email
-        assert_eq!(get("verifyemail", &router), bad_request_error);
+        // The tokens are kept by an undeleted domain, and dropped by a
+        // transfer.
+        let response = get(
+            &format!("adddiscovery?token={}&label=phone", token),
+            &router,
+        );
+        let phone: DiscoveryTokenInfo = serde_json::from_str(&response.0).unwrap();
+        let phone_token = phone.token.unwrap();
         assert_eq!(
-            get("verifyemail?s=wrong_link", &router),
-            (config.options.email.error_page.unwrap(), status::NotFound)
+            get(&format!("unsubscribe?token={}", token), &router),
+            empty_ok
         );
         assert_eq!(
-            get(&format!("verifyemail?s={}", link), &router),
-            (config.options.email.success_page.unwrap(), status::Ok)
+            get(&format!("discovery?token={}", phone_token), &router),
+            error_response(ApiError::UnknownToken)
+        );
+        assert_eq!(
+            get(&format!("undelete?token={}", token), &router).1,
+            status::Ok
+        );
+        assert_eq!(list(&token).len(), MAX_DISCOVERY_TOKENS);
+        assert_eq!(
+            get(&format!("discovery?token={}", phone_token), &router).1,
+            status::Ok
         );
 
-        // 3. check that the email has been set on the domain record.
-        let domain_record = conn.get_domain_by_token(&token).unwrap();
-        assert!(domain_record.verified);
-
-        // 3a. Before revoking, finish testing domain reclamation.
+        let response = get(&format!("transfer?token={}", token), &router);
+        let transfer: TransferCode = serde_json::from_str(&response.0).unwrap();
+        let response = get(&format!("redeem?code={}", transfer.code), &router);
+        let redeemed: NameAndToken = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(list(&redeemed.token), vec![]);
         assert_eq!(
-            get(&format!("subscribe?name=test&email={}", email), &router),
-            (
-                r#"{"error": "UnavailableNameReclamationPossible"}"#.to_owned(),
-                status::BadRequest
-            )
+            get(&format!("discovery?token={}", phone_token), &router),
+            error_response(ApiError::UnknownToken)
         );
-        assert_eq!(get("reclaim?name=test", &router), empty_ok);
-        let domain_record = conn.get_domain_by_token(&token).unwrap();
-        let res = get("subscribe?name=test&reclamationToken=wrongtoken", &router);
+
+        // A token can expire, up to a year after it was added.
+        for ttl in &[0, MAX_DISCOVERY_TTL + 1] {
+            assert_eq!(
+                get(
+                    &format!(
+                        "adddiscovery?token={}&label=guest&ttl={}",
+                        redeemed.token, ttl
+                    ),
+                    &router
+                ),
+                error_response(ApiError::InvalidParameter)
+            );
+        }
+        let response = get(
+            &format!("adddiscovery?token={}&label=guest&ttl=3600", redeemed.token),
+            &router,
+        );
+        assert_eq!(response.1, status::Ok);
+        let guest: DiscoveryTokenInfo = serde_json::from_str(&response.0).unwrap();
+        assert_eq!(guest.expires_at, guest.created_at + 3600);
         assert_eq!(
-            res,
-            (
-                r#"{"error": "ReclamationTokenMismatch"}"#.to_owned(),
-                status::BadRequest
+            get(
+                &format!("discovery?token={}", guest.token.unwrap()),
+                &router
             )
+            .1,
+            status::Ok
         );
-        let res = get(
-            &format!(
-                "subscribe?name=test&reclamationToken={}",
-                &domain_record.reclamation_token
-            ),
-            &router,
+
+        // An expired token finds nothing, isn't listed, and is purged.
+        let record = conn.get_domain_by_token(&redeemed.token).unwrap();
+        conn.add_discovery_token(&NewDiscoveryToken {
+            domain_id: record.id,
+            token: &auth_key("expired"),
+            label: "expired",
+            created_at: now() - 60,
+            last_used_at: 0,
+            expires_at: now() - 1,
+        })
+        .unwrap();
+        assert_eq!(
+            get("discovery?token=expired", &router),
+            error_response(ApiError::UnknownToken)
         );
-        let registration: NameAndToken = serde_json::from_str(&res.0).unwrap();
+        let listed = list(&redeemed.token);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, guest.id);
+        assert_eq!(conn.purge_expired_discovery_tokens(now()), Ok(1));
+        assert_eq!(conn.purge_expired_discovery_tokens(now()), Ok(0));
+        assert_eq!(list(&redeemed.token).len(), 1);
+    }
+
+    // The in-memory database has a single connection, so a request that took
+    // a second one while holding the first would wait for db_timeout and fail.
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_memory_database_routes() {
+        use admin_routes::{create_api_key, AuditPage};
+        use database::MEMORY_PATH;
+        use iron_test::request;
+
+        let _ = env_logger::init();
+
+        let db = DatabasePool::with_options(MEMORY_PATH, 0, 1);
+        assert_eq!(db.run_migrations(), Ok(()));
+
+        let args = ArgsParser::from_vec(vec![
+            "registration_server",
+            "--config-file=./config/config.toml",
+        ]);
+        let config = Config::from_args_with_db(args, db.clone());
+        let chain = create_chain("/", &config);
+        let key = create_api_key("memory", &config).unwrap();
+        let get = |path: &str, bearer: Option<&str>| -> (String, Status) {
+            let mut headers = Headers::new();
+            if let Some(bearer) = bearer {
+                headers.set(Authorization(Bearer {
+                    token: bearer.to_owned(),
+                }));
+            }
+            let url = format!("http://localhost/{}", path);
+            let resp = match request::get(&url, headers, &chain) {
+                Ok(response) => response,
+                Err(err) => err.response,
+            };
+            let status = resp.status.unwrap();
+            (response::extract_body_to_string(resp), status)
+        };
+
+        // The audited requests, whose middleware takes its own connection
+        // before and after the handler.
+        let (body, status) = get("subscribe?name=memory&email=memory@example.com", None);
+        assert_eq!(status, status::Ok);
+        let registration: NameAndToken = serde_json::from_str(&body).unwrap();
         let token = registration.token;
-        assert_eq!(registration.name, "test".to_owned());
+        let ping = format!("ping?token={}&local_ip=10.0.0.2", token);
+        assert_eq!(get(&ping, None).1, status::Ok);
 
-        // 4. email revocation
-        assert_eq!(get("revokeemail", &router), bad_request_error);
+        // The routes that check an API key, in the handler or before it.
         assert_eq!(
-            get("revokeemail?token=wrong_token", &router),
-            not_found_error
+            get("domains?email=memory@example.com", Some(&key)).1,
+            status::Ok
         );
         assert_eq!(
-            get(&format!("revokeemail?token={}", token), &router),
-            empty_ok
+            get(&format!("unsubscribe?token={}", token), None).1,
+            status::Ok
         );
-
-        // 5. Verify the verification link is empty.
-        let record = conn.get_domain_by_token(&token).unwrap();
-        assert_eq!(record.verification_token, "");
-        assert!(!record.verified);
+        let (body, status) = get("admin/audit?name=memory", Some(&key));
+        assert_eq!(status, status::Ok);
+        let page: AuditPage = serde_json::from_str(&body).unwrap();
+        let actions: Vec<&str> = page
+            .events
+            .iter()
+            .map(|event| event.action.as_str())
+            .collect();
+        assert_eq!(actions, vec!["subscribe", "ping", "unsubscribe"]);
     }
 }
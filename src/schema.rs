@@ -2,6 +2,59 @@ table! {
     accounts (id) {
         id -> Integer,
         email -> Text,
+        oidc_subject -> Text,
+    }
+}
+
+table! {
+    api_keys (id) {
+        id -> Integer,
+        name -> Text,
+        key_hash -> Text,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Integer,
+        name -> Text,
+        action -> Text,
+        actor -> Text,
+        client_ip -> Text,
+        request_id -> Text,
+        details -> Text,
+        created_at -> BigInt,
+    }
+}
+
+table! {
+    banned_names (id) {
+        id -> Integer,
+        name -> Text,
+        banned_at -> BigInt,
+    }
+}
+
+table! {
+    deleted_domains (id) {
+        id -> Integer,
+        name -> Text,
+        token -> Text,
+        record -> Text,
+        deleted_at -> BigInt,
+    }
+}
+
+table! {
+    discovery_tokens (id) {
+        id -> Integer,
+        domain_id -> Integer,
+        token -> Text,
+        label -> Text,
+        created_at -> BigInt,
+        last_used_at -> BigInt,
+        expires_at -> BigInt,
     }
 }
 
@@ -18,9 +71,60 @@ table! {
         verification_token -> Text,
         verified -> Bool,
         continent -> Text,
+        local_ips -> Text,
+        warning_sent_at -> BigInt,
+        wildcard -> Bool,
+        challenge_set_at -> BigInt,
+        auth_key -> Text,
+        mx_target -> Text,
+        mx_priority -> Integer,
+        local_prefix -> Text,
+        device_token -> Text,
+        created_at -> BigInt,
+        updated_at -> BigInt,
+    }
+}
+
+table! {
+    srv_records (id) {
+        id -> Integer,
+        domain_id -> Integer,
+        service -> Text,
+        protocol -> Text,
+        priority -> Integer,
+        weight -> Integer,
+        port -> Integer,
+    }
+}
+
+table! {
+    transfers (id) {
+        id -> Integer,
+        domain_id -> Integer,
+        code -> Text,
+        expires_at -> BigInt,
+    }
+}
+
+table! {
+    txt_records (id) {
+        id -> Integer,
+        domain_id -> Integer,
+        value -> Text,
     }
 }
 
 joinable!(domains -> accounts (account_id));
 
-allow_tables_to_appear_in_same_query!(accounts, domains,);
+allow_tables_to_appear_in_same_query!(
+    accounts,
+    api_keys,
+    audit_events,
+    banned_names,
+    deleted_domains,
+    discovery_tokens,
+    domains,
+    srv_records,
+    transfers,
+    txt_records,
+);
@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Handles the signals sent to the server. SIGHUP reloads the configuration
+// file. SIGTERM and SIGINT stop the server: new API requests are turned away
+// while the ones being handled and the eviction sweep in progress finish, for
+// at most `shutdown_timeout` seconds, and the PowerDNS socket is removed. No transaction is left open once the
+// requests are done, so the database connections are simply closed when the
+// process exits.
+
+extern crate env_logger;
+use args::ArgsParser;
+use config::Config;
+use errors::*;
+use iron::prelude::*;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware};
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+use std::fs;
+use std::io;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    // Number of API requests being handled.
+    count: usize,
+    stopping: bool,
+}
+
+// Tracks the API requests being handled, so that the server can wait for them
+// before stopping. Shared by all the clones of the configuration.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight::default()
+    }
+
+    fn state(&self) -> MutexGuard<State> {
+        match self.state.0.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Counts a new request, unless the server is stopping. The background
+    // tasks that write to the database are counted too, so that the server
+    // waits for them.
+    pub fn start(&self) -> bool {
+        let mut state = self.state();
+        if state.stopping {
+            return false;
+        }
+        state.count += 1;
+        true
+    }
+
+    pub fn finish(&self) {
+        let mut state = self.state();
+        state.count = state.count.saturating_sub(1);
+        if state.count == 0 {
+            self.state.1.notify_all();
+        }
+    }
+
+    // Turns away the new requests and waits for the current ones to finish.
+    // Returns false if some are still running after the timeout.
+    pub fn stop(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        state.stopping = true;
+
+        while state.count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = match self.state.1.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        true
+    }
+}
+
+struct Tracked;
+
+impl Key for Tracked {
+    type Value = ();
+}
+
+// Counts the requests being handled, and answers with a ShuttingDown error
+// once the server is stopping. It has to be linked both before and after the
+// handlers.
+#[derive(Clone)]
+pub struct TrackRequests {
+    in_flight: InFlight,
+}
+
+impl TrackRequests {
+    pub fn new(in_flight: &InFlight) -> Self {
+        TrackRequests {
+            in_flight: in_flight.clone(),
+        }
+    }
+
+    fn finish(&self, req: &mut Request) {
+        if req.extensions.remove::<Tracked>().is_some() {
+            self.in_flight.finish();
+        }
+    }
+}
+
+impl BeforeMiddleware for TrackRequests {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if !self.in_flight.start() {
+            return EndpointError::with(ApiError::ShuttingDown).map(|_| ());
+        }
+        req.extensions.insert::<Tracked>(());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for TrackRequests {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.finish(req);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.finish(req);
+        Err(err)
+    }
+}
+
+// Handles a signal, and returns whether the server should keep running.
+pub fn handle_signal(signal: c_int, config: &Config, config_file: &Option<PathBuf>) -> bool {
+    if signal == SIGHUP {
+        match *config_file {
+            Some(ref path) => match ArgsParser::try_from_file(path) {
+                Ok(args) => {
+                    info!("Reloading the configuration from {:?}", path);
+                    config.reload(args);
+                }
+                Err(err) => error!("handle_signal(): Keeping the configuration: {}", err),
+            },
+            None => error!("handle_signal(): No configuration file to reload"),
+        }
+        return true;
+    }
+
+    info!("Stopping the server");
+    let current = config.current();
+    let timeout = Duration::from_secs(current.options.general.shutdown_timeout);
+    if !config.in_flight.stop(timeout) {
+        error!("handle_signal(): Stopping before all the requests are done");
+    }
+
+    let sockets = [
+        &current.options.pdns.socket_path,
+        &current.options.general.api_socket_path,
+    ];
+    for socket in &sockets {
+        if let Some(ref path) = **socket {
+            if let Err(err) = fs::remove_file(path) {
+                error!("handle_signal(): Failed to remove {}: {}", path, err);
+            }
+        }
+    }
+    false
+}
+
+// Handles the signals until the server has to stop.
+pub fn wait_for_signals(config: &Config, config_file: Option<PathBuf>) -> io::Result<()> {
+    let signals = Signals::new(&[SIGHUP, SIGINT, SIGTERM])?;
+    for signal in signals.forever() {
+        if !handle_signal(signal, config, &config_file) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_in_flight() {
+    let _ = env_logger::init();
+
+    let in_flight = InFlight::new();
+    assert!(in_flight.start());
+    assert!(in_flight.start());
+    in_flight.finish();
+
+    // A request is still running.
+    assert!(!in_flight.stop(Duration::from_millis(10)));
+    assert!(!in_flight.start());
+
+    // The stop waits for it to finish.
+    let running = in_flight.clone();
+    let finisher = ::std::thread::spawn(move || {
+        ::std::thread::sleep(Duration::from_millis(50));
+        running.finish();
+    });
+    assert!(in_flight.stop(Duration::from_secs(10)));
+    finisher.join().unwrap();
+}
+
+#[test]
+fn test_track_requests() {
+    use iron::{Chain, Headers};
+    use iron::status::Status;
+    use iron_test::{request, response};
+
+    let _ = env_logger::init();
+
+    let in_flight = InFlight::new();
+    let mut chain = Chain::new(|_: &mut Request| -> IronResult<Response> {
+        Ok(Response::with((Status::Ok, "done")))
+    });
+    let track = TrackRequests::new(&in_flight);
+    chain.link_before(track.clone());
+    chain.link_after(track);
+
+    let resp = request::get("http://localhost/", Headers::new(), &chain).unwrap();
+    assert_eq!(response::extract_body_to_string(resp), "done");
+    assert!(in_flight.stop(Duration::from_secs(0)));
+
+    let err = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+    assert_eq!(err.response.status, Some(Status::ServiceUnavailable));
+    assert_eq!(
+        response::extract_body_to_string(err.response),
+        "{\"code\":22,\"error\":\"ShuttingDown\",\"message\":\"The server is shutting down, try again later.\"}"
+    );
+}
@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Sends the metrics to a StatsD server over UDP, for the operators whose
+// monitoring doesn't scrape Prometheus, like Datadog. Each request and DNS
+// lookup is sent as it is recorded, eg. `registration.http_requests.ping.2xx:1|c`
+// and `registration.http_request_duration.ping:3.125|ms`. With `statsd_tags`,
+// the labels are sent as DogStatsD tags instead, eg.
+// `registration.http_requests:1|c|#route:ping,status:2xx`. The datagrams are
+// fire and forget, so a missing server never slows the requests down. The
+// number of domains is only served to Prometheus, as it is counted on scrapes.
+
+extern crate env_logger;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+pub struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+    tags: bool,
+}
+
+impl Statsd {
+    pub fn new(host: &str, port: u16, prefix: &str, tags: bool) -> Result<Self, String> {
+        let addr = (host, port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("Failed to resolve {}:{}", host, port))?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).map_err(|err| format!("Failed to bind: {}", err))?;
+        Ok(Statsd {
+            socket: socket,
+            addr: addr,
+            prefix: prefix.to_owned(),
+            tags: tags,
+        })
+    }
+
+    fn line(&self, name: &str, value: &str, kind: &str, labels: &[(&str, &str)]) -> String {
+        let mut name = format!("{}.{}", self.prefix, name);
+        if self.tags {
+            let tags: Vec<String> = labels
+                .iter()
+                .map(|&(label, value)| format!("{}:{}", label, value))
+                .collect();
+            return format!("{}:{}|{}|#{}", name, value, kind, tags.join(","));
+        }
+        for &(_, value) in labels {
+            name.push('.');
+            name.push_str(value);
+        }
+        format!("{}:{}|{}", name, value, kind)
+    }
+
+    fn send(&self, line: &str) {
+        if let Err(err) = self.socket.send_to(line.as_bytes(), self.addr) {
+            debug!("Failed to send {} to StatsD: {}", line, err);
+        }
+    }
+
+    pub fn count(&self, name: &str, labels: &[(&str, &str)]) {
+        self.send(&self.line(name, "1", "c", labels));
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration, labels: &[(&str, &str)]) {
+        let millis = duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1e6;
+        self.send(&self.line(name, &format!("{:.3}", millis), "ms", labels));
+    }
+}
+
+#[test]
+fn test_statsd() {
+    let _ = env_logger::init();
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    let receive = || {
+        let mut buf = [0; 512];
+        let size = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..size].to_vec()).unwrap()
+    };
+
+    let statsd = Statsd::new("127.0.0.1", port, "registration", false).unwrap();
+    statsd.count("http_requests", &[("route", "ping"), ("status", "2xx")]);
+    assert_eq!(receive(), "registration.http_requests.ping.2xx:1|c");
+    statsd.timing(
+        "http_request_duration",
+        Duration::from_micros(3125),
+        &[("route", "ping")],
+    );
+    assert_eq!(
+        receive(),
+        "registration.http_request_duration.ping:3.125|ms"
+    );
+
+    let statsd = Statsd::new("127.0.0.1", port, "gateways", true).unwrap();
+    statsd.count("dns_lookups", &[("result", "hit")]);
+    assert_eq!(receive(), "gateways.dns_lookups:1|c|#result:hit");
+
+    assert!(Statsd::new("invalid host name", port, "registration", false).is_err());
+}
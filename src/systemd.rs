@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Takes the listening sockets passed by systemd socket activation, see
+// sd_listen_fds(3). systemd keeps them open while the server restarts, so the
+// connections made in the meantime wait in their backlog instead of being
+// refused. The sockets are told apart by the FileDescriptorName= of their
+// .socket unit: `http`, `https`, `metrics` and `api` for the API socket are
+// served instead of binding the configured addresses, and `pdns` replaces the
+// PowerDNS socket. Without names, a single socket is the HTTP listener.
+
+extern crate env_logger;
+use std::collections::HashMap;
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::process;
+
+// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Default)]
+pub struct ListenFds {
+    fds: HashMap<String, RawFd>,
+}
+
+// Returns the file descriptors by name, if they are meant for this process.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<HashMap<String, RawFd>, String> {
+    let mut fds = HashMap::new();
+    let (pid, count) = match (pid, count) {
+        (Some(pid), Some(count)) => (pid, count),
+        _ => return Ok(fds),
+    };
+    if pid.parse::<u32>() != Ok(own_pid) {
+        return Ok(fds);
+    }
+    let count = count
+        .parse::<RawFd>()
+        .map_err(|_| format!("Invalid LISTEN_FDS: {}", count))?;
+
+    let names: Vec<&str> = match names {
+        Some(names) => names.split(':').collect(),
+        None if count == 1 => vec!["http"],
+        None => vec![],
+    };
+    if names.len() != count as usize {
+        return Err(format!(
+            "{} sockets were passed, but LISTEN_FDNAMES has {} names",
+            count,
+            names.len()
+        ));
+    }
+    for (index, name) in names.into_iter().enumerate() {
+        if fds
+            .insert(name.to_owned(), LISTEN_FDS_START + index as RawFd)
+            .is_some()
+        {
+            return Err(format!("The {} socket was passed twice", name));
+        }
+    }
+    Ok(fds)
+}
+
+impl ListenFds {
+    // Reads the sockets passed to the process. The variables are removed, so
+    // that the processes it starts don't take them.
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok();
+        let result = parse_listen_fds(
+            var("LISTEN_PID").as_ref().map(String::as_str),
+            var("LISTEN_FDS").as_ref().map(String::as_str),
+            var("LISTEN_FDNAMES").as_ref().map(String::as_str),
+            process::id(),
+        );
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+
+        match result {
+            Ok(fds) => {
+                if !fds.is_empty() {
+                    info!("Using the sockets passed by systemd: {:?}", fds);
+                }
+                ListenFds { fds: fds }
+            }
+            Err(err) => {
+                error!("Ignoring the sockets passed by systemd: {}", err);
+                ListenFds::default()
+            }
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fds.contains_key(name)
+    }
+
+    // The sockets can only be taken once, as the listeners own them.
+    pub fn take_tcp(&mut self, name: &str) -> Option<TcpListener> {
+        self.fds
+            .remove(name)
+            .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+    }
+
+    pub fn take_unix(&mut self, name: &str) -> Option<UnixListener> {
+        self.fds
+            .remove(name)
+            .map(|fd| unsafe { UnixListener::from_raw_fd(fd) })
+    }
+}
+
+#[test]
+fn test_parse_listen_fds() {
+    let _ = env_logger::init();
+
+    let fds = |values: &[(&str, RawFd)]| -> HashMap<String, RawFd> {
+        values
+            .iter()
+            .map(|&(name, fd)| (name.to_owned(), fd))
+            .collect()
+    };
+
+    // Nothing passed, or passed to another process.
+    assert_eq!(parse_listen_fds(None, None, None, 42), Ok(fds(&[])));
+    assert_eq!(
+        parse_listen_fds(Some("41"), Some("1"), None, 42),
+        Ok(fds(&[]))
+    );
+
+    assert_eq!(
+        parse_listen_fds(Some("42"), Some("1"), None, 42),
+        Ok(fds(&[("http", 3)]))
+    );
+    assert_eq!(
+        parse_listen_fds(Some("42"), Some("3"), Some("http:metrics:pdns"), 42),
+        Ok(fds(&[("http", 3), ("metrics", 4), ("pdns", 5)]))
+    );
+
+    assert!(parse_listen_fds(Some("42"), Some("two"), None, 42).is_err());
+    assert!(parse_listen_fds(Some("42"), Some("2"), None, 42).is_err());
+    assert!(parse_listen_fds(Some("42"), Some("2"), Some("http"), 42).is_err());
+    assert!(parse_listen_fds(Some("42"), Some("2"), Some("http:http"), 42).is_err());
+}
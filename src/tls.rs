@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Terminates TLS in the server itself, so that it doesn't need a proxy in
+// front of it. The HTTPS listener uses the PEM certificate chain and private
+// key set with `tls_certificate` and `tls_key`, eg. the files written by an
+// ACME client like certbot. The HTTP-01 challenges of this client are served
+// by the plain listener from `acme_webroot`, so the certificates can be issued
+// and renewed without stopping the server. A renewed certificate is used after
+// the next restart.
+
+extern crate env_logger;
+use hyper_rustls::TlsServer;
+use iron::headers::ContentType;
+use iron::prelude::*;
+use iron::status;
+use iron::Handler;
+use rustls::internal::pemfile;
+use rustls::{Certificate, PrivateKey};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+// The path under which the ACME server looks for the HTTP-01 challenges.
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Failed to open {}: {}", path.display(), err))
+}
+
+// Reads the certificate chain of a PEM file, the certificate of the server
+// first.
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+    match pemfile::certs(&mut open(path)?) {
+        Ok(ref certificates) if certificates.is_empty() => {
+            Err(format!("No certificate in {}", path.display()))
+        }
+        Ok(certificates) => Ok(certificates),
+        Err(_) => Err(format!("Invalid certificate in {}", path.display())),
+    }
+}
+
+// Reads the private key of a PEM file, in the PKCS#8 or the RSA format.
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    match pkcs8.into_iter().chain(rsa).next() {
+        Some(key) => Ok(key),
+        None => Err(format!("No private key in {}", path.display())),
+    }
+}
+
+// Returns the TLS configuration of the HTTPS listener.
+pub fn tls_server(certificate: &Path, key: &Path) -> Result<TlsServer, String> {
+    Ok(TlsServer::new(
+        load_certificates(certificate)?,
+        load_key(key)?,
+    ))
+}
+
+// The tokens of the challenges are base64url strings, which keeps the requests
+// inside the directory.
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+// Serves the files written by the ACME client in the challenge directory.
+pub struct AcmeChallenges {
+    directory: PathBuf,
+}
+
+impl AcmeChallenges {
+    pub fn new(directory: &Path) -> Self {
+        AcmeChallenges {
+            directory: directory.to_path_buf(),
+        }
+    }
+}
+
+impl Handler for AcmeChallenges {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        // The handler is mounted under the challenge path, so what is left
+        // is the token.
+        let token = req.url.path().join("/");
+        if !is_valid_token(&token) {
+            return Ok(Response::with(status::NotFound));
+        }
+
+        let mut content = String::new();
+        match File::open(self.directory.join(&token)) {
+            Ok(mut file) => match file.read_to_string(&mut content) {
+                Ok(_) => {
+                    info!("GET {}{}", ACME_CHALLENGE_PATH, token);
+                    let mut response = Response::with((status::Ok, content));
+                    response.headers.set(ContentType::plaintext());
+                    Ok(response)
+                }
+                Err(err) => {
+                    error!("Failed to read the challenge {}: {}", token, err);
+                    Ok(Response::with(status::InternalServerError))
+                }
+            },
+            Err(_) => Ok(Response::with(status::NotFound)),
+        }
+    }
+}
+
+#[test]
+fn test_load_certificates() {
+    let _ = env_logger::init();
+
+    let directory = PathBuf::from("./test-data/tls");
+    let certificate = directory.join("certificate.pem");
+    assert_eq!(load_certificates(&certificate).unwrap().len(), 1);
+    assert!(load_key(&directory.join("key.pem")).is_ok());
+    assert!(load_key(&directory.join("rsa_key.pem")).is_ok());
+    assert!(tls_server(&certificate, &directory.join("key.pem")).is_ok());
+
+    // Missing files, and files without what is looked for.
+    assert!(load_certificates(&directory.join("missing.pem")).is_err());
+    assert!(load_certificates(&directory.join("key.pem")).is_err());
+    assert!(load_key(&certificate).is_err());
+}
+
+#[test]
+fn test_acme_challenges() {
+    use iron::Headers;
+    use iron_test::{request, response};
+    use mount::Mount;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let _ = env_logger::init();
+
+    let directory = env::temp_dir().join("registration_server_acme_challenges");
+    fs::create_dir_all(&directory).unwrap();
+    File::create(directory.join("token-1_A"))
+        .unwrap()
+        .write_all(b"token-1_A.thumbprint")
+        .unwrap();
+
+    let mut mount = Mount::new();
+    mount.mount(ACME_CHALLENGE_PATH, AcmeChallenges::new(&directory));
+    let get = |path: &str| {
+        let url = format!("http://localhost{}{}", ACME_CHALLENGE_PATH, path);
+        let response = request::get(&url, Headers::new(), &mount).unwrap();
+        let status = response.status.unwrap();
+        (response::extract_body_to_string(response), status)
+    };
+
+    assert_eq!(
+        get("token-1_A"),
+        ("token-1_A.thumbprint".to_owned(), status::Ok)
+    );
+    assert_eq!(get("token-2").1, status::NotFound);
+    assert_eq!(get("").1, status::NotFound);
+    assert_eq!(get("..%2Fkey.pem").1, status::NotFound);
+    assert_eq!(get("sub/token-1_A").1, status::NotFound);
+
+    fs::remove_dir_all(&directory).unwrap();
+}